@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use crate::config::{AndroidConfig, Config, ConfigError};
+use crate::ctx;
+use crate::device::{ConfiguredDevice, Device, DeviceKind};
+use crate::mountable::Mountable;
+use crate::ptp_device::{self, ObjectInfo, PtpConnection, PtpFile, PtpSession, FORMAT_ASSOCIATION, SIZE_UNKNOWN};
+use crate::staging::StageFromDevice;
+
+use failure::Error;
+
+#[cfg(feature = "usb")]
+use ptp;
+#[cfg(not(feature = "usb"))]
+use crate::dummy_ptp as ptp;
+
+/// The folder phones keep their camera's files in, at the root of each storage.
+const DCIM: &str = "DCIM";
+
+/// An Android phone that's attached, along with which of its files we want.
+#[derive(Debug)]
+pub struct AndroidPhone<'a> {
+    camera: ptp_device::PtpCamera<'a>,
+    extensions: Vec<String>,
+}
+
+/// An Android phone with an MTP session open on it.
+#[derive(Debug)]
+pub struct AndroidConnection<'a> {
+    connection: PtpConnection<'a>,
+    extensions: Vec<String>,
+}
+
+impl<'a> Mountable for AndroidPhone<'a> {
+    type Target = AndroidConnection<'a>;
+
+    fn mount(self) -> Result<AndroidConnection<'a>, Error> {
+        Ok(AndroidConnection {
+            connection: self.camera.mount()?,
+            extensions: self.extensions,
+        })
+    }
+}
+
+impl<'a> StageFromDevice for AndroidConnection<'a> {
+    type FileType = PtpFile<ptp::PtpCamera<'a>>;
+
+    fn files(&self) -> Result<Vec<PtpFile<ptp::PtpCamera<'a>>>, Error> {
+        let files = dcim_files(self.connection.session(), &self.extensions)?;
+        info!("Loaded {} files from {:?}", files.len(), &self.connection);
+        Ok(files)
+    }
+}
+
+/// Find the files under `DCIM` in every storage on the device, that have one of `extensions`.
+pub fn dcim_files<S: PtpSession>(
+    session: &Rc<Mutex<S>>,
+    extensions: &[String],
+) -> Result<Vec<PtpFile<S>>, Error> {
+    let mut objects = vec![];
+    {
+        let mut session = session.lock().unwrap();
+        for storage_id in session.storage_ids()? {
+            for handle in session.object_handles(storage_id, None)? {
+                let object = session.object_info(handle)?;
+                if object.format == FORMAT_ASSOCIATION && object.filename.eq_ignore_ascii_case(DCIM) {
+                    walk_folder(&mut *session, storage_id, handle, &mut objects)?;
+                }
+            }
+        }
+    }
+
+    Ok(objects
+        .into_iter()
+        .filter(|(_, object)| has_extension(&object.filename, extensions))
+        .filter(|(_, object)| {
+            if object.size == SIZE_UNKNOWN {
+                warn!("Leaving {}, it's too large to copy over MTP", &object.filename);
+                return false;
+            }
+            true
+        })
+        .map(|(handle, object)| {
+            let file = PtpFile::new(handle, object, session);
+            trace!("Adding {:?} to the plan", &file);
+            file
+        })
+        .collect())
+}
+
+/// Collect every object under `folder`, skipping hidden ones like `.thumbnails`.
+fn walk_folder<S: PtpSession>(
+    session: &mut S,
+    storage_id: u32,
+    folder: u32,
+    out: &mut Vec<(u32, ObjectInfo)>,
+) -> Result<(), Error> {
+    for handle in session.object_handles(storage_id, Some(folder))? {
+        let object = session.object_info(handle)?;
+        if object.filename.starts_with('.') {
+            continue;
+        }
+        if object.format == FORMAT_ASSOCIATION {
+            walk_folder(session, storage_id, handle, out)?;
+        } else {
+            out.push((handle, object));
+        }
+    }
+    Ok(())
+}
+
+fn has_extension(filename: &str, extensions: &[String]) -> bool {
+    Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.iter().any(|wanted| wanted.eq_ignore_ascii_case(ext)))
+}
+
+/// Android phones, which we talk MTP to and stage the media from under `DCIM`.
+#[derive(Debug)]
+pub struct AndroidDevices;
+
+impl AndroidDevices {
+    fn configs(cfg: &Config) -> Result<Vec<AndroidConfig>, ConfigError> {
+        let configs: Vec<AndroidConfig> = cfg.device_entries(AndroidDevices.name())?;
+        if configs.iter().any(|phone| phone.extensions.as_ref().is_some_and(Vec::is_empty)) {
+            return Err(ConfigError::InvalidDevice(
+                AndroidDevices.name().into(),
+                "extensions can't be empty".into(),
+            ));
+        }
+        Ok(configs)
+    }
+}
+
+impl DeviceKind for AndroidDevices {
+    fn name(&self) -> &'static str {
+        "android"
+    }
+
+    fn configured(&self, cfg: &Config) -> Result<Vec<ConfiguredDevice>, ConfigError> {
+        Ok(AndroidDevices::configs(cfg)?
+            .into_iter()
+            .map(|phone| ConfiguredDevice {
+                name: phone.name,
+                timezone: phone.timezone.unwrap_or_else(|| "local".into()),
+                clock_skew_secs: phone.clock_skew_secs,
+            })
+            .collect())
+    }
+
+    fn locate<'a>(&self, ctx: &'a ctx::Ctx) -> Result<Vec<Device<'a>>, Error> {
+        // Like PTP cameras, opening each phone to ask for its serial is slow.
+        let mut searches: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for config in AndroidDevices::configs(&ctx.cfg)? {
+            searches.entry((config.vendor_id, config.product_id)).or_default().push(config);
+        }
+
+        let mut devices = vec![];
+        for ((vendor_id, product_id), configs) in searches {
+            for (_, camera) in ptp_device::locate_cameras(ctx, vendor_id, product_id, None)? {
+                if let Some(config) = configs.iter().find(|config| config.serial == camera.serial) {
+                    let phone = AndroidPhone {
+                        camera,
+                        extensions: config.extensions.clone().unwrap_or_else(|| vec!["mp4".into()]),
+                    };
+                    devices.push(Device::new(self.name(), config.name.clone(), phone));
+                }
+            }
+        }
+        Ok(devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dummy_ptp::{self, PtpObjectInfo};
+    use crate::staging::{DateTimeUploadable, Stager};
+    use crate::test_helpers;
+
+    use chrono::prelude::*;
+
+    fn folder(name: &str) -> PtpObjectInfo {
+        PtpObjectInfo {
+            Filename: name.into(),
+            ObjectFormat: FORMAT_ASSOCIATION,
+            ..Default::default()
+        }
+    }
+
+    fn video(name: &str, date: &str) -> PtpObjectInfo {
+        PtpObjectInfo {
+            Filename: name.into(),
+            CaptureDate: date.into(),
+            ObjectCompressedSize: 5,
+            ObjectFormat: ptp_device::FORMAT_MTP_MP4,
+            ..Default::default()
+        }
+    }
+
+    /// A phone with a couple of videos and a photo in its camera folder, and an SD card with
+    /// another video on it.
+    fn phone() -> (dummy_ptp::PtpCamera<'static>, Vec<u32>) {
+        let mut phone = dummy_ptp::PtpCamera::new();
+        phone.add_storage(0x0001_0001);
+        phone.add_storage(0x0002_0001);
+
+        let dcim = phone.add_object(0x0001_0001, None, folder("DCIM"), vec![]);
+        let camera = phone.add_object(0x0001_0001, Some(dcim), folder("Camera"), vec![]);
+        let mut videos = vec![
+            phone.add_object(0x0001_0001, Some(camera), video("PXL_20190601_093000.mp4", "20190601T093000"), b"exit1".to_vec()),
+            phone.add_object(0x0001_0001, Some(camera), video("PXL_20190601_110000.MP4", "20190601T110000.0"), b"exit2".to_vec()),
+        ];
+        phone.add_object(0x0001_0001, Some(camera), PtpObjectInfo {
+            Filename: "PXL_20190601_093500.jpg".into(),
+            ObjectFormat: ptp_device::FORMAT_EXIF_JPEG,
+            ..Default::default()
+        }, vec![]);
+        let thumbnails = phone.add_object(0x0001_0001, Some(dcim), folder(".thumbnails"), vec![]);
+        phone.add_object(0x0001_0001, Some(thumbnails), video("thumb.mp4", "20190601T093000"), vec![]);
+        // Only files under DCIM are media.
+        phone.add_object(0x0001_0001, None, video("whatsapp.mp4", "20190601T120000"), vec![]);
+
+        // Not every phone fills in when it captured something.
+        let card_dcim = phone.add_object(0x0002_0001, None, folder("DCIM"), vec![]);
+        videos.push(phone.add_object(0x0002_0001, Some(card_dcim), PtpObjectInfo {
+            ModificationDate: "20190602T080000".into(),
+            ..video("VID_0003.mp4", "")
+        }, b"exit3".to_vec()));
+
+        (phone, videos)
+    }
+
+    fn extensions() -> Vec<String> {
+        vec!["mp4".into()]
+    }
+
+    #[test]
+    fn test_finds_files_under_dcim() {
+        let (phone, _) = phone();
+        let session = Rc::new(Mutex::new(phone));
+        let files = dcim_files(&session, &extensions()).unwrap();
+
+        let dates: Vec<_> = files.iter().map(|file| file.capture_datetime().unwrap()).collect();
+        assert_eq!(dates, vec![
+            NaiveDate::from_ymd(2019, 6, 1).and_hms(9, 30, 0),
+            NaiveDate::from_ymd(2019, 6, 1).and_hms(11, 0, 0),
+            NaiveDate::from_ymd(2019, 6, 2).and_hms(8, 0, 0),
+        ]);
+        for file in &files {
+            assert_eq!(file.extension(), "mp4");
+        }
+
+        let files = dcim_files(&session, &["jpg".into()]).unwrap();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn test_staging_deletes_files() {
+        let (phone, videos) = phone();
+        let session = Rc::new(Mutex::new(phone));
+        let stager = test_helpers::temp_stager();
+
+        for file in dcim_files(&session, &extensions()).unwrap() {
+            stager.stage(file, "pixel").unwrap();
+        }
+
+        // A file and a manifest for each video
+        assert_eq!(test_helpers::count_files(stager.staging_location()), 6);
+        for video in videos {
+            assert!(!session.lock().unwrap().has_object(video));
+        }
+    }
+
+    #[test]
+    fn test_leaves_files_over_4gb() {
+        let (mut phone, videos) = phone();
+        let dcim = phone.get_objecthandles(0x0001_0001, ptp_device::ROOT_OBJECTS, None, None).unwrap()[0];
+        let huge = phone.add_object(0x0001_0001, Some(dcim), PtpObjectInfo {
+            ObjectCompressedSize: SIZE_UNKNOWN,
+            ..video("PXL_20190601_120000.mp4", "20190601T120000")
+        }, b"the first 4GB".to_vec());
+        let session = Rc::new(Mutex::new(phone));
+        let stager = test_helpers::temp_stager();
+
+        let files = dcim_files(&session, &extensions()).unwrap();
+        assert_eq!(files.len(), videos.len());
+        for file in files {
+            stager.stage(file, "pixel").unwrap();
+        }
+
+        assert_eq!(test_helpers::count_files(stager.staging_location()), 6);
+        assert!(session.lock().unwrap().has_object(huge));
+    }
+
+    #[test]
+    fn test_preserving_files() {
+        let (phone, videos) = phone();
+        let session = Rc::new(Mutex::new(phone));
+
+        let stager = Stager::preserving(test_helpers::tempdir());
+        for file in dcim_files(&session, &extensions()).unwrap() {
+            stager.stage(file, "pixel").unwrap();
+        }
+        assert_eq!(test_helpers::count_files(stager.staging_location()), 6);
+
+        for video in videos {
+            assert!(session.lock().unwrap().has_object(video));
+        }
+    }
+
+    #[test]
+    fn test_android_config() {
+        let config = |phone: &str| format!(r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+[dropbox]
+token = "TOKEN"
+
+[[device.android]]
+name = "pixel"
+vendor_id = 0x18d1
+serial = "8XV7N18B17000000"
+{}
+"#, phone);
+        let cfg: Config = config("timezone = \"utc\"").parse().unwrap();
+        let phones = AndroidDevices::configs(&cfg).unwrap();
+        assert_eq!(phones[0].vendor_id, 0x18d1);
+        assert_eq!(phones[0].extensions, None);
+        let devices = AndroidDevices.configured(&cfg).unwrap();
+        assert_eq!(devices[0].name, "pixel");
+        assert_eq!(devices[0].timezone, "utc");
+
+        let err = config("extensions = []").parse::<Config>().unwrap_err();
+        assert_eq!(err, ConfigError::InvalidDevice("android".into(), "extensions can't be empty".into()));
+    }
+}
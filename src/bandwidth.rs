@@ -0,0 +1,196 @@
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+
+/// The most we read at once, so that slow limits are paced out rather than sent in bursts.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A token bucket, shared between every reader that should be limited together.
+///
+/// Readers are allowed to overdraw the bucket, and then have to wait until it's paid back.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    available: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                available: bytes_per_sec as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Account for `bytes` having been read, returning how long to wait before reading more.
+    fn consume(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+        let rate = self.bytes_per_sec as f64;
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        // Never bank more than a second's worth, or an idle limiter would allow a huge burst.
+        bucket.available = (bucket.available + refill).min(rate);
+        bucket.updated = now;
+        bucket.available -= bytes as f64;
+        if bucket.available >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-bucket.available / rate)
+        }
+    }
+}
+
+/// The set of limits that apply to uploads for a given adaptor.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl Throttle {
+    /// Also apply `limiter`, which may well be shared with other throttles.
+    pub fn limited_by(mut self, limiter: Arc<RateLimiter>) -> Throttle {
+        self.limiters.push(limiter);
+        self
+    }
+
+    pub fn is_limited(&self) -> bool {
+        !self.limiters.is_empty()
+    }
+
+    pub fn wrap<R: Read>(&self, inner: R) -> ThrottledReader<R> {
+        ThrottledReader {
+            inner,
+            limiters: self.limiters.clone(),
+        }
+    }
+}
+
+/// A reader that doesn't go any faster than its limits allow.
+#[derive(Debug)]
+pub struct ThrottledReader<R> {
+    inner: R,
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.limiters.is_empty() {
+            return self.inner.read(buf);
+        }
+        let len = buf.len().min(CHUNK_SIZE);
+        let read = self.inner.read(&mut buf[..len])?;
+        let delay = self
+            .limiters
+            .iter()
+            .map(|limiter| limiter.consume(read))
+            .max()
+            .unwrap_or_else(|| Duration::from_secs(0));
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+        Ok(read)
+    }
+}
+
+/// A time of day during which we're allowed to upload.
+///
+/// Windows that end before they start wrap around midnight, and a window that ends when it starts
+/// covers the whole day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl UploadWindow {
+    /// Parse a window from a pair of times like `19:00`.
+    pub fn parse(start: &str, end: &str) -> Result<UploadWindow, chrono::ParseError> {
+        Ok(UploadWindow {
+            start: NaiveTime::parse_from_str(start, "%H:%M")?,
+            end: NaiveTime::parse_from_str(end, "%H:%M")?,
+        })
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else if self.start > self.end {
+            time >= self.start || time < self.end
+        } else {
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_limiter_makes_readers_wait() {
+        let limiter = RateLimiter::new(1000);
+        // The first second's worth is free.
+        assert_eq!(limiter.consume(1000), Duration::from_secs(0));
+        let delay = limiter.consume(500);
+        assert!(delay > Duration::from_millis(400), "{:?}", delay);
+        assert!(delay <= Duration::from_millis(500), "{:?}", delay);
+    }
+
+    #[test]
+    fn test_unlimited_reader_passes_through() {
+        let throttle: Throttle = Default::default();
+        assert!(!throttle.is_limited());
+        let mut out = vec![];
+        throttle.wrap(Cursor::new(vec![0x42; 100_000])).read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 100_000);
+    }
+
+    #[test]
+    fn test_throttled_reader_reads_everything() {
+        let throttle = Throttle::default().limited_by(Arc::new(RateLimiter::new(1024 * 1024)));
+        let mut out = vec![];
+        throttle.wrap(Cursor::new(vec![0x42; 100_000])).read_to_end(&mut out).unwrap();
+        assert_eq!(out, vec![0x42; 100_000]);
+    }
+
+    #[test]
+    fn test_upload_windows() {
+        let evening = UploadWindow::parse("19:00", "23:30").unwrap();
+        assert!(evening.contains(time("19:00")));
+        assert!(evening.contains(time("21:15")));
+        assert!(!evening.contains(time("23:30")));
+        assert!(!evening.contains(time("12:00")));
+
+        let overnight = UploadWindow::parse("19:00", "07:00").unwrap();
+        assert!(overnight.contains(time("23:59")));
+        assert!(overnight.contains(time("03:00")));
+        assert!(!overnight.contains(time("07:00")));
+        assert!(!overnight.contains(time("15:00")));
+
+        let always = UploadWindow::parse("00:00", "00:00").unwrap();
+        assert!(always.contains(time("15:00")));
+
+        assert!(UploadWindow::parse("7pm", "07:00").is_err());
+    }
+}
@@ -37,7 +37,7 @@ fn main() {
             Ctx::create_without_lock(cfg?)?
         };
 
-        let devices = device::attached_devices(&ctx)?;
+        let (devices, _) = device::attached_devices(&ctx);
 
         info!("Attached devices:");
         for device in &devices {
@@ -52,10 +52,11 @@ fn main() {
         }
         info!("");
 
+        let clocks = ctx.cfg.device_clocks();
         for device in devices {
             info!("Device: {}", device.name());
-            for file in device.mass_storage_files()? {
-                info!("  {:?}", &file);
+            for (_, desc) in device.mount()?.descriptors(&clocks)? {
+                info!("  {:?}", &desc);
             }
         }
 
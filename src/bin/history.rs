@@ -0,0 +1,79 @@
+use clap::{App, Arg};
+
+use stokepile::formatting::human_readable_size;
+use stokepile::history::{HistoryEntry, HistoryEvent, UploadHistory};
+
+fn cli_opts<'a, 'b>(base: App<'a, 'b>) -> App<'a, 'b> {
+    base.about("Shows what has been staged and uploaded")
+        .arg(
+            Arg::with_name("device")
+            .long("device")
+            .takes_value(true)
+            .help("Only show files from this device")
+        )
+        .arg(
+            Arg::with_name("hash")
+            .long("hash")
+            .takes_value(true)
+            .help("Only show files whose content hash starts with this")
+        )
+        .arg(
+            Arg::with_name("backend")
+            .long("backend")
+            .takes_value(true)
+            .help("Only show uploads to this backend, by name or id")
+        )
+        .arg(
+            Arg::with_name("failed")
+            .long("failed")
+            .help("Only show uploads that failed")
+        )
+}
+
+fn describe(entry: &HistoryEntry) -> String {
+    let file = format!(
+        "{} {} ({}b) from {}",
+        &entry.content_hash[..12],
+        entry.remote_path.display(),
+        human_readable_size(entry.size),
+        entry.device
+    );
+    match &entry.event {
+        HistoryEvent::Staged => format!("staged {}", file),
+        HistoryEvent::Uploaded { backend, result, remote_id, .. } => match remote_id {
+            Some(id) => format!("{} {}: {} as {}", backend, file, result, id),
+            None => format!("{} {}: {}", backend, file, result),
+        },
+    }
+}
+
+fn main() {
+    stokepile::cli::run(cli_opts, |matches| {
+        let history = UploadHistory::in_home()?;
+
+        for entry in history.entries()? {
+            if let Some(device) = matches.value_of("device") {
+                if entry.device != device {
+                    continue;
+                }
+            }
+            if let Some(hash) = matches.value_of("hash") {
+                if !entry.content_hash.starts_with(&hash.to_lowercase()) {
+                    continue;
+                }
+            }
+            let wanted = match &entry.event {
+                HistoryEvent::Staged => !matches.is_present("backend") && !matches.is_present("failed"),
+                HistoryEvent::Uploaded { backend, backend_id, succeeded, .. } => {
+                    matches.value_of("backend").is_none_or(|b| b == backend || backend_id.as_deref() == Some(b))
+                        && !(matches.is_present("failed") && *succeeded)
+                }
+            };
+            if wanted {
+                println!("{} {}", entry.time.format("%Y-%m-%d %H:%M:%S"), describe(&entry));
+            }
+        }
+
+        Ok(())
+    })
+}
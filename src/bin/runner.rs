@@ -1,14 +1,17 @@
 #[macro_use]
 extern crate log;
 
+use chrono::prelude::*;
 use clap::{App, Arg};
 
 use stokepile::config;
 use stokepile::ctx::Ctx;
 use stokepile::device;
+use stokepile::formatting::human_readable_size;
+use stokepile::history::UploadHistory;
 use stokepile::mailer::MailReport;
 use stokepile::mountable::Mountable;
-use stokepile::staging::Stager;
+use stokepile::staging::{Staged, Stager, StagingError, StagingLocation};
 use stokepile::storage;
 
 use std::io;
@@ -38,7 +41,7 @@ fn main() {
             Ctx::create_without_lock(cfg?)?
         };
 
-        let devices = device::attached_devices(&ctx)?;
+        let (devices, unlocated) = device::attached_devices(&ctx);
 
         info!("Attached devices:");
         for device in &devices {
@@ -56,34 +59,113 @@ fn main() {
         let staging_location = ctx.staging().mount()?;
         info!("Staging to {:?}", &staging_location);
 
-        let stager = match ctx.cfg.preserve_device_files() {
+        let mut stager = match ctx.cfg.preserve_device_files() {
             true => Stager::preserving(staging_location),
             false => Stager::destructive(staging_location),
         };
+        if ctx.cfg.verify_staging() {
+            stager = stager.verifying();
+        }
+        stager = stager
+            .deduplicating_within(ctx.cfg.dedupe_window())
+            .with_path_templates(ctx.cfg.path_templates(None))
+            .with_device_clocks(ctx.cfg.device_clocks())
+            .with_router(ctx.cfg.router());
+        let migrated = stager.migrate_manifests()?;
+        if migrated > 0 {
+            info!("Migrated {} manifests from an older version", migrated);
+        }
+        match stager.prune_index() {
+            Ok(0) => {},
+            Ok(pruned) => info!("Forgot {} stale entries in the staging index", pruned),
+            Err(e) => warn!("Couldn't prune the staging index: {:?}", e),
+        }
 
+        let history = UploadHistory::in_home()?;
+
+        let notify = |msg: String| {
+            if let Err(e) = ctx.notify(&msg) {
+                error!("Failed to send push notification: {:?}", e);
+            }
+        };
+
+        for (kind, _) in &unlocated {
+            notify(format!("Couldn't look for {} devices, their files were left on them", kind));
+        }
+
+        let mut mounted = vec![];
+        let mut failed_devices = unlocated;
         for device in devices {
-            let finish_msg = format!("Finished staging: {}", device.name());
-            let incomplete_msg = format!("Partially staged {}, device will need a second run", device.name());
-            let notify = |msg: String| {
-                if let Err(e) = ctx.notify(&msg) {
-                    error!("Failed to send push notification: {:?}", e);
+            let name = device.name().to_string();
+            match device.mount() {
+                Ok(device) => mounted.push(device),
+                // Everything else can still be staged, and this one's files are safe where they are.
+                Err(e) => {
+                    error!("Couldn't mount {}: {:?}", &name, e);
+                    notify(format!("Couldn't mount {}, its files were left on it", &name));
+                    failed_devices.push((name, e));
                 }
-            };
-            match device.stage_files(&stager) {
-                Ok(num_files) => {
-                    if num_files > 0 {
-                        notify(finish_msg);
+            }
+        }
+
+        let available = stager.staging_location().available_space()?;
+        let (plan, unplanned) = device::plan_staging(&mounted, available, &ctx.cfg);
+        let mut planned = vec![true; mounted.len()];
+        for (i, e) in unplanned {
+            let name = mounted[i].name().to_string();
+            error!("Couldn't plan staging from {}: {:?}", &name, e);
+            notify(format!("Couldn't read {}, its files were left on it", &name));
+            planned[i] = false;
+            failed_devices.push((name, e));
+        }
+        info!("Staging {} files into {} of free space", plan.staged().len(), human_readable_size(available));
+        if !plan.deferred().is_empty() {
+            warn!("Not enough room to stage {} files ({}), leaving them on their devices",
+                  plan.deferred().len(), human_readable_size(plan.deferred_size()));
+        }
+        for file in plan.deferred() {
+            info!("  Deferring {}", file.desc.staging_name());
+        }
+
+        let mut remaining = vec![0; mounted.len()];
+        for file in plan.staged().iter().chain(plan.deferred()) {
+            remaining[file.device] += 1;
+        }
+        let mut staged = vec![0; mounted.len()];
+        let mut duplicates = vec![];
+        let mut unstaged = vec![];
+        let mut staging_errors = vec![];
+        for (i, file) in plan.staged().iter().enumerate() {
+            match mounted[file.device].stage(file.index, &stager) {
+                Ok(Staged::New(desc)) => {
+                    staged[file.device] += 1;
+                    remaining[file.device] -= 1;
+                    if let Err(e) = history.record_staged(&desc) {
+                        warn!("Couldn't record staging {} in the history: {:?}", desc.staging_name(), e);
                     }
                 },
+                Ok(Staged::Duplicate(duplicate)) => {
+                    remaining[file.device] -= 1;
+                    duplicates.push(duplicate);
+                },
                 // TODO(richo) We probably want to just have this be an io::Error instead of
                 // faffing about with failure. As it stands, we use .context() to help with
                 // debugging which more or less implies failure, but maybe there's some
                 // middleground.
                 Err(err) => {
+                    // The file is still on the device, so we can try again next time.
+                    if err.downcast_ref::<StagingError>().is_some() {
+                        let name = mounted[file.device].name().to_string();
+                        error!("Couldn't stage from {}: {}", &name, err);
+                        notify(format!("{} didn't give back a good copy of a file, it was left on the device", &name));
+                        staging_errors.push((name, err));
+                        continue
+                    }
                     if let Some(err) = err.downcast_ref::<io::Error>() {
+                        // Our plan is only an estimate, so we can still run out of room.
                         if err.kind() == io::ErrorKind::Interrupted {
                             warn!("Staging device full, continuing");
-                            notify(incomplete_msg);
+                            unstaged.extend(plan.staged()[i..].iter().map(|file| file.desc.clone()));
                             break
                         }
                     }
@@ -92,12 +174,56 @@ fn main() {
             }
         }
 
+        for (i, device) in mounted.iter().enumerate() {
+            if !planned[i] {
+                continue;
+            }
+            if remaining[i] > 0 {
+                notify(format!("Partially staged {}, device will need a second run", device.name()));
+                continue;
+            }
+            device.cleanup()?;
+            if staged[i] > 0 {
+                notify(format!("Finished staging: {}", device.name()));
+            }
+        }
+        let deferred = plan.into_deferred();
+
         if matches.is_present("stage-only") {
             info!("Not uploading any data");
             return Ok(());
         }
 
-        let report = storage::upload_from_staged(&stager.staging_location(), &backends)?;
+        // Whatever happened while staging still goes in the report, even if nothing is uploaded.
+        let mut report = if !ctx.cfg.uploads_allowed_at(Local::now().time()) {
+            info!("Outside of the configured upload windows, leaving files staged for a later run");
+            Default::default()
+        } else {
+            // Uploads can take a while, so the window might close part way through.
+            storage::upload_from_staged_while(
+                &stager.staging_location(),
+                &backends,
+                &ctx.cfg.router(),
+                ctx.cfg.upload_concurrency(),
+                Some(&history),
+                || ctx.cfg.uploads_allowed_at(Local::now().time()),
+            )?
+        };
+        for desc in unstaged {
+            report.record_deferred(desc);
+        }
+        for file in deferred {
+            report.record_deferred(file.desc);
+        }
+        for (name, error) in failed_devices {
+            report.record_failed_device(name, &error);
+        }
+        for duplicate in duplicates {
+            report.record_duplicate(duplicate);
+        }
+        for (name, error) in staging_errors {
+            report.record_device_staging_error(&name, &error);
+        }
 
         if report.num_uploads() > 0 {
             if let Err(e) = ctx.notify("Finished uploading media") {
@@ -108,7 +234,10 @@ fn main() {
         let plaintext = report.to_plaintext()?;
         println!("{}", plaintext);
 
-        if report.num_uploads() > 0 {
+        if report.num_uploads() > 0 || report.num_unrouted() > 0 || report.num_deferred() > 0
+            || report.num_quarantined() > 0 || report.num_duplicates() > 0 || report.num_failed_devices() > 0
+            || report.num_staging_errors() > 0
+        {
             if let Err(e) = ctx.mailer.send_report(&plaintext) {
                 error!("Failed to send upload report: {:?}", e);
             }
@@ -15,7 +15,7 @@ fn main() {
         println!("Found the following gopros:");
 
         for gopro in ptp_device::locate_gopros(&ctx)?.iter() {
-            println!("  {:?} : {}", gopro.kind, gopro.serial);
+            println!("  {} : {}", gopro.model, gopro.serial);
         }
 
         Ok(())
@@ -7,7 +7,8 @@ use std::path::PathBuf;
 use stokepile::config;
 use stokepile::ctx::Ctx;
 use stokepile::manual_file::ManualFile;
-use stokepile::staging::Stager;
+use stokepile::history::UploadHistory;
+use stokepile::staging::{Staged, Stager};
 use stokepile::mountable::Mountable;
 
 fn cli_opts<'a, 'b>(base: App<'a, 'b>) -> App<'a, 'b> {
@@ -37,7 +38,7 @@ fn main() {
         let staging = ctx.staging().mount()?;
         info!("Staging to: {:?}", &staging);
 
-        let stager = match matches.is_present("preserve") {
+        let mut stager = match matches.is_present("preserve") {
             true => {
                 info!("Preserving input files");
                 Stager::preserving(staging)
@@ -47,10 +48,22 @@ fn main() {
                 Stager::destructive(staging)
             }
         };
+        if ctx.cfg.verify_staging() {
+            stager = stager.verifying();
+        }
+        stager = stager
+            .deduplicating_within(ctx.cfg.dedupe_window())
+            .with_path_templates(ctx.cfg.path_templates(None))
+            .with_router(ctx.cfg.router());
 
 
+        let history = UploadHistory::in_home()?;
         for file in ManualFile::iter_from(path) {
-            stager.stage(file, &device_name)?;
+            if let Staged::New(desc) = stager.stage(file, &device_name)? {
+                if let Err(e) = history.record_staged(&desc) {
+                    warn!("Couldn't record staging {} in the history: {:?}", desc.staging_name(), e);
+                }
+            }
         }
 
         Ok(())
@@ -52,7 +52,7 @@ fn poll_devices(state: &mut DeviceState) -> Result<(), Error> {
 
     // Look through all the attached devices, setting their state to Connected if they're new, or
     // unwrapping Indeterminate if that's what we find.
-    for device in device::attached_devices(&CTX)? {
+    for device in device::attached_devices(&CTX).0 {
         info!("  {:?}", device);
         state.entry(device)
             .and_modify(|v| {
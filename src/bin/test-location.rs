@@ -14,7 +14,7 @@ fn main() {
         let cfg = config::Config::from_file(matches.value_of("config").unwrap_or("stokepile.toml"));
         let ctx = Ctx::create_without_lock(cfg?)?;
 
-        let devices = device::attached_devices(&ctx)?;
+        let (devices, _) = device::attached_devices(&ctx);
 
         for device in devices {
             println!("  {:?}", &device);
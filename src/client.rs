@@ -109,6 +109,35 @@ impl StokepileClient {
         }
     }
 
+    /// Ask the server for a fresh access token for the integration named `provider`.
+    pub fn refresh_token(&self, provider: &str) -> Result<String, Error> {
+        let mut endpoint = self.base.clone();
+        endpoint.set_path(&format!("/refresh_token/{}", provider));
+
+        let headers = self.add_authorization(HeaderMap::new())?;
+
+        let mut resp = self
+            .client
+            .get(endpoint)
+            .headers(headers)
+            .send()?;
+
+        if resp.status() == 500 {
+            Err(ClientError::ServerError(resp.text()?))?;
+        }
+
+        let resp: messages::RefreshToken = resp.json()?;
+        match resp {
+            messages::RefreshToken::Token(token) => Ok(token),
+            messages::RefreshToken::NotConfigured => {
+                Err(format_err!("{} is not configured on the server", provider))
+            },
+            messages::RefreshToken::Error(e) => {
+                Err(format_err!("{:?}", e))
+            },
+        }
+    }
+
     pub fn login(&self, email: &str, password: &str) -> Result<SessionToken, Error> {
         let mut endpoint = self.base.clone();
         endpoint.set_path("/json/signin");
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+
+use chrono::prelude::*;
+use chrono::{Duration, LocalResult};
+
+/// Which zone a device's clock is set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockZone {
+    /// Whatever zone we're running in, which is what cameras set up from a phone usually end up
+    /// with.
+    Local,
+    /// A fixed offset from UTC, or UTC itself.
+    Fixed(FixedOffset),
+}
+
+#[derive(Fail, Debug, PartialEq, Eq)]
+#[fail(display = "Invalid timezone {}, expected local, utc or an offset like +10:00.", _0)]
+pub struct InvalidZone(pub String);
+
+impl ClockZone {
+    /// Parse `local`, `utc`, or an offset from UTC like `+10:00` or `-0530`.
+    pub fn parse(zone: &str) -> Result<ClockZone, InvalidZone> {
+        let invalid = || InvalidZone(zone.to_string());
+        match &zone.to_lowercase()[..] {
+            "local" => return Ok(ClockZone::Local),
+            "utc" | "z" => return Ok(ClockZone::Fixed(FixedOffset::east(0))),
+            _ => {}
+        }
+        let sign = match zone.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(invalid()),
+        };
+        let digits: String = zone[1..].chars().filter(|c| *c != ':').collect();
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let hours: i32 = digits[..2].parse().map_err(|_| invalid())?;
+        let minutes: i32 = digits[2..].parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(ClockZone::Fixed(FixedOffset::east(sign * (hours * 3600 + minutes * 60))))
+    }
+}
+
+/// How to turn what a device's clock read into when something actually happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceClock {
+    zone: ClockZone,
+    /// How far ahead of the real time the device's clock runs.
+    skew: Duration,
+}
+
+impl Default for DeviceClock {
+    fn default() -> DeviceClock {
+        DeviceClock {
+            zone: ClockZone::Local,
+            skew: Duration::zero(),
+        }
+    }
+}
+
+impl DeviceClock {
+    pub fn new(zone: ClockZone, skew: Duration) -> DeviceClock {
+        DeviceClock { zone, skew }
+    }
+
+    /// When something the device stamped with `reading` happened.
+    pub fn capture_time(&self, reading: NaiveDateTime) -> DateTime<FixedOffset> {
+        let corrected = reading - self.skew;
+        match self.zone {
+            ClockZone::Fixed(offset) => offset.from_local_datetime(&corrected).unwrap(),
+            ClockZone::Local => {
+                let local = match Local.from_local_datetime(&corrected) {
+                    LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time,
+                    // The clock reads a time that's skipped when daylight savings starts, so it
+                    // can't have been changed over yet.
+                    LocalResult::None => Local.from_local_datetime(&(corrected - Duration::hours(1)))
+                        .earliest()
+                        .map(|time| time + Duration::hours(1))
+                        .unwrap_or_else(|| Local.from_utc_datetime(&corrected)),
+                };
+                local.with_timezone(&local.offset().fix())
+            }
+        }
+    }
+}
+
+/// The clocks of each device, by name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceClocks {
+    devices: BTreeMap<String, DeviceClock>,
+}
+
+impl DeviceClocks {
+    pub fn new(devices: BTreeMap<String, DeviceClock>) -> DeviceClocks {
+        DeviceClocks { devices }
+    }
+
+    /// The clock of the device called `device`, which is assumed to be on local time if we don't
+    /// know any better.
+    pub fn for_device(&self, device: &str) -> DeviceClock {
+        self.devices.get(device).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading() -> NaiveDateTime {
+        NaiveDate::from_ymd(2019, 6, 1).and_hms(9, 30, 0)
+    }
+
+    #[test]
+    fn test_parses_zones() {
+        assert_eq!(ClockZone::parse("local"), Ok(ClockZone::Local));
+        assert_eq!(ClockZone::parse("UTC"), Ok(ClockZone::Fixed(FixedOffset::east(0))));
+        assert_eq!(ClockZone::parse("+10:00"), Ok(ClockZone::Fixed(FixedOffset::east(10 * 3600))));
+        assert_eq!(ClockZone::parse("-0530"), Ok(ClockZone::Fixed(FixedOffset::west(5 * 3600 + 30 * 60))));
+        assert_eq!(ClockZone::parse("Europe/Berlin"), Err(InvalidZone("Europe/Berlin".into())));
+        assert_eq!(ClockZone::parse("+25:00"), Err(InvalidZone("+25:00".into())));
+        assert_eq!(ClockZone::parse("+1:00"), Err(InvalidZone("+1:00".into())));
+    }
+
+    #[test]
+    fn test_capture_times_keep_the_device_zone() {
+        let clock = DeviceClock::new(ClockZone::parse("+02:00").unwrap(), Duration::zero());
+        let time = clock.capture_time(reading());
+        assert_eq!(time.to_rfc3339(), "2019-06-01T09:30:00+02:00");
+        assert_eq!(time.naive_local(), reading());
+
+        let clock = DeviceClock::new(ClockZone::parse("utc").unwrap(), Duration::zero());
+        assert_eq!(clock.capture_time(reading()).to_rfc3339(), "2019-06-01T09:30:00+00:00");
+
+        let time = DeviceClock::default().capture_time(reading());
+        assert_eq!(time.naive_local(), reading());
+        assert_eq!(time, Local.from_local_datetime(&reading()).unwrap());
+    }
+
+    #[test]
+    fn test_corrects_skew() {
+        let clock = DeviceClock::new(ClockZone::parse("utc").unwrap(), Duration::seconds(90));
+        assert_eq!(clock.capture_time(reading()).to_rfc3339(), "2019-06-01T09:28:30+00:00");
+        let clock = DeviceClock::new(ClockZone::parse("utc").unwrap(), Duration::seconds(-30));
+        assert_eq!(clock.capture_time(reading()).to_rfc3339(), "2019-06-01T09:30:30+00:00");
+    }
+
+    #[test]
+    fn test_unknown_devices_are_local() {
+        let utc = DeviceClock::new(ClockZone::parse("utc").unwrap(), Duration::zero());
+        let mut devices = BTreeMap::new();
+        devices.insert("data".to_string(), utc);
+        let clocks = DeviceClocks::new(devices);
+        assert_eq!(clocks.for_device("data"), utc);
+        assert_eq!(clocks.for_device("gopro"), DeviceClock::default());
+    }
+}
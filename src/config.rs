@@ -1,19 +1,37 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::fmt;
 
+use chrono::NaiveTime;
 use failure::{Error, ResultExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use toml;
 use url;
 
+use crate::bandwidth::{RateLimiter, Throttle, UploadWindow};
+use crate::clock::{ClockZone, DeviceClock, DeviceClocks};
+use crate::device;
 use crate::dropbox;
 use crate::mailer::SendgridMailer;
 use crate::pushover_notifier::{Notify, PushoverNotifier};
 use crate::web_notifier::WebNotifier;
 use crate::vimeo::VimeoClient;
+use crate::s3::S3Client;
+use crate::sftp::SftpClient;
+use crate::webdav::WebdavClient;
+use crate::google::GoogleToken;
+use crate::google_drive::GoogleDriveClient;
+use crate::youtube::YoutubeClient;
 use crate::mountable::{Mountable, MountableFilesystem};
+use crate::path_template::{PathTemplate, PathTemplates};
+use crate::retry::RetryPolicy;
+use crate::routing::Router;
+use crate::staging;
 use crate::storage::MaybeStorageAdaptor;
 
 
@@ -84,30 +102,48 @@ impl AccessToken {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub enum DeviceConfig {
-    Gopro(GoproConfig),
-    MassStorage(MassStorageConfig),
-    Flysight(FlysightConfig),
-    UnknownDevice(String),
-}
-
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     stokepile: StokepileConfig,
     staging: StagingConfig,
     dropbox: Option<DropboxConfig>,
     vimeo: Option<VimeoConfig>,
-    // youtube: Option<YoutubeConfig>,
-    flysight: Option<Vec<FlysightConfig>>,
-    gopro: Option<Vec<GoproConfig>>,
-    mass_storage: Option<Vec<MassStorageConfig>>,
+    s3: Option<S3Config>,
+    sftp: Option<SftpConfig>,
+    webdav: Option<WebdavConfig>,
+    google_drive: Option<GoogleDriveConfig>,
+    youtube: Option<YoutubeConfig>,
+    /// Deprecated sections from before `[[device.<kind>]]`, which are moved in there when the
+    /// config is loaded.
+    flysight: Option<Vec<toml::Value>>,
+    gopro: Option<Vec<toml::Value>>,
+    mass_storage: Option<Vec<toml::Value>>,
+    /// Devices by kind, eg `[[device.flysight]]`.
+    device: Option<BTreeMap<String, Vec<toml::Value>>>,
     local_backup: Option<Vec<LocalBackupConfig>>,
+    route: Option<Vec<RouteConfig>>,
+    retry: Option<BTreeMap<String, RetryConfig>>,
+    bandwidth: Option<BandwidthConfig>,
+    upload_window: Option<Vec<UploadWindowConfig>>,
+    path_template: Option<PathTemplateConfig>,
     // gswoop: Option<GswoopConfig>,
     sendgrid: Option<SendgridConfig>,
     pushover: Option<PushoverConfig>,
     web_notifications: Option<WebNotificationsConfig>,
+    /// What the sections above came to once they were checked, so nothing has to parse them again.
+    #[serde(skip)]
+    parsed: ParsedConfig,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct ParsedConfig {
+    devices: Vec<device::ConfiguredDevice>,
+    clocks: DeviceClocks,
+    windows: Vec<UploadWindow>,
+    /// The templates for staging, which every backend without one of its own also uses.
+    templates: PathTemplates,
+    backend_templates: BTreeMap<String, PathTemplate>,
 }
 
 #[derive(Debug, Default)]
@@ -116,23 +152,25 @@ pub struct ConfigBuilder {
     staging: Option<StagingConfig>,
     dropbox: Option<DropboxConfig>,
     vimeo: Option<VimeoConfig>,
-    // youtube: Option<YoutubeConfig>,
-    flysight: Option<Vec<FlysightConfig>>,
-    gopro: Option<Vec<GoproConfig>>,
-    mass_storage: Option<Vec<MassStorageConfig>>,
+    s3: Option<S3Config>,
+    sftp: Option<SftpConfig>,
+    webdav: Option<WebdavConfig>,
+    google_drive: Option<GoogleDriveConfig>,
+    youtube: Option<YoutubeConfig>,
+    /// Devices by kind, eg `[[device.flysight]]`.
+    device: Option<BTreeMap<String, Vec<toml::Value>>>,
     local_backup: Option<Vec<LocalBackupConfig>>,
+    route: Option<Vec<RouteConfig>>,
+    retry: Option<BTreeMap<String, RetryConfig>>,
+    bandwidth: Option<BandwidthConfig>,
+    upload_window: Option<Vec<UploadWindowConfig>>,
+    path_template: Option<PathTemplateConfig>,
     // gswoop: Option<GswoopConfig>,
     sendgrid: Option<SendgridConfig>,
     pushover: Option<PushoverConfig>,
     web_notifications: Option<WebNotificationsConfig>,
 }
 
-lazy_static! {
-    static ref EMPTY_MASS_STORAGES: Vec<MassStorageConfig> = vec![];
-    static ref EMPTY_FLYSIGHTS: Vec<FlysightConfig> = vec![];
-    static ref EMPTY_GOPROS: Vec<GoproConfig> = vec![];
-}
-
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 #[serde(deny_unknown_fields)]
 /// The configuration entry associated with a staging location.
@@ -170,6 +208,25 @@ pub struct StokepileConfig {
     api_base: Option<String>,
     api_token: Option<String>,
     preserve_device_files: Option<bool>,
+    /// Read staged files back and check them before deleting them from devices.
+    verify_staging: Option<bool>,
+    /// How many files each storage adaptor uploads at once.
+    upload_concurrency: Option<usize>,
+    /// Which files get staged first when they won't all fit.
+    staging_order: Option<StagingOrder>,
+    /// The names of devices to stage from before any others, most important first.
+    device_priority: Option<Vec<String>>,
+    /// How many days to remember uploads for, so that the same contents aren't staged again.
+    dedupe_days: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+/// The order files are staged in, which matters when staging doesn't have room for all of them.
+pub enum StagingOrder {
+    SmallestFirst,
+    NewestFirst,
+    OldestFirst,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
@@ -184,6 +241,87 @@ pub struct VimeoConfig {
     token: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+/// The configuration for an S3 compatible object store.
+pub struct S3Config {
+    /// The base url of the service, eg `https://s3.us-west-2.amazonaws.com` or
+    /// `http://minio.local:9000`.
+    pub endpoint: String,
+    pub bucket: String,
+    /// Defaults to `us-east-1`, which is also what MinIO expects unless told otherwise.
+    pub region: Option<String>,
+    /// An optional path within the bucket to store everything under.
+    pub prefix: Option<String>,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+/// The configuration for archiving to a remote host over sftp.
+pub struct SftpConfig {
+    pub host: String,
+    /// Defaults to 22.
+    pub port: Option<u16>,
+    pub username: String,
+    /// The private key to authenticate with. Password authentication is not supported.
+    pub private_key: PathBuf,
+    pub passphrase: Option<String>,
+    /// The host key must already be present in this file. Defaults to `~/.ssh/known_hosts`.
+    pub known_hosts: Option<PathBuf>,
+    /// The absolute path on the remote host to archive into.
+    pub root: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+/// The configuration for a WebDAV server, such as Nextcloud.
+pub struct WebdavConfig {
+    /// The collection to archive into. For Nextcloud this looks like
+    /// `https://cloud.example.org/remote.php/dav/files/<user>/<folder>`, which also enables
+    /// chunked uploads.
+    pub url: String,
+    pub username: String,
+    /// For Nextcloud, this should be an app password.
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GoogleDriveConfig {
+    token: String,
+    /// The folder in the root of the drive to archive into. Defaults to `stokepile`.
+    folder: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct YoutubeConfig {
+    token: String,
+    /// Defaults to unlisted.
+    privacy: Option<YoutubePrivacy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum YoutubePrivacy {
+    Private,
+    Unlisted,
+    Public,
+}
+
+impl YoutubePrivacy {
+    /// The value youtube expects for `privacyStatus`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            YoutubePrivacy::Private => "private",
+            YoutubePrivacy::Unlisted => "unlisted",
+            YoutubePrivacy::Public => "public",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
 #[serde(deny_unknown_fields)]
 pub enum MountableDeviceLocation {
@@ -231,6 +369,10 @@ pub struct FlysightConfig {
     pub name: String,
     #[serde(flatten)]
     pub location: MountableDeviceLocation,
+    /// Defaults to `utc`, which is what the FlySight logs in.
+    pub timezone: Option<String>,
+    /// How many seconds ahead of the real time the device's clock runs.
+    pub clock_skew_secs: Option<i64>,
 }
 
 impl FlysightConfig {
@@ -254,8 +396,87 @@ pub struct MassStorageConfig {
     pub location: MountableDeviceLocation,
     pub extensions: Vec<String>,
     pub cleanup_extensions: Option<Vec<String>>,
+    /// The zone the device's clock is set to: `local`, `utc` or an offset like `+10:00`.
+    pub timezone: Option<String>,
+    /// How many seconds ahead of the real time the device's clock runs.
+    pub clock_skew_secs: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+/// A rule deciding which backends a staged file is sent to. Every criteria that's set must match.
+pub struct RouteConfig {
+    /// The names of the devices the file came from.
+    pub devices: Option<Vec<String>>,
+    /// Compared case insensitively.
+    pub extensions: Option<Vec<String>>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub kind: Option<RouteKind>,
+    /// The names of the backends to send matching files to, eg `dropbox` or `local backup`.
+    pub backends: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+/// Which sort of remote path a file has, see `RemotePathDescriptor`.
+pub enum RouteKind {
+    /// Files from devices, filed by when they were captured.
+    DateTime,
+    /// Files that were uploaded manually, which keep their path.
+    SpecifiedPath,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
+#[serde(deny_unknown_fields)]
+/// How hard to try uploading to a backend. Anything left unset uses the defaults.
+pub struct RetryConfig {
+    /// Including the first attempt. Defaults to 3.
+    pub max_attempts: Option<usize>,
+    /// How long to wait after the first failure, which doubles with each attempt. Defaults to 2.
+    pub initial_backoff_secs: Option<u64>,
+    /// The longest we'll wait between attempts. Defaults to 300.
+    pub max_backoff_secs: Option<u64>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
+#[serde(deny_unknown_fields)]
+/// Limits on how fast we upload, in kilobytes per second.
+pub struct BandwidthConfig {
+    /// Shared between every backend.
+    pub limit_kbytes_per_sec: Option<u64>,
+    /// Limits for particular backends by name, which apply on top of the shared limit.
+    pub backends: Option<BTreeMap<String, u64>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
+#[serde(deny_unknown_fields)]
+/// Where files end up, see `PathTemplate` for the placeholders.
+pub struct PathTemplateConfig {
+    /// Used for staging and every backend.
+    pub template: Option<String>,
+    /// Templates for particular backends by name, which win over everything else.
+    pub backends: Option<BTreeMap<String, String>>,
+    /// Templates for files from particular devices by name, which win over `template`.
+    pub devices: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+/// A time of day during which uploads are allowed, as local times like `19:00`.
+pub struct UploadWindowConfig {
+    pub start: String,
+    pub end: String,
+}
+
+/// The `retry` entry that applies to every backend.
+const DEFAULT_RETRY: &str = "default";
+
+/// The names that can be used to refer to backends in routes, retry policies and bandwidth limits.
+const ROUTABLE_BACKENDS: &[&str] = &[
+    "dropbox", "vimeo", "s3", "sftp", "webdav", "google_drive", "youtube", "local backup",
+];
+
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct WebNotificationsConfig {
     pub enabled: bool,
@@ -282,11 +503,62 @@ pub struct SendgridConfig {
 pub struct GoproConfig {
     pub name: String,
     pub serial: String,
+    /// The zone the device's clock is set to: `local`, `utc` or an offset like `+10:00`.
+    pub timezone: Option<String>,
+    /// How many seconds ahead of the real time the device's clock runs.
+    pub clock_skew_secs: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+/// A camera we talk to over PTP, configured under `[[device.ptp_camera]]`.
+pub struct PtpCameraConfig {
+    pub name: String,
+    /// The USB vendor ID, eg `0x054c` for Sony.
+    pub vendor_id: u16,
+    /// The USB product ID, or any of the vendor's products if unset.
+    pub product_id: Option<u16>,
+    /// The manufacturer the camera reports, for vendors whose IDs turn up on other things too.
+    pub manufacturer: Option<String>,
+    pub serial: String,
+    /// Which sorts of files to stage. Defaults to video.
+    pub formats: Option<Vec<PtpFormat>>,
+    /// The zone the device's clock is set to: `local`, `utc` or an offset like `+10:00`.
+    pub timezone: Option<String>,
+    /// How many seconds ahead of the real time the device's clock runs.
+    pub clock_skew_secs: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+/// The sorts of files we can stage from PTP cameras, see `ptp_device::object_formats`.
+pub enum PtpFormat {
+    Video,
+    Stills,
+    Raw,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+/// A phone we talk to over MTP, configured under `[[device.android]]`.
+pub struct AndroidConfig {
+    pub name: String,
+    /// The USB vendor ID, eg `0x18d1` for Google.
+    pub vendor_id: u16,
+    /// The USB product ID, or any of the vendor's products if unset.
+    pub product_id: Option<u16>,
+    pub serial: String,
+    /// Which files under `DCIM` to stage, by extension. Defaults to `mp4`.
+    pub extensions: Option<Vec<String>>,
+    /// The zone the device's clock is set to: `local`, `utc` or an offset like `+10:00`.
+    pub timezone: Option<String>,
+    /// How many seconds ahead of the real time the device's clock runs.
+    pub clock_skew_secs: Option<i64>,
 }
 
 #[derive(Fail, Debug, PartialEq)]
 pub enum ConfigError {
-    #[fail(display = "Must have at least one of dropbox and vimeo configured.")]
+    #[fail(display = "Must have at least one of dropbox, vimeo, s3, sftp, webdav, google_drive and youtube configured.")]
     MissingBackend,
     #[fail(display = "Must have either a `staging_path` or `staging_device` set.")]
     MissingStaging,
@@ -302,6 +574,42 @@ pub enum ConfigError {
     NoTokenFile,
     #[fail(display = "mass_storage entries must have an extensions = [...] key")]
     MassStorageMissingExtensions,
+    #[fail(display = "Invalid url for s3 endpoint: {}.", _0)]
+    InvalidS3Endpoint(url::ParseError),
+    #[fail(display = "The sftp root must be an absolute path.")]
+    RelativeSftpRoot,
+    #[fail(display = "Invalid url for webdav: {}.", _0)]
+    InvalidWebdavUrl(url::ParseError),
+    #[fail(display = "upload_concurrency must be at least 1.")]
+    InvalidUploadConcurrency,
+    #[fail(display = "Unknown backend in route: {}.", _0)]
+    UnknownRouteBackend(String),
+    #[fail(display = "Route sends files to {}, which isn't configured.", _0)]
+    UnconfiguredRouteBackend(String),
+    #[fail(display = "Unknown backend in retry: {}.", _0)]
+    UnknownRetryBackend(String),
+    #[fail(display = "retry.{}.max_attempts must be at least 1.", _0)]
+    InvalidRetryAttempts(String),
+    #[fail(display = "Unknown device in device_priority: {}.", _0)]
+    UnknownPriorityDevice(String),
+    #[fail(display = "Unknown backend in bandwidth: {}.", _0)]
+    UnknownBandwidthBackend(String),
+    #[fail(display = "Bandwidth limits must be at least 1.")]
+    InvalidBandwidthLimit,
+    #[fail(display = "Invalid upload window {}-{}, times must look like 19:00.", _0, _1)]
+    InvalidUploadWindow(String, String),
+    #[fail(display = "Invalid path template {}: {}", _0, _1)]
+    InvalidPathTemplate(String, String),
+    #[fail(display = "Unknown backend in path_template: {}.", _0)]
+    UnknownPathTemplateBackend(String),
+    #[fail(display = "Unknown device in path_template: {}.", _0)]
+    UnknownPathTemplateDevice(String),
+    #[fail(display = "Invalid timezone for {}: {}, expected local, utc or an offset like +10:00.", _0, _1)]
+    InvalidClockZone(String, String),
+    #[fail(display = "Unknown device kind: {}.", _0)]
+    UnknownDeviceKind(String),
+    #[fail(display = "Invalid {} config: {}", _0, _1)]
+    InvalidDevice(String, String),
 }
 
 impl FromStr for Config {
@@ -336,13 +644,35 @@ impl Config {
         toml::to_string(self).map_err(|e| ConfigError::GenerateError(e).into())
     }
 
-    fn check_config(config: Config) -> Result<Config, ConfigError> {
-        if config.dropbox.is_none() && config.vimeo.is_none() {
+    fn check_config(mut config: Config) -> Result<Config, ConfigError> {
+        if config.dropbox.is_none() && config.vimeo.is_none() && config.s3.is_none() &&
+            config.sftp.is_none() && config.webdav.is_none() &&
+            config.google_drive.is_none() && config.youtube.is_none() {
             Err(ConfigError::MissingBackend)?;
         }
 
         Config::check_staging(&config.staging)?;
-        Config::check_mass_storages(config.mass_storages())?;
+
+        config.move_deprecated_devices();
+        for kind in config.device.iter().flat_map(|d| d.keys()) {
+            if !device::KINDS.iter().any(|k| k.name() == kind) {
+                Err(ConfigError::UnknownDeviceKind(kind.clone()))?;
+            }
+        }
+        for kind in device::KINDS {
+            let configured = kind.configured(&config)?;
+            config.parsed.devices.extend(configured);
+        }
+
+        if config.stokepile.upload_concurrency == Some(0) {
+            Err(ConfigError::InvalidUploadConcurrency)?;
+        }
+
+        for device in config.stokepile.device_priority.iter().flatten() {
+            if !config.is_device(device) {
+                Err(ConfigError::UnknownPriorityDevice(device.clone()))?;
+            }
+        }
 
         if let Some(base) = &config.stokepile.api_base {
             if let Err(err) = url::Url::parse(&base) {
@@ -350,9 +680,124 @@ impl Config {
             }
         }
 
+        if let Some(s3) = &config.s3 {
+            if let Err(err) = url::Url::parse(&s3.endpoint) {
+                Err(ConfigError::InvalidS3Endpoint(err))?;
+            }
+        }
+
+        if let Some(sftp) = &config.sftp {
+            if sftp.root.is_relative() {
+                Err(ConfigError::RelativeSftpRoot)?;
+            }
+        }
+
+        if let Some(webdav) = &config.webdav {
+            if let Err(err) = url::Url::parse(&webdav.url) {
+                Err(ConfigError::InvalidWebdavUrl(err))?;
+            }
+        }
+
+        for route in config.route.iter().flatten() {
+            for backend in &route.backends {
+                if !ROUTABLE_BACKENDS.contains(&&backend[..]) {
+                    Err(ConfigError::UnknownRouteBackend(backend.clone()))?;
+                }
+                // Files routed to a backend we don't have would never be uploaded.
+                if !config.has_backend(backend) {
+                    Err(ConfigError::UnconfiguredRouteBackend(backend.clone()))?;
+                }
+            }
+        }
+
+        for (backend, retry) in config.retry.iter().flatten() {
+            if backend != DEFAULT_RETRY && !ROUTABLE_BACKENDS.contains(&&backend[..]) {
+                Err(ConfigError::UnknownRetryBackend(backend.clone()))?;
+            }
+            if retry.max_attempts == Some(0) {
+                Err(ConfigError::InvalidRetryAttempts(backend.clone()))?;
+            }
+        }
+
+        if let Some(bandwidth) = &config.bandwidth {
+            if bandwidth.limit_kbytes_per_sec == Some(0) {
+                Err(ConfigError::InvalidBandwidthLimit)?;
+            }
+            for (backend, limit) in bandwidth.backends.iter().flatten() {
+                if !ROUTABLE_BACKENDS.contains(&&backend[..]) {
+                    Err(ConfigError::UnknownBandwidthBackend(backend.clone()))?;
+                }
+                if *limit == 0 {
+                    Err(ConfigError::InvalidBandwidthLimit)?;
+                }
+            }
+        }
+
+        for window in config.upload_window.iter().flatten() {
+            match UploadWindow::parse(&window.start, &window.end) {
+                Ok(parsed) => config.parsed.windows.push(parsed),
+                Err(_) => Err(ConfigError::InvalidUploadWindow(window.start.clone(), window.end.clone()))?,
+            }
+        }
+
+        let mut clocks = BTreeMap::new();
+        for device in &config.parsed.devices {
+            let zone = ClockZone::parse(&device.timezone)
+                .map_err(|_| ConfigError::InvalidClockZone(device.name.clone(), device.timezone.clone()))?;
+            let skew = chrono::Duration::seconds(device.clock_skew_secs.unwrap_or(0));
+            clocks.insert(device.name.clone(), DeviceClock::new(zone, skew));
+        }
+        config.parsed.clocks = DeviceClocks::new(clocks);
+
+        if let Some(templates) = &config.path_template {
+            for backend in templates.backends.iter().flat_map(|b| b.keys()) {
+                if !ROUTABLE_BACKENDS.contains(&&backend[..]) {
+                    Err(ConfigError::UnknownPathTemplateBackend(backend.clone()))?;
+                }
+            }
+            for device in templates.devices.iter().flat_map(|d| d.keys()) {
+                if !config.is_device(device) {
+                    Err(ConfigError::UnknownPathTemplateDevice(device.clone()))?;
+                }
+            }
+            let parse = |template: &String| PathTemplate::parse(template)
+                .map_err(|err| ConfigError::InvalidPathTemplate(template.clone(), err.to_string()));
+            let default = templates.template.as_ref().map(parse).transpose()?;
+            config.parsed.backend_templates = templates.backends.iter()
+                .flatten()
+                .map(|(backend, template)| Ok((backend.clone(), parse(template)?)))
+                .collect::<Result<_, ConfigError>>()?;
+            let devices = templates.devices.iter()
+                .flatten()
+                .map(|(device, template)| Ok((device.clone(), parse(template)?)))
+                .collect::<Result<_, ConfigError>>()?;
+            config.parsed.templates = PathTemplates::new(default, devices);
+        }
+
         Ok(config)
     }
 
+    /// Move the deprecated `[[flysight]]`, `[[gopro]]` and `[[mass_storage]]` sections into
+    /// `[[device.<kind>]]`, ahead of anything already there, so that each kind only has one place
+    /// to look.
+    fn move_deprecated_devices(&mut self) {
+        let deprecated = vec![
+            ("flysight", self.flysight.take()),
+            ("gopro", self.gopro.take()),
+            ("mass_storage", self.mass_storage.take()),
+        ];
+        for (kind, entries) in deprecated {
+            let mut entries = match entries {
+                Some(entries) => entries,
+                None => continue,
+            };
+            warn!("[[{}]] sections are deprecated, use [[device.{}]] instead", kind, kind);
+            let devices = self.device.get_or_insert_with(BTreeMap::new);
+            entries.extend(devices.remove(kind).unwrap_or_default());
+            devices.insert(kind.to_string(), entries);
+        }
+    }
+
     #[must_use]
     fn check_staging(staging: &StagingConfig) -> Result<(), ConfigError> {
         match &staging.location {
@@ -367,14 +812,24 @@ impl Config {
         Ok(())
     }
 
-    #[must_use]
-    fn check_mass_storages(storages: &[MassStorageConfig]) -> Result<(), ConfigError> {
-        for ms in storages {
-            if ms.extensions.len() == 0 {
-                Err(ConfigError::MassStorageMissingExtensions)?
-            }
-        }
-        Ok(())
+    /// Is there a device called `name`?
+    fn is_device(&self, name: &str) -> bool {
+        self.parsed.devices.iter().any(|d| d.name == name)
+    }
+
+    /// Every configured device, whatever its kind.
+    pub fn configured_devices(&self) -> &[device::ConfiguredDevice] {
+        &self.parsed.devices
+    }
+
+    /// The `[[device.<kind>]]` entries for `kind`, parsed by that kind.
+    pub fn device_entries<T: DeserializeOwned>(&self, kind: &str) -> Result<Vec<T>, ConfigError> {
+        self.device.iter()
+            .filter_map(|devices| devices.get(kind))
+            .flatten()
+            .map(|entry| entry.clone().try_into()
+                 .map_err(|e: toml::de::Error| ConfigError::InvalidDevice(kind.to_string(), e.to_string())))
+            .collect()
     }
 
     /// Get the api base of this config, or return the default
@@ -385,28 +840,6 @@ impl Config {
         }
     }
 
-    // Do we eventually want to make a camera/mass_storage distinction?
-    pub fn mass_storages(&self) -> &Vec<MassStorageConfig> {
-        match self.mass_storage {
-            None => &EMPTY_MASS_STORAGES,
-            Some(ref v) => v,
-        }
-    }
-
-    pub fn flysights(&self) -> &Vec<FlysightConfig> {
-        match self.flysight {
-            None => &EMPTY_FLYSIGHTS,
-            Some(ref v) => v,
-        }
-    }
-
-    pub fn gopros(&self) -> &Vec<GoproConfig> {
-        match self.gopro {
-            None => &EMPTY_GOPROS,
-            Some(ref v) => v,
-        }
-    }
-
     pub fn notifier(&self) -> Option<Box<dyn Notify>> {
         // Loool
         if let Some(ref web) = self.web_notifications {
@@ -438,6 +871,21 @@ impl Config {
         None
     }
 
+    /// Is at least one of the backend called `name` configured?
+    fn has_backend(&self, name: &str) -> bool {
+        match name {
+            "dropbox" => self.dropbox.is_some(),
+            "vimeo" => self.vimeo.is_some(),
+            "s3" => self.s3.is_some(),
+            "sftp" => self.sftp.is_some(),
+            "webdav" => self.webdav.is_some(),
+            "google_drive" => self.google_drive.is_some(),
+            "youtube" => self.youtube.is_some(),
+            "local backup" => self.local_backup.as_ref().is_some_and(|locals| !locals.is_empty()),
+            _ => false,
+        }
+    }
+
     /// Returns a vec of all configured backends
     pub fn backends(&self) -> Vec<MaybeStorageAdaptor> {
         let mut out = vec![];
@@ -445,7 +893,10 @@ impl Config {
             for adaptor in locals {
                 out.push(match Mountable::mount(adaptor.clone()) {
                     Ok(mounted) => MaybeStorageAdaptor::Ok(mounted),
-                    Err(e) => MaybeStorageAdaptor::Err(adaptor.location().to_string(), e),
+                    Err(e) => {
+                        error!("Couldn't mount local backup at {}: {:?}", adaptor.location(), e);
+                        MaybeStorageAdaptor::Err("local backup".to_string(), e).with_id(adaptor.id())
+                    }
                 });
             }
         }
@@ -453,9 +904,61 @@ impl Config {
             out.push(MaybeStorageAdaptor::Ok(dropbox::DropboxFilesClient::new(dropbox.token.clone())));
         }
         if let Some(ref vimeo) = self.vimeo {
-            out.push(MaybeStorageAdaptor::Ok(VimeoClient::new(vimeo.token.clone())));
+            out.push(match VimeoClient::new(vimeo.token.clone()) {
+                Ok(client) => MaybeStorageAdaptor::Ok(client),
+                Err(e) => MaybeStorageAdaptor::Err("vimeo".to_string(), e),
+            });
+        }
+        if let Some(ref s3) = self.s3 {
+            out.push(match S3Client::new(s3) {
+                Ok(client) => MaybeStorageAdaptor::Ok(client),
+                Err(e) => MaybeStorageAdaptor::Err("s3".to_string(), e),
+            });
+        }
+        if let Some(ref sftp) = self.sftp {
+            out.push(match SftpClient::new(sftp) {
+                Ok(client) => MaybeStorageAdaptor::Ok(client),
+                Err(e) => MaybeStorageAdaptor::Err("sftp".to_string(), e),
+            });
         }
-        out
+        if let Some(ref webdav) = self.webdav {
+            out.push(match WebdavClient::new(webdav) {
+                Ok(client) => MaybeStorageAdaptor::Ok(client),
+                Err(e) => MaybeStorageAdaptor::Err("webdav".to_string(), e),
+            });
+        }
+        if let Some(ref drive) = self.google_drive {
+            let token = GoogleToken::from_config("drive", drive.token.clone(), &self);
+            out.push(match GoogleDriveClient::new(token, drive.folder.clone()) {
+                Ok(client) => MaybeStorageAdaptor::Ok(client),
+                Err(e) => MaybeStorageAdaptor::Err("google_drive".to_string(), e),
+            });
+        }
+        if let Some(ref youtube) = self.youtube {
+            let token = GoogleToken::from_config("youtube", youtube.token.clone(), &self);
+            let privacy = youtube.privacy.unwrap_or(YoutubePrivacy::Unlisted);
+            out.push(match YoutubeClient::new(token, privacy) {
+                Ok(client) => MaybeStorageAdaptor::Ok(client),
+                Err(e) => MaybeStorageAdaptor::Err("youtube".to_string(), e),
+            });
+        }
+        // Every network backend shares the one global limiter, so the limit holds across all of
+        // them. It's there to spare the connection, which local backups don't touch.
+        let global = self.bandwidth_limit(None).map(|limit| Arc::new(RateLimiter::new(limit)));
+        out.into_iter()
+            .map(|adaptor| {
+                let policy = self.retry_policy(adaptor.name());
+                let mut throttle = Throttle::default();
+                if let Some(global) = global.as_ref().filter(|_| adaptor.name() != "local backup") {
+                    throttle = throttle.limited_by(global.clone());
+                }
+                if let Some(limit) = self.bandwidth_limit(Some(adaptor.name())) {
+                    throttle = throttle.limited_by(Arc::new(RateLimiter::new(limit)));
+                }
+                let templates = self.path_templates(Some(adaptor.name()));
+                adaptor.with_retry_policy(policy).with_throttle(throttle).with_path_templates(templates)
+            })
+            .collect()
     }
 
     /// Returns the configured staging location
@@ -465,10 +968,87 @@ impl Config {
         self.staging.clone()
     }
 
+    /// Returns the retry policy for the backend called `name`
+    pub fn retry_policy(&self, name: &str) -> RetryPolicy {
+        let retry = match &self.retry {
+            Some(retry) => retry,
+            None => return Default::default(),
+        };
+        [DEFAULT_RETRY, name]
+            .iter()
+            .filter_map(|name| retry.get(*name))
+            .fold(RetryPolicy::default(), |policy, config| policy.apply(config))
+    }
+
+    /// Returns the upload limit in bytes per second for the backend called `name`, or the limit
+    /// shared between every backend if `name` is None
+    pub fn bandwidth_limit(&self, name: Option<&str>) -> Option<u64> {
+        let bandwidth = self.bandwidth.as_ref()?;
+        let kbytes = match name {
+            None => bandwidth.limit_kbytes_per_sec?,
+            Some(name) => *bandwidth.backends.as_ref()?.get(name)?,
+        };
+        Some(kbytes * 1024)
+    }
+
+    /// Returns the templates deciding where files from each device go on the backend called
+    /// `name`, or in staging if `name` is None. A backend's own template wins over any device
+    /// templates, which win over the global one.
+    pub fn path_templates(&self, name: Option<&str>) -> PathTemplates {
+        match name.and_then(|name| self.parsed.backend_templates.get(name)) {
+            Some(template) => PathTemplates::new(Some(template.clone()), BTreeMap::new()),
+            None => self.parsed.templates.clone(),
+        }
+    }
+
+    /// Returns the clocks of every device, for working out when their files were captured
+    pub fn device_clocks(&self) -> DeviceClocks {
+        self.parsed.clocks.clone()
+    }
+
+    /// Are uploads allowed at `time`? They always are if no upload windows are configured.
+    pub fn uploads_allowed_at(&self, time: NaiveTime) -> bool {
+        let windows = &self.parsed.windows;
+        windows.is_empty() || windows.iter().any(|window| window.contains(time))
+    }
+
+    /// Returns the router deciding which backends each file is sent to
+    pub fn router(&self) -> Router {
+        Router::new(self.route.clone().unwrap_or_else(|| vec![]))
+    }
+
     /// Should we be saving the files from the devices?
     pub fn preserve_device_files(&self) -> bool {
         self.stokepile.preserve_device_files.unwrap_or(false)
     }
+
+    /// Should we check what we staged before deleting it from the device?
+    pub fn verify_staging(&self) -> bool {
+        self.stokepile.verify_staging.unwrap_or(false)
+    }
+
+    /// How many files should each storage adaptor be uploading at once?
+    pub fn upload_concurrency(&self) -> usize {
+        self.stokepile.upload_concurrency.unwrap_or(1)
+    }
+
+    /// What order should files be staged in?
+    pub fn staging_order(&self) -> StagingOrder {
+        self.stokepile.staging_order.unwrap_or(StagingOrder::SmallestFirst)
+    }
+
+    /// How long after uploading something should we skip staging it again?
+    pub fn dedupe_window(&self) -> chrono::Duration {
+        chrono::Duration::days(self.stokepile.dedupe_days.unwrap_or(staging::DEFAULT_DEDUPE_DAYS).into())
+    }
+
+    /// Which devices should be staged from first?
+    pub fn device_priority(&self) -> &[String] {
+        match &self.stokepile.device_priority {
+            Some(devices) => &devices[..],
+            None => &[],
+        }
+    }
 }
 
 impl ConfigBuilder {
@@ -490,14 +1070,41 @@ impl ConfigBuilder {
         self
     }
 
-    /// Add this flysight to the config object
-    pub fn flysight(mut self, flysight: FlysightConfig) -> Self {
-        let mut flysights = self.flysight.unwrap_or_else(|| vec![]);
-        flysights.push(flysight);
-        self.flysight = Some(flysights);
+    /// Configure an S3 compatible object store for this object. This enables s3 support.
+    pub fn s3(mut self, s3: S3Config) -> Self {
+        self.s3 = Some(s3);
+        self
+    }
+
+    /// Configure a remote host to archive to over sftp. This enables sftp support.
+    pub fn sftp(mut self, sftp: SftpConfig) -> Self {
+        self.sftp = Some(sftp);
+        self
+    }
+
+    /// Configure a WebDAV server to archive to. This enables webdav support.
+    pub fn webdav(mut self, webdav: WebdavConfig) -> Self {
+        self.webdav = Some(webdav);
+        self
+    }
+
+    /// Set the google drive access token for this object. This enables google drive support.
+    pub fn google_drive(mut self, token: String) -> Self {
+        self.google_drive = Some(GoogleDriveConfig { token, folder: None });
         self
     }
 
+    /// Set the youtube access token for this object. This enables youtube support.
+    pub fn youtube(mut self, token: String) -> Self {
+        self.youtube = Some(YoutubeConfig { token, privacy: None });
+        self
+    }
+
+    /// Add this flysight to the config object
+    pub fn flysight(self, flysight: FlysightConfig) -> Self {
+        self.typed_device("flysight", flysight)
+    }
+
     /// Add multiple flysights to this config
     pub fn flysights(self, flysights: Vec<FlysightConfig>) -> Self {
         flysights
@@ -506,11 +1113,8 @@ impl ConfigBuilder {
     }
 
     /// Add this mass_storage to the config object
-    pub fn mass_storage(mut self, mass_storage: MassStorageConfig) -> Self {
-        let mut mass_storages = self.mass_storage.unwrap_or_else(|| vec![]);
-        mass_storages.push(mass_storage);
-        self.mass_storage = Some(mass_storages);
-        self
+    pub fn mass_storage(self, mass_storage: MassStorageConfig) -> Self {
+        self.typed_device("mass_storage", mass_storage)
     }
 
     /// Add multiple mass_storages to this config
@@ -521,11 +1125,8 @@ impl ConfigBuilder {
     }
 
     /// Add this gopro to the config object
-    pub fn gopro(mut self, gopro: GoproConfig) -> Self {
-        let mut gopros = self.gopro.unwrap_or_else(|| vec![]);
-        gopros.push(gopro);
-        self.gopro = Some(gopros);
-        self
+    pub fn gopro(self, gopro: GoproConfig) -> Self {
+        self.typed_device("gopro", gopro)
     }
 
     /// Add multiple gopros to this config
@@ -533,6 +1134,19 @@ impl ConfigBuilder {
         gopros.into_iter().fold(self, |cfg, gopro| cfg.gopro(gopro))
     }
 
+    /// Add a device of `kind` to this config, configured however that kind expects
+    pub fn device(mut self, kind: &str, config: toml::Value) -> Self {
+        let mut devices = self.device.unwrap_or_default();
+        devices.entry(kind.to_string()).or_insert_with(|| vec![]).push(config);
+        self.device = Some(devices);
+        self
+    }
+
+    fn typed_device<T: Serialize>(self, kind: &str, config: T) -> Self {
+        let config = toml::Value::try_from(config).expect("Device configs are always valid toml");
+        self.device(kind, config)
+    }
+
     /// Add a local backup to this config
     pub fn local_backup(mut self, local_backup: LocalBackupConfig) -> Self {
         let mut local_backups = self.local_backup.unwrap_or_else(|| vec![]);
@@ -571,6 +1185,109 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn verify_staging(mut self) -> Self {
+        self.stokepile.verify_staging = Some(true);
+        self
+    }
+
+    /// Set the retry policy for the backend called `name`, or for every backend if it's `default`
+    pub fn retry(mut self, name: &str, retry: RetryConfig) -> Self {
+        self.retry
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.to_string(), retry);
+        self
+    }
+
+    /// Limit how fast we upload across every backend, in kilobytes per second
+    pub fn bandwidth_limit(mut self, kbytes_per_sec: u64) -> Self {
+        self.bandwidth
+            .get_or_insert_with(Default::default)
+            .limit_kbytes_per_sec = Some(kbytes_per_sec);
+        self
+    }
+
+    /// Limit how fast we upload to the backend called `name`, in kilobytes per second
+    pub fn backend_bandwidth_limit(mut self, name: &str, kbytes_per_sec: u64) -> Self {
+        self.bandwidth
+            .get_or_insert_with(Default::default)
+            .backends
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.to_string(), kbytes_per_sec);
+        self
+    }
+
+    /// Only upload between `start` and `end`, which are local times like `19:00`
+    pub fn upload_window(mut self, start: &str, end: &str) -> Self {
+        self.upload_window
+            .get_or_insert_with(Vec::new)
+            .push(UploadWindowConfig {
+                start: start.to_string(),
+                end: end.to_string(),
+            });
+        self
+    }
+
+    /// Set the template for where files end up, in staging and on every backend
+    pub fn path_template(mut self, template: &str) -> Self {
+        self.path_template
+            .get_or_insert_with(Default::default)
+            .template = Some(template.to_string());
+        self
+    }
+
+    /// Set the template for where files end up on the backend called `name`
+    pub fn backend_path_template(mut self, name: &str, template: &str) -> Self {
+        self.path_template
+            .get_or_insert_with(Default::default)
+            .backends
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.to_string(), template.to_string());
+        self
+    }
+
+    /// Set the template for where files from the device called `name` end up
+    pub fn device_path_template(mut self, name: &str, template: &str) -> Self {
+        self.path_template
+            .get_or_insert_with(Default::default)
+            .devices
+            .get_or_insert_with(BTreeMap::new)
+            .insert(name.to_string(), template.to_string());
+        self
+    }
+
+    /// Add a route deciding where files are uploaded to. Routes are checked in the order they're
+    /// added.
+    pub fn route(mut self, route: RouteConfig) -> Self {
+        let mut routes = self.route.unwrap_or_else(|| vec![]);
+        routes.push(route);
+        self.route = Some(routes);
+        self
+    }
+
+    /// Set how many files each storage adaptor uploads at once
+    pub fn upload_concurrency(mut self, concurrency: usize) -> Self {
+        self.stokepile.upload_concurrency = Some(concurrency);
+        self
+    }
+
+    /// Set the order files are staged in
+    pub fn staging_order(mut self, order: StagingOrder) -> Self {
+        self.stokepile.staging_order = Some(order);
+        self
+    }
+
+    /// Skip staging files whose contents were uploaded within this many days
+    pub fn dedupe_days(mut self, days: u32) -> Self {
+        self.stokepile.dedupe_days = Some(days);
+        self
+    }
+
+    /// Stage files from the named devices before any others, most important first
+    pub fn device_priority(mut self, devices: Vec<String>) -> Self {
+        self.stokepile.device_priority = Some(devices);
+        self
+    }
+
     /// Finalise this config object
     pub fn finish(self) -> Result<Config, ConfigError> {
         let staging = match self.staging {
@@ -582,13 +1299,25 @@ impl ConfigBuilder {
             staging: staging,
             dropbox: self.dropbox,
             vimeo: self.vimeo,
-            flysight: self.flysight,
-            gopro: self.gopro,
+            s3: self.s3,
+            sftp: self.sftp,
+            webdav: self.webdav,
+            google_drive: self.google_drive,
+            youtube: self.youtube,
+            flysight: None,
+            gopro: None,
             local_backup: self.local_backup,
-            mass_storage: self.mass_storage,
+            route: self.route,
+            retry: self.retry,
+            bandwidth: self.bandwidth,
+            upload_window: self.upload_window,
+            path_template: self.path_template,
+            mass_storage: None,
+            device: self.device,
             sendgrid: self.sendgrid,
             pushover: self.pushover,
             web_notifications: self.web_notifications,
+            parsed: Default::default(),
         })
     }
 }
@@ -598,6 +1327,13 @@ mod tests {
     use super::*;
     use crate::test_helpers;
 
+    /// Just enough config to be valid, for tests to add what they're interested in to.
+    fn minimal_config() -> ConfigBuilder {
+        Config::build()
+            .dropbox("TOKEN".into())
+            .staging(StagingConfig { location: MountableDeviceLocation::Mountpoint("/test".into()) })
+    }
+
     #[test]
     fn test_example_config_parses() {
         let config = Config::from_file("stokepile.toml.example").unwrap();
@@ -608,6 +1344,11 @@ mod tests {
                 api_token: Some("STOKEPILE_TOKEN_GOES_HERE".into()),
                 api_base: Some("https://test-api.base".into()),
                 preserve_device_files: None,
+                verify_staging: None,
+                upload_concurrency: None,
+                staging_order: None,
+                device_priority: None,
+                dedupe_days: None,
             }
         );
 
@@ -626,21 +1367,25 @@ mod tests {
         );
 
         assert_eq!(
-            config.flysight,
-            Some(vec![FlysightConfig {
+            config.device_entries::<FlysightConfig>("flysight").unwrap(),
+            vec![FlysightConfig {
                 name: "data".into(),
                 location: MountableDeviceLocation::from_mountpoint("/mnt/stokepile/flysight".into()),
-            }])
+                timezone: None,
+                clock_skew_secs: None,
+            }]
         );
 
         assert_eq!(
-            config.mass_storage,
-            Some(vec![MassStorageConfig {
+            config.device_entries::<MassStorageConfig>("mass_storage").unwrap(),
+            vec![MassStorageConfig {
                 name: "video".into(),
                 location: MountableDeviceLocation::from_mountpoint("/mnt/stokepile/mass_storage".into()),
                 extensions: vec!["mp4".into()],
                 cleanup_extensions: Some(vec!["lrv".into(), "thm".into()]),
-            }])
+                timezone: None,
+                clock_skew_secs: None,
+            }]
         );
 
         assert_eq!(
@@ -804,6 +1549,584 @@ token = "TOKEN"
         assert_eq!(cfg.backends().len(), 2);
     }
 
+    #[test]
+    fn test_s3_backend() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[s3]
+endpoint = "http://localhost:9000"
+bucket = "footage"
+access_key_id = "ACCESS_KEY"
+secret_access_key = "SECRET_KEY"
+"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.s3,
+                   Some(S3Config {
+                       endpoint: "http://localhost:9000".into(),
+                       bucket: "footage".into(),
+                       region: None,
+                       prefix: None,
+                       access_key_id: "ACCESS_KEY".into(),
+                       secret_access_key: "SECRET_KEY".into(),
+                   }));
+        let backends = cfg.backends();
+        let backend_names: Vec<_> = backends.iter().map(|b| b.name()).collect();
+        assert_eq!(&backend_names, &["s3"]);
+    }
+
+    #[test]
+    fn test_invalid_s3_endpoint() {
+        let err = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[s3]
+endpoint = "localhost"
+bucket = "footage"
+access_key_id = "ACCESS_KEY"
+secret_access_key = "SECRET_KEY"
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(ConfigError::InvalidS3Endpoint(url::ParseError::RelativeUrlWithoutBase), err);
+    }
+
+    #[test]
+    fn test_sftp_backend() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[sftp]
+host = "nas.local"
+username = "stokepile"
+private_key = "/home/stokepile/.ssh/id_ed25519"
+root = "/volume1/footage"
+"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.sftp,
+                   Some(SftpConfig {
+                       host: "nas.local".into(),
+                       port: None,
+                       username: "stokepile".into(),
+                       private_key: "/home/stokepile/.ssh/id_ed25519".into(),
+                       passphrase: None,
+                       known_hosts: None,
+                       root: "/volume1/footage".into(),
+                   }));
+        let backends = cfg.backends();
+        let backend_names: Vec<_> = backends.iter().map(|b| b.name()).collect();
+        assert_eq!(&backend_names, &["sftp"]);
+    }
+
+    #[test]
+    fn test_relative_sftp_root() {
+        let err = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[sftp]
+host = "nas.local"
+username = "stokepile"
+private_key = "/home/stokepile/.ssh/id_ed25519"
+root = "footage"
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(ConfigError::RelativeSftpRoot, err);
+    }
+
+    #[test]
+    fn test_webdav_backend() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[webdav]
+url = "https://cloud.example.org/remote.php/dav/files/richo/footage"
+username = "richo"
+password = "APP_PASSWORD"
+"#,
+        )
+        .unwrap();
+        let backends = cfg.backends();
+        let backend_names: Vec<_> = backends.iter().map(|b| b.name()).collect();
+        assert_eq!(&backend_names, &["webdav"]);
+    }
+
+    #[test]
+    fn test_google_drive_backend() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[google_drive]
+token = "TOKEN"
+folder = "footage"
+"#,
+        )
+        .unwrap();
+        let backends = cfg.backends();
+        let backend_names: Vec<_> = backends.iter().map(|b| b.name()).collect();
+        assert_eq!(&backend_names, &["google_drive"]);
+    }
+
+    #[test]
+    fn test_youtube_backend() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[youtube]
+token = "TOKEN"
+"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.youtube.as_ref().unwrap().privacy, None);
+        let backends = cfg.backends();
+        let backend_names: Vec<_> = backends.iter().map(|b| b.name()).collect();
+        assert_eq!(&backend_names, &["youtube"]);
+    }
+
+    #[test]
+    fn test_youtube_privacy() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[youtube]
+token = "TOKEN"
+privacy = "private"
+"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.youtube.unwrap().privacy, Some(YoutubePrivacy::Private));
+    }
+
+    #[test]
+    fn test_routes() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[local_backup]]
+mountpoint = "/mnt/backup"
+
+[[route]]
+devices = ["flysight"]
+kind = "date_time"
+backends = ["dropbox", "local backup"]
+
+[[route]]
+extensions = ["mp4"]
+min_size = 1024
+backends = ["dropbox"]
+"#,
+        )
+        .unwrap();
+        let routes = cfg.route.unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].kind, Some(RouteKind::DateTime));
+        assert_eq!(routes[1].min_size, Some(1024));
+    }
+
+    #[test]
+    fn test_unknown_route_backend() {
+        let err = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[route]]
+backends = ["dropbox", "floppy"]
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(ConfigError::UnknownRouteBackend("floppy".into()), err);
+
+        let err = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[route]]
+backends = ["dropbox", "local backup"]
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(ConfigError::UnconfiguredRouteBackend("local backup".into()), err);
+    }
+
+    #[test]
+    fn test_retry_policies() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[retry.default]
+max_attempts = 5
+
+[retry.dropbox]
+initial_backoff_secs = 10
+"#,
+        )
+        .unwrap();
+        let dropbox = cfg.retry_policy("dropbox");
+        assert_eq!(dropbox.max_attempts, 5);
+        assert_eq!(dropbox.initial_backoff, std::time::Duration::from_secs(10));
+
+        let vimeo = cfg.retry_policy("vimeo");
+        assert_eq!(vimeo.max_attempts, 5);
+        assert_eq!(vimeo.initial_backoff, RetryPolicy::default().initial_backoff);
+
+        assert_eq!(cfg.backends()[0].retry_policy(), &dropbox);
+    }
+
+    #[test]
+    fn test_unknown_retry_backend() {
+        let err = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[retry.floppy]
+max_attempts = 5
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(ConfigError::UnknownRetryBackend("floppy".into()), err);
+    }
+
+    #[test]
+    fn test_staging_order() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+staging_order = "newest_first"
+device_priority = ["data"]
+
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[flysight]]
+name = "data"
+mountpoint = "/mnt/flysight"
+"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.staging_order(), StagingOrder::NewestFirst);
+        assert_eq!(cfg.device_priority(), &["data".to_string()]);
+
+        let err = Config::from_str(
+            r#"
+[stokepile]
+device_priority = ["gopro"]
+
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(ConfigError::UnknownPriorityDevice("gopro".into()), err);
+    }
+
+    #[test]
+    fn test_dedupe_window() {
+        let cfg = minimal_config().finish().unwrap();
+        assert_eq!(cfg.dedupe_window(), chrono::Duration::days(30));
+
+        let cfg = minimal_config().dedupe_days(0).finish().unwrap();
+        assert_eq!(cfg.dedupe_window(), chrono::Duration::zero());
+    }
+
+    #[test]
+    fn test_bandwidth_limits() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[vimeo]
+token = "TOKEN"
+
+[[local_backup]]
+location = "/mnt/backup"
+
+[bandwidth]
+limit_kbytes_per_sec = 2048
+
+[bandwidth.backends]
+vimeo = 512
+"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.bandwidth_limit(None), Some(2048 * 1024));
+        assert_eq!(cfg.bandwidth_limit(Some("vimeo")), Some(512 * 1024));
+        assert_eq!(cfg.bandwidth_limit(Some("dropbox")), None);
+        // The global limit is only for backends that go over the network.
+        for backend in cfg.backends() {
+            assert_eq!(backend.throttle().is_limited(), backend.name() != "local backup", "{}", backend.name());
+        }
+    }
+
+    #[test]
+    fn test_unknown_bandwidth_backend() {
+        let err = minimal_config()
+            .backend_bandwidth_limit("floppy", 512)
+            .finish()
+            .unwrap_err();
+        assert_eq!(ConfigError::UnknownBandwidthBackend("floppy".into()), err);
+    }
+
+    #[test]
+    fn test_upload_windows() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[upload_window]]
+start = "19:00"
+end = "07:00"
+"#,
+        )
+        .unwrap();
+        assert!(cfg.uploads_allowed_at(NaiveTime::from_hms(21, 0, 0)));
+        assert!(!cfg.uploads_allowed_at(NaiveTime::from_hms(14, 0, 0)));
+
+        let err = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[upload_window]]
+start = "7pm"
+end = "07:00"
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(ConfigError::InvalidUploadWindow("7pm".into(), "07:00".into()), err);
+    }
+
+    #[test]
+    fn test_path_templates() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[flysight]]
+name = "data"
+mountpoint = "/flysight"
+
+[path_template]
+template = "/{year}/{device}/{time}.{extension}"
+
+[path_template.backends]
+dropbox = "/{year}-{month}/{device}-{hash}.{extension}"
+
+[path_template.devices]
+data = "/flysight/{year}/{filename}.{extension}"
+"#,
+        )
+        .unwrap();
+        let global = PathTemplate::parse("/{year}/{device}/{time}.{extension}").unwrap();
+        let data = PathTemplate::parse("/flysight/{year}/{filename}.{extension}").unwrap();
+        let dropbox = PathTemplate::parse("/{year}-{month}/{device}-{hash}.{extension}").unwrap();
+
+        let staging = cfg.path_templates(None);
+        assert_eq!(staging.for_device("data"), Some(&data));
+        assert_eq!(staging.for_device("gopro"), Some(&global));
+        assert_eq!(cfg.path_templates(Some("vimeo")).for_device("data"), Some(&data));
+        assert_eq!(cfg.path_templates(Some("dropbox")).for_device("data"), Some(&dropbox));
+        assert_eq!(cfg.backends()[0].path_templates().for_device("gopro"), Some(&dropbox));
+    }
+
+    #[test]
+    fn test_device_clocks() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[flysight]]
+name = "data"
+mountpoint = "/flysight"
+
+[[gopro]]
+name = "gopro"
+serial = "C3131127500000"
+timezone = "+02:00"
+clock_skew_secs = -4
+"#,
+        )
+        .unwrap();
+        let clocks = cfg.device_clocks();
+        let reading = chrono::NaiveDate::from_ymd(2019, 6, 1).and_hms(9, 30, 0);
+        assert_eq!(clocks.for_device("data").capture_time(reading).to_rfc3339(), "2019-06-01T09:30:00+00:00");
+        assert_eq!(clocks.for_device("gopro").capture_time(reading).to_rfc3339(), "2019-06-01T09:30:04+02:00");
+
+        let err = Config::from_str(
+            r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[gopro]]
+name = "gopro"
+serial = "C3131127500000"
+timezone = "Europe/Berlin"
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(ConfigError::InvalidClockZone("gopro".into(), "Europe/Berlin".into()), err);
+    }
+
+    #[test]
+    fn test_device_sections() {
+        let cfg = Config::from_str(
+            r#"
+[stokepile]
+device_priority = ["comp"]
+[staging]
+mountpoint = "/test"
+
+[dropbox]
+token = "TOKEN"
+
+[[flysight]]
+name = "training"
+mountpoint = "/flysight"
+
+[[device.flysight]]
+name = "comp"
+label = "COMP_FLYSIGHT"
+clock_skew_secs = 3
+"#,
+        )
+        .unwrap();
+        let names: Vec<_> = cfg.configured_devices().iter().map(|d| &d.name[..]).collect();
+        assert_eq!(names, vec!["training", "comp"]);
+        // The deprecated section is read as though it were the first [[device.flysight]].
+        let entries: Vec<FlysightConfig> = cfg.device_entries("flysight").unwrap();
+        assert_eq!(entries[0].location, MountableDeviceLocation::from_mountpoint("/flysight".into()));
+        assert_eq!(entries[1].location, MountableDeviceLocation::Label("COMP_FLYSIGHT".into()));
+        assert_eq!(entries[1].clock_skew_secs, Some(3));
+        let toml = cfg.to_toml().unwrap();
+        assert!(!toml.contains("[[flysight]]"));
+        assert_eq!(toml.parse::<Config>().unwrap().configured_devices(), cfg.configured_devices());
+
+        let err = minimal_config()
+            .device("parachute", toml::Value::Table(Default::default()))
+            .finish()
+            .unwrap_err();
+        assert_eq!(ConfigError::UnknownDeviceKind("parachute".into()), err);
+
+        let err = minimal_config()
+            .device("gopro", toml::Value::Table(Default::default()))
+            .finish()
+            .unwrap_err();
+        assert_eq!(ConfigError::InvalidDevice("gopro".into(), "missing field `name`".into()), err);
+    }
+
+    #[test]
+    fn test_invalid_path_templates() {
+        let cfg = minimal_config().finish().unwrap();
+        assert_eq!(cfg.path_templates(None).for_device("data"), None);
+
+        let err = minimal_config()
+            .path_template("/{year}/{jumper}")
+            .finish()
+            .unwrap_err();
+        assert_eq!(
+            ConfigError::InvalidPathTemplate("/{year}/{jumper}".into(), "Unknown placeholder {jumper}.".into()),
+            err
+        );
+
+        let err = minimal_config()
+            .backend_path_template("floppy", "/{year}")
+            .finish()
+            .unwrap_err();
+        assert_eq!(ConfigError::UnknownPathTemplateBackend("floppy".into()), err);
+
+        let err = minimal_config()
+            .device_path_template("gopro", "/{year}")
+            .finish()
+            .unwrap_err();
+        assert_eq!(ConfigError::UnknownPathTemplateDevice("gopro".into()), err);
+    }
+
     #[test]
     fn test_pushover() {
         let cfg = Config::from_str(
@@ -854,30 +2177,32 @@ token="DROPBOX_TOKEN_GOES_HERE"
     }
 
     fn assert_no_mass_storages(cfg: &Config) {
-        assert_eq!(cfg.mass_storage, None);
-        assert_eq!(cfg.mass_storages(), &vec![]);
+        assert_eq!(cfg.device_entries::<MassStorageConfig>("mass_storage").unwrap(), vec![]);
     }
 
     fn assert_no_flysights(cfg: &Config) {
-        assert_eq!(cfg.flysight, None);
-        assert_eq!(cfg.flysights(), &vec![]);
+        assert_eq!(cfg.device_entries::<FlysightConfig>("flysight").unwrap(), vec![]);
     }
 
     fn assert_mass_storages(cfg: &Config) {
         assert_eq!(
-            cfg.mass_storages(),
-            &vec![
+            cfg.device_entries::<MassStorageConfig>("mass_storage").unwrap(),
+            vec![
                 MassStorageConfig {
                     name: "front".into(),
                     location: MountableDeviceLocation::Mountpoint("/mnt/stokepile/front".into()),
                     extensions: vec!["mp4".into()],
                     cleanup_extensions: None,
+                    timezone: None,
+                    clock_skew_secs: None,
                 },
                 MassStorageConfig {
                     name: "back".into(),
                     location: MountableDeviceLocation::Label("back_mass_storage".into()),
                     extensions: vec!["mov".into()],
                     cleanup_extensions: None,
+                    timezone: None,
+                    clock_skew_secs: None,
                 }
             ]
         )
@@ -885,15 +2210,19 @@ token="DROPBOX_TOKEN_GOES_HERE"
 
     fn assert_gopros(cfg: &Config) {
         assert_eq!(
-            cfg.gopros(),
-            &vec![
+            cfg.device_entries::<GoproConfig>("gopro").unwrap(),
+            vec![
                 GoproConfig {
                     name: "gopro4".into(),
                     serial: "C3131127500000".into(),
+                    timezone: None,
+                    clock_skew_secs: None,
                 },
                 GoproConfig {
                     name: "gopro5".into(),
                     serial: "C3131127500001".into(),
+                    timezone: None,
+                    clock_skew_secs: None,
                 }
             ]
         )
@@ -901,15 +2230,19 @@ token="DROPBOX_TOKEN_GOES_HERE"
 
     fn assert_flysights(cfg: &Config) {
         assert_eq!(
-            cfg.flysights(),
-            &vec![
+            cfg.device_entries::<FlysightConfig>("flysight").unwrap(),
+            vec![
                 FlysightConfig {
                     name: "training".into(),
                     location: MountableDeviceLocation::Mountpoint("/mnt/stokepile/training".into()),
+                    timezone: None,
+                    clock_skew_secs: None,
                 },
                 FlysightConfig {
                     name: "comp".into(),
                     location: MountableDeviceLocation::Label("COMP_FLYSIGHT".into()),
+                    timezone: None,
+                    clock_skew_secs: None,
                 }
             ]
         )
@@ -1,127 +1,227 @@
-use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 
 use failure::Error;
 
-use crate::config;
+use crate::android::AndroidDevices;
+use crate::clock::DeviceClocks;
+use crate::config::{self, ConfigBuilder, ConfigError};
 use crate::ctx;
-use crate::ptp_device;
-use crate::staging::{StageFromDevice, StagingLocation, Stager};
-use crate::mountable::{Mountable, MountableFilesystem};
-use crate::mass_storage;
+use crate::flysight::FlysightDevices;
+use crate::mass_storage::MassStorageDevices;
+use crate::mountable::Mountable;
+use crate::plan::{PlannedFile, StagingPlan};
+use crate::ptp_device::{GoproDevices, PtpCameraDevices};
+use crate::staging::{DeviceFiles, StageFromDevice, Staged, StagingLocation, Stager, UploadDescriptor};
 
-#[derive(Eq, PartialEq, Debug, Hash)]
-pub struct DeviceDescription {
+/// Every kind of device we know about. Adding a kind is a matter of implementing `DeviceKind` and
+/// listing it here.
+pub static KINDS: &[&dyn DeviceKind] = &[
+    &GoproDevices, &PtpCameraDevices, &AndroidDevices, &FlysightDevices, &MassStorageDevices,
+];
+
+/// A kind of device we know how to find and stage from, like a GoPro or a FlySight.
+///
+/// Each kind parses the config for its own devices, works out which of them are attached, and
+/// says how to get at their files once they are.
+pub trait DeviceKind: Sync {
+    /// What this kind is called in the config, eg `flysight`.
+    fn name(&self) -> &'static str;
+
+    /// Parse and check the config of every device of this kind.
+    fn configured(&self, cfg: &config::Config) -> Result<Vec<ConfiguredDevice>, ConfigError>;
+
+    /// The configured devices of this kind that are attached right now.
+    fn locate<'a>(&self, ctx: &'a ctx::Ctx) -> Result<Vec<Device<'a>>, Error>;
+
+    /// What the web UI calls this kind, if devices of it can be set up there.
+    fn web_name(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Add a device that was set up in the web UI, which only knows its name and an identifier.
+    fn add_from_web(&self, cfg: ConfigBuilder, _name: String, _identifier: String) -> ConfigBuilder {
+        cfg
+    }
+}
+
+/// Find the kind that the web UI calls `name`.
+pub fn kind_for_web(name: &str) -> Option<&'static dyn DeviceKind> {
+    KINDS.iter().cloned().find(|kind| kind.web_name() == Some(name))
+}
+
+/// What every device has in its config, whatever its kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfiguredDevice {
     pub name: String,
+    /// The zone the device's clock is set to, with the kind's default filled in.
+    pub timezone: String,
+    /// How many seconds ahead of the real time the device's clock runs.
+    pub clock_skew_secs: Option<i64>,
 }
 
-#[derive(Eq, PartialEq, Debug, Hash)]
-// TODO(richo) if we implement the ptp connection stuff in terms of mount, suddenly we can unify
-// this whole thing behind a trait!, and I think the DeviceDescription is now pointless as well.
-pub enum Device<'a> {
-    Gopro(DeviceDescription, ptp_device::Gopro<'a>),
-    MassStorage(DeviceDescription, config::MassStorageConfig),
-    Flysight(DeviceDescription, config::FlysightConfig),
-}
-
-impl Device<'_> {
-    pub fn stage_files<T: StagingLocation>(self, stager: &Stager<T>) -> Result<usize, Error> {
-        match self {
-            Device::Gopro(desc, gopro) => {
-                Mountable::mount(gopro)?.stage_files(&desc.name, stager)
-            },
-            Device::MassStorage(desc, mass_storage) => {
-                Mountable::mount(mass_storage)?.stage_files(&desc.name, stager)
-            },
-            Device::Flysight(desc, flysight) => {
-                Mountable::mount(flysight)?.stage_files(&desc.name, stager)
-            },
+/// A configured device that's attached, and can be mounted to get at its files.
+pub trait AttachedDevice<'a>: fmt::Debug {
+    fn mount(self: Box<Self>, name: &str) -> Result<Box<dyn ListedFiles + 'a>, Error>;
+}
+
+impl<'a, M> AttachedDevice<'a> for M
+where
+    M: Mountable + fmt::Debug,
+    M::Target: StageFromDevice + 'a,
+{
+    fn mount(self: Box<Self>, name: &str) -> Result<Box<dyn ListedFiles + 'a>, Error> {
+        Ok(Box::new(DeviceFiles::list(name, Mountable::mount(*self)?)?))
+    }
+}
+
+/// The files on a mounted device, see `DeviceFiles`.
+pub trait ListedFiles: fmt::Debug {
+    fn name(&self) -> &str;
+    fn descriptors(&self, clocks: &DeviceClocks) -> Result<Vec<(usize, UploadDescriptor)>, Error>;
+    fn stage(&mut self, index: usize, stager: &Stager<&dyn StagingLocation>) -> Result<Staged, Error>;
+    fn cleanup(&self) -> Result<(), Error>;
+}
+
+impl<D: StageFromDevice> ListedFiles for DeviceFiles<D> {
+    fn name(&self) -> &str {
+        DeviceFiles::name(self)
+    }
+
+    fn descriptors(&self, clocks: &DeviceClocks) -> Result<Vec<(usize, UploadDescriptor)>, Error> {
+        DeviceFiles::descriptors(self, clocks)
+    }
+
+    fn stage(&mut self, index: usize, stager: &Stager<&dyn StagingLocation>) -> Result<Staged, Error> {
+        DeviceFiles::stage(self, index, stager)
+    }
+
+    fn cleanup(&self) -> Result<(), Error> {
+        DeviceFiles::cleanup(self)
+    }
+}
+
+/// A device that's attached, which is the same device as another if they have the same kind and
+/// name.
+#[derive(Debug)]
+pub struct Device<'a> {
+    kind: &'static str,
+    name: String,
+    device: Box<dyn AttachedDevice<'a> + 'a>,
+}
+
+impl<'a> Device<'a> {
+    pub fn new<D>(kind: &'static str, name: String, device: D) -> Device<'a>
+        where D: AttachedDevice<'a> + 'a
+    {
+        Device {
+            kind,
+            name,
+            device: Box::new(device),
         }
     }
 
+    /// Mount this device and find out what's on it, so that it can be planned for.
+    pub fn mount(self) -> Result<MountedDevice<'a>, Error> {
+        Ok(MountedDevice {
+            files: self.device.mount(&self.name)?,
+        })
+    }
+
     pub fn name(&self) -> &str {
-        match self {
-            Device::Gopro(ref desc, _)
-            | Device::MassStorage(ref desc, _)
-            | Device::Flysight(ref desc, _) => &desc.name[..],
-        }
+        &self.name
     }
 
-    pub fn mass_storage_files(self) -> Result<Vec<mass_storage::MassStorageFile>, Error> {
-        match self {
-            Device::Gopro(desc, gopro) => {
-                unreachable!()
-            },
-            Device::MassStorage(desc, mass_storage) => {
-                Mountable::mount(mass_storage)?.files()
-            },
-            Device::Flysight(desc, flysight) => {
-                unreachable!()
-            },
-        }
+    pub fn kind(&self) -> &'static str {
+        self.kind
     }
 }
 
-pub fn attached_devices(ctx: &ctx::Ctx) -> Result<Vec<Device<'_>>, Error> {
-    let mut devices = vec![];
+impl PartialEq for Device<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.name == other.name
+    }
+}
 
-    // Should errors actually stop us finding other devices?
-    devices.extend(locate_gopros(&ctx)?);
-    devices.extend(locate_flysights(&ctx.cfg)?);
-    devices.extend(locate_mass_storages(&ctx.cfg)?);
-
-    Ok(devices)
-}
-
-fn locate_gopros(ctx: &ctx::Ctx) -> Result<impl Iterator<Item = Device<'_>>, Error> {
-    let gopro_serials: HashMap<_, _> = ctx
-        .cfg
-        .gopros()
-        .iter()
-        .map(|x| (x.serial.clone(), x.name.clone()))
-        .collect();
-
-    Ok(ptp_device::locate_gopros(ctx)?
-        .into_iter()
-        .filter_map(move |gopro| {
-            gopro_serials.get(&gopro.serial).map(|name| {
-                Device::Gopro(
-                    DeviceDescription {
-                        name: name.to_string(),
-                    },
-                    gopro,
-                )
-            })
-        }))
+impl Eq for Device<'_> {}
+
+impl Hash for Device<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind.hash(state);
+        self.name.hash(state);
+    }
 }
 
-fn locate_flysights(
-    cfg: &config::Config,
-) -> Result<impl Iterator<Item = Device<'_>>, Error> {
-    Ok(cfg.flysights().iter().filter_map(|cfg| {
-        cfg.clone().get().map(|fs| {
-            Device::Flysight(
-                DeviceDescription {
-                    name: cfg.name().to_string(),
-                },
-                fs,
-            )
-        })
-    }))
+/// A device that's mounted, with its files listed and waiting to be staged.
+#[derive(Debug)]
+pub struct MountedDevice<'a> {
+    files: Box<dyn ListedFiles + 'a>,
 }
 
-fn locate_mass_storages(
+impl MountedDevice<'_> {
+    pub fn name(&self) -> &str {
+        self.files.name()
+    }
+
+    /// Describe every file on the device that hasn't been staged yet, along with its index.
+    pub fn descriptors(&self, clocks: &DeviceClocks) -> Result<Vec<(usize, UploadDescriptor)>, Error> {
+        self.files.descriptors(clocks)
+    }
+
+    pub fn stage<T: StagingLocation>(&mut self, index: usize, stager: &Stager<T>) -> Result<Staged, Error> {
+        self.files.stage(index, &stager.borrowed())
+    }
+
+    pub fn cleanup(&self) -> Result<(), Error> {
+        self.files.cleanup()
+    }
+
+    fn planned_files(&self, device: usize, clocks: &DeviceClocks) -> Result<Vec<PlannedFile>, Error> {
+        Ok(self.descriptors(clocks)?
+            .into_iter()
+            .map(|(index, desc)| PlannedFile { device, index, desc })
+            .collect())
+    }
+}
+
+/// Work out which files on `devices` will fit in the `available` bytes we have in staging.
+///
+/// A device we can't describe every file on, say because one has a date we can't make sense of,
+/// is left out of the plan entirely and returned by its index along with why.
+pub fn plan_staging(
+    devices: &[MountedDevice<'_>],
+    available: u64,
     cfg: &config::Config,
-) -> Result<impl Iterator<Item = Device<'_>>, Error> {
-    Ok(cfg.mass_storages().iter().filter_map(|cfg| {
-        cfg.clone().get().map(|ms| {
-            Device::MassStorage(
-                DeviceDescription {
-                    name: cfg.name.clone(),
-                },
-                ms,
-            )
-        })
-    }))
+) -> (StagingPlan, Vec<(usize, Error)>) {
+    let clocks = cfg.device_clocks();
+    let mut files = vec![];
+    let mut unplanned = vec![];
+    for (i, device) in devices.iter().enumerate() {
+        match device.planned_files(i, &clocks) {
+            Ok(planned) => files.extend(planned),
+            Err(e) => unplanned.push((i, e)),
+        }
+    }
+    (StagingPlan::new(files, available, cfg.staging_order(), cfg.device_priority()), unplanned)
+}
+
+/// Find every configured device that's attached, along with the kinds we couldn't look for and
+/// why. One kind failing, like a phone refusing to talk MTP, doesn't hide the devices of the others.
+pub fn attached_devices(ctx: &ctx::Ctx) -> (Vec<Device<'_>>, Vec<(String, Error)>) {
+    let mut devices = vec![];
+    let mut failed = vec![];
+
+    for kind in KINDS {
+        match kind.locate(ctx) {
+            Ok(found) => devices.extend(found),
+            Err(e) => {
+                error!("Couldn't look for {} devices: {:?}", kind.name(), e);
+                failed.push((kind.name().to_string(), e));
+            }
+        }
+    }
+
+    (devices, failed)
 }
 
 #[cfg(test)]
@@ -129,27 +229,84 @@ mod tests {
     use crate::config::Config;
     use super::*;
 
+    fn ctx() -> ctx::Ctx {
+        let cfg = Config::from_file("test-data/stokepile.toml").unwrap();
+        ctx::Ctx::create_without_lock(cfg).unwrap()
+    }
+
     #[test]
     fn test_locates_flysights() {
-        let cfg = Config::from_file("test-data/stokepile.toml").unwrap();
-        let flysights: Vec<_> = locate_flysights(&cfg).unwrap().collect();
+        let ctx = ctx();
+        let flysights = FlysightDevices.locate(&ctx).unwrap();
         assert_eq!(flysights.len(), 1);
-        if let Device::Flysight(ref _desc, ref flysight) = flysights[0] {
-            assert_eq!(&flysight.name()[..], "data");
-        } else {
-            panic!("Unsure what we ended up with: {:?}", flysights);
-        }
+        assert_eq!(flysights[0].name(), "data");
+        assert_eq!(flysights[0].kind(), "flysight");
     }
 
     #[test]
     fn test_locates_mass_storages() {
+        let ctx = ctx();
+        let mass_storages = MassStorageDevices.locate(&ctx).unwrap();
+        assert_eq!(mass_storages.len(), 1);
+        assert_eq!(mass_storages[0].name(), "video");
+        assert_eq!(mass_storages[0].kind(), "mass_storage");
+    }
+
+    #[test]
+    fn test_configured_devices() {
         let cfg = Config::from_file("test-data/stokepile.toml").unwrap();
-        let flysights: Vec<_> = locate_flysights(&cfg).unwrap().collect();
-        assert_eq!(flysights.len(), 1);
-        if let Device::Flysight(ref _desc, ref flysight) = flysights[0] {
-            assert_eq!(&flysight.name()[..], "data");
-        } else {
-            panic!("Unsure what we ended up with: {:?}", flysights);
+        let names: Vec<_> = KINDS.iter()
+            .flat_map(|kind| kind.configured(&cfg).unwrap())
+            .map(|device| device.name)
+            .collect();
+        assert_eq!(names, vec![
+            "data", "unmounted_fs", "nonexistant_fs", "video", "unmounted_ms", "nonexistant_ms",
+        ]);
+        assert_eq!(kind_for_web("ptp").map(|kind| kind.name()), Some("gopro"));
+        assert!(kind_for_web("bogus").is_none());
+    }
+
+    /// A device with one file on it, or one we can't make sense of.
+    #[derive(Debug)]
+    struct OneFile {
+        name: String,
+        readable: bool,
+    }
+
+    impl ListedFiles for OneFile {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn descriptors(&self, _clocks: &DeviceClocks) -> Result<Vec<(usize, UploadDescriptor)>, Error> {
+            if !self.readable {
+                bail!("Couldn't parse the capture date of {}'s file", &self.name);
+            }
+            Ok(vec![(0, UploadDescriptor::test_descriptor())])
+        }
+
+        fn stage(&mut self, _index: usize, _stager: &Stager<&dyn StagingLocation>) -> Result<Staged, Error> {
+            unimplemented!()
         }
+
+        fn cleanup(&self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_unreadable_devices_are_left_out_of_the_plan() {
+        let cfg = Config::from_file("test-data/stokepile.toml").unwrap();
+        let devices: Vec<_> = [("broken", false), ("working", true)].iter()
+            .map(|(name, readable)| MountedDevice {
+                files: Box::new(OneFile { name: name.to_string(), readable: *readable }),
+            })
+            .collect();
+
+        let (plan, unplanned) = plan_staging(&devices, u64::MAX, &cfg);
+        assert_eq!(plan.staged().len(), 1);
+        assert_eq!(plan.staged()[0].device, 1);
+        assert_eq!(unplanned.len(), 1);
+        assert_eq!(unplanned[0].0, 0);
     }
 }
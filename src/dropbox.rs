@@ -2,17 +2,22 @@
 ///
 /// If this library is useful, I'll consider fleshing it out into a whole thing
 use serde::{Deserialize, Deserializer};
-use std::io::Read;
+use std::fs;
+use std::io::{self, Read};
 use std::path::Path;
 
-use crate::staging;
+use crate::retry::{ErrorKind, UploadError};
+use crate::staging::{self, Sidecar, StagedFile};
 use crate::storage::{StorageAdaptor, StorageStatus};
 use crate::version;
 
+use chrono::prelude::*;
+use chrono::Duration;
 use failure::Error;
 use hex::FromHex;
 use reqwest;
 use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
 use serde_json;
 
 lazy_static! {
@@ -21,6 +26,9 @@ lazy_static! {
 
 const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024;
 
+/// Dropbox forgets about upload sessions after a week, we give up on them a little before that.
+const SESSION_LIFETIME_HOURS: i64 = 6 * 24;
+
 #[derive(Clone, RedactedDebug)]
 pub struct DropboxFilesClient {
     #[redacted]
@@ -105,12 +113,107 @@ struct UploadSessionFinishRequest<'a> {
     commit: &'a Commit<'a>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 struct Cursor {
     session_id: String,
     offset: u64,
 }
 
+/// An upload session that was in progress, which we keep next to the staged file so that a later
+/// attempt can carry on from where the last one got to.
+#[derive(Serialize, Deserialize, Debug)]
+struct PersistedSession {
+    cursor: Cursor,
+    started: DateTime<Utc>,
+}
+
+impl PersistedSession {
+    fn load(path: &Path) -> Option<PersistedSession> {
+        let contents = fs::read(path).ok()?;
+        match serde_json::from_slice::<PersistedSession>(&contents) {
+            Ok(session) if Utc::now() - session.started < Duration::hours(SESSION_LIFETIME_HOURS) => {
+                Some(session)
+            }
+            Ok(session) => {
+                info!("Upload session {} has expired", &session.cursor.session_id);
+                None
+            }
+            Err(e) => {
+                warn!("Couldn't load upload session from {:?}: {:?}", path, e);
+                None
+            }
+        }
+    }
+
+    fn save(&self, path: Option<&Path>) -> Result<(), Error> {
+        if let Some(path) = path {
+            // Write it out alongside and move it into place, so a crash never leaves us with half
+            // a session that we'd have to start over from the beginning.
+            let mut partial = path.as_os_str().to_os_string();
+            partial.push(".partial");
+            {
+                let mut file = fs::File::create(&partial)?;
+                serde_json::to_writer(&mut file, self)?;
+                file.sync_all()?;
+            }
+            fs::rename(&partial, path)?;
+        }
+        Ok(())
+    }
+
+    fn discard(path: Option<&Path>) {
+        if let Some(path) = path {
+            if let Err(e) = fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Couldn't remove upload session {:?}: {:?}", path, e);
+                }
+            }
+        }
+    }
+}
+
+/// The errors from the upload session endpoints that we know how to recover from.
+#[derive(Fail, Debug)]
+pub enum SessionError {
+    #[fail(display = "Dropbox expected the upload to be at offset {}", _0)]
+    IncorrectOffset(u64),
+    #[fail(display = "The upload session no longer exists")]
+    NotFound,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    error: serde_json::Value,
+}
+
+/// Pull apart an error response from one of the upload session endpoints.
+///
+/// finish wraps the same errors as append_v2 in a lookup_failed.
+fn session_error(status: StatusCode, headers: &HeaderMap, text: &str) -> Error {
+    let other = || UploadError::from_response(status, headers, format!("Dropbox error: {}", text)).into();
+    let error = match serde_json::from_str::<ErrorResponse>(text) {
+        Ok(response) => response.error,
+        Err(_) => return other(),
+    };
+    let error = match error[".tag"].as_str() {
+        Some("lookup_failed") => &error["lookup_failed"],
+        _ => &error,
+    };
+    match error[".tag"].as_str() {
+        Some("incorrect_offset") => match error["correct_offset"].as_u64() {
+            Some(offset) => SessionError::IncorrectOffset(offset).into(),
+            None => other(),
+        },
+        Some("not_found") | Some("closed") => SessionError::NotFound.into(),
+        // Trying again won't help, but the file can go somewhere else.
+        Some("path") if error["path"][".tag"] == "conflict" => UploadError::new(
+            ErrorKind::Conflict,
+            "Dropbox already has a different file at this path, not overwriting it".to_string(),
+        ).into(),
+        _ => other(),
+    }
+}
+
 enum DropboxBody {
     JSON(Vec<u8>),
     Binary(Vec<u8>),
@@ -120,6 +223,19 @@ enum DropboxBody {
 struct Commit<'a> {
     path: &'a Path,
     mode: String,
+    autorename: bool,
+}
+
+impl<'a> Commit<'a> {
+    /// Commit to `path`, unless there's already a different file there. Dropbox doesn't count a
+    /// file with the same contents as a conflict.
+    fn new(path: &'a Path) -> Commit<'a> {
+        Commit {
+            path,
+            mode: "add".to_string(),
+            autorename: false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -136,11 +252,7 @@ impl<'a> UploadSession<'a> {
     }
 
     pub fn finish(self, path: &Path) -> Result<UploadMetadataResponse, Error> {
-        let commit = Commit {
-            path: &path,
-            mode: "overwrite".to_string(),
-        };
-        self.client.upload_session_finish(&[], self.cursor, commit)
+        self.client.upload_session_finish(&[], self.cursor, Commit::new(path))
     }
 }
 
@@ -224,6 +336,10 @@ impl DropboxFilesClient {
             headers,
         )?;
         let text = &res.text()?;
+        if !res.status().is_success() {
+            let message = format!("Dropbox error: {}", text);
+            Err(UploadError::from_response(res.status(), res.headers(), message))?;
+        }
         match serde_json::from_str(&text) {
             Ok(meta) => Ok(meta),
             Err(_) => Err(format_err!("Dropbox error: {}", text)),
@@ -243,7 +359,10 @@ impl DropboxFilesClient {
             Binary(data.to_vec()),
             headers,
         )?;
-        res.text()?;
+        let text = res.text()?;
+        if !res.status().is_success() {
+            return Err(session_error(res.status(), res.headers(), &text));
+        }
         Ok(())
     }
 
@@ -269,37 +388,83 @@ impl DropboxFilesClient {
             headers,
         )?;
         let text = res.text()?;
+        if !res.status().is_success() {
+            return Err(session_error(res.status(), res.headers(), &text));
+        }
         match serde_json::from_str(&text) {
             Ok(meta) => Ok(meta),
             Err(_) => Err(format_err!("Dropbox error: {}", text)),
         }
     }
-}
 
-impl<T> StorageAdaptor<T> for DropboxFilesClient
-where
-    T: Read,
-{
-    fn already_uploaded(&self, manifest: &staging::UploadDescriptor) -> bool {
-        match self.get_metadata(&manifest.remote_path()) {
-            Ok(ref metadata) if metadata.content_hash() == &manifest.content_hash => {
-                true
+    /// Pick up the session persisted at `path` if there is one, skipping over whatever Dropbox
+    /// already has, or start a new one.
+    fn resume_session<T: Read>(&self, reader: &mut T, path: Option<&Path>) -> Result<PersistedSession, Error> {
+        if let Some(session) = path.and_then(PersistedSession::load) {
+            info!("Resuming upload session {} at offset {}", &session.cursor.session_id, session.cursor.offset);
+            // We only have a Read, so we have to read our way up to the offset rather than seek.
+            let skipped = io::copy(&mut reader.by_ref().take(session.cursor.offset), &mut io::sink())?;
+            if skipped != session.cursor.offset {
+                PersistedSession::discard(path);
+                bail!("Upload session was at offset {} but the file is only {} bytes", session.cursor.offset, skipped);
             }
-            _ => false,
+            return Ok(session);
         }
+
+        let session = PersistedSession {
+            cursor: Cursor {
+                session_id: self.start_upload_session()?.session_id,
+                offset: 0,
+            },
+            started: Utc::now(),
+        };
+        session.save(path)?;
+        Ok(session)
     }
 
-    fn upload(
+    /// Send `chunk`, which starts at the session's current offset.
+    ///
+    /// If Dropbox disagrees about the offset and it lands inside this chunk we just send the rest
+    /// of it, otherwise we save the corrected offset and bail so the next attempt can resume from it.
+    fn append_chunk(&self, mut chunk: &[u8], session: &mut PersistedSession, path: Option<&Path>) -> Result<(), Error> {
+        while !chunk.is_empty() {
+            let error = match self.upload_session_append(chunk, &session.cursor) {
+                Ok(()) => {
+                    session.cursor.offset += chunk.len() as u64;
+                    return session.save(path);
+                }
+                Err(e) => e,
+            };
+
+            match error.downcast::<SessionError>() {
+                Ok(SessionError::IncorrectOffset(correct)) => {
+                    let start = session.cursor.offset;
+                    warn!("Dropbox expected offset {} rather than {}", correct, start);
+                    session.cursor.offset = correct;
+                    session.save(path)?;
+                    if correct < start || correct > start + chunk.len() as u64 {
+                        bail!("Upload session is at offset {}, resuming from there next attempt", correct);
+                    }
+                    chunk = &chunk[(correct - start) as usize..];
+                }
+                Ok(SessionError::NotFound) => {
+                    PersistedSession::discard(path);
+                    bail!("Upload session {} has gone away, starting over next attempt", &session.cursor.session_id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        session.save(path)
+    }
+
+    fn upload_with_session<T: Read>(
         &self,
         mut reader: T,
         manifest: &staging::UploadDescriptor,
+        path: Option<&Path>,
     ) -> Result<StorageStatus, Error> {
-        let id = self.start_upload_session()?;
+        let mut session = self.resume_session(&mut reader, path)?;
         let mut buffer = vec![0; DEFAULT_CHUNK_SIZE];
-        let mut cursor = Cursor {
-            session_id: id.session_id,
-            offset: 0,
-        };
 
         loop {
             // There's more juggling than I would really like here but ok :(
@@ -308,16 +473,70 @@ where
                 // We're probably at EOF? Hopefully?
                 break;
             }
-            self.upload_session_append(&buffer[..read_bytes], &cursor)?;
-            cursor.offset += read_bytes as u64;
+            self.append_chunk(&buffer[..read_bytes], &mut session, path)?;
         }
 
-        let commit = Commit {
-            path: &manifest.remote_path(),
-            mode: "overwrite".to_string(),
-        };
-        self.upload_session_finish(&[], cursor, commit)
-            .map(|r| r.into())
+        let remote_path = manifest.remote_path();
+        match self.upload_session_finish(&[], session.cursor, Commit::new(&remote_path)) {
+            Ok(response) => {
+                PersistedSession::discard(path);
+                Ok(response.into())
+            }
+            Err(e) => {
+                // If the session is gone or confused there's no point trying to resume it.
+                if e.downcast_ref::<SessionError>().is_some() {
+                    PersistedSession::discard(path);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T> StorageAdaptor<T> for DropboxFilesClient
+where
+    T: Read,
+{
+    fn already_uploaded(&self, manifest: &staging::UploadDescriptor) -> bool {
+        match self.get_metadata(&manifest.remote_path()) {
+            Ok(ref metadata) if metadata.content_hash() == &manifest.content_hash => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn verify(&self, manifest: &staging::UploadDescriptor) -> Result<(), Error> {
+        let metadata = self.get_metadata(&manifest.remote_path())?;
+        if metadata.content_hash() != &manifest.content_hash {
+            bail!(
+                "Dropbox has content_hash {} but we uploaded {}",
+                hex::encode(metadata.content_hash()),
+                hex::encode(manifest.content_hash)
+            );
+        }
+        if metadata.size as u64 != manifest.size {
+            bail!("Dropbox has {} bytes but we uploaded {}", metadata.size, manifest.size);
+        }
+        Ok(())
+    }
+
+    fn upload(
+        &self,
+        reader: T,
+        manifest: &staging::UploadDescriptor,
+    ) -> Result<StorageStatus, Error> {
+        self.upload_with_session(reader, manifest, None)
+    }
+
+    fn upload_staged(
+        &self,
+        reader: T,
+        manifest: &staging::UploadDescriptor,
+        staged: &StagedFile,
+    ) -> Result<StorageStatus, Error> {
+        let path = staged.sidecar_path(Sidecar::DropboxSession);
+        self.upload_with_session(reader, manifest, Some(&path))
     }
 
     fn name(&self) -> String {
@@ -329,10 +548,77 @@ where
 mod tests {
     use dropbox_content_hasher::DropboxContentHasher;
     use super::*;
+    use crate::retry::{self, ErrorKind};
     use sha2::Digest;
     use std::env;
     use std::fs;
 
+    #[test]
+    fn test_parses_session_errors() {
+        let conflict = |text| session_error(StatusCode::CONFLICT, &HeaderMap::new(), text);
+        let append = r#"{"error_summary": "incorrect_offset/..", "error": {".tag": "incorrect_offset", "correct_offset": 4194304}}"#;
+        match conflict(append).downcast::<SessionError>() {
+            Ok(SessionError::IncorrectOffset(4194304)) => {},
+            other => panic!("Unexpected error: {:?}", other),
+        }
+
+        let finish = r#"{"error_summary": "lookup_failed/not_found/", "error": {".tag": "lookup_failed", "lookup_failed": {".tag": "not_found"}}}"#;
+        match conflict(finish).downcast::<SessionError>() {
+            Ok(SessionError::NotFound) => {},
+            other => panic!("Unexpected error: {:?}", other),
+        }
+
+        let other = conflict(r#"{"error_summary": "path/insufficient_space/", "error": {".tag": "path"}}"#);
+        assert_eq!(retry::classify(&other), ErrorKind::Permanent);
+
+        let exists = conflict(r#"{"error_summary": "path/conflict/file/", "error": {".tag": "path", "path": {".tag": "conflict", "conflict": {".tag": "file"}}}}"#);
+        assert_eq!(retry::classify(&exists), ErrorKind::Conflict);
+        assert!(exists.to_string().contains("different file"));
+
+        let rate_limited = session_error(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new(), "too_many_requests");
+        assert_eq!(retry::classify(&rate_limited), ErrorKind::Quota);
+    }
+
+    #[test]
+    fn test_expired_sessions_are_not_resumed() {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let path = dir.path().join("session");
+        let mut session = PersistedSession {
+            cursor: Cursor {
+                session_id: "session".into(),
+                offset: 4,
+            },
+            started: Utc::now(),
+        };
+        session.save(Some(&path)).expect("Couldn't save session");
+        assert_eq!(PersistedSession::load(&path).map(|s| s.cursor.offset), Some(4));
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+
+        session.started = Utc::now() - Duration::days(7);
+        session.save(Some(&path)).expect("Couldn't save session");
+        assert!(PersistedSession::load(&path).is_none());
+    }
+
+    #[test]
+    fn test_resumed_sessions_skip_uploaded_data() {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let path = dir.path().join("session");
+        let session = PersistedSession {
+            cursor: Cursor {
+                session_id: "session".into(),
+                offset: 4,
+            },
+            started: Utc::now(),
+        };
+        session.save(Some(&path)).expect("Couldn't save session");
+
+        let client = DropboxFilesClient::new("TOKEN".into());
+        let mut reader = &b"yes hello"[..];
+        let session = client.resume_session(&mut reader, Some(&path)).expect("Couldn't resume session");
+        assert_eq!(session.cursor.session_id, "session");
+        assert_eq!(reader, b"hello");
+    }
+
     #[test]
     #[ignore]
     fn test_fetches_metadata() {
@@ -1,40 +1,139 @@
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
 pub use failure::Error;
 
-#[derive(Debug)]
+use crate::ptp_device::{ObjectInfo, PtpSession, ALL_STORAGES, ROOT_OBJECTS};
+
+/// A camera that only exists in memory. Objects are numbered from 1 in the order they're added.
+#[derive(Debug, Default)]
 pub struct PtpCamera<'c> {
-    _phantom: &'c std::marker::PhantomData<()>,
+    storages: Vec<u32>,
+    objects: BTreeMap<u32, Object>,
+    _phantom: PhantomData<&'c ()>,
+}
+
+#[derive(Debug)]
+struct Object {
+    storage_id: u32,
+    parent: u32,
+    info: PtpObjectInfo,
+    contents: Vec<u8>,
 }
 
 impl<'c> PtpCamera<'c> {
-    pub fn delete_object(&mut self, _handle: u32, _1: Option<()>) -> Result<(), Error> {
-        unimplemented!("You shouldn't be calling methods from dummy_ptp");
+    pub fn new() -> PtpCamera<'c> {
+        Default::default()
+    }
+
+    pub fn add_storage(&mut self, storage_id: u32) {
+        self.storages.push(storage_id);
+    }
+
+    /// Add an object to `storage_id`, in the folder `parent` or at the root if there isn't one.
+    /// Returns the new object's handle.
+    pub fn add_object(&mut self, storage_id: u32, parent: Option<u32>, info: PtpObjectInfo, contents: Vec<u8>) -> u32 {
+        let handle = self.objects.keys().next_back().map_or(1, |last| last + 1);
+        self.objects.insert(handle, Object {
+            storage_id,
+            parent: parent.unwrap_or(ROOT_OBJECTS),
+            info,
+            contents,
+        });
+        handle
+    }
+
+    pub fn has_object(&self, handle: u32) -> bool {
+        self.objects.contains_key(&handle)
+    }
+
+    fn object(&self, handle: u32) -> Result<&Object, Error> {
+        self.objects.get(&handle).ok_or_else(|| format_err!("No object with handle {}", handle))
+    }
+
+    pub fn get_storageids(&mut self, _1: Option<()>) -> Result<Vec<u32>, Error> {
+        Ok(self.storages.clone())
+    }
+
+    pub fn get_objecthandles(&mut self, storage_id: u32, handle_id: u32, filter: Option<u32>, _1: Option<()>) -> Result<Vec<u32>, Error> {
+        Ok(self.objects
+            .iter()
+            .filter(|(_, object)| storage_id == ALL_STORAGES || object.storage_id == storage_id)
+            .filter(|(_, object)| handle_id == 0 || object.parent == handle_id)
+            .filter(|(_, object)| filter.is_none_or(|format| u32::from(object.info.ObjectFormat) == format))
+            .map(|(handle, _)| *handle)
+            .collect())
+    }
+
+    pub fn delete_object(&mut self, handle: u32, _1: Option<()>) -> Result<(), Error> {
+        self.object(handle)?;
+        self.objects.remove(&handle);
+        Ok(())
     }
 
-    pub fn get_partialobject(&mut self, _handle: u32, _size: u32, _whatever: u32, _1: Option<()>) -> Result<Vec<u8>, Error> {
-        unimplemented!("You shouldn't be calling methods from dummy_ptp");
+    pub fn get_partialobject(&mut self, handle: u32, offset: u32, size: u32, _1: Option<()>) -> Result<Vec<u8>, Error> {
+        let contents = &self.object(handle)?.contents;
+        let start = contents.len().min(offset as usize);
+        let end = contents.len().min(start + size as usize);
+        Ok(contents[start..end].to_vec())
     }
 
-    pub fn get_objecthandles_all(&mut self, _handle: u32, _kind: Option<u32>, _1: Option<()>) -> Result<Vec<u32>, Error> {
-        unimplemented!("You shouldn't be calling methods from dummy_ptp");
+    pub fn get_objecthandles_all(&mut self, storage_id: u32, filter: Option<u32>, _1: Option<()>) -> Result<Vec<u32>, Error> {
+        self.get_objecthandles(storage_id, 0, filter, None)
     }
 
-    pub fn get_objectinfo(&mut self, _handle: u32, _1: Option<()>) -> Result<ObjectInfo, Error> {
-        unimplemented!("You shouldn't be calling methods from dummy_ptp");
+    pub fn get_objectinfo(&mut self, handle: u32, _1: Option<()>) -> Result<PtpObjectInfo, Error> {
+        Ok(self.object(handle)?.info.clone())
     }
 
     pub fn close_session(&mut self, _1: Option<()>) -> Result<(), Error> {
-        unimplemented!("You shouldn't be calling methods from dummy_ptp");
+        Ok(())
     }
 
     pub fn power_down(&mut self, _1: Option<()>) -> Result<(), Error> {
-        unimplemented!("You shouldn't be calling methods from dummy_ptp");
+        Ok(())
     }
 }
 
-#[derive(Debug)]
+impl PtpSession for PtpCamera<'_> {
+    fn storage_ids(&mut self) -> Result<Vec<u32>, Error> {
+        self.get_storageids(None)
+    }
+
+    fn object_handles(&mut self, storage_id: u32, parent: Option<u32>) -> Result<Vec<u32>, Error> {
+        self.get_objecthandles(storage_id, parent.unwrap_or(ROOT_OBJECTS), None, None)
+    }
+
+    fn object_handles_of_format(&mut self, format: u16) -> Result<Vec<u32>, Error> {
+        self.get_objecthandles_all(ALL_STORAGES, Some(u32::from(format)), None)
+    }
+
+    fn object_info(&mut self, handle: u32) -> Result<ObjectInfo, Error> {
+        let info = self.get_objectinfo(handle, None)?;
+        Ok(ObjectInfo {
+            filename: info.Filename,
+            format: info.ObjectFormat,
+            size: info.ObjectCompressedSize,
+            capture_date: info.CaptureDate,
+            modification_date: info.ModificationDate,
+        })
+    }
+
+    fn read_object(&mut self, handle: u32, offset: u32, size: u32) -> Result<Vec<u8>, Error> {
+        self.get_partialobject(handle, offset, size, None)
+    }
+
+    fn remove_object(&mut self, handle: u32) -> Result<(), Error> {
+        self.delete_object(handle, None)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 #[allow(non_snake_case)]
-pub struct ObjectInfo {
+pub struct PtpObjectInfo {
+    pub Filename: String,
     pub CaptureDate: String,
+    pub ModificationDate: String,
     pub ObjectCompressedSize: u32,
     pub ObjectFormat: u16,
 }
@@ -4,7 +4,9 @@ use std::fs::{self, File, DirEntry};
 use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 
-use crate::config::{FlysightConfig, MountableDeviceLocation};
+use crate::config::{Config, ConfigBuilder, ConfigError, FlysightConfig, MountableDeviceLocation};
+use crate::ctx::Ctx;
+use crate::device::{ConfiguredDevice, Device, DeviceKind};
 use crate::mountable::{MountedFilesystem, MountableFilesystem, MountableKind};
 use crate::staging::{StageFromDevice, DateTimeUploadable};
 
@@ -59,18 +61,22 @@ impl DateTimeUploadable for FlysightFile {
         "csv"
     }
 
-    fn capture_datetime(&self) -> Result<DateTime<Local>, chrono::ParseError> {
+    fn capture_datetime(&self) -> Result<NaiveDateTime, chrono::ParseError> {
         // Adding time is hard, we'll just allocate our faces off
         let mut datetime = self.capturedate.clone();
         datetime.push_str("/");
         datetime.push_str(&self.capturetime);
-        Local.datetime_from_str(&datetime, "%y-%m-%d/%H-%M-%S")
+        NaiveDateTime::parse_from_str(&datetime, "%y-%m-%d/%H-%M-%S")
     }
 
     fn reader(&mut self) -> &mut File {
         &mut self.file
     }
 
+    fn original_name(&self) -> Option<String> {
+        self.source_path.file_stem().and_then(|s| s.to_str()).map(String::from)
+    }
+
     fn delete(&mut self) -> Result<(), Error> {
         fs::remove_file(&self.source_path)?;
         // TODO(richo)
@@ -172,6 +178,55 @@ impl StageFromDevice for MountedFlysight {
     }
 }
 
+/// FlySights, which show up as a filesystem of track logs.
+#[derive(Debug)]
+pub struct FlysightDevices;
+
+impl FlysightDevices {
+    fn configs(cfg: &Config) -> Result<Vec<FlysightConfig>, ConfigError> {
+        cfg.device_entries(FlysightDevices.name())
+    }
+}
+
+impl DeviceKind for FlysightDevices {
+    fn name(&self) -> &'static str {
+        "flysight"
+    }
+
+    fn configured(&self, cfg: &Config) -> Result<Vec<ConfiguredDevice>, ConfigError> {
+        Ok(FlysightDevices::configs(cfg)?
+            .into_iter()
+            .map(|flysight| ConfiguredDevice {
+                name: flysight.name,
+                // The FlySight logs in UTC.
+                timezone: flysight.timezone.unwrap_or_else(|| "utc".into()),
+                clock_skew_secs: flysight.clock_skew_secs,
+            })
+            .collect())
+    }
+
+    fn locate<'a>(&self, ctx: &'a Ctx) -> Result<Vec<Device<'a>>, Error> {
+        Ok(FlysightDevices::configs(&ctx.cfg)?
+            .into_iter()
+            .filter_map(|flysight| flysight.get())
+            .map(|flysight| Device::new(self.name(), flysight.name.clone(), flysight))
+            .collect())
+    }
+
+    fn web_name(&self) -> Option<&'static str> {
+        Some("flysight")
+    }
+
+    fn add_from_web(&self, cfg: ConfigBuilder, name: String, identifier: String) -> ConfigBuilder {
+        cfg.flysight(FlysightConfig {
+            name,
+            location: MountableDeviceLocation::from_label(identifier),
+            timezone: None,
+            clock_skew_secs: None,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -182,6 +237,8 @@ mod tests {
         let flysight = FlysightConfig {
             name: "data".into(),
             location: MountableDeviceLocation::from_mountpoint("test-data/flysight".into()),
+            timezone: None,
+            clock_skew_secs: None,
         };
         let mounted = flysight.mount_for_test();
 
@@ -194,21 +251,23 @@ mod tests {
         let flysight = FlysightConfig {
             name: "data".into(),
             location: MountableDeviceLocation::from_mountpoint("test-data/flysight".into()),
+            timezone: None,
+            clock_skew_secs: None,
         };
         let mounted = flysight.mount_for_test();
 
         let files = mounted.files().expect("Couldn't load test files");
         assert_eq!(
             files[0].capture_datetime().unwrap(),
-            Local.ymd(2018, 8, 24).and_hms(9, 55, 30)
+            NaiveDate::from_ymd(2018, 8, 24).and_hms(9, 55, 30)
         );
         assert_eq!(
             files[1].capture_datetime().unwrap(),
-            Local.ymd(2018, 8, 24).and_hms(10, 39, 58)
+            NaiveDate::from_ymd(2018, 8, 24).and_hms(10, 39, 58)
         );
         assert_eq!(
             files[2].capture_datetime().unwrap(),
-            Local.ymd(2018, 8, 24).and_hms(11, 0, 28)
+            NaiveDate::from_ymd(2018, 8, 24).and_hms(11, 0, 28)
         );
     }
 
@@ -220,16 +279,16 @@ mod tests {
         let flysight = FlysightConfig {
             name: "data".into(),
             location: MountableDeviceLocation::from_mountpoint(source.path().to_path_buf()),
+            timezone: None,
+            clock_skew_secs: None,
         };
         let mounted = flysight.mount_for_test();
 
 
         mounted.stage_files("data", &dest).unwrap();
         // TODO(richo) test harder
-        let iter = fs::read_dir(&dest.staging_location()).unwrap();
-        let files: Vec<_> = iter.collect();
 
-        assert_eq!(files.len(), 6);
+        assert_eq!(test_helpers::count_files(dest.staging_location()), 6);
     }
 
     #[test]
@@ -240,6 +299,8 @@ mod tests {
         let flysight = FlysightConfig {
             name: "data".into(),
             location: MountableDeviceLocation::from_mountpoint(source.path().to_path_buf()),
+            timezone: None,
+            clock_skew_secs: None,
         };
         let mounted = flysight.mount_for_test();
 
@@ -0,0 +1,179 @@
+/// Google only hands out short lived access tokens, so rather than shipping client secrets around
+/// we let the web service hold on to the refresh token and ask it for a fresh access token
+/// whenever google starts rejecting the one we have.
+use std::io::Read;
+use std::sync::Mutex;
+
+use failure::Error;
+use reqwest;
+use reqwest::header::{self, HeaderValue};
+use reqwest::StatusCode;
+use serde_json;
+
+use crate::client::StokepileClient;
+use crate::config::Config;
+use crate::retry::UploadError;
+
+/// Google requires chunks of resumable uploads to be a multiple of 256kb.
+const DEFAULT_CHUNK_SIZE: u64 = 32 * 256 * 1024;
+
+#[derive(RedactedDebug)]
+pub struct GoogleToken {
+    provider: &'static str,
+    #[redacted]
+    token: Mutex<String>,
+    refresher: Option<StokepileClient>,
+}
+
+impl GoogleToken {
+    /// Create a token for `provider`, which should match the name of the integration on the web
+    /// side, eg `drive`.
+    pub fn new(provider: &'static str, token: String, refresher: Option<StokepileClient>) -> GoogleToken {
+        GoogleToken {
+            provider,
+            token: Mutex::new(token),
+            refresher,
+        }
+    }
+
+    /// Create a token that can be refreshed using the web service configured in `cfg`, if we're
+    /// logged in to it.
+    pub fn from_config(provider: &'static str, token: String, cfg: &Config) -> GoogleToken {
+        let refresher = StokepileClient::new(cfg.api_base()).and_then(|mut client| {
+            client.load_token()?;
+            Ok(client)
+        });
+        if let Err(e) = &refresher {
+            info!("Can't refresh {} tokens: {}", provider, e);
+        }
+        GoogleToken::new(provider, token, refresher.ok())
+    }
+
+    fn authorization(&self) -> String {
+        format!("Bearer {}", self.token.lock().expect("token lock poisoned"))
+    }
+
+    fn refresh(&self) -> Result<(), Error> {
+        match &self.refresher {
+            Some(client) => {
+                let token = client.refresh_token(self.provider)?;
+                *self.token.lock().expect("token lock poisoned") = token;
+                Ok(())
+            }
+            None => bail!("No way to refresh the {} token", self.provider),
+        }
+    }
+
+    /// Send the request built by `build`, which is passed the value for the Authorization header.
+    ///
+    /// If google rejects our token, we refresh it and try again exactly once.
+    pub fn send<F>(&self, build: F) -> Result<reqwest::Response, Error>
+    where
+        F: Fn(&str) -> reqwest::RequestBuilder,
+    {
+        let res = build(&self.authorization()).send()?;
+        if res.status() != StatusCode::UNAUTHORIZED || self.refresher.is_none() {
+            return Ok(res);
+        }
+
+        info!("{} token was rejected, refreshing it", self.provider);
+        self.refresh()?;
+        Ok(build(&self.authorization()).send()?)
+    }
+}
+
+/// Build a client suitable for google's resumable upload protocol.
+pub fn http_client() -> Result<reqwest::Client, Error> {
+    // Google uses 308 to mean "keep going" during resumable uploads, which reqwest would otherwise
+    // try to follow.
+    Ok(reqwest::Client::builder()
+        .redirect(reqwest::RedirectPolicy::none())
+        .build()?)
+}
+
+/// Start a resumable upload of `size` bytes to `endpoint`, returning the session url to send the
+/// content to.
+pub fn start_resumable_upload(
+    token: &GoogleToken,
+    client: &reqwest::Client,
+    endpoint: &str,
+    metadata: &serde_json::Value,
+    size: u64,
+) -> Result<String, Error> {
+    let body = metadata.to_string();
+    let size = size.to_string();
+    let mut res = token.send(|authorization| {
+        client
+            .post(endpoint)
+            .header(header::AUTHORIZATION, authorization)
+            .header(header::CONTENT_TYPE, "application/json; charset=UTF-8")
+            .header("X-Upload-Content-Length", size.as_str())
+            .body(body.clone())
+    })?;
+    if !res.status().is_success() {
+        let message = format!("Error starting upload: {} {}", res.status(), res.text()?);
+        Err(UploadError::from_response(res.status(), res.headers(), message))?;
+    }
+    res.headers()
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .ok_or_else(|| format_err!("No session url returned when starting upload"))
+}
+
+/// Send everything in `reader` to a resumable upload session, returning the body of the final
+/// response, which describes whatever we just created.
+pub fn upload_resumable<T: Read>(
+    token: &GoogleToken,
+    client: &reqwest::Client,
+    reader: &mut T,
+    session: &str,
+) -> Result<String, Error> {
+    let mut offset = 0;
+    loop {
+        let mut buffer = Vec::with_capacity(DEFAULT_CHUNK_SIZE as usize);
+        let read_bytes = reader.by_ref().take(DEFAULT_CHUNK_SIZE).read_to_end(&mut buffer)? as u64;
+
+        // We don't know the total until we've hit EOF, which a short read tells us.
+        let range = if read_bytes == 0 {
+            format!("bytes */{}", offset)
+        } else if read_bytes < DEFAULT_CHUNK_SIZE {
+            format!("bytes {}-{}/{}", offset, offset + read_bytes - 1, offset + read_bytes)
+        } else {
+            format!("bytes {}-{}/*", offset, offset + read_bytes - 1)
+        };
+        trace!("Uploading {} to {}", &range, session);
+
+        let range = HeaderValue::from_str(&range)?;
+        let mut res = token.send(|authorization| {
+            client
+                .put(session)
+                .header(header::AUTHORIZATION, authorization)
+                .header(header::CONTENT_RANGE, range.clone())
+                .body(buffer.clone())
+        })?;
+
+        match res.status() {
+            StatusCode::OK | StatusCode::CREATED => return Ok(res.text()?),
+            StatusCode::PERMANENT_REDIRECT => {
+                // The Range header tells us how much google has, which should be all of it.
+                let persisted = res
+                    .headers()
+                    .get(header::RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.rsplit('-').next())
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .map(|end| end + 1)
+                    .unwrap_or(0);
+                offset += read_bytes;
+                if persisted != offset {
+                    bail!("Google only persisted {} of {} bytes", persisted, offset);
+                }
+            }
+            status => {
+                let message = format!("Error during upload: {} {}", status, res.text()?);
+                Err(UploadError::from_response(status, res.headers(), message))?;
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+/// A client for the bits of the Google Drive v3 API we need.
+///
+/// Files are uploaded with the resumable upload protocol into a folder tree that mirrors the
+/// `/YYYY/MM/DD/device/` layout we use everywhere else. Our content hash is attached to each file
+/// as an app property, which is what `already_uploaded` searches on.
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+
+use failure::Error;
+use reqwest;
+use reqwest::header;
+use serde_json;
+
+use crate::google::{self, GoogleToken};
+use crate::retry::UploadError;
+use crate::staging;
+use crate::storage::{StorageAdaptor, StorageStatus};
+
+const FILES_ENDPOINT: &str = "https://www.googleapis.com/drive/v3/files";
+const UPLOAD_ENDPOINT: &str = "https://www.googleapis.com/upload/drive/v3/files?uploadType=resumable";
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+const CONTENT_HASH_PROPERTY: &str = "content_hash";
+pub const DEFAULT_FOLDER: &str = "stokepile";
+
+#[derive(Debug)]
+pub struct GoogleDriveClient {
+    token: GoogleToken,
+    folder: String,
+    client: reqwest::Client,
+    /// Maps folder paths like `stokepile/2018/08/26` to their ids, so that we don't have to walk
+    /// the whole tree for every file.
+    folders: Mutex<HashMap<String, String>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FileList {
+    files: Vec<DriveFile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DriveFile {
+    id: String,
+    // Drive returns int64s as strings.
+    size: Option<String>,
+}
+
+/// Escape a value for use inside a quoted string in a Drive search query.
+fn escape_query(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn folder_components(manifest: &staging::UploadDescriptor) -> Vec<String> {
+    let remote_path = manifest.remote_path();
+    remote_path
+        .parent()
+        .unwrap_or_else(|| Path::new("/"))
+        .iter()
+        .skip(1)
+        .map(|c| c.to_str().expect("path wasn't valid utf8").to_string())
+        .collect()
+}
+
+fn file_name(manifest: &staging::UploadDescriptor) -> String {
+    manifest
+        .remote_path()
+        .file_name()
+        .expect("remote path has no filename")
+        .to_str()
+        .expect("path wasn't valid utf8")
+        .to_string()
+}
+
+impl GoogleDriveClient {
+    pub fn new(token: GoogleToken, folder: Option<String>) -> Result<GoogleDriveClient, Error> {
+        Ok(GoogleDriveClient {
+            token,
+            folder: folder.unwrap_or_else(|| DEFAULT_FOLDER.to_string()),
+            client: google::http_client()?,
+            folders: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn check_response(mut res: reqwest::Response, what: &str) -> Result<String, Error> {
+        let text = res.text()?;
+        if !res.status().is_success() {
+            let message = format!("Google Drive error during {}: {} {}", what, res.status(), text);
+            Err(UploadError::from_response(res.status(), res.headers(), message))?;
+        }
+        Ok(text)
+    }
+
+    fn list_files(&self, query: &str) -> Result<Vec<DriveFile>, Error> {
+        let query = [
+            ("q", query),
+            ("fields", "files(id,size)"),
+            ("spaces", "drive"),
+        ];
+        let res = self.token.send(|authorization| {
+            self.client
+                .get(FILES_ENDPOINT)
+                .query(&query)
+                .header(header::AUTHORIZATION, authorization)
+        })?;
+        let text = Self::check_response(res, "list_files")?;
+        let list: FileList = serde_json::from_str(&text)
+            .map_err(|e| format_err!("list_files: {:?} {}", e, text))?;
+        Ok(list.files)
+    }
+
+    fn find_folder(&self, name: &str, parent: &str) -> Result<Option<String>, Error> {
+        let query = format!(
+            "name = '{}' and '{}' in parents and mimeType = '{}' and trashed = false",
+            escape_query(name),
+            escape_query(parent),
+            FOLDER_MIME_TYPE
+        );
+        Ok(self.list_files(&query)?.into_iter().next().map(|f| f.id))
+    }
+
+    fn create_folder(&self, name: &str, parent: &str) -> Result<String, Error> {
+        let body = json!({
+            "name": name,
+            "mimeType": FOLDER_MIME_TYPE,
+            "parents": [parent],
+        })
+        .to_string();
+        let res = self.token.send(|authorization| {
+            self.client
+                .post(FILES_ENDPOINT)
+                .header(header::AUTHORIZATION, authorization)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.clone())
+        })?;
+        let text = Self::check_response(res, "create_folder")?;
+        let file: DriveFile = serde_json::from_str(&text)
+            .map_err(|e| format_err!("create_folder: {:?} {}", e, text))?;
+        Ok(file.id)
+    }
+
+    /// Find the id of the folder that `manifest` belongs in, creating any that are missing.
+    fn ensure_folder(&self, manifest: &staging::UploadDescriptor) -> Result<String, Error> {
+        let mut folders = self.folders.lock().expect("folder cache poisoned");
+        let mut parent = "root".to_string();
+        let mut path = String::new();
+
+        for name in Some(self.folder.clone()).into_iter().chain(folder_components(manifest)) {
+            path.push_str(&name);
+            path.push('/');
+            if let Some(id) = folders.get(&path) {
+                parent = id.clone();
+                continue;
+            }
+
+            let id = match self.find_folder(&name, &parent)? {
+                Some(id) => id,
+                None => {
+                    trace!("Creating drive folder {}", &path);
+                    self.create_folder(&name, &parent)?
+                }
+            };
+            folders.insert(path.clone(), id.clone());
+            parent = id;
+        }
+        Ok(parent)
+    }
+
+    /// Start a resumable upload, returning the session url to send the content to.
+    fn start_upload(&self, parent: &str, manifest: &staging::UploadDescriptor) -> Result<String, Error> {
+        let metadata = json!({
+            "name": file_name(manifest),
+            "parents": [parent],
+            "appProperties": {
+                CONTENT_HASH_PROPERTY: hex::encode(manifest.content_hash),
+            },
+        });
+        google::start_resumable_upload(&self.token, &self.client, UPLOAD_ENDPOINT, &metadata, manifest.size)
+    }
+}
+
+impl<T> StorageAdaptor<T> for GoogleDriveClient
+where
+    T: Read,
+{
+    fn already_uploaded(&self, manifest: &staging::UploadDescriptor) -> bool {
+        let query = format!(
+            "appProperties has {{ key='{}' and value='{}' }} and trashed = false",
+            CONTENT_HASH_PROPERTY,
+            hex::encode(manifest.content_hash)
+        );
+        match self.list_files(&query) {
+            Ok(files) => files
+                .iter()
+                .any(|f| f.size.as_ref().and_then(|s| s.parse().ok()) == Some(manifest.size)),
+            Err(e) => {
+                warn!("Couldn't search google drive: {:?}", e);
+                false
+            }
+        }
+    }
+
+    fn upload(
+        &self,
+        mut reader: T,
+        manifest: &staging::UploadDescriptor,
+    ) -> Result<StorageStatus, Error> {
+        let parent = self.ensure_folder(manifest)?;
+        let session = self.start_upload(&parent, manifest)?;
+        google::upload_resumable(&self.token, &self.client, &mut reader, &session)?;
+        Ok(StorageStatus::Success)
+    }
+
+    fn name(&self) -> String {
+        "google_drive".to_string()
+    }
+
+    fn id(&self) -> String {
+        format!("google_drive:{}", &self.folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_folder_components() {
+        let manifest = staging::UploadDescriptor::test_descriptor();
+        assert_eq!(folder_components(&manifest), vec!["2018", "08", "26", "test-device"]);
+        assert_eq!(file_name(&manifest), "14-30-00.mp4");
+    }
+
+    #[test]
+    fn test_escape_query() {
+        assert_eq!(escape_query(r"richo's \ camera"), r"richo\'s \\ camera");
+    }
+
+    fn test_client() -> GoogleDriveClient {
+        let token = GoogleToken::new(
+            "drive",
+            env::var("STOKEPILE_TEST_GOOGLE_DRIVE_TOKEN").expect("Didn't provide test token"),
+            None,
+        );
+        GoogleDriveClient::new(token, Some("stokepile-test".into())).expect("Couldn't create client")
+    }
+
+    #[test]
+    #[ignore]
+    fn test_uploads_large_file() {
+        let client = test_client();
+        let localfile =
+            fs::File::open("/usr/share/dict/web2").expect("Couldn't open dummy dictionary");
+        if let Err(e) = client.upload(localfile, &staging::UploadDescriptor::test_descriptor()) {
+            panic!("{:?}", e);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_roundtripped_content_hash_works() {
+        let client = test_client();
+        let localfile = b"yes hello";
+        let mut manifest = staging::UploadDescriptor::test_descriptor();
+        manifest.size = localfile.len() as u64;
+        manifest.content_hash = [0x42; 32];
+
+        if let Err(e) = client.upload(&localfile[..], &manifest) {
+            panic!("{:?}", e);
+        }
+        assert!(StorageAdaptor::<&[u8]>::already_uploaded(&client, &manifest));
+
+        manifest.content_hash = [0x41; 32];
+        assert!(!StorageAdaptor::<&[u8]>::already_uploaded(&client, &manifest));
+    }
+}
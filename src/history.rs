@@ -0,0 +1,262 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use failure::Error;
+use serde_json;
+
+use crate::config;
+use crate::reporting::UploadStatus;
+use crate::staging::{RemotePathDescriptor, UploadDescriptor};
+
+/// A record of everything we've staged and uploaded, which outlives the staged files themselves.
+///
+/// This is an append-only log with one json entry per line, kept in the home directory. Entries
+/// are only ever added, so a run that dies partway through costs us at most the line it was
+/// writing.
+#[derive(Debug)]
+pub struct UploadHistory {
+    path: PathBuf,
+    log: Mutex<File>,
+    /// (backend id, hex encoded content hash) for everything that's made it somewhere.
+    uploaded: Mutex<HashSet<(String, String)>>,
+}
+
+/// A single line of the history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub time: DateTime<Utc>,
+    pub device: String,
+    pub capture_time: Option<DateTime<FixedOffset>>,
+    pub content_hash: String,
+    pub size: u64,
+    pub remote_path: PathBuf,
+    pub event: HistoryEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryEvent {
+    Staged,
+    Uploaded {
+        backend: String,
+        /// Which of the backends called `backend` this was, see `StorageAdaptor::id`. Entries
+        /// from before there were ids only have the name.
+        #[serde(default)]
+        backend_id: Option<String>,
+        succeeded: bool,
+        result: String,
+        /// Whatever the backend calls what we created, if it's not just the remote path.
+        remote_id: Option<String>,
+    },
+}
+
+impl HistoryEntry {
+    fn new(desc: &UploadDescriptor, event: HistoryEvent) -> HistoryEntry {
+        let capture_time = match &desc.path {
+            RemotePathDescriptor::DateTime { capture_time, .. } => Some(*capture_time),
+            RemotePathDescriptor::SpecifiedPath { .. } => None,
+        };
+        HistoryEntry {
+            time: Utc::now(),
+            device: desc.device_name.clone(),
+            capture_time,
+            content_hash: hex::encode(desc.content_hash),
+            size: desc.size,
+            remote_path: desc.remote_path(),
+            event,
+        }
+    }
+}
+
+impl UploadHistory {
+    /// Open the history in the home directory.
+    pub fn in_home() -> Result<UploadHistory, Error> {
+        UploadHistory::open(config::get_home()?.as_ref().join(".stokepile-history.jsonl"))
+    }
+
+    /// Open the history at `path`, which is fine to not exist yet.
+    pub fn open(path: PathBuf) -> Result<UploadHistory, Error> {
+        let mut uploaded = HashSet::new();
+        for entry in read_entries(&path)? {
+            if let HistoryEvent::Uploaded { backend, backend_id, succeeded: true, .. } = entry.event {
+                uploaded.insert((backend_id.unwrap_or(backend), entry.content_hash));
+            }
+        }
+        let mut log = OpenOptions::new().create(true).append(true).open(&path)?;
+        // If we died partway through a line, start afresh on the next one.
+        if fs::read(&path)?.last().is_some_and(|c| *c != b'\n') {
+            log.write_all(b"\n")?;
+        }
+        Ok(UploadHistory {
+            path,
+            log: Mutex::new(log),
+            uploaded: Mutex::new(uploaded),
+        })
+    }
+
+    /// Everything in the history, oldest first.
+    pub fn entries(&self) -> Result<Vec<HistoryEntry>, Error> {
+        read_entries(&self.path)
+    }
+
+    /// Has the backend with the id `backend_id` ever successfully taken these contents?
+    pub fn was_uploaded(&self, backend_id: &str, content_hash: &[u8; 32]) -> bool {
+        let uploaded = self.uploaded.lock().expect("history lock poisoned");
+        uploaded.contains(&(backend_id.to_string(), hex::encode(content_hash)))
+    }
+
+    pub fn record_staged(&self, desc: &UploadDescriptor) -> Result<(), Error> {
+        self.append(&HistoryEntry::new(desc, HistoryEvent::Staged))
+    }
+
+    pub fn record_upload(
+        &self,
+        desc: &UploadDescriptor,
+        backend: &str,
+        backend_id: &str,
+        status: &UploadStatus,
+        remote_id: Option<String>,
+    ) -> Result<(), Error> {
+        let succeeded = matches!(status, UploadStatus::Succeeded | UploadStatus::AlreadyUploaded);
+        let entry = HistoryEntry::new(desc, HistoryEvent::Uploaded {
+            backend: backend.to_string(),
+            backend_id: Some(backend_id.to_string()),
+            succeeded,
+            result: status.to_string(),
+            remote_id,
+        });
+        self.append(&entry)?;
+        if succeeded {
+            let mut uploaded = self.uploaded.lock().expect("history lock poisoned");
+            uploaded.insert((backend_id.to_string(), entry.content_hash));
+        }
+        Ok(())
+    }
+
+    fn append(&self, entry: &HistoryEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut log = self.log.lock().expect("history lock poisoned");
+        log.write_all(&line)?;
+        log.sync_data()?;
+        Ok(())
+    }
+}
+
+fn read_entries(path: &Path) -> Result<Vec<HistoryEntry>, Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            // Most likely we died while writing it.
+            Err(e) => warn!("Skipping line {} of {:?}: {:?}", i + 1, path, e),
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor() -> UploadDescriptor {
+        let mut desc = UploadDescriptor::test_descriptor();
+        desc.content_hash = [0x42; 32];
+        desc
+    }
+
+    #[test]
+    fn test_history_roundtrips() {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let path = dir.path().join("history.jsonl");
+        let desc = descriptor();
+
+        let history = UploadHistory::open(path.clone()).expect("Couldn't open empty history");
+        assert!(!history.was_uploaded("vimeo", &desc.content_hash));
+        history.record_staged(&desc).expect("Couldn't record staging");
+        history.record_upload(&desc, "dropbox", "dropbox", &UploadStatus::Errored(format_err!("nope")), None)
+            .expect("Couldn't record upload");
+        history.record_upload(&desc, "vimeo", "vimeo", &UploadStatus::Succeeded, Some("/videos/1234".into()))
+            .expect("Couldn't record upload");
+        assert!(history.was_uploaded("vimeo", &desc.content_hash));
+
+        let history = UploadHistory::open(path).expect("Couldn't reopen history");
+        assert!(history.was_uploaded("vimeo", &desc.content_hash));
+        assert!(!history.was_uploaded("dropbox", &desc.content_hash));
+        assert!(!history.was_uploaded("vimeo", &[0x41; 32]));
+
+        let entries = history.entries().expect("Couldn't read history");
+        assert_eq!(3, entries.len());
+        assert_eq!(HistoryEvent::Staged, entries[0].event);
+        assert_eq!("test-device", entries[0].device);
+        assert_eq!(Some(Local.ymd(2018, 8, 26).and_hms(14, 30, 0).into()), entries[0].capture_time);
+        assert_eq!(hex::encode([0x42; 32]), entries[0].content_hash);
+        assert_eq!(PathBuf::from("/2018/08/26/test-device/14-30-00.mp4"), entries[2].remote_path);
+        match &entries[2].event {
+            HistoryEvent::Uploaded { backend, succeeded: true, remote_id: Some(id), .. } => {
+                assert_eq!("vimeo", backend);
+                assert_eq!("/videos/1234", id);
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_backends_with_the_same_name_are_told_apart() {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let path = dir.path().join("history.jsonl");
+        let desc = descriptor();
+
+        let history = UploadHistory::open(path.clone()).expect("Couldn't open empty history");
+        history.record_upload(&desc, "local backup", "local backup:/mnt/first", &UploadStatus::Succeeded, None)
+            .expect("Couldn't record upload");
+        assert!(history.was_uploaded("local backup:/mnt/first", &desc.content_hash));
+        assert!(!history.was_uploaded("local backup:/mnt/second", &desc.content_hash));
+
+        // Entries from before there were ids are keyed by name, like they always were.
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        let mut old = HistoryEntry::new(&desc, HistoryEvent::Staged);
+        old.event = HistoryEvent::Uploaded {
+            backend: "vimeo".into(),
+            backend_id: None,
+            succeeded: true,
+            result: "Succeeded".into(),
+            remote_id: None,
+        };
+        let mut line = serde_json::to_value(&old).unwrap();
+        line["event"]["uploaded"].as_object_mut().unwrap().remove("backend_id");
+        writeln!(log, "{}", line).unwrap();
+
+        let history = UploadHistory::open(path).expect("Couldn't reopen history");
+        assert!(history.was_uploaded("local backup:/mnt/first", &desc.content_hash));
+        assert!(!history.was_uploaded("local backup:/mnt/second", &desc.content_hash));
+        assert!(history.was_uploaded("vimeo", &desc.content_hash));
+    }
+
+    #[test]
+    fn test_skips_torn_lines() {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let path = dir.path().join("history.jsonl");
+        let history = UploadHistory::open(path.clone()).expect("Couldn't open empty history");
+        history.record_staged(&descriptor()).expect("Couldn't record staging");
+        drop(history);
+
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"{\"time\": \"2019-").unwrap();
+
+        let history = UploadHistory::open(path).expect("Couldn't reopen history");
+        assert_eq!(1, history.entries().expect("Couldn't read history").len());
+        history.record_staged(&descriptor()).expect("Couldn't record staging");
+        assert_eq!(2, history.entries().expect("Couldn't read history").len());
+    }
+}
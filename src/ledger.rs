@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use failure::Error;
+use serde_json;
+
+use crate::config;
+
+/// A record of what we've uploaded to a service, for services that give us no good way to ask.
+///
+/// This maps hex encoded content hashes to whatever the service calls the thing we created, eg a
+/// video id, and is persisted as json in the home directory.
+#[derive(Debug)]
+pub struct UploadLedger {
+    path: PathBuf,
+    uploads: Mutex<BTreeMap<String, String>>,
+}
+
+impl UploadLedger {
+    /// Load the ledger for `service` from the home directory.
+    pub fn for_service(service: &str) -> Result<UploadLedger, Error> {
+        let path = config::get_home()?.as_ref().join(format!(".stokepile-{}.json", service));
+        UploadLedger::load(path)
+    }
+
+    /// Load the ledger at `path`, which is fine to not exist yet.
+    pub fn load(path: PathBuf) -> Result<UploadLedger, Error> {
+        let uploads = match File::open(&path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                serde_json::from_str(&contents)?
+            }
+            Err(_) => BTreeMap::new(),
+        };
+        Ok(UploadLedger {
+            path,
+            uploads: Mutex::new(uploads),
+        })
+    }
+
+    pub fn get(&self, content_hash: &[u8; 32]) -> Option<String> {
+        let uploads = self.uploads.lock().expect("ledger lock poisoned");
+        uploads.get(&hex::encode(content_hash)).cloned()
+    }
+
+    pub fn record(&self, content_hash: &[u8; 32], id: String) -> Result<(), Error> {
+        let mut uploads = self.uploads.lock().expect("ledger lock poisoned");
+        uploads.insert(hex::encode(content_hash), id);
+        self.persist(&uploads)
+    }
+
+    /// Forget about an upload, eg because it's been deleted upstream.
+    pub fn forget(&self, content_hash: &[u8; 32]) -> Result<(), Error> {
+        let mut uploads = self.uploads.lock().expect("ledger lock poisoned");
+        if uploads.remove(&hex::encode(content_hash)).is_some() {
+            self.persist(&uploads)?;
+        }
+        Ok(())
+    }
+
+    fn persist(&self, uploads: &BTreeMap<String, String>) -> Result<(), Error> {
+        // Write it out alongside and move it into place, so we never truncate the ledger.
+        let tmp = self.path.with_extension("json.tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(serde_json::to_string_pretty(uploads)?.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ledger_roundtrips() {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let path = dir.path().join("ledger.json");
+
+        let ledger = UploadLedger::load(path.clone()).expect("Couldn't load empty ledger");
+        assert_eq!(ledger.get(&[0x42; 32]), None);
+        ledger.record(&[0x42; 32], "dQw4w9WgXcQ".into()).expect("Couldn't record upload");
+
+        let ledger = UploadLedger::load(path.clone()).expect("Couldn't reload ledger");
+        assert_eq!(ledger.get(&[0x42; 32]), Some("dQw4w9WgXcQ".into()));
+        assert_eq!(ledger.get(&[0x41; 32]), None);
+
+        ledger.forget(&[0x42; 32]).expect("Couldn't forget upload");
+        let ledger = UploadLedger::load(path).expect("Couldn't reload ledger");
+        assert_eq!(ledger.get(&[0x42; 32]), None);
+    }
+}
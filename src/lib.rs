@@ -38,9 +38,18 @@ macro_rules! client_for_routes {
     };
 }
 
+/// Android phones, which we walk over MTP to find the media they've captured.
+pub mod android;
+
+/// Keeping uploads from hogging the uplink, by limiting how fast they go and when they happen.
+pub mod bandwidth;
+
 /// A client to the web interface.
 pub mod client;
 
+/// Working out when files were captured from what the clocks of the devices they came from said.
+pub mod clock;
+
 /// Details pertaining to parsing the configuration file, as well as constructing the internal
 /// objects specified by the configuration.
 pub mod config;
@@ -53,9 +62,8 @@ pub mod ctx;
 /// Some helpers associated with driving the clis that ship with stokepile.
 pub mod cli;
 
-/// Some helpers to abstract over the various types of devices that we can interact with. Much of
-/// this will probably go away at some point, and Device will become a trait instead of the enum
-/// that it is today.
+/// The registry of the kinds of devices that we can interact with, and the types that abstract
+/// over them.
 ///
 /// This module also contains the logic for simply enumerating all currently attached devices as
 /// part of generating a plan for an upload run.
@@ -70,7 +78,8 @@ pub mod dummy_libusb;
 /// A drop in replacement for ptp for use in contexts where we can't actually link against
 /// libusb (eg, the web server).
 ///
-/// Attempting to invoke any actual ptp stuff panics.
+/// Its camera only exists in memory, so it's also what we test the code that walks cameras
+/// against.
 pub mod dummy_ptp;
 
 /// Our interface to the dropbox API. This should really be it's own crate, but until I have the
@@ -84,6 +93,19 @@ mod flysight;
 /// A module concerning itself with presenting information in a human readable format.
 pub mod formatting;
 
+/// Plumbing shared by the adaptors for google properties, mostly around keeping access tokens
+/// fresh.
+pub mod google;
+
+/// A storage adaptor for Google Drive.
+pub mod google_drive;
+
+/// A local log of everything we've staged and uploaded, which outlives the staged files.
+pub mod history;
+
+/// A local record of what we've uploaded, for services that can't tell us themselves.
+pub mod ledger;
+
 /// A storage adaptor governing a local storage device to archive the data onto.
 pub mod local_backup;
 
@@ -105,6 +127,12 @@ pub mod messages;
 /// Contains machinery relating to mounting and unmounting devices.
 pub mod mountable;
 
+/// Templates for where files end up, both in staging and on each backend.
+pub mod path_template;
+
+/// Working out which files from attached devices fit in staging, and what order to stage them in.
+pub mod plan;
+
 /// Our bindings to the ptp crate, which we use to talk to devices like Gopros over USB, allowing
 /// us to avoid having to pull the SD card in order to upload footage.
 pub mod ptp_device;
@@ -113,6 +141,18 @@ pub mod ptp_device;
 /// little local glue to bind `config` and `pushover` together.
 pub mod pushover_notifier;
 
+/// Classifying upload errors, and deciding whether and when to try again.
+pub mod retry;
+
+/// Rules deciding which storage adaptors each staged file is sent to.
+pub mod routing;
+
+/// A storage adaptor for S3 compatible object stores, including MinIO.
+pub mod s3;
+
+/// A storage adaptor that archives to a remote host over sftp, for NAS boxes and the like.
+pub mod sftp;
+
 /// Contains the machinery for generating an upload report. This handles both building the report
 /// object up in memory, as well as rendering it to something we can mail to a user.
 mod reporting;
@@ -129,11 +169,17 @@ pub mod storage;
 /// The vimeo upload backend.
 pub mod vimeo;
 
+/// A storage adaptor for WebDAV servers, with support for Nextcloud's chunked uploads.
+pub mod webdav;
+
 mod version;
 
 /// A notifier that pushes notifications out via the web service.
 mod web_notifier;
 
+/// A storage adaptor for youtube, which only concerns itself with video files.
+pub mod youtube;
+
 /// What version of stokepile do you have :)
 pub use crate::version::VERSION;
 
@@ -1,5 +1,6 @@
 use crate::config::{LocalBackupConfig, MountableDeviceLocation};
 use crate::mountable::{MountableFilesystem, MountableKind, MountedFilesystem, MOUNTABLE_DEVICE_FOLDER};
+use crate::retry::{ErrorKind, UploadError};
 use crate::staging;
 use crate::storage::{StorageAdaptor, StorageStatus};
 use dropbox_content_hasher;
@@ -30,6 +31,17 @@ impl MountedLocalBackup {
     }
 }
 
+impl LocalBackupConfig {
+    /// Tells this backup apart from any others, see `StorageAdaptor::id`.
+    pub fn id(&self) -> String {
+        match &self.location {
+            MountableDeviceLocation::Mountpoint(path) |
+            MountableDeviceLocation::Location(path) => format!("local backup:{}", path.display()),
+            MountableDeviceLocation::Label(label) => format!("local backup:label:{}", label),
+        }
+    }
+}
+
 impl MountableFilesystem for LocalBackupConfig {
     type Target = MountedLocalBackup;
 
@@ -76,25 +88,62 @@ where
         let containing_dir = self.containing_dir(&manifest);
         let local_path = self.local_path(&manifest);
 
+        // Anything already there isn't this file, or we wouldn't have been asked to upload it.
+        let conflict = || {
+            let message = format!("{:?} is already a different file, not overwriting it", &local_path);
+            UploadError::new(ErrorKind::Conflict, message)
+        };
+        if local_path.exists() {
+            Err(conflict())?;
+        }
+
         // TODO(richo) assert that we're mounted first?
         fs::create_dir_all(&containing_dir)?;
 
-        let mut local_file = File::create(&local_path)?;
+        let mut partial = local_path.clone().into_os_string();
+        partial.push(".partial");
+        let mut local_file = File::create(&partial)?;
 
         io::copy(&mut reader, &mut local_file)?;
+        local_file.sync_all()?;
 
+        // Something else could have turned up while we were copying.
+        if local_path.exists() {
+            fs::remove_file(&partial)?;
+            Err(conflict())?;
+        }
+        fs::rename(&partial, &local_path)?;
 
         Ok(StorageStatus::Success)
     }
 
+    fn verify(&self, manifest: &staging::UploadDescriptor) -> Result<(), Error> {
+        let local_path = self.local_path(&manifest);
+        let mut file = File::open(&local_path)?;
+        let size = file.metadata()?.len();
+        if size != manifest.size {
+            bail!("{:?} is {} bytes but we copied {}", &local_path, size, manifest.size);
+        }
+        let hash = dropbox_content_hasher::DropboxContentHasher::hash_reader(&mut file)?;
+        if hash.as_slice() != manifest.content_hash {
+            bail!("{:?} has content_hash {:x} but we copied {}", &local_path, hash, hex::encode(manifest.content_hash));
+        }
+        Ok(())
+    }
+
     fn name(&self) -> String {
         "local backup".to_string()
     }
+
+    fn id(&self) -> String {
+        self.local_backup.id()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::retry;
     use crate::staging::UploadDescriptor;
     use crate::test_helpers;
     use dropbox_content_hasher::DropboxContentHasher;
@@ -122,6 +171,19 @@ mod tests {
                    PathBuf::from("/test/directory/stokepile/2018/08/26/test-device/14-30-00.mp4"));
     }
 
+    #[test]
+    fn test_backups_have_their_own_ids() {
+        let first = LocalBackupConfig {
+            location: MountableDeviceLocation::Mountpoint("/mnt/first".into()),
+        }.mount_for_test();
+        let second = LocalBackupConfig {
+            location: MountableDeviceLocation::Mountpoint("/mnt/second".into()),
+        }.mount_for_test();
+
+        assert_eq!(StorageAdaptor::<&[u8]>::id(&first), "local backup:/mnt/first");
+        assert_ne!(StorageAdaptor::<&[u8]>::id(&first), StorageAdaptor::<&[u8]>::id(&second));
+    }
+
     #[test]
     fn test_already_uploaded() {
         let tmp = test_helpers::tempdir();
@@ -145,4 +207,45 @@ mod tests {
 
         assert!(StorageAdaptor::<&[u8]>::already_uploaded(&adaptor, &manifest));
     }
+
+    #[test]
+    fn test_verify() {
+        let tmp = test_helpers::tempdir();
+        let adaptor = LocalBackupConfig {
+            location: MountableDeviceLocation::Mountpoint(tmp.path().to_path_buf()),
+        }.mount_for_test();
+
+        let data = b"This is some dummy data to stage";
+        let mut manifest = UploadDescriptor::test_descriptor();
+        manifest.size = data.len() as u64;
+        manifest.content_hash.copy_from_slice(&DropboxContentHasher::hash_reader(&mut &data[..]).unwrap());
+
+        adaptor.upload(&data[..], &manifest).expect("Couldn't upload test data");
+        assert!(StorageAdaptor::<&[u8]>::verify(&adaptor, &manifest).is_ok());
+
+        fs::write(adaptor.local_path(&manifest), b"This is some dummy data to stagf").unwrap();
+        assert!(StorageAdaptor::<&[u8]>::verify(&adaptor, &manifest).is_err());
+    }
+
+    #[test]
+    fn test_doesnt_overwrite() {
+        let tmp = test_helpers::tempdir();
+        let adaptor = LocalBackupConfig {
+            location: MountableDeviceLocation::Mountpoint(tmp.path().to_path_buf()),
+        }.mount_for_test();
+        let manifest = UploadDescriptor::test_descriptor();
+
+        fs::create_dir_all(adaptor.containing_dir(&manifest)).expect("Couldn't create containing directory");
+        fs::write(adaptor.local_path(&manifest), b"Something else entirely").unwrap();
+
+        let mut contents = &b"This is some dummy data to stage"[..];
+        match adaptor.upload(&mut contents, &manifest) {
+            Err(e) => assert_eq!(retry::classify(&e), ErrorKind::Conflict),
+            other => panic!("Expected a conflict, got {:?}", other),
+        }
+        // We knew better than to copy it at all.
+        assert_eq!(contents.len(), 32);
+        assert_eq!(fs::read(adaptor.local_path(&manifest)).unwrap(), b"Something else entirely");
+        assert_eq!(1, fs::read_dir(adaptor.containing_dir(&manifest)).unwrap().count());
+    }
 }
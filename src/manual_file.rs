@@ -1,6 +1,7 @@
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::fmt::Debug;
+use crate::clock::DeviceClock;
 use crate::staging::{StorableFile, RemotePathDescriptor};
 
 use chrono;
@@ -79,7 +80,7 @@ impl ManualFile {
 impl StorableFile for ManualFile {
     type Reader = File;
 
-    fn remote_path(&self) -> Result<RemotePathDescriptor, Error> {
+    fn remote_path(&self, _clock: DeviceClock) -> Result<RemotePathDescriptor, Error> {
         Ok(RemotePathDescriptor::SpecifiedPath {
             path: self.dest_path.to_path_buf(),
         })
@@ -118,7 +119,7 @@ mod tests {
         assert!(test_data.write_all(b"This is some test data").is_ok());
 
         let fh = ManualFile::from_paths(path, PathBuf::from("test-file.ogv")).expect("Couldn't create manualfile");
-        let desc = fh.descriptor("test-upload");
+        let desc = fh.descriptor("test-upload", DeviceClock::default());
 
         stager.stage(fh, "manual").expect("Didn't stage correct");
 
@@ -144,7 +145,7 @@ mod tests {
 
         let mut iter = ManualFile::iter_from(event);
         let mf = iter.next().expect("Couldn't get a test file");
-        let desc = mf.descriptor("event name").expect("Couldn't get descriptor");
+        let desc = mf.descriptor("event name", DeviceClock::default()).expect("Couldn't get descriptor");
         assert_eq!(&desc.path,
                    &staging::RemotePathDescriptor::SpecifiedPath {
                        path: PathBuf::from("person/day/video.ogv")
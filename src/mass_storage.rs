@@ -1,7 +1,9 @@
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 
-use crate::config::{MassStorageConfig, MountableDeviceLocation};
+use crate::config::{Config, ConfigBuilder, ConfigError, MassStorageConfig, MountableDeviceLocation};
+use crate::ctx::Ctx;
+use crate::device::{ConfiguredDevice, Device, DeviceKind};
 use crate::mountable::{MountableFilesystem, MountedFilesystem, MountableKind};
 use crate::staging::{StageFromDevice, DateTimeUploadable};
 
@@ -18,7 +20,7 @@ pub struct MountedMassStorage {
 
 #[derive(Debug)]
 pub struct MassStorageFile {
-    capturedatetime: DateTime<Local>,
+    capturedatetime: NaiveDateTime,
     file: File,
     extension: String,
     source_path: PathBuf,
@@ -31,7 +33,7 @@ impl DateTimeUploadable for MassStorageFile {
         &self.extension
     }
 
-    fn capture_datetime(&self) -> Result<DateTime<Local>, chrono::ParseError> {
+    fn capture_datetime(&self) -> Result<NaiveDateTime, chrono::ParseError> {
         Ok(self.capturedatetime)
     }
 
@@ -39,6 +41,10 @@ impl DateTimeUploadable for MassStorageFile {
         &mut self.file
     }
 
+    fn original_name(&self) -> Option<String> {
+        self.source_path.file_stem().and_then(|s| s.to_str()).map(String::from)
+    }
+
     fn delete(&mut self) -> Result<(), Error> {
         fs::remove_file(&self.source_path)?;
         Ok(())
@@ -60,7 +66,9 @@ impl StageFromDevice for MountedMassStorage {
             // Could definitely lift this into some domain object
             let extension = path.extension().unwrap().to_str().unwrap().to_lowercase();
             let file = MassStorageFile {
-                capturedatetime: path.metadata()?.modified()?.into(),
+                // FAT doesn't know about timezones, so this is whatever the device's clock read,
+                // taken to be in our zone.
+                capturedatetime: DateTime::<Local>::from(path.metadata()?.modified()?).naive_local(),
                 file: File::open(path)
                     .context("Opening content file for MountedMassStorage")?,
                 source_path: path.to_path_buf(),
@@ -166,6 +174,61 @@ impl MountableKind for MountedMassStorage {
     }
 }
 
+/// Anything that shows up as a filesystem with media files on it, like an SD card.
+#[derive(Debug)]
+pub struct MassStorageDevices;
+
+impl MassStorageDevices {
+    fn configs(cfg: &Config) -> Result<Vec<MassStorageConfig>, ConfigError> {
+        let configs: Vec<MassStorageConfig> = cfg.device_entries(MassStorageDevices.name())?;
+        if configs.iter().any(|ms| ms.extensions.is_empty()) {
+            return Err(ConfigError::MassStorageMissingExtensions);
+        }
+        Ok(configs)
+    }
+}
+
+impl DeviceKind for MassStorageDevices {
+    fn name(&self) -> &'static str {
+        "mass_storage"
+    }
+
+    fn configured(&self, cfg: &Config) -> Result<Vec<ConfiguredDevice>, ConfigError> {
+        Ok(MassStorageDevices::configs(cfg)?
+            .into_iter()
+            .map(|ms| ConfiguredDevice {
+                name: ms.name,
+                timezone: ms.timezone.unwrap_or_else(|| "local".into()),
+                clock_skew_secs: ms.clock_skew_secs,
+            })
+            .collect())
+    }
+
+    fn locate<'a>(&self, ctx: &'a Ctx) -> Result<Vec<Device<'a>>, Error> {
+        Ok(MassStorageDevices::configs(&ctx.cfg)?
+            .into_iter()
+            .filter_map(|ms| ms.get())
+            .map(|ms| Device::new(self.name(), ms.name.clone(), ms))
+            .collect())
+    }
+
+    fn web_name(&self) -> Option<&'static str> {
+        Some("mass_storage")
+    }
+
+    fn add_from_web(&self, cfg: ConfigBuilder, name: String, identifier: String) -> ConfigBuilder {
+        cfg.mass_storage(MassStorageConfig {
+            name,
+            // TODO(richo) add a metadata field and store this there
+            extensions: vec!["mp4".into()],
+            location: MountableDeviceLocation::from_label(identifier),
+            cleanup_extensions: None,
+            timezone: None,
+            clock_skew_secs: None,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -206,6 +269,8 @@ mod tests {
             location: MountableDeviceLocation::from_mountpoint("test-data/mass_storage".into()),
             extensions: extensions(),
             cleanup_extensions: None,
+            timezone: None,
+            clock_skew_secs: None,
         };
         let mounted = mass_storage.mount_for_test();
 
@@ -227,6 +292,8 @@ mod tests {
             location: MountableDeviceLocation::from_mountpoint(source.path().to_path_buf()),
             extensions: extensions(),
             cleanup_extensions: Some(vec!["lrv".into()]),
+            timezone: None,
+            clock_skew_secs: None,
         };
 
         let mounted = mass_storage.mount_for_test();
@@ -236,11 +303,9 @@ mod tests {
 
         let mounted = mounted.stage_files_for_test("data", &dest).unwrap();
         // TODO(richo) test harder
-        let iter = fs::read_dir(&dest.staging_location()).unwrap();
-        let files: Vec<_> = iter.collect();
 
         // Two files for the two mp4 files, two files for the manifests
-        assert_eq!(files.len(), 4);
+        assert_eq!(test_helpers::count_files(dest.staging_location()), 4);
 
         // Assert that the original lrv's are gone.
         assert_eq!(mounted.files_matching_cleanup_extensions().len(), 0);
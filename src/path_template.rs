@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use chrono::prelude::*;
+
+use crate::staging::{RemotePathDescriptor, UploadDescriptor};
+
+/// How many characters of the content hash `{hash}` expands to by default.
+const DEFAULT_HASH_LENGTH: usize = 8;
+
+/// A template for where files end up, like `/{year}/{month}/{day}/{device}/{time}.{extension}`.
+///
+/// The placeholders are:
+///
+/// * `{year}`, `{month}`, `{day}`, `{hour}`, `{minute}` and `{second}`, from the capture time
+/// * `{time}`, which is the capture time as `HH-MM-SS`
+/// * `{device}`, the name of the device the file came from
+/// * `{filename}`, the name of the file on the device without its extension, or `{time}` for
+///   devices that don't name their files
+/// * `{extension}`
+/// * `{hash}`, the start of the content hash, or `{hash:N}` for the first N characters of it
+///
+/// Every template needs one of `{filename}`, `{hash}`, `{time}` or `{second}` so that files don't
+/// all land in the same place. Templates only apply to files with a capture time, files staged by
+/// hand keep the path they were given. Files that would otherwise end up with the same name get
+/// their discriminator added before the extension.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PathTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Time,
+    Device,
+    Filename,
+    Extension,
+    Hash(usize),
+}
+
+#[derive(Fail, Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    #[fail(display = "Unknown placeholder {{{}}}.", _0)]
+    UnknownPlaceholder(String),
+    #[fail(display = "Unmatched brace.")]
+    UnmatchedBrace,
+    #[fail(display = "Hash length must be between 1 and 64.")]
+    InvalidHashLength,
+    #[fail(display = "Templates must be absolute, and can't contain `..` or end in `/`.")]
+    InvalidPath,
+    #[fail(display = "Templates need one of {{filename}}, {{hash}}, {{time}} or {{second}} to tell files apart.")]
+    NotUnique,
+}
+
+impl PathTemplate {
+    pub fn parse(source: &str) -> Result<PathTemplate, TemplateError> {
+        let mut parts = vec![];
+        let mut rest = source;
+        while !rest.is_empty() {
+            let (literal, placeholder) = match rest.find(['{', '}']) {
+                Some(i) if rest[i..].starts_with('}') => return Err(TemplateError::UnmatchedBrace),
+                Some(i) => {
+                    let end = rest[i..].find('}').ok_or(TemplateError::UnmatchedBrace)? + i;
+                    let placeholder = &rest[i + 1..end];
+                    if placeholder.contains('{') {
+                        return Err(TemplateError::UnmatchedBrace);
+                    }
+                    let literal = &rest[..i];
+                    rest = &rest[end + 1..];
+                    (literal, Some(placeholder))
+                }
+                None => {
+                    let literal = rest;
+                    rest = "";
+                    (literal, None)
+                }
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Literal(literal.to_string()));
+            }
+            if let Some(placeholder) = placeholder {
+                parts.push(Part::parse(placeholder)?);
+            }
+        }
+
+        let path = Path::new(source);
+        if !path.is_absolute() || source.ends_with('/') || path.components().any(|c| c == Component::ParentDir) {
+            return Err(TemplateError::InvalidPath);
+        }
+        // Anything else would send every file from a device, or from a day, to the same place.
+        if !parts.iter().any(|part| matches!(part, Part::Filename | Part::Hash(_) | Part::Time | Part::Second)) {
+            return Err(TemplateError::NotUnique);
+        }
+        Ok(PathTemplate {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    /// Where `desc` ends up under this template.
+    pub fn render(&self, desc: &UploadDescriptor) -> PathBuf {
+        let capture_time = match &desc.path {
+            RemotePathDescriptor::DateTime { capture_time, .. } => capture_time,
+            RemotePathDescriptor::SpecifiedPath { .. } => return desc.default_remote_path(),
+        };
+        let hash = hex::encode(desc.content_hash);
+        let time = capture_time.format("%H-%M-%S").to_string();
+        let mut out = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Literal(literal) => {
+                    out.push_str(literal);
+                    continue;
+                }
+                Part::Year => format!("{:04}", capture_time.year()),
+                Part::Month => format!("{:02}", capture_time.month()),
+                Part::Day => format!("{:02}", capture_time.day()),
+                Part::Hour => format!("{:02}", capture_time.hour()),
+                Part::Minute => format!("{:02}", capture_time.minute()),
+                Part::Second => format!("{:02}", capture_time.second()),
+                Part::Time => time.clone(),
+                Part::Device => desc.device_name.clone(),
+                Part::Filename => desc.original_name.clone().unwrap_or_else(|| time.clone()),
+                Part::Extension => desc.extension().unwrap_or("").to_string(),
+                Part::Hash(len) => hash[..*len].to_string(),
+            };
+            // Whatever we substitute in stays within its own path component.
+            out.push_str(&value.replace('/', "-"));
+        }
+        PathBuf::from(desc.discriminated(out))
+    }
+
+    /// A name for `desc` in the flat staging directory, based on where it'll end up.
+    pub fn staging_name(&self, desc: &UploadDescriptor) -> String {
+        let path = self.render(desc);
+        let path = path.to_str().expect("path wasn't valid utf8");
+        path.trim_start_matches('/').replace('/', "-")
+    }
+}
+
+impl Part {
+    fn parse(placeholder: &str) -> Result<Part, TemplateError> {
+        Ok(match placeholder {
+            "year" => Part::Year,
+            "month" => Part::Month,
+            "day" => Part::Day,
+            "hour" => Part::Hour,
+            "minute" => Part::Minute,
+            "second" => Part::Second,
+            "time" => Part::Time,
+            "device" => Part::Device,
+            "filename" => Part::Filename,
+            "extension" => Part::Extension,
+            "hash" => Part::Hash(DEFAULT_HASH_LENGTH),
+            other if other.starts_with("hash:") => match other["hash:".len()..].parse() {
+                Ok(len) if (1..=64).contains(&len) => Part::Hash(len),
+                _ => return Err(TemplateError::InvalidHashLength),
+            },
+            other => return Err(TemplateError::UnknownPlaceholder(other.to_string())),
+        })
+    }
+}
+
+impl fmt::Debug for PathTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PathTemplate({:?})", &self.source)
+    }
+}
+
+impl fmt::Display for PathTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+// Templates are kept in manifests as the string they were parsed from.
+impl TryFrom<String> for PathTemplate {
+    type Error = TemplateError;
+
+    fn try_from(source: String) -> Result<PathTemplate, TemplateError> {
+        PathTemplate::parse(&source)
+    }
+}
+
+impl From<PathTemplate> for String {
+    fn from(template: PathTemplate) -> String {
+        template.source
+    }
+}
+
+/// The templates that apply to a backend, or to staging, picked between by device.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PathTemplates {
+    default: Option<PathTemplate>,
+    devices: BTreeMap<String, PathTemplate>,
+}
+
+impl PathTemplates {
+    pub fn new(default: Option<PathTemplate>, devices: BTreeMap<String, PathTemplate>) -> PathTemplates {
+        PathTemplates { default, devices }
+    }
+
+    /// The template for files from `device`, if there's anything other than the built in layout.
+    pub fn for_device(&self, device: &str) -> Option<&PathTemplate> {
+        self.devices.get(device).or(self.default.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor() -> UploadDescriptor {
+        let mut desc = UploadDescriptor::build("jumper/one".to_string())
+            .date_time(Local.ymd(2019, 6, 1).and_hms(9, 5, 7), "mp4".to_string());
+        desc.content_hash = [0xab; 32];
+        desc
+    }
+
+    #[test]
+    fn test_renders_placeholders() {
+        let template = PathTemplate::parse("/{year}/{month}-{day}/{hour}{minute}{second}/{device}/{time}-{hash}.{extension}").unwrap();
+        assert_eq!(
+            template.render(&descriptor()),
+            PathBuf::from("/2019/06-01/090507/jumper-one/09-05-07-abababab.mp4")
+        );
+
+        let template = PathTemplate::parse("/dz/{filename}-{hash:2}.{extension}").unwrap();
+        assert_eq!(template.render(&descriptor()), PathBuf::from("/dz/09-05-07-ab.mp4"));
+        let mut desc = descriptor();
+        desc.original_name = Some("GOPR0042".into());
+        assert_eq!(template.render(&desc), PathBuf::from("/dz/GOPR0042-ab.mp4"));
+        assert_eq!(template.staging_name(&desc), "dz-GOPR0042-ab.mp4");
+    }
+
+    #[test]
+    fn test_default_layout_matches_builtin() {
+        let template = PathTemplate::parse("/{year}/{month}/{day}/{device}/{time}.{extension}").unwrap();
+        let desc = UploadDescriptor::test_descriptor();
+        assert_eq!(template.render(&desc), desc.default_remote_path());
+    }
+
+    #[test]
+    fn test_specified_paths_are_kept() {
+        let template = PathTemplate::parse("/{year}/{device}/{time}.{extension}").unwrap();
+        let desc = UploadDescriptor::build("manual".to_string()).manual_file("some/file.csv".into());
+        assert_eq!(template.render(&desc), PathBuf::from("/manual/some/file.csv"));
+    }
+
+    #[test]
+    fn test_rejects_invalid_templates() {
+        assert_eq!(PathTemplate::parse("/{year}/{jumper}"), Err(TemplateError::UnknownPlaceholder("jumper".into())));
+        assert_eq!(PathTemplate::parse("/{year/{device}"), Err(TemplateError::UnmatchedBrace));
+        assert_eq!(PathTemplate::parse("/{year}}"), Err(TemplateError::UnmatchedBrace));
+        assert_eq!(PathTemplate::parse("/{year"), Err(TemplateError::UnmatchedBrace));
+        assert_eq!(PathTemplate::parse("/{hash:0}"), Err(TemplateError::InvalidHashLength));
+        assert_eq!(PathTemplate::parse("/{hash:65}"), Err(TemplateError::InvalidHashLength));
+        assert_eq!(PathTemplate::parse("{year}/{device}"), Err(TemplateError::InvalidPath));
+        assert_eq!(PathTemplate::parse("/{year}/../{device}"), Err(TemplateError::InvalidPath));
+        assert_eq!(PathTemplate::parse("/{year}/"), Err(TemplateError::InvalidPath));
+        assert_eq!(PathTemplate::parse("/{device}.{extension}"), Err(TemplateError::NotUnique));
+        assert_eq!(PathTemplate::parse("/{year}/{month}/{day}/{device}.{extension}"), Err(TemplateError::NotUnique));
+    }
+
+    #[test]
+    fn test_device_templates_win() {
+        let default = PathTemplate::parse("/{device}/{time}.{extension}").unwrap();
+        let gopro = PathTemplate::parse("/gopro/{time}.{extension}").unwrap();
+        let mut devices = BTreeMap::new();
+        devices.insert("gopro".to_string(), gopro.clone());
+
+        let templates = PathTemplates::new(Some(default.clone()), devices.clone());
+        assert_eq!(templates.for_device("gopro"), Some(&gopro));
+        assert_eq!(templates.for_device("data"), Some(&default));
+
+        let templates = PathTemplates::new(None, devices);
+        assert_eq!(templates.for_device("data"), None);
+    }
+}
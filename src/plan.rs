@@ -0,0 +1,144 @@
+use std::cmp::Reverse;
+
+use chrono::prelude::*;
+
+use crate::config::StagingOrder;
+use crate::staging::{RemotePathDescriptor, UploadDescriptor};
+
+/// Room we leave for each file on top of its size, for the manifest and filesystem overhead.
+const PER_FILE_OVERHEAD: u64 = 64 * 1024;
+
+/// A file on an attached device that we could stage.
+#[derive(Debug)]
+pub struct PlannedFile {
+    /// Which of the devices being planned for the file is on.
+    pub device: usize,
+    /// Where the file is in that device's list of files.
+    pub index: usize,
+    pub desc: UploadDescriptor,
+}
+
+impl PlannedFile {
+    fn capture_time(&self) -> Option<DateTime<FixedOffset>> {
+        match &self.desc.path {
+            RemotePathDescriptor::DateTime { capture_time, .. } => Some(*capture_time),
+            RemotePathDescriptor::SpecifiedPath { .. } => None,
+        }
+    }
+}
+
+/// Which files to stage this run, in the order to stage them, and which have to wait for the next
+/// run because staging doesn't have room for them.
+#[derive(Debug)]
+pub struct StagingPlan {
+    staged: Vec<PlannedFile>,
+    deferred: Vec<PlannedFile>,
+}
+
+impl StagingPlan {
+    /// Fit as many of `files` as we can into `available` bytes.
+    ///
+    /// Files from devices earlier in `device_priority` go first, followed by any other devices,
+    /// and within that files are taken in `order`. Anything that doesn't fit is skipped, but
+    /// smaller files after it may still make it in.
+    pub fn new(
+        mut files: Vec<PlannedFile>,
+        available: u64,
+        order: StagingOrder,
+        device_priority: &[String],
+    ) -> StagingPlan {
+        let rank = |file: &PlannedFile| {
+            device_priority
+                .iter()
+                .position(|name| name == &file.desc.device_name)
+                .unwrap_or(device_priority.len())
+        };
+        match order {
+            StagingOrder::SmallestFirst => files.sort_by_key(|f| (rank(f), f.desc.size)),
+            StagingOrder::NewestFirst => files.sort_by_key(|f| (rank(f), Reverse(f.capture_time()))),
+            StagingOrder::OldestFirst => files.sort_by_key(|f| (rank(f), f.capture_time())),
+        }
+
+        let mut remaining = available;
+        let mut staged = vec![];
+        let mut deferred = vec![];
+        for file in files {
+            let needed = file.desc.size + PER_FILE_OVERHEAD;
+            if needed <= remaining {
+                remaining -= needed;
+                staged.push(file);
+            } else {
+                deferred.push(file);
+            }
+        }
+        StagingPlan { staged, deferred }
+    }
+
+    /// The files to stage, in the order we should stage them.
+    pub fn staged(&self) -> &[PlannedFile] {
+        &self.staged
+    }
+
+    /// The files that don't fit.
+    pub fn deferred(&self) -> &[PlannedFile] {
+        &self.deferred
+    }
+
+    pub fn into_deferred(self) -> Vec<PlannedFile> {
+        self.deferred
+    }
+
+    /// How many bytes we're leaving on devices.
+    pub fn deferred_size(&self) -> u64 {
+        self.deferred.iter().map(|f| f.desc.size).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(device: &str, index: usize, hour: u32, size: u64) -> PlannedFile {
+        let mut desc = UploadDescriptor::build(device.to_string())
+            .date_time(Local.ymd(2019, 6, 1).and_hms(hour, 0, 0), "mp4".to_string());
+        desc.size = size;
+        PlannedFile { device: 0, index, desc }
+    }
+
+    fn indices(files: &[PlannedFile]) -> Vec<usize> {
+        files.iter().map(|f| f.index).collect()
+    }
+
+    fn files() -> Vec<PlannedFile> {
+        vec![
+            file("gopro", 0, 9, 3 * 1024 * 1024),
+            file("gopro", 1, 12, 1024 * 1024),
+            file("data", 2, 10, 2 * 1024 * 1024),
+        ]
+    }
+
+    #[test]
+    fn test_everything_fits() {
+        let plan = StagingPlan::new(files(), 1 << 30, StagingOrder::SmallestFirst, &[]);
+        assert_eq!(indices(plan.staged()), vec![1, 2, 0]);
+        assert!(plan.deferred().is_empty());
+        assert_eq!(plan.deferred_size(), 0);
+    }
+
+    #[test]
+    fn test_defers_what_does_not_fit() {
+        let plan = StagingPlan::new(files(), 9 * 512 * 1024, StagingOrder::OldestFirst, &[]);
+        // The oldest file fits, the next doesn't but the one after that still does.
+        assert_eq!(indices(plan.staged()), vec![0, 1]);
+        assert_eq!(indices(plan.deferred()), vec![2]);
+        assert_eq!(plan.deferred_size(), 2 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_device_priority_goes_first() {
+        let priority = vec!["data".to_string()];
+        let plan = StagingPlan::new(files(), 4 * 1024 * 1024, StagingOrder::OldestFirst, &priority);
+        assert_eq!(indices(plan.staged()), vec![2, 1]);
+        assert_eq!(indices(plan.deferred()), vec![0]);
+    }
+}
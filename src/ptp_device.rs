@@ -1,9 +1,13 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::io::{self, Read};
+use std::path::Path;
 use std::rc::Rc;
 use std::sync::Mutex;
 
+use crate::config::{Config, ConfigBuilder, ConfigError, GoproConfig, PtpCameraConfig, PtpFormat};
 use crate::ctx;
+use crate::device::{ConfiguredDevice, Device, DeviceKind};
 use crate::staging::{StageFromDevice, DateTimeUploadable};
 use crate::mountable::{Mountable};
 
@@ -21,62 +25,208 @@ use ptp;
 #[cfg(not(feature = "usb"))]
 use crate::dummy_ptp as ptp;
 
-use std::hash::{Hash, Hasher};
+/// Parse a date from an `ObjectInfo`, like `20190601T093000`.
+///
+/// These can also have tenths of a second and a zone on the end, which we ignore in favour of the
+/// zone configured for the device's clock.
+fn parse_ptp_date(date: &str) -> Result<NaiveDateTime, chrono::ParseError> {
+    let wall_clock = date.get(..15).unwrap_or(date);
+    NaiveDateTime::parse_from_str(wall_clock, "%Y%m%dT%H%M%S")
+}
+
+// Object formats from the PTP spec, along with the vendor extensions we know about.
+pub const FORMAT_AVI: u16 = 0x300a;
+pub const FORMAT_MPEG: u16 = 0x300b;
+/// What GoPros call their mp4s.
+pub const FORMAT_QUICKTIME: u16 = 0x300d;
+pub const FORMAT_EXIF_JPEG: u16 = 0x3801;
+/// Which DNGs show up as.
+pub const FORMAT_TIFF_EP: u16 = 0x3802;
+pub const FORMAT_JFIF: u16 = 0x3808;
+pub const FORMAT_PNG: u16 = 0x380b;
+pub const FORMAT_TIFF: u16 = 0x380d;
+/// Sony's ARWs, and Canon's CRWs.
+pub const FORMAT_VENDOR_RAW: u16 = 0xb101;
+pub const FORMAT_CANON_CR2: u16 = 0xb103;
+pub const FORMAT_CANON_CR3: u16 = 0xb108;
+pub const FORMAT_MTP_MP4: u16 = 0xb982;
+pub const FORMAT_MTP_3GP: u16 = 0xb984;
+/// Folders, which PTP calls associations.
+pub const FORMAT_ASSOCIATION: u16 = 0x3001;
+
+/// Asks for objects from every storage on the device.
+pub const ALL_STORAGES: u32 = 0xFFFF_FFFF;
+/// The parent of the objects at the root of a storage.
+pub const ROOT_OBJECTS: u32 = 0xFFFF_FFFF;
+/// The size reported for objects over 4GB, like a long 4K video. PTP can only read the first 4GB
+/// of those, so rather than staging a truncated copy and then deleting the original we leave them
+/// on the device.
+pub const SIZE_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// The object formats that make up each sort of file.
+pub fn object_formats(format: PtpFormat) -> &'static [u16] {
+    match format {
+        PtpFormat::Video => &[FORMAT_AVI, FORMAT_MPEG, FORMAT_QUICKTIME, FORMAT_MTP_MP4, FORMAT_MTP_3GP],
+        PtpFormat::Stills => &[FORMAT_EXIF_JPEG, FORMAT_JFIF, FORMAT_PNG, FORMAT_TIFF],
+        PtpFormat::Raw => &[FORMAT_TIFF_EP, FORMAT_VENDOR_RAW, FORMAT_CANON_CR2, FORMAT_CANON_CR3],
+    }
+}
+
+/// What to call files of `format` that the camera didn't give a name.
+fn default_extension(format: u16) -> &'static str {
+    match format {
+        FORMAT_AVI => "avi",
+        FORMAT_MPEG => "mpg",
+        FORMAT_MTP_3GP => "3gp",
+        FORMAT_EXIF_JPEG | FORMAT_JFIF => "jpg",
+        FORMAT_PNG => "png",
+        FORMAT_TIFF | FORMAT_TIFF_EP => "tif",
+        FORMAT_VENDOR_RAW | FORMAT_CANON_CR2 | FORMAT_CANON_CR3 => "raw",
+        _ => "mp4",
+    }
+}
 
-fn parse_gopro_date(date: &str) -> Result<DateTime<Local>, chrono::ParseError> {
-    Local.datetime_from_str(date, "%Y%m%dT%H%M%S")
+/// What we need to know about an object on a device, whichever ptp we're talking to it with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectInfo {
+    pub filename: String,
+    pub format: u16,
+    pub size: u32,
+    pub capture_date: String,
+    pub modification_date: String,
 }
 
-pub struct GoproFile<'c> {
+/// An open session on a device that speaks PTP, or MTP which is built on top of it.
+///
+/// This is implemented for both the real ptp crate and `dummy_ptp`, so that the code walking a
+/// device's objects can be tested without one plugged in.
+pub trait PtpSession {
+    fn storage_ids(&mut self) -> Result<Vec<u32>, Error>;
+    /// The objects in the folder `parent` of `storage_id`, or at its root.
+    fn object_handles(&mut self, storage_id: u32, parent: Option<u32>) -> Result<Vec<u32>, Error>;
+    /// Every object of `format`, in any storage.
+    fn object_handles_of_format(&mut self, format: u16) -> Result<Vec<u32>, Error>;
+    fn object_info(&mut self, handle: u32) -> Result<ObjectInfo, Error>;
+    fn read_object(&mut self, handle: u32, offset: u32, size: u32) -> Result<Vec<u8>, Error>;
+    fn remove_object(&mut self, handle: u32) -> Result<(), Error>;
+}
+
+#[cfg(feature = "usb")]
+impl PtpSession for ptp::PtpCamera<'_> {
+    fn storage_ids(&mut self) -> Result<Vec<u32>, Error> {
+        Ok(self.get_storageids(None)?)
+    }
+
+    fn object_handles(&mut self, storage_id: u32, parent: Option<u32>) -> Result<Vec<u32>, Error> {
+        Ok(self.get_objecthandles(storage_id, parent.unwrap_or(ROOT_OBJECTS), None, None)?)
+    }
+
+    fn object_handles_of_format(&mut self, format: u16) -> Result<Vec<u32>, Error> {
+        Ok(self.get_objecthandles_all(ALL_STORAGES, Some(u32::from(format)), None)?)
+    }
+
+    fn object_info(&mut self, handle: u32) -> Result<ObjectInfo, Error> {
+        let info = self.get_objectinfo(handle, None)?;
+        Ok(ObjectInfo {
+            filename: info.Filename,
+            format: info.ObjectFormat,
+            size: info.ObjectCompressedSize,
+            capture_date: info.CaptureDate,
+            modification_date: info.ModificationDate,
+        })
+    }
+
+    fn read_object(&mut self, handle: u32, offset: u32, size: u32) -> Result<Vec<u8>, Error> {
+        Ok(self.get_partialobject(handle, offset, size, None)?)
+    }
+
+    fn remove_object(&mut self, handle: u32) -> Result<(), Error> {
+        Ok(self.delete_object(handle, None)?)
+    }
+}
+
+pub struct PtpFile<S> {
     pub capturedate: String,
+    /// What the camera calls this file, if anything.
+    filename: String,
+    extension: String,
     // TODO(richo) I think this handle gets invalidated when we close the session down
     handle: u32,
     offset: u32,
     size: u32,
-    camera: Rc<Mutex<ptp::PtpCamera<'c>>>,
+    session: Rc<Mutex<S>>,
+}
+
+impl<S: PtpSession> PtpFile<S> {
+    pub fn new(handle: u32, object: ObjectInfo, session: &Rc<Mutex<S>>) -> PtpFile<S> {
+        let extension = Path::new(&object.filename)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .unwrap_or_else(|| default_extension(object.format).to_string());
+        // Not every camera fills in when it captured something.
+        let capturedate = if object.capture_date.is_empty() {
+            object.modification_date
+        } else {
+            object.capture_date
+        };
+        PtpFile {
+            capturedate,
+            filename: object.filename,
+            extension,
+            handle,
+            offset: 0,
+            size: object.size,
+            session: Rc::clone(session),
+        }
+    }
 }
 
-impl<'c> fmt::Debug for GoproFile<'c> {
+impl<S> fmt::Debug for PtpFile<S> {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("GoproFile")
+        fmt.debug_struct("PtpFile")
+            .field("filename", &self.filename)
             .field("handle", &self.handle)
             .field("offset", &self.offset)
             .field("size", &self.size)
-            .field("camera", &"Rc<Mutex<ptp::PtpCamera<'c> { ... }>>")
+            .field("session", &"Rc<Mutex<PtpSession { ... }>>")
             .finish()
     }
 }
 
-impl<'c> DateTimeUploadable for GoproFile<'c> {
-    type Reader = GoproFile<'c>;
+impl<S: PtpSession> DateTimeUploadable for PtpFile<S> {
+    type Reader = PtpFile<S>;
 
     fn extension(&self) -> &str {
-        "mp4"
+        &self.extension
     }
 
-    fn capture_datetime(&self) -> Result<DateTime<Local>, chrono::ParseError> {
-        parse_gopro_date(&self.capturedate)
+    fn capture_datetime(&self) -> Result<NaiveDateTime, chrono::ParseError> {
+        parse_ptp_date(&self.capturedate)
     }
 
-    fn reader(&mut self) -> &mut GoproFile<'c> {
+    fn reader(&mut self) -> &mut PtpFile<S> {
         self
     }
 
     fn delete(&mut self) -> Result<(), Error> {
-        // lol how even does into
-        Ok(self
-            .camera
-            .lock()
-            .unwrap()
-            .delete_object(self.handle, None)?)
+        self.session.lock().unwrap().remove_object(self.handle)
     }
 
     fn size(&self) -> Result<u64, Error> {
         Ok(u64::from(self.size))
     }
+
+    fn original_name(&self) -> Option<String> {
+        Path::new(&self.filename)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .filter(|stem| !stem.is_empty())
+            .map(|stem| stem.to_string())
+    }
 }
 
-impl<'b> Read for GoproFile<'b> {
+impl<S: PtpSession> Read for PtpFile<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // Tragically, ptp really wants to allocate it's own memory :(
         // If I have luck with my other patches, we can try to upstream something
@@ -85,37 +235,17 @@ impl<'b> Read for GoproFile<'b> {
             size = (self.size - self.offset) as usize;
         }
         let vec = self
-            .camera
+            .session
             .lock()
             .unwrap()
-            .get_partialobject(self.handle, self.offset, size as u32, None)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            .read_object(self.handle, self.offset, size as u32)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.compat()))?;
         buf[..vec.len()].copy_from_slice(&vec[..]);
         self.offset += vec.len() as u32;
         Ok(vec.len())
     }
 }
 
-#[repr(u16)]
-#[derive(Eq, PartialEq, Debug)]
-enum GoproObjectFormat {
-    Directory = 0x3001,
-    Video = 0x300d,
-    GetStarted = 0x3005,
-}
-
-impl GoproObjectFormat {
-    fn from_u16(format: u16) -> Option<GoproObjectFormat> {
-        use self::GoproObjectFormat::*;
-        match format {
-            0x3001 => Some(Directory),
-            0x300d => Some(Video),
-            0x3005 => Some(GetStarted),
-            _ => None,
-        }
-    }
-}
-
 const GOPRO_VENDOR: u16 = 0x2672;
 const GOPRO_MANUFACTURER: &str = "GoPro";
 
@@ -148,143 +278,142 @@ impl GoproKind {
     }
 }
 
-// Specialising to PTP devices later might be neat, but for now this is fine
-pub struct Gopro<'d> {
-    // TODO(richo) having a name in here would simplify the Staging impl
-    pub kind: GoproKind,
+/// A camera attached over USB that we can talk PTP to.
+pub struct PtpCamera<'d> {
+    /// What sort of camera this is, for the logs.
+    pub model: String,
     pub serial: String,
+    /// The object formats of the files we stage from it.
+    formats: Vec<u16>,
     device: libusb::Device<'d>,
 }
 
-impl<'d> PartialEq for Gopro<'d> {
-    fn eq(&self, other: &Gopro<'d>) -> bool {
-        self.kind == other.kind &&
-            self.serial == other.serial
-    }
+pub struct PtpConnection<'c> {
+    camera: PtpCamera<'c>,
+    session: Rc<Mutex<ptp::PtpCamera<'c>>>,
 }
 
-impl<'d> Eq for Gopro<'d> {}
+impl<'c> StageFromDevice for PtpConnection<'c> where {
+    type FileType = PtpFile<ptp::PtpCamera<'c>>;
 
-impl<'d> Hash for Gopro<'d> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.kind.hash(state);
-        self.serial.hash(state);
-    }
-}
-
-pub struct GoproConnection<'c> {
-    gopro: Gopro<'c>,
-    camera: Rc<Mutex<ptp::PtpCamera<'c>>>,
-}
-
-impl<'c> StageFromDevice for GoproConnection<'c> where {
-    type FileType = GoproFile<'c>;
-
-    fn files(&self) -> Result<Vec<GoproFile<'c>>, Error> {
+    fn files(&self) -> Result<Vec<PtpFile<ptp::PtpCamera<'c>>>, Error> {
         let mut out = vec![];
-        let timeout = None;
 
         // TODO(richo) Encapsulate this into some object that actually lets you poke around in the
         // libusb::Device and won't let you not close your session, etc.
-        let filehandles = self.camera.lock().unwrap().get_objecthandles_all(
-            0xFFFF_FFFF,
-            Some(GoproObjectFormat::Video as u32),
-            timeout,
-        )?;
-        for filehandle in filehandles {
-            let object = self
-                .camera
-                .lock()
-                .unwrap()
-                .get_objectinfo(filehandle, timeout)?;
-            assert_eq!(
-                GoproObjectFormat::from_u16(object.ObjectFormat),
-                Some(GoproObjectFormat::Video)
-            );
-            let file = GoproFile {
-                capturedate: object.CaptureDate,
-                handle: filehandle,
-                offset: 0,
-                size: object.ObjectCompressedSize,
-                camera: Rc::clone(&self.camera),
-            };
-            trace!("Adding {:?} to the plan", &file);
-            out.push(file)
+        for format in &self.camera.formats {
+            let filehandles = self.session.lock().unwrap().object_handles_of_format(*format)?;
+            for filehandle in filehandles {
+                let object = self.session.lock().unwrap().object_info(filehandle)?;
+                if object.format != *format {
+                    warn!("Asked for objects of format {:#06x}, but got {:#06x}", format, object.format);
+                    continue;
+                }
+                if object.size == SIZE_UNKNOWN {
+                    warn!("Leaving {} on {}, it's too large to copy over PTP", &object.filename, &self.camera.model);
+                    continue;
+                }
+                let file = PtpFile::new(filehandle, object, &self.session);
+                trace!("Adding {:?} to the plan", &file);
+                out.push(file)
+            }
         }
 
         info!(
-            "Loaded {} files from {:?} serial {}",
+            "Loaded {} files from {} serial {}",
             out.len(),
-            &self.gopro.kind,
-            &self.gopro.serial
+            &self.camera.model,
+            &self.camera.serial
         );
 
         Ok(out)
     }
 }
 
-impl<'c> GoproConnection<'c> {
+impl<'c> PtpConnection<'c> {
+    /// The session this connection holds open on the camera.
+    pub(crate) fn session(&self) -> &Rc<Mutex<ptp::PtpCamera<'c>>> {
+        &self.session
+    }
+
     pub fn power_down(&mut self) -> Result<(), ptp::Error> {
-        self.camera.lock().unwrap().power_down(None)
+        self.session.lock().unwrap().power_down(None)
     }
 }
 
-impl<'c> Drop for GoproConnection<'c> {
+impl<'c> Drop for PtpConnection<'c> {
     fn drop(&mut self) {
         // If this fails.. who cares I guess
         info!("Closing session on {:?}", &self);
-        let _ = self.camera.lock().unwrap().close_session(None);
+        let _ = self.session.lock().unwrap().close_session(None);
     }
 }
 
-impl<'a> Mountable for Gopro<'a> {
-    type Target = GoproConnection<'a>;
+impl<'a> Mountable for PtpCamera<'a> {
+    type Target = PtpConnection<'a>;
 
     #[cfg(not(feature = "usb"))]
-    fn mount(self) -> Result<GoproConnection<'a>, Error> {
-        unimplemented!("Can't mount nonexistant gopros");
+    fn mount(self) -> Result<PtpConnection<'a>, Error> {
+        unimplemented!("Can't mount nonexistant cameras");
     }
 
     #[cfg(feature = "usb")]
-    fn mount(self) -> Result<GoproConnection<'a>, Error> {
-        let mut camera = ptp::PtpCamera::new(&self.device)?;
-        camera.open_session(None)
+    fn mount(self) -> Result<PtpConnection<'a>, Error> {
+        let mut session = ptp::PtpCamera::new(&self.device)?;
+        session.open_session(None)
             .context("Creating session on camera")?;
 
-        Ok(GoproConnection {
-            gopro: self,
-            camera: Rc::new(Mutex::new(camera)),
+        Ok(PtpConnection {
+            camera: self,
+            session: Rc::new(Mutex::new(session)),
         })
     }
 }
 
-impl<'a> Gopro<'a> {
-    pub fn new(kind: GoproKind, serial: String, device: libusb::Device<'_>) -> Result<Gopro<'_>, Error> {
-        Ok(Gopro {
-            kind,
+impl<'a> PtpCamera<'a> {
+    pub fn new(model: String, serial: String, device: libusb::Device<'_>) -> PtpCamera<'_> {
+        PtpCamera {
+            model,
             serial,
+            formats: object_formats(PtpFormat::Video).to_vec(),
             device,
-        })
+        }
+    }
+
+    /// Stage files of these object formats, rather than just videos.
+    pub fn with_formats(mut self, formats: Vec<u16>) -> PtpCamera<'a> {
+        self.formats = formats;
+        self
     }
 }
 
-impl<'a> fmt::Debug for Gopro<'a> {
+impl<'a> fmt::Debug for PtpCamera<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("Gopro")
-            .field("kind", &self.kind)
+        fmt.debug_struct("PtpCamera")
+            .field("model", &self.model)
             .field("serial", &self.serial)
+            .field("formats", &self.formats)
             .field("device", &"libusb::Device")
             .finish()
     }
 }
 
-impl<'c> fmt::Debug for GoproConnection<'c> {
+impl<'c> fmt::Debug for PtpConnection<'c> {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.gopro.fmt(fmt)
+        self.camera.fmt(fmt)
     }
 }
 
-pub fn locate_gopros(ctx: &ctx::Ctx) -> Result<Vec<Gopro<'_>>, Error> {
+/// Find the attached cameras from `vendor_id` that speak PTP, along with their product IDs.
+///
+/// If `product_id` or `manufacturer` are set, only cameras that match them are returned.
+#[cfg_attr(not(feature = "usb"), allow(unused_variables, unused_mut))]
+pub fn locate_cameras<'a>(
+    ctx: &'a ctx::Ctx,
+    vendor_id: u16,
+    product_id: Option<u16>,
+    manufacturer: Option<&str>,
+) -> Result<Vec<(u16, PtpCamera<'a>)>, Error> {
     let mut res = vec![];
 
     // TODO(richo) It'd be really nice to have this still build, but the ptpCamera stuff looks like
@@ -299,28 +428,157 @@ pub fn locate_gopros(ctx: &ctx::Ctx) -> Result<Vec<Gopro<'_>>, Error> {
             }
         };
 
-        if device_desc.vendor_id() != GOPRO_VENDOR {
+        if device_desc.vendor_id() != vendor_id {
+            continue;
+        }
+        if product_id.is_some_and(|id| id != device_desc.product_id()) {
             continue;
         }
-
-        // We'll just use the Manufacturer tag in the PtpDevice
 
         let mut camera = ptp::PtpCamera::new(&device)?;
         let info = camera.get_device_info(None)?;
 
-        if info.Manufacturer != GOPRO_MANUFACTURER {
+        if manufacturer.is_some_and(|m| m != info.Manufacturer) {
             continue;
         }
 
-        // TODO(richo) include the product from info so we can do something useful with unknowns
-        match GoproKind::from_u16(device_desc.product_id()) {
-            Some(kind) => {
-                res.push(Gopro::new(kind, info.SerialNumber, device)?);
+        res.push((device_desc.product_id(), PtpCamera::new(info.Model, info.SerialNumber, device)));
+    }
+    Ok(res)
+}
+
+/// Find the attached GoPros, whether they're configured or not.
+pub fn locate_gopros(ctx: &ctx::Ctx) -> Result<Vec<PtpCamera<'_>>, Error> {
+    // We'll just use the Manufacturer tag in the PtpDevice
+    Ok(locate_cameras(ctx, GOPRO_VENDOR, None, Some(GOPRO_MANUFACTURER))?
+        .into_iter()
+        .filter_map(|(product_id, mut camera)| {
+            // TODO(richo) include the product from info so we can do something useful with unknowns
+            camera.model = format!("{:?}", GoproKind::from_u16(product_id)?);
+            Some(camera)
+        })
+        .collect())
+}
+
+/// Any camera we can talk PTP to, found by its vendor and product IDs and then its serial.
+#[derive(Debug)]
+pub struct PtpCameraDevices;
+
+impl PtpCameraDevices {
+    fn configs(cfg: &Config) -> Result<Vec<PtpCameraConfig>, ConfigError> {
+        let configs: Vec<PtpCameraConfig> = cfg.device_entries(PtpCameraDevices.name())?;
+        if configs.iter().any(|camera| camera.formats.as_ref().is_some_and(Vec::is_empty)) {
+            return Err(ConfigError::InvalidDevice(
+                PtpCameraDevices.name().into(),
+                "formats can't be empty".into(),
+            ));
+        }
+        Ok(configs)
+    }
+
+    /// The cameras configured by `configs` that are attached, as devices of `kind`.
+    fn locate<'a>(ctx: &'a ctx::Ctx, kind: &'static str, configs: Vec<PtpCameraConfig>) -> Result<Vec<Device<'a>>, Error> {
+        // Opening a camera to ask for its serial is slow, so only look through each sort once.
+        let mut searches: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for config in configs {
+            searches.entry((config.vendor_id, config.product_id, config.manufacturer.clone())).or_default().push(config);
+        }
+
+        let mut devices = vec![];
+        for ((vendor_id, product_id, manufacturer), configs) in searches {
+            for (_, camera) in locate_cameras(ctx, vendor_id, product_id, manufacturer.as_deref())? {
+                if let Some(config) = configs.iter().find(|config| config.serial == camera.serial) {
+                    let formats = config.formats.clone().unwrap_or_else(|| vec![PtpFormat::Video]);
+                    let formats = formats.into_iter().flat_map(|f| object_formats(f).iter().cloned()).collect();
+                    devices.push(Device::new(kind, config.name.clone(), camera.with_formats(formats)));
+                }
             }
-            None => continue,
         }
+        Ok(devices)
+    }
+}
+
+impl DeviceKind for PtpCameraDevices {
+    fn name(&self) -> &'static str {
+        "ptp_camera"
+    }
+
+    fn configured(&self, cfg: &Config) -> Result<Vec<ConfiguredDevice>, ConfigError> {
+        Ok(PtpCameraDevices::configs(cfg)?
+            .into_iter()
+            .map(|camera| ConfiguredDevice {
+                name: camera.name,
+                timezone: camera.timezone.unwrap_or_else(|| "local".into()),
+                clock_skew_secs: camera.clock_skew_secs,
+            })
+            .collect())
+    }
+
+    fn locate<'a>(&self, ctx: &'a ctx::Ctx) -> Result<Vec<Device<'a>>, Error> {
+        PtpCameraDevices::locate(ctx, self.name(), PtpCameraDevices::configs(&ctx.cfg)?)
+    }
+}
+
+/// GoPros, which are PTP cameras with a known vendor that we only stage videos from.
+#[derive(Debug)]
+pub struct GoproDevices;
+
+impl GoproDevices {
+    fn configs(cfg: &Config) -> Result<Vec<GoproConfig>, ConfigError> {
+        cfg.device_entries(GoproDevices.name())
+    }
+
+    /// How to find `gopro`, as a generic PTP camera.
+    fn preset(gopro: GoproConfig) -> PtpCameraConfig {
+        PtpCameraConfig {
+            name: gopro.name,
+            vendor_id: GOPRO_VENDOR,
+            product_id: None,
+            manufacturer: Some(GOPRO_MANUFACTURER.into()),
+            serial: gopro.serial,
+            formats: Some(vec![PtpFormat::Video]),
+            timezone: gopro.timezone,
+            clock_skew_secs: gopro.clock_skew_secs,
+        }
+    }
+}
+
+impl DeviceKind for GoproDevices {
+    fn name(&self) -> &'static str {
+        "gopro"
+    }
+
+    fn configured(&self, cfg: &Config) -> Result<Vec<ConfiguredDevice>, ConfigError> {
+        Ok(GoproDevices::configs(cfg)?
+            .into_iter()
+            .map(|gopro| ConfiguredDevice {
+                name: gopro.name,
+                timezone: gopro.timezone.unwrap_or_else(|| "local".into()),
+                clock_skew_secs: gopro.clock_skew_secs,
+            })
+            .collect())
+    }
+
+    fn locate<'a>(&self, ctx: &'a ctx::Ctx) -> Result<Vec<Device<'a>>, Error> {
+        let configs = GoproDevices::configs(&ctx.cfg)?
+            .into_iter()
+            .map(GoproDevices::preset)
+            .collect();
+        PtpCameraDevices::locate(ctx, self.name(), configs)
+    }
+
+    fn web_name(&self) -> Option<&'static str> {
+        Some("ptp")
+    }
+
+    fn add_from_web(&self, cfg: ConfigBuilder, name: String, identifier: String) -> ConfigBuilder {
+        cfg.gopro(GoproConfig {
+            name,
+            serial: identifier,
+            timezone: None,
+            clock_skew_secs: None,
+        })
     }
-    Ok(res)
 }
 
 #[cfg(test)]
@@ -328,9 +586,72 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_parses_gopro_date_correctly() {
-        let dt = Local.ymd(2015, 1, 1).and_hms(0, 6, 49);
+    fn test_parses_ptp_dates_correctly() {
+        let dt = NaiveDate::from_ymd(2015, 1, 1).and_hms(0, 6, 49);
         // TODO(richo) get better testcases
-        assert_eq!(parse_gopro_date("20150101T000649"), Ok(dt.clone()));
+        assert_eq!(parse_ptp_date("20150101T000649"), Ok(dt.clone()));
+        assert_eq!(parse_ptp_date("20150101T000649.3"), Ok(dt.clone()));
+        assert_eq!(parse_ptp_date("20150101T000649+1000"), Ok(dt.clone()));
+        assert!(parse_ptp_date("").is_err());
+        assert!(parse_ptp_date("2015-01-01 00:06:49").is_err());
+    }
+
+    #[test]
+    fn test_format_filters() {
+        assert!(object_formats(PtpFormat::Video).contains(&FORMAT_QUICKTIME));
+        assert!(object_formats(PtpFormat::Stills).contains(&FORMAT_EXIF_JPEG));
+        assert!(object_formats(PtpFormat::Raw).contains(&FORMAT_VENDOR_RAW));
+        for format in object_formats(PtpFormat::Video) {
+            assert!(!object_formats(PtpFormat::Stills).contains(format));
+            assert!(!object_formats(PtpFormat::Raw).contains(format));
+        }
+        assert_eq!(default_extension(FORMAT_QUICKTIME), "mp4");
+        assert_eq!(default_extension(FORMAT_JFIF), "jpg");
+    }
+
+    #[test]
+    fn test_ptp_camera_config() {
+        let config = |camera: &str| format!(r#"
+[stokepile]
+[staging]
+mountpoint = "/test"
+[dropbox]
+token = "TOKEN"
+
+[[device.ptp_camera]]
+name = "sony"
+serial = "1234"
+{}
+"#, camera);
+        let cfg: Config = config("vendor_id = 0x054c\nformats = [\"video\", \"raw\"]").parse().unwrap();
+        let cameras = PtpCameraDevices::configs(&cfg).unwrap();
+        assert_eq!(cameras[0].vendor_id, 0x054c);
+        assert_eq!(cameras[0].product_id, None);
+        assert_eq!(cameras[0].manufacturer, None);
+        assert_eq!(cameras[0].formats, Some(vec![PtpFormat::Video, PtpFormat::Raw]));
+        let devices = PtpCameraDevices.configured(&cfg).unwrap();
+        assert_eq!(devices[0].name, "sony");
+        assert_eq!(devices[0].timezone, "local");
+
+        let err = config("vendor_id = 0x054c\nformats = []").parse::<Config>().unwrap_err();
+        assert_eq!(err, ConfigError::InvalidDevice("ptp_camera".into(), "formats can't be empty".into()));
+        let err = config("formats = [\"video\"]").parse::<Config>().unwrap_err();
+        assert_eq!(err, ConfigError::InvalidDevice("ptp_camera".into(), "missing field `vendor_id`".into()));
+    }
+
+    #[test]
+    fn test_gopros_are_ptp_cameras() {
+        let camera = GoproDevices::preset(GoproConfig {
+            name: "helmet".into(),
+            serial: "C3131127500000".into(),
+            timezone: Some("+02:00".into()),
+            clock_skew_secs: None,
+        });
+        assert_eq!(camera.vendor_id, GOPRO_VENDOR);
+        assert_eq!(camera.product_id, None);
+        assert_eq!(camera.manufacturer.as_deref(), Some(GOPRO_MANUFACTURER));
+        assert_eq!(camera.serial, "C3131127500000");
+        assert_eq!(camera.formats, Some(vec![PtpFormat::Video]));
+        assert_eq!(camera.timezone, Some("+02:00".into()));
     }
 }
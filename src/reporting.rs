@@ -1,6 +1,7 @@
 use std::collections::HashMap;
+use std::fmt;
 
-use crate::staging::UploadDescriptor;
+use crate::staging::{Duplicate, Quarantined, UploadDescriptor};
 use crate::formatting::human_readable_size;
 
 use failure::Error;
@@ -22,7 +23,44 @@ fn handlebars() -> Handlebars {
 pub enum UploadStatus {
     AlreadyUploaded,
     Succeeded,
+    /// The adaptor doesn't handle this kind of file.
+    Skipped,
+    /// The adaptor finished with this file on an earlier run, so we didn't check again.
+    PreviouslyUploaded,
     Errored(Error),
+    /// The upload claimed to work, but we couldn't find the file afterwards.
+    VerificationFailed(Error),
+    /// The upload window closed before the adaptor got to this file.
+    Postponed,
+}
+
+impl UploadStatus {
+    /// Is the adaptor done with this file?
+    pub fn is_success(&self) -> bool {
+        match self {
+            UploadStatus::AlreadyUploaded |
+            UploadStatus::Succeeded |
+            UploadStatus::Skipped |
+            UploadStatus::PreviouslyUploaded => true,
+            UploadStatus::Errored(_) |
+            UploadStatus::VerificationFailed(_) |
+            UploadStatus::Postponed => false,
+        }
+    }
+}
+
+impl fmt::Display for UploadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadStatus::AlreadyUploaded => write!(f, "Already uploaded"),
+            UploadStatus::Succeeded => write!(f, "Succeeded"),
+            UploadStatus::Skipped => write!(f, "Skipped"),
+            UploadStatus::PreviouslyUploaded => write!(f, "Uploaded on a previous run"),
+            UploadStatus::Errored(error) => write!(f, "Upload failed: {:?}", error),
+            UploadStatus::VerificationFailed(error) => write!(f, "Verification failed: {:?}", error),
+            UploadStatus::Postponed => write!(f, "Postponed until the next upload window"),
+        }
+    }
 }
 
 impl Serialize for UploadStatus {
@@ -30,12 +68,7 @@ impl Serialize for UploadStatus {
     where
         S: Serializer,
     {
-        let msg = match self {
-            UploadStatus::AlreadyUploaded => "Already uploaded".to_string(),
-            UploadStatus::Succeeded => "Succeeded".to_string(),
-            UploadStatus::Errored(error) => format!("Upload failed: {:?}", error),
-        };
-        serializer.serialize_str(&msg)
+        serializer.collect_str(self)
     }
 }
 
@@ -43,6 +76,19 @@ impl Serialize for UploadStatus {
 #[derive(Debug, Default, Serialize)]
 pub struct UploadReport {
     files: HashMap<String, Vec<ReportEntry>>,
+    /// Files that no route sent anywhere, which are still sitting in staging.
+    unrouted: Vec<ReportEntry>,
+    /// Files that didn't fit in staging, which are still on their devices.
+    deferred: Vec<ReportEntry>,
+    /// Things in staging we couldn't make sense of, and moved out of the way.
+    quarantined: Vec<QuarantineEntry>,
+    /// Things in staging we couldn't even get far enough with to quarantine, and files we couldn't
+    /// get a good copy of from their devices.
+    staging_errors: Vec<String>,
+    /// Files we already had the contents of, which weren't staged again.
+    duplicates: Vec<DuplicateEntry>,
+    /// Devices we couldn't get at, which still have all their files on them.
+    failed_devices: Vec<FailedDeviceEntry>,
     uploaded_tally: HashMap<String, u64>,
 }
 
@@ -56,6 +102,28 @@ pub struct ReportEntry {
     results: Vec<(String, UploadStatus)>,
 }
 
+/// Something that was moved into quarantine, and why.
+#[derive(Debug, Serialize)]
+pub struct QuarantineEntry {
+    path: String,
+    reason: String,
+}
+
+/// A device we couldn't stage anything from, and why.
+#[derive(Debug, Serialize)]
+pub struct FailedDeviceEntry {
+    name: String,
+    reason: String,
+}
+
+/// A file that wasn't staged, and what we already had with the same contents.
+#[derive(Debug, Serialize)]
+pub struct DuplicateEntry {
+    #[serde(serialize_with = "format_report")]
+    desc: UploadDescriptor,
+    reason: String,
+}
+
 // We serialize with a custom serializer here, in order to use our date representation in the
 // reports.
 //
@@ -79,12 +147,11 @@ impl ReportEntry {
 }
 
 impl ReportEntry {
-    /// Was every attempt to upload in this transaction successful.
+    /// Was every attempt to upload in this transaction successful, with at least one adaptor
+    /// actually taking the file rather than skipping it.
     pub fn is_success(&self) -> bool {
-        self.results.iter().all(|r| match r.1 {
-            UploadStatus::AlreadyUploaded | UploadStatus::Succeeded => true,
-            UploadStatus::Errored(_) => false,
-        })
+        self.results.iter().all(|r| r.1.is_success()) &&
+            self.results.iter().any(|r| !matches!(r.1, UploadStatus::Skipped))
     }
 }
 
@@ -109,6 +176,58 @@ impl UploadReport {
         uploads.push(entry);
     }
 
+    /// Note that no backends were picked for this file.
+    pub fn record_unrouted(&mut self, desc: UploadDescriptor) {
+        self.unrouted.push(ReportEntry::new(desc, vec![]));
+    }
+
+    /// Note that this file was left on its device because staging was full.
+    pub fn record_deferred(&mut self, desc: UploadDescriptor) {
+        self.deferred.push(ReportEntry::new(desc, vec![]));
+    }
+
+    /// Note that something in staging was moved into quarantine.
+    pub fn record_quarantined(&mut self, quarantined: Quarantined) {
+        self.quarantined.push(QuarantineEntry {
+            path: quarantined.path.display().to_string(),
+            reason: quarantined.reason,
+        });
+    }
+
+    /// Note that we couldn't get at a device, so nothing was staged from it.
+    pub fn record_failed_device(&mut self, name: String, error: &Error) {
+        self.failed_devices.push(FailedDeviceEntry {
+            name,
+            reason: error.to_string(),
+        });
+    }
+
+    /// Note that something went wrong reading staging, which might have hidden files from us.
+    pub fn record_staging_error(&mut self, error: &Error) {
+        self.staging_errors.push(error.to_string());
+    }
+
+    /// Note that a file from `device` couldn't be staged, and was left on it.
+    pub fn record_device_staging_error(&mut self, device: &str, error: &Error) {
+        self.staging_errors.push(format!("{}: {}", device, error));
+    }
+
+    /// Note that a file was skipped because we already had its contents.
+    pub fn record_duplicate(&mut self, duplicate: Duplicate) {
+        let reason = match duplicate.original.uploaded {
+            Some(uploaded) => format!(
+                "uploaded as {} on {}",
+                duplicate.original.staging_name,
+                uploaded.format("%Y-%m-%d")
+            ),
+            None => format!("already staged as {}", duplicate.original.staging_name),
+        };
+        self.duplicates.push(DuplicateEntry {
+            desc: duplicate.desc,
+            reason,
+        });
+    }
+
     pub fn to_plaintext(&self) -> Result<String, TemplateRenderError> {
         handlebars().render_template(UPLOAD_REPORT_TEMPLATE, &self)
     }
@@ -119,11 +238,42 @@ impl UploadReport {
             .values() // Each device
             .fold(0, |i, v| i + v.len())
     }
+
+    /// Returns the number of files that weren't routed to any backends.
+    pub fn num_unrouted(&self) -> usize {
+        self.unrouted.len()
+    }
+
+    /// Returns the number of files that were left on devices.
+    pub fn num_deferred(&self) -> usize {
+        self.deferred.len()
+    }
+
+    /// Returns the number of things that were moved into quarantine.
+    pub fn num_quarantined(&self) -> usize {
+        self.quarantined.len()
+    }
+
+    /// Returns the number of files that weren't staged because we already had them.
+    pub fn num_duplicates(&self) -> usize {
+        self.duplicates.len()
+    }
+
+    /// How many things went wrong staging
+    pub fn num_staging_errors(&self) -> usize {
+        self.staging_errors.len()
+    }
+
+    /// How many devices we couldn't stage from
+    pub fn num_failed_devices(&self) -> usize {
+        self.failed_devices.len()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::staging::IndexEntry;
     use chrono::prelude::*;
 
     fn dummy_report() -> UploadReport {
@@ -182,6 +332,158 @@ mod tests {
         assert_eq!(report.num_uploads(), 4);
     }
 
+    #[test]
+    fn test_renders_unrouted_files() {
+        let mut report: UploadReport = Default::default();
+        let mut desc = UploadDescriptor::build("data".to_string())
+            .manual_file("track.csv".into());
+        desc.size = 2048;
+        report.record_unrouted(desc);
+
+        let expected = "\
+STOKEPILE UPLOAD REPORT
+=======================
+
+Unrouted Files
+==============
+
+    /data/track.csv (2kb)
+
+Uploaded Data
+=============
+
+";
+        assert_eq!(report.to_plaintext().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_renders_deferred_files() {
+        let mut report: UploadReport = Default::default();
+        let mut desc = UploadDescriptor::build("gopro".to_string())
+            .date_time(Local.ymd(2019, 6, 1).and_hms(9, 30, 0), "mp4".to_string());
+        desc.size = 4096;
+        report.record_deferred(desc);
+        assert_eq!(report.num_deferred(), 1);
+
+        let expected = "\
+STOKEPILE UPLOAD REPORT
+=======================
+
+Deferred Files
+==============
+
+    /2019/06/01/gopro/09-30-00.mp4 (4kb)
+
+Uploaded Data
+=============
+
+";
+        assert_eq!(report.to_plaintext().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_renders_quarantined_files() {
+        let mut report: UploadReport = Default::default();
+        report.record_quarantined(Quarantined {
+            path: "/staging/quarantine/stray.mp4".into(),
+            reason: "No manifest".into(),
+        });
+        assert_eq!(report.num_quarantined(), 1);
+
+        let expected = "\
+STOKEPILE UPLOAD REPORT
+=======================
+
+Quarantined Files
+=================
+
+    /staging/quarantine/stray.mp4: No manifest
+
+Uploaded Data
+=============
+
+";
+        assert_eq!(report.to_plaintext().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_renders_failed_devices() {
+        let mut report: UploadReport = Default::default();
+        report.record_failed_device("gopro5".into(), &format_err!("Couldn't open a session"));
+        assert_eq!(report.num_failed_devices(), 1);
+
+        let expected = "\
+STOKEPILE UPLOAD REPORT
+=======================
+
+Failed Devices
+==============
+
+    gopro5: Couldn't open a session
+
+Uploaded Data
+=============
+
+";
+        assert_eq!(report.to_plaintext().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_renders_staging_errors() {
+        let mut report: UploadReport = Default::default();
+        report.record_staging_error(&format_err!("Permission denied"));
+        report.record_device_staging_error("gopro5", &format_err!("Staged 3 bytes of gopro5.mp4, expected 5."));
+        assert_eq!(report.num_staging_errors(), 2);
+
+        let expected = "\
+STOKEPILE UPLOAD REPORT
+=======================
+
+Staging Errors
+==============
+
+    Permission denied
+
+    gopro5: Staged 3 bytes of gopro5.mp4, expected 5.
+
+Uploaded Data
+=============
+
+";
+        assert_eq!(report.to_plaintext().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_renders_duplicate_files() {
+        let mut report: UploadReport = Default::default();
+        let mut desc = UploadDescriptor::build("gopro".to_string())
+            .date_time(Local.ymd(2019, 6, 1).and_hms(9, 30, 0), "mp4".to_string());
+        desc.size = 4096;
+        report.record_duplicate(Duplicate {
+            desc,
+            original: IndexEntry {
+                staging_name: "gopro-original.mp4".into(),
+                uploaded: Some(Utc.ymd(2019, 5, 28).and_hms(20, 0, 0)),
+            },
+        });
+        assert_eq!(report.num_duplicates(), 1);
+
+        let expected = "\
+STOKEPILE UPLOAD REPORT
+=======================
+
+Duplicate Files
+===============
+
+    /2019/06/01/gopro/09-30-00.mp4 (4kb): uploaded as gopro-original.mp4 on 2019-05-28
+
+Uploaded Data
+=============
+
+";
+        assert_eq!(report.to_plaintext().unwrap(), expected);
+    }
+
     #[test]
     fn test_deals_with_large_totals() {
         let mut report: UploadReport = Default::default();
@@ -387,6 +689,36 @@ static UPLOAD_REPORT_TEMPLATE: &'static str = "\
 {{/each}}
 {{/each}}\
 
+{{#if unrouted}}{{header \"Unrouted Files\"}}
+{{#each unrouted}}
+    {{this.desc.remote_path}} ({{this.desc.size}}b)
+{{/each}}
+{{/if}}\
+{{#if deferred}}{{header \"Deferred Files\"}}
+{{#each deferred}}
+    {{this.desc.remote_path}} ({{this.desc.size}}b)
+{{/each}}
+{{/if}}\
+{{#if quarantined}}{{header \"Quarantined Files\"}}
+{{#each quarantined}}
+    {{this.path}}: {{this.reason}}
+{{/each}}
+{{/if}}\
+{{#if staging_errors}}{{header \"Staging Errors\"}}
+{{#each staging_errors}}
+    {{this}}
+{{/each}}
+{{/if}}\
+{{#if duplicates}}{{header \"Duplicate Files\"}}
+{{#each duplicates}}
+    {{this.desc.remote_path}} ({{this.desc.size}}b): {{this.reason}}
+{{/each}}
+{{/if}}\
+{{#if failed_devices}}{{header \"Failed Devices\"}}
+{{#each failed_devices}}
+    {{this.name}}: {{this.reason}}
+{{/each}}
+{{/if}}\
 {{header \"Uploaded Data\"}}
 {{#each uploaded_tally}}
 {{@key}}: {{human_readable_size this}}\
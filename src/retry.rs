@@ -0,0 +1,254 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use failure::Error;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+
+use crate::config::RetryConfig;
+
+pub const DEFAULT_MAX_ATTEMPTS: usize = 3;
+pub const DEFAULT_INITIAL_BACKOFF_SECS: u64 = 2;
+pub const DEFAULT_MAX_BACKOFF_SECS: u64 = 300;
+
+/// Broadly, what went wrong with an upload, which decides whether it's worth trying again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Dropped connections, timeouts and the server having a bad day. Worth retrying.
+    Transient,
+    /// The service doesn't like our credentials. Retrying won't change its mind.
+    Auth,
+    /// We're being rate limited. Worth retrying once the service says we can.
+    Quota,
+    /// Anything else the service rejected outright, including running out of space.
+    Permanent,
+    /// Something other than this file is already where it was going. Retrying won't help, but
+    /// putting it somewhere else will.
+    Conflict,
+}
+
+/// An error from an upload that knows what kind of error it is.
+///
+/// Adaptors return these inside a `failure::Error`, anything else is assumed to be transient.
+#[derive(Debug, Fail)]
+#[fail(display = "{}", message)]
+pub struct UploadError {
+    pub kind: ErrorKind,
+    /// How long the service asked us to wait before trying again.
+    pub retry_after: Option<Duration>,
+    message: String,
+}
+
+impl UploadError {
+    pub fn new(kind: ErrorKind, message: String) -> UploadError {
+        UploadError {
+            kind,
+            retry_after: None,
+            message,
+        }
+    }
+
+    /// Build an error from an unsuccessful response, using its status and any Retry-After header.
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, message: String) -> UploadError {
+        UploadError {
+            kind: kind_for_status(status),
+            retry_after: retry_after(headers),
+            message,
+        }
+    }
+}
+
+pub fn kind_for_status(status: StatusCode) -> ErrorKind {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::Auth,
+        StatusCode::TOO_MANY_REQUESTS => ErrorKind::Quota,
+        StatusCode::REQUEST_TIMEOUT => ErrorKind::Transient,
+        // Out of space isn't going to fix itself between attempts.
+        StatusCode::INSUFFICIENT_STORAGE => ErrorKind::Permanent,
+        s if s.is_server_error() => ErrorKind::Transient,
+        _ => ErrorKind::Permanent,
+    }
+}
+
+/// Parse a Retry-After header, which is either a number of seconds or a date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+/// Figure out what kind of error `error` is.
+pub fn classify(error: &Error) -> ErrorKind {
+    if let Some(error) = error.downcast_ref::<UploadError>() {
+        return error.kind;
+    }
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        if let Some(status) = error.status() {
+            return kind_for_status(status);
+        }
+    }
+    // Trying again won't make a file appear, or give us permission to it.
+    if let Some(error) = error.downcast_ref::<io::Error>() {
+        return match error.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => ErrorKind::Permanent,
+            _ => ErrorKind::Transient,
+        };
+    }
+    // This is what we did with everything before errors had kinds.
+    ErrorKind::Transient
+}
+
+/// How hard we try to upload things to a given adaptor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: Duration::from_secs(DEFAULT_INITIAL_BACKOFF_SECS),
+            max_backoff: Duration::from_secs(DEFAULT_MAX_BACKOFF_SECS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Override anything that's set in `config`.
+    pub fn apply(mut self, config: &RetryConfig) -> RetryPolicy {
+        if let Some(max_attempts) = config.max_attempts {
+            self.max_attempts = max_attempts;
+        }
+        if let Some(secs) = config.initial_backoff_secs {
+            self.initial_backoff = Duration::from_secs(secs);
+        }
+        if let Some(secs) = config.max_backoff_secs {
+            self.max_backoff = Duration::from_secs(secs);
+        }
+        self
+    }
+
+    /// How long to wait before another go after `attempt` failed with `error`, or None if we
+    /// should give up.
+    pub fn delay(&self, attempt: usize, error: &Error) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        match classify(error) {
+            ErrorKind::Transient | ErrorKind::Quota => {}
+            ErrorKind::Auth | ErrorKind::Permanent | ErrorKind::Conflict => return None,
+        }
+        if let Some(retry_after) = error.downcast_ref::<UploadError>().and_then(|e| e.retry_after) {
+            // A service that wants us gone for a day shouldn't hold the whole run up for one.
+            return Some(retry_after.min(self.max_backoff));
+        }
+        Some(self.backoff(attempt))
+    }
+
+    /// Exponential backoff with full jitter, so that parallel uploads don't all come back at once.
+    fn backoff(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31) as u32;
+        let ceiling = self
+            .initial_backoff
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        let millis = ceiling.as_millis() as u64;
+        if millis == 0 {
+            return ceiling;
+        }
+        Duration::from_millis(jitter() % (millis + 1))
+    }
+}
+
+/// A random number, without pulling in a whole crate for it.
+fn jitter() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(4),
+        }
+    }
+
+    #[test]
+    fn test_classifies_statuses() {
+        assert_eq!(kind_for_status(StatusCode::UNAUTHORIZED), ErrorKind::Auth);
+        assert_eq!(kind_for_status(StatusCode::TOO_MANY_REQUESTS), ErrorKind::Quota);
+        assert_eq!(kind_for_status(StatusCode::BAD_GATEWAY), ErrorKind::Transient);
+        assert_eq!(kind_for_status(StatusCode::BAD_REQUEST), ErrorKind::Permanent);
+        assert_eq!(kind_for_status(StatusCode::INSUFFICIENT_STORAGE), ErrorKind::Permanent);
+        assert_eq!(classify(&format_err!("Something broke")), ErrorKind::Transient);
+        let denied: Error = io::Error::new(io::ErrorKind::PermissionDenied, "Read-only filesystem").into();
+        assert_eq!(classify(&denied), ErrorKind::Permanent);
+        let reset: Error = io::Error::new(io::ErrorKind::ConnectionReset, "Connection reset").into();
+        assert_eq!(classify(&reset), ErrorKind::Transient);
+    }
+
+    #[test]
+    fn test_parses_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        // That's in the past, so there's nothing to wait for.
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_backs_off_exponentially() {
+        let policy = policy();
+        let error = format_err!("Connection reset");
+        for attempt in 1..5 {
+            let ceiling = Duration::from_secs(1 << (attempt - 1)).min(policy.max_backoff);
+            let delay = policy.delay(attempt, &error).expect("Should have retried");
+            assert!(delay <= ceiling, "{:?} > {:?}", delay, ceiling);
+        }
+        assert_eq!(policy.delay(5, &error), None);
+    }
+
+    #[test]
+    fn test_fails_fast_on_permanent_errors() {
+        let policy = policy();
+        let auth: Error = UploadError::new(ErrorKind::Auth, "Expired token".into()).into();
+        assert_eq!(policy.delay(1, &auth), None);
+        let permanent: Error = UploadError::new(ErrorKind::Permanent, "Bad request".into()).into();
+        assert_eq!(policy.delay(1, &permanent), None);
+        let conflict: Error = UploadError::new(ErrorKind::Conflict, "Something else is there".into()).into();
+        assert_eq!(policy.delay(1, &conflict), None);
+    }
+
+    #[test]
+    fn test_honors_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+        let error: Error = UploadError::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, "Slow down".into()).into();
+        assert_eq!(policy().delay(1, &error), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_caps_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+        let error: Error = UploadError::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, "Slow down".into()).into();
+        assert_eq!(policy().delay(1, &error), Some(Duration::from_secs(4)));
+    }
+}
@@ -0,0 +1,164 @@
+use crate::config::{RouteConfig, RouteKind};
+use crate::staging::{RemotePathDescriptor, UploadDescriptor};
+
+/// Decides which storage adaptors each staged file should be sent to.
+///
+/// Routes are checked in the order they're configured and the first one to match wins. With no
+/// routes configured at all, everything goes everywhere.
+#[derive(Debug, Default, Clone)]
+pub struct Router {
+    routes: Vec<RouteConfig>,
+}
+
+/// Where a given file should go.
+#[derive(Debug, PartialEq)]
+pub enum Route<'a> {
+    /// No routes are configured, so every adaptor gets it.
+    Everywhere,
+    /// Only the named adaptors get it.
+    Only(&'a [String]),
+    /// No route matched, so nothing gets it.
+    Unmatched,
+}
+
+impl<'a> Route<'a> {
+    /// Should the adaptor called `name` be sent this file?
+    pub fn includes(&self, name: &str) -> bool {
+        match self {
+            Route::Everywhere => true,
+            Route::Only(backends) => backends.iter().any(|b| b == name),
+            Route::Unmatched => false,
+        }
+    }
+}
+
+impl Router {
+    pub fn new(routes: Vec<RouteConfig>) -> Router {
+        Router { routes }
+    }
+
+    pub fn route(&self, desc: &UploadDescriptor) -> Route<'_> {
+        if self.routes.is_empty() {
+            return Route::Everywhere;
+        }
+        self.routes
+            .iter()
+            .find(|route| matches(route, desc))
+            .map(|route| Route::Only(&route.backends[..]))
+            .unwrap_or(Route::Unmatched)
+    }
+}
+
+fn matches(route: &RouteConfig, desc: &UploadDescriptor) -> bool {
+    if let Some(devices) = &route.devices {
+        if !devices.contains(&desc.device_name) {
+            return false;
+        }
+    }
+
+    if let Some(extensions) = &route.extensions {
+        // Templates decide the remote path, but not what kind of file it is.
+        let extension = desc.extension().unwrap_or("");
+        if !extensions.iter().any(|e| e.eq_ignore_ascii_case(extension)) {
+            return false;
+        }
+    }
+
+    if let Some(min_size) = route.min_size {
+        if desc.size < min_size {
+            return false;
+        }
+    }
+
+    if let Some(max_size) = route.max_size {
+        if desc.size > max_size {
+            return false;
+        }
+    }
+
+    matches!(
+        (route.kind, &desc.path),
+        (None, _) |
+        (Some(RouteKind::DateTime), RemotePathDescriptor::DateTime { .. }) |
+        (Some(RouteKind::SpecifiedPath), RemotePathDescriptor::SpecifiedPath { .. })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_template::PathTemplate;
+
+    fn route(backends: &[&str]) -> RouteConfig {
+        RouteConfig {
+            devices: None,
+            extensions: None,
+            min_size: None,
+            max_size: None,
+            kind: None,
+            backends: backends.iter().map(|b| b.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_no_routes_goes_everywhere() {
+        let router: Router = Default::default();
+        let desc = UploadDescriptor::test_descriptor();
+        assert_eq!(router.route(&desc), Route::Everywhere);
+        assert!(router.route(&desc).includes("vimeo"));
+    }
+
+    #[test]
+    fn test_first_matching_route_wins() {
+        let router = Router::new(vec![
+            RouteConfig {
+                extensions: Some(vec!["csv".into()]),
+                ..route(&["dropbox"])
+            },
+            RouteConfig {
+                extensions: Some(vec!["MP4".into()]),
+                ..route(&["vimeo", "dropbox"])
+            },
+            route(&["local backup"]),
+        ]);
+        let desc = UploadDescriptor::test_descriptor();
+        let route = router.route(&desc);
+        assert!(route.includes("vimeo"));
+        assert!(route.includes("dropbox"));
+        assert!(!route.includes("local backup"));
+    }
+
+    #[test]
+    fn test_routes_by_the_files_own_extension() {
+        let router = Router::new(vec![
+            RouteConfig {
+                extensions: Some(vec!["mp4".into()]),
+                ..route(&["vimeo"])
+            },
+        ]);
+        let mut desc = UploadDescriptor::test_descriptor();
+        desc.template = Some(PathTemplate::parse("/{device}/{filename}-{hash}.video").unwrap());
+        assert!(router.route(&desc).includes("vimeo"));
+    }
+
+    #[test]
+    fn test_unmatched_files() {
+        let router = Router::new(vec![
+            RouteConfig {
+                devices: Some(vec!["flysight".into()]),
+                ..route(&["dropbox"])
+            },
+            RouteConfig {
+                max_size: Some(512),
+                ..route(&["dropbox"])
+            },
+            RouteConfig {
+                kind: Some(RouteKind::SpecifiedPath),
+                ..route(&["dropbox"])
+            },
+        ]);
+        let desc = UploadDescriptor::test_descriptor();
+        assert_eq!(router.route(&desc), Route::Unmatched);
+        assert!(!router.route(&desc).includes("dropbox"));
+    }
+}
@@ -0,0 +1,326 @@
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+use failure::{Error, ResultExt};
+use ssh2::{CheckResult, KnownHostFileKind, RenameFlags, Session, Sftp};
+
+use crate::config::{self, SftpConfig};
+use crate::retry::{ErrorKind, UploadError};
+use crate::staging;
+use crate::storage::{StorageAdaptor, StorageStatus};
+
+const DEFAULT_PORT: u16 = 22;
+
+/// A storage adaptor that archives to a directory on a remote host over sftp.
+///
+/// Files are written to a temporary name alongside their destination and renamed into place once
+/// they're complete, so a dropped connection never leaves a truncated file where a real one should
+/// be. The content hash of each file is stored in a hidden sidecar next to it, which is how we
+/// answer `already_uploaded` without having to read the whole file back.
+#[derive(Clone, RedactedDebug)]
+pub struct SftpClient {
+    host: String,
+    port: u16,
+    username: String,
+    private_key: PathBuf,
+    #[redacted]
+    passphrase: Option<String>,
+    known_hosts: PathBuf,
+    root: PathBuf,
+}
+
+#[derive(Fail, Debug)]
+pub enum SftpError {
+    #[fail(display = "{} is not in known_hosts. Connect to it with ssh once to add it.", _0)]
+    UnknownHost(String),
+    #[fail(display = "The host key for {} does not match known_hosts!", _0)]
+    HostKeyMismatch(String),
+    #[fail(display = "Couldn't check the host key for {}.", _0)]
+    HostKeyCheckFailed(String),
+    #[fail(display = "{} didn't present a host key.", _0)]
+    NoHostKey(String),
+}
+
+/// Returns the path of a hidden file alongside `path`, eg `/foo/.bar.mp4.partial`.
+fn sidecar_path(path: &Path, suffix: &str) -> Result<PathBuf, Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format_err!("{:?} has no filename to put a sidecar next to", path))?;
+    let mut sidecar = OsString::from(".");
+    sidecar.push(name);
+    sidecar.push(".");
+    sidecar.push(suffix);
+    Ok(path.with_file_name(sidecar))
+}
+
+/// Rename a file, replacing the destination if it exists.
+fn rename_into_place(sftp: &Sftp, from: &Path, to: &Path) -> Result<(), Error> {
+    let flags = Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE);
+    if let Err(e) = sftp.rename(from, to, flags) {
+        if sftp.stat(to).is_err() {
+            return Err(e.into());
+        }
+        // SFTPv3 servers (including OpenSSH) ignore the flags and refuse to clobber an existing
+        // file. Rather than unlinking it, which would leave nothing there at all if we died before
+        // the next rename, we move it aside so there's always a copy under one name or the other.
+        let old = sidecar_path(to, "old")?;
+        if sftp.stat(&old).is_ok() {
+            // Left over from a replacement that finished everything but cleaning up.
+            sftp.unlink(&old)?;
+        }
+        sftp.rename(to, &old, flags)?;
+        if let Err(e) = sftp.rename(from, to, flags) {
+            if let Err(restore) = sftp.rename(&old, to, flags) {
+                error!("Couldn't move {:?} back to {:?}: {:?}", &old, to, restore);
+            }
+            return Err(e.into());
+        }
+        if let Err(e) = sftp.unlink(&old) {
+            warn!("Couldn't remove {:?}: {:?}", &old, e);
+        }
+    }
+    Ok(())
+}
+
+fn create_dir_all(sftp: &Sftp, dir: &Path) -> Result<(), Error> {
+    if sftp.stat(dir).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dir_all(sftp, parent)?;
+    }
+    trace!("Creating remote directory {:?}", dir);
+    sftp.mkdir(dir, 0o755)
+        .context(format!("Creating remote directory {:?}", dir))?;
+    Ok(())
+}
+
+impl SftpClient {
+    pub fn new(config: &SftpConfig) -> Result<SftpClient, Error> {
+        let known_hosts = match &config.known_hosts {
+            Some(path) => path.clone(),
+            None => config::get_home()?.as_ref().join(".ssh").join("known_hosts"),
+        };
+
+        Ok(SftpClient {
+            host: config.host.clone(),
+            port: config.port.unwrap_or(DEFAULT_PORT),
+            username: config.username.clone(),
+            private_key: config.private_key.clone(),
+            passphrase: config.passphrase.clone(),
+            known_hosts,
+            root: config.root.clone(),
+        })
+    }
+
+    fn connect(&self) -> Result<Sftp, Error> {
+        let tcp = TcpStream::connect((&self.host[..], self.port))
+            .context(format!("Connecting to {}:{}", &self.host, self.port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+        self.check_host_key(&session)?;
+        session
+            .userauth_pubkey_file(
+                &self.username,
+                None,
+                &self.private_key,
+                self.passphrase.as_ref().map(|s| s.as_str()),
+            )
+            .context(format!("Authenticating as {}", &self.username))?;
+        Ok(session.sftp()?)
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result<(), Error> {
+        let mut known_hosts = session.known_hosts()?;
+        known_hosts
+            .read_file(&self.known_hosts, KnownHostFileKind::OpenSSH)
+            .context(format!("Reading known_hosts from {:?}", &self.known_hosts))?;
+
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| SftpError::NoHostKey(self.host.clone()))?;
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => Err(SftpError::UnknownHost(self.host.clone()).into()),
+            CheckResult::Mismatch => Err(SftpError::HostKeyMismatch(self.host.clone()).into()),
+            CheckResult::Failure => Err(SftpError::HostKeyCheckFailed(self.host.clone()).into()),
+        }
+    }
+
+    fn remote_path(&self, manifest: &staging::UploadDescriptor) -> PathBuf {
+        let root = PathBuf::from("/");
+        self.root
+            .join(manifest.remote_path().strip_prefix(&root).unwrap())
+    }
+
+    fn write_atomically<R: Read>(&self, sftp: &Sftp, reader: &mut R, path: &Path) -> Result<(), Error> {
+        let partial = sidecar_path(path, "partial")?;
+        {
+            let mut file = sftp.create(&partial)
+                .context(format!("Creating {:?}", &partial))?;
+            io::copy(reader, &mut file)?;
+            // Not every server supports fsync, and if it doesn't there's not much we can do.
+            if let Err(e) = file.fsync() {
+                debug!("Remote fsync of {:?} failed: {:?}", &partial, e);
+            }
+        }
+        rename_into_place(sftp, &partial, path)
+    }
+
+    /// The content hash we stored alongside `remote_path`, if there is one.
+    fn content_hash_at(&self, sftp: &Sftp, remote_path: &Path) -> Option<String> {
+        let mut hash = String::new();
+        let mut sidecar = sidecar_path(remote_path, "content_hash").and_then(|path| Ok(sftp.open(&path)?)).ok()?;
+        sidecar.read_to_string(&mut hash).ok()?;
+        Some(hash.trim().to_string())
+    }
+}
+
+impl<T> StorageAdaptor<T> for SftpClient
+where
+    T: Read,
+{
+    fn already_uploaded(&self, manifest: &staging::UploadDescriptor) -> bool {
+        let sftp = match self.connect() {
+            Ok(sftp) => sftp,
+            Err(e) => {
+                warn!("Couldn't connect to {}: {:?}", &self.host, e);
+                return false;
+            }
+        };
+        let remote_path = self.remote_path(manifest);
+
+        match sftp.stat(&remote_path) {
+            Ok(ref stat) if stat.size == Some(manifest.size) => {}
+            _ => return false,
+        }
+
+        self.content_hash_at(&sftp, &remote_path) == Some(hex::encode(manifest.content_hash))
+    }
+
+    fn upload(
+        &self,
+        mut reader: T,
+        manifest: &staging::UploadDescriptor,
+    ) -> Result<StorageStatus, Error> {
+        let sftp = self.connect()?;
+        let remote_path = self.remote_path(manifest);
+        let hash = hex::encode(manifest.content_hash);
+
+        // Renaming into place would replace whatever's there, which is only ever ours to replace
+        // if it has our content hash. Anything without one gets a copy alongside it instead, even
+        // if it's our own upload that didn't get as far as its sidecar.
+        if sftp.stat(&remote_path).is_ok() && self.content_hash_at(&sftp, &remote_path).as_ref() != Some(&hash) {
+            let message = format!("{:?} is already a different file, not overwriting it", &remote_path);
+            Err(UploadError::new(ErrorKind::Conflict, message))?;
+        }
+
+        create_dir_all(&sftp, remote_path.parent().unwrap())?;
+        self.write_atomically(&sftp, &mut reader, &remote_path)?;
+
+        // The sidecar goes last, so that a content file without one is never mistaken for being
+        // complete.
+        self.write_atomically(
+            &sftp,
+            &mut hash.as_bytes(),
+            &sidecar_path(&remote_path, "content_hash")?,
+        )?;
+
+        Ok(StorageStatus::Success)
+    }
+
+    fn name(&self) -> String {
+        "sftp".to_string()
+    }
+
+    fn id(&self) -> String {
+        format!("sftp:{}@{}:{}{}", &self.username, &self.host, self.port, self.root.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn test_client() -> SftpClient {
+        SftpClient::new(&SftpConfig {
+            host: "nas.local".into(),
+            port: None,
+            username: "stokepile".into(),
+            private_key: "/home/stokepile/.ssh/id_ed25519".into(),
+            passphrase: None,
+            known_hosts: None,
+            root: "/volume1/footage".into(),
+        })
+        .expect("Couldn't create client")
+    }
+
+    #[test]
+    fn test_remote_path() {
+        let client = test_client();
+        let manifest = staging::UploadDescriptor::test_descriptor();
+
+        assert_eq!(client.remote_path(&manifest),
+                   PathBuf::from("/volume1/footage/2018/08/26/test-device/14-30-00.mp4"));
+    }
+
+    #[test]
+    fn test_sidecar_path() {
+        let path = Path::new("/volume1/footage/2018/08/26/test-device/14-30-00.mp4");
+        assert_eq!(sidecar_path(&path, "partial").unwrap(),
+                   PathBuf::from("/volume1/footage/2018/08/26/test-device/.14-30-00.mp4.partial"));
+        assert!(sidecar_path(Path::new("/"), "partial").is_err());
+    }
+
+    #[test]
+    fn test_defaults_to_port_22() {
+        assert_eq!(test_client().port, 22);
+    }
+
+    fn nas_client() -> SftpClient {
+        SftpClient::new(&SftpConfig {
+            host: env::var("STOKEPILE_TEST_SFTP_HOST").expect("Didn't provide test host"),
+            port: None,
+            username: env::var("STOKEPILE_TEST_SFTP_USER").expect("Didn't provide test user"),
+            private_key: env::var("STOKEPILE_TEST_SFTP_KEY").expect("Didn't provide test key").into(),
+            passphrase: None,
+            known_hosts: None,
+            root: env::var("STOKEPILE_TEST_SFTP_ROOT").expect("Didn't provide test root").into(),
+        })
+        .expect("Couldn't create client")
+    }
+
+    #[test]
+    #[ignore]
+    fn test_uploads_large_file() {
+        let client = nas_client();
+        let localfile =
+            fs::File::open("/usr/share/dict/web2").expect("Couldn't open dummy dictionary");
+        if let Err(e) = client.upload(localfile, &staging::UploadDescriptor::test_descriptor()) {
+            panic!("{:?}", e);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_roundtripped_content_hash_works() {
+        let client = nas_client();
+        let localfile = b"yes hello";
+        let mut manifest = staging::UploadDescriptor::test_descriptor();
+        manifest.size = localfile.len() as u64;
+        manifest.content_hash = [0x42; 32];
+
+        if let Err(e) = client.upload(&localfile[..], &manifest) {
+            panic!("{:?}", e);
+        }
+        assert!(StorageAdaptor::<&[u8]>::already_uploaded(&client, &manifest));
+
+        manifest.content_hash = [0x41; 32];
+        assert!(!StorageAdaptor::<&[u8]>::already_uploaded(&client, &manifest));
+    }
+}
@@ -1,7 +1,11 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
 use std::path::{Path, PathBuf};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::fs::{self, File};
 use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
 
 use chrono;
 use chrono::prelude::*;
@@ -14,11 +18,14 @@ use serde_json;
 
 use crate::config::{MountableDeviceLocation, StagingConfig};
 use crate::mountable::{MountedFilesystem, MountableFilesystem, MountableKind, MOUNTABLE_DEVICE_FOLDER};
+use crate::clock::{DeviceClock, DeviceClocks};
+use crate::path_template::{PathTemplate, PathTemplates};
+use crate::routing::{Route, Router};
 
-#[derive(Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub enum RemotePathDescriptor {
     DateTime {
-        capture_time: DateTime<Local>,
+        capture_time: DateTime<FixedOffset>,
         extension: String,
     },
     SpecifiedPath {
@@ -67,17 +74,27 @@ pub struct MountedStaging {
 pub trait StorableFile {
     type Reader: Read;
 
-    fn remote_path(&self) -> Result<RemotePathDescriptor, Error>;
+    fn remote_path(&self, clock: DeviceClock) -> Result<RemotePathDescriptor, Error>;
     fn delete(&mut self) -> Result<(), Error>;
     fn size(&self) -> Result<u64, Error>;
     fn reader(&mut self) -> &mut Self::Reader;
 
-    fn descriptor(&self, name: &str) -> Result<UploadDescriptor, Error> {
+    /// What the device calls this file, without its extension.
+    fn original_name(&self) -> Option<String> {
+        None
+    }
+
+    fn descriptor(&self, name: &str, clock: DeviceClock) -> Result<UploadDescriptor, Error> {
         Ok(UploadDescriptor {
-            path: self.remote_path()?,
+            version: MANIFEST_VERSION,
+            path: self.remote_path(clock)?,
             content_hash: [0; 32],
             device_name: name.to_string(),
             size: self.size()?,
+            original_name: self.original_name(),
+            discriminator: None,
+            template: None,
+            backend_template: None,
         })
     }
 }
@@ -85,11 +102,12 @@ pub trait StorableFile {
 pub trait DateTimeUploadable {
     type Reader: Read;
     fn extension(&self) -> &str;
-    fn capture_datetime(&self) -> Result<DateTime<Local>, chrono::ParseError>;
+    /// What the device's clock read when this was captured.
+    fn capture_datetime(&self) -> Result<NaiveDateTime, chrono::ParseError>;
 
-    fn remote_path(&self) -> Result<RemotePathDescriptor, Error> {
+    fn remote_path(&self, clock: DeviceClock) -> Result<RemotePathDescriptor, Error> {
         Ok(RemotePathDescriptor::DateTime {
-            capture_time: self.capture_datetime()?,
+            capture_time: clock.capture_time(self.capture_datetime()?),
             extension: self.extension().to_string(),
         })
     }
@@ -97,13 +115,18 @@ pub trait DateTimeUploadable {
     fn delete(&mut self) -> Result<(), Error>;
     fn size(&self) -> Result<u64, Error>;
     fn reader(&mut self) -> &mut Self::Reader;
+
+    /// What the device calls this file, without its extension, for devices that name their files.
+    fn original_name(&self) -> Option<String> {
+        None
+    }
 }
 
 impl<T> StorableFile for T where T: DateTimeUploadable {
     type Reader = T::Reader;
 
-    fn remote_path(&self) -> Result<RemotePathDescriptor, Error> {
-        self.remote_path()
+    fn remote_path(&self, clock: DeviceClock) -> Result<RemotePathDescriptor, Error> {
+        self.remote_path(clock)
     }
     fn delete(&mut self) -> Result<(), Error> {
         self.delete()
@@ -114,47 +137,346 @@ impl<T> StorableFile for T where T: DateTimeUploadable {
     fn reader(&mut self) -> &mut Self::Reader {
         self.reader()
     }
+    fn original_name(&self) -> Option<String> {
+        self.original_name()
+    }
+}
+
+/// Staged files being written out have this suffix until they're complete.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Where staged files we can't make sense of are moved to, relative to the staging location.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Where we keep track of the contents of everything we've staged, relative to the staging
+/// location, so that we don't stage the same thing twice.
+pub const INDEX_DIR: &str = "index";
+
+/// The version of the manifest format we write, see `Stager::migrate_manifests`.
+///
+/// Before version 1, capture times were whatever the device's clock said, taken to be in our own
+/// zone.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// How long we remember uploads for, unless told otherwise.
+pub const DEFAULT_DEDUPE_DAYS: u32 = 30;
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().expect("staged file has no name").to_os_string();
+    name.push(PARTIAL_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Ask the kernel to forget its cached copy of `file`, so that reading it back has to go to the
+/// disk rather than being answered from memory. It's only advice, and only clean pages are
+/// dropped, so `file` needs to have been synced first.
+#[cfg(target_os = "linux")]
+fn drop_cached_pages(file: &File) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
 }
 
-fn stage_file<T, U>(file: &mut T, destination: &U, name: &str) -> Result<(), Error>
+#[cfg(not(target_os = "linux"))]
+fn drop_cached_pages(_: &File) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Make sure renames into `dir` have made it to disk.
+fn sync_dir(dir: &Path) -> Result<(), io::Error> {
+    File::open(dir)?.sync_all()
+}
+
+/// Reasons the staged copy of a file can't be trusted, in which case we leave the original alone.
+#[derive(Fail, Debug)]
+pub enum StagingError {
+    #[fail(display = "Staged {} bytes of {}, expected {}.", actual, name, expected)]
+    SizeMismatch { name: String, expected: u64, actual: u64 },
+    #[fail(display = "Staged copy of {} doesn't match what we read from the device.", _0)]
+    HashMismatch(String),
+}
+
+fn hash_file(path: &Path) -> Result<(u64, Vec<u8>), io::Error> {
+    let mut file = File::open(path)?;
+    let (size, hash) = hashing_copy::copy_and_hash::<_, _, DropboxContentHasher>(
+        &mut file,
+        &mut io::sink(),
+        )?;
+    Ok((size, hash.to_vec()))
+}
+
+fn stage_file<T, U>(file: &mut T, stager: &Stager<U>, name: &str) -> Result<Staged, Error>
 where T: StorableFile,
       U: StagingLocation,
 {
-    let mut desc = file.descriptor(name)?;
-
-    let staging_name = desc.staging_name();
-    let manifest_name = desc.manifest_name();
+    let destination = &stager.location;
+    let clock = stager.clocks.for_device(name);
+    let (verify, dedupe_window, templates, router) = (stager.verify, stager.dedupe_window, &stager.templates, &stager.router);
+    let mut desc = file.descriptor(name, clock)?;
 
     let mut options = fs::OpenOptions::new();
     let options = options.write(true).create(true).truncate(true);
 
-    let staging_path = destination.path_for_name(&staging_name);
-    let manifest_path = destination.path_for_name(&manifest_name);
-
-    info!("Staging {} to {:?}", &staging_name, &staging_path);
-    {
-        let mut staged = options.open(&staging_path)?;
+    // Everything is written under a temporary name and only moved into place once it's on disk,
+    // and the manifest goes last. If we lose power partway through, we're left with files that
+    // `staged_files` knows aren't complete, rather than a manifest for half a file.
+    //
+    // The template can depend on the content hash, so the real name isn't known until we've
+    // copied the file in.
+    let partial = partial_path(&destination.path_for_name(&desc.staging_name()));
+    info!("Staging {} to {:?}", desc.staging_name(), &partial);
+    let (size, hash) = {
+        let mut staged = options.open(&partial)?;
         let (size, hash) = hashing_copy::copy_and_hash::<_, _, DropboxContentHasher>(
             file.reader(),
             &mut staged,
             )
             .context("Copying file to staging")?;
-        assert_eq!(size, desc.size);
-        desc.content_hash.copy_from_slice(&hash);
-        info!("Staged {}: shasum={:x} size={}", &staging_name, &hash, formatting::human_readable_size(size));
-    } // Ensure that we've closed our staging file
+        staged.sync_all().context("Syncing staged file")?;
+        // Otherwise verifying would most likely read back what we just wrote from memory, and
+        // never see what the disk made of it.
+        if verify {
+            if let Err(e) = drop_cached_pages(&staged) {
+                warn!("Couldn't drop cached pages for {:?}, verifying might not read from disk: {:?}", &partial, e);
+            }
+        }
+        (size, hash)
+    }; // Ensure that we've closed our staging file
+
+    let mut problem = None;
+    if size != desc.size {
+        problem = Some(StagingError::SizeMismatch {
+            name: desc.staging_name(),
+            expected: desc.size,
+            actual: size,
+        });
+    } else if verify {
+        info!("Verifying {}", desc.staging_name());
+        let (staged_size, staged_hash) = hash_file(&partial).context("Verifying staged file")?;
+        if staged_size != size || staged_hash[..] != hash[..] {
+            problem = Some(StagingError::HashMismatch(desc.staging_name()));
+        }
+    }
+    if let Some(problem) = problem {
+        fs::remove_file(&partial)?;
+        Err(problem)?;
+    }
 
-    {
-        info!("Manifesting {}", &manifest_name);
-        trace!(" To {:?}", manifest_path);
-        let mut staged = options.open(&manifest_path)
-            .context("Opening manifest")?;
-        serde_json::to_writer(&mut staged, &desc)?;
+    desc.content_hash.copy_from_slice(&hash);
+    desc.template = templates.for_device(name).cloned();
+
+    // Empty files are only told apart by their names, and there's nothing to save by skipping them.
+    let duplicate = match size {
+        0 => None,
+        _ => destination.hash_index().duplicate_of(destination, &desc.content_hash, &router.route(&desc), dedupe_window)?,
+    };
+    if let Some(original) = duplicate {
+        info!("{} has the same contents as {}, skipping it", desc.staging_name(), &original.staging_name);
+        fs::remove_file(&partial)?;
+        return Ok(Staged::Duplicate(Duplicate { desc, original }));
     }
 
+    if is_taken(destination, &desc) {
+        let taken = desc.staging_name();
+        discriminate(destination, &mut desc);
+        warn!("{} is already staged with different contents, staging this as {}", taken, desc.staging_name());
+    }
+    let staging_name = desc.staging_name();
+    let manifest_name = desc.manifest_name();
+    let staging_path = destination.path_for_name(&staging_name);
+    let manifest_path = destination.path_for_name(&manifest_name);
+
+    fs::rename(&partial, &staging_path)?;
+    info!("Staged {}: shasum={:x} size={}", &staging_name, &hash, formatting::human_readable_size(size));
+
+    info!("Manifesting {}", &manifest_name);
+    trace!(" To {:?}", manifest_path);
+    write_manifest(&manifest_path, &desc)?;
+
+    if let Some(dir) = manifest_path.parent() {
+        sync_dir(dir).context("Syncing staging directory")?;
+    }
+
+    destination.hash_index().record_staged(&desc.content_hash, &router.route(&desc), staging_name)
+        .context("Updating staging index")?;
+
+    Ok(Staged::New(desc))
+}
+
+/// Write out `desc` to `path`, replacing whatever was there in one go.
+fn write_manifest(path: &Path, desc: &UploadDescriptor) -> Result<(), Error> {
+    let partial = partial_path(path);
+    let mut manifest = File::create(&partial)
+        .context("Opening manifest")?;
+    serde_json::to_writer(&mut manifest, desc)?;
+    manifest.sync_all().context("Syncing manifest")?;
+    fs::rename(&partial, path)?;
     Ok(())
 }
 
+/// Is there already something staged where `desc` would go?
+fn is_taken<U: StagingLocation>(destination: &U, desc: &UploadDescriptor) -> bool {
+    destination.path_for_name(&desc.staging_name()).exists() ||
+        destination.path_for_name(&desc.manifest_name()).exists()
+}
+
+/// Give `desc` a discriminator so it doesn't clobber something else from the same device and
+/// second. We go by the name the device gave the file if it has one, otherwise the start of the
+/// contents hash, and count up from there if even that's taken.
+fn discriminate<U: StagingLocation>(destination: &U, desc: &mut UploadDescriptor) {
+    let base = desc.original_name.clone()
+        .unwrap_or_else(|| hex::encode(&desc.content_hash[..4]));
+    desc.discriminator = Some(base.clone());
+    let mut n = 1;
+    while is_taken(destination, desc) {
+        n += 1;
+        desc.discriminator = Some(format!("{}-{}", base, n));
+    }
+}
+
+/// What became of a file we were asked to stage.
+#[derive(Debug)]
+pub enum Staged {
+    /// It's in staging, waiting to be uploaded.
+    New(UploadDescriptor),
+    /// We already had its contents, so we didn't stage it again.
+    Duplicate(Duplicate),
+}
+
+/// A file that was skipped because something with the same contents was already staged, or was
+/// uploaded recently.
+#[derive(Debug)]
+pub struct Duplicate {
+    pub desc: UploadDescriptor,
+    pub original: IndexEntry,
+}
+
+/// What we know about some contents we've staged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// The name the contents were staged under.
+    pub staging_name: String,
+    /// When the contents finished uploading, if they have.
+    pub uploaded: Option<DateTime<Utc>>,
+}
+
+impl IndexEntry {
+    /// Do we still have these contents, staged in `location` or uploaded within `window`?
+    fn is_current<T: StagingLocation + ?Sized>(&self, location: &T, window: chrono::Duration) -> bool {
+        match self.uploaded {
+            Some(uploaded) => Utc::now() - uploaded <= window,
+            // If it's been quarantined or removed by hand, it's fair game to stage again.
+            None => location.path_for_name(&self.staging_name).exists(),
+        }
+    }
+}
+
+/// An index of staged contents by their content hash and route, kept as a file per entry so that
+/// updating it never involves rewriting anything else.
+#[derive(Debug)]
+pub struct HashIndex {
+    dir: PathBuf,
+}
+
+impl HashIndex {
+    /// Contents are only a duplicate if they're going to the same places as what we already have,
+    /// otherwise the other backends would never get them, so the route is part of the key.
+    fn entry_path(&self, hash: &[u8; 32], route: &Route<'_>) -> PathBuf {
+        let hash = hex::encode(hash);
+        self.dir.join(match route {
+            Route::Everywhere => hash,
+            Route::Only(backends) => {
+                let mut backends = backends.to_vec();
+                backends.sort();
+                format!("{}-{}", hash, hex::encode(backends.join("\n")))
+            },
+            Route::Unmatched => format!("{}-unmatched", hash),
+        })
+    }
+
+    pub fn get(&self, hash: &[u8; 32], route: &Route<'_>) -> Option<IndexEntry> {
+        let path = self.entry_path(hash, route);
+        let contents = fs::read(&path).ok()?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| warn!("Couldn't parse index entry {:?}, ignoring it: {:?}", &path, e))
+            .ok()
+    }
+
+    fn put(&self, hash: &[u8; 32], route: &Route<'_>, entry: &IndexEntry) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.entry_path(hash, route);
+        let partial = partial_path(&path);
+        fs::write(&partial, serde_json::to_vec(entry)?)?;
+        fs::rename(&partial, &path)?;
+        Ok(())
+    }
+
+    pub fn record_staged(&self, hash: &[u8; 32], route: &Route<'_>, staging_name: String) -> Result<(), Error> {
+        self.put(hash, route, &IndexEntry { staging_name, uploaded: None })
+    }
+
+    /// Note that these contents have been uploaded everywhere `route` sends them.
+    pub fn record_uploaded(&self, hash: &[u8; 32], route: &Route<'_>) -> Result<(), Error> {
+        match self.get(hash, route) {
+            Some(mut entry) => {
+                entry.uploaded = Some(Utc::now());
+                self.put(hash, route, &entry)
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// If we already have these contents going to the same places, staged in `location` or
+    /// uploaded within `window`, say what we have. Entries for uploads older than that are
+    /// forgotten.
+    pub fn duplicate_of<T: StagingLocation + ?Sized>(
+        &self,
+        location: &T,
+        hash: &[u8; 32],
+        route: &Route<'_>,
+        window: chrono::Duration,
+    ) -> Result<Option<IndexEntry>, Error> {
+        let entry = match self.get(hash, route) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if entry.is_current(location, window) {
+            return Ok(Some(entry));
+        }
+        if entry.uploaded.is_some() {
+            fs::remove_file(self.entry_path(hash, route))?;
+        }
+        Ok(None)
+    }
+
+    /// Forget everything that wouldn't count as a duplicate any more, so the index doesn't grow
+    /// forever with contents that never turn up again. Returns how many entries were removed.
+    pub fn prune<T: StagingLocation + ?Sized>(&self, location: &T, window: chrono::Duration) -> Result<usize, Error> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut pruned = 0;
+        for entry in entries {
+            let path = entry?.path();
+            // Anything we can't read, including leftovers from an interrupted write, is no use.
+            let current = fs::read(&path)
+                .ok()
+                .and_then(|contents| serde_json::from_slice::<IndexEntry>(&contents).ok())
+                .is_some_and(|entry| entry.is_current(location, window));
+            if !current {
+                trace!("Pruning {:?} from the staging index", &path);
+                fs::remove_file(&path)?;
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+}
+
 /// The contract of StageableLocation is a directory with a bunch of flat files under it. Doing
 /// things other than this will probably panic implementors.
 pub trait StagingLocation: Debug + Sync + Send {
@@ -191,32 +513,176 @@ pub trait StagingLocation: Debug + Sync + Send {
 
     fn read_dir(&self) -> Result<fs::ReadDir, io::Error>;
 
-    // TODO(richo) iterator
-    fn staged_files(&self) -> Result<Vec<(StagedFile, UploadDescriptor)>, Error> {
-        let mut out = vec![];
-        for entry in self.read_dir().context("Reading staged files")? {
-            // Find manifests and work backward
-            let entry = entry?;
-            trace!("Looking at {:?}", entry.path());
-            if !is_manifest(&entry.path()) {
-                continue;
+    /// How many bytes we can still fit in this location.
+    fn available_space(&self) -> Result<u64, io::Error> {
+        available_space(&self.relative_path(Path::new("")))
+    }
+
+    /// The index of the contents we've staged here.
+    fn hash_index(&self) -> HashIndex {
+        HashIndex {
+            dir: self.relative_path(Path::new(INDEX_DIR)),
+        }
+    }
+
+    /// Iterate over what's staged here.
+    ///
+    /// Anything that isn't part of a complete staged file, eg because we lost power partway
+    /// through staging it or its manifest is garbage, is moved into the quarantine directory as
+    /// we come across it and turns up as a `Quarantined` error, so that one bad file doesn't stop
+    /// the rest from being uploaded.
+    fn staged_files(&self) -> Result<StagedFiles, Error> {
+        Ok(StagedFiles {
+            entries: self.read_dir().context("Reading staged files")?,
+            quarantine: self.relative_path(Path::new(QUARANTINE_DIR)),
+        })
+    }
+}
+
+/// Something in staging that we couldn't make sense of, and moved out of the way.
+#[derive(Fail, Debug)]
+#[fail(display = "Quarantined {:?}: {}", path, reason)]
+pub struct Quarantined {
+    /// Where it was moved to.
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// An iterator over the files in a staging location, see `StagingLocation::staged_files`.
+#[derive(Debug)]
+pub struct StagedFiles {
+    entries: fs::ReadDir,
+    quarantine: PathBuf,
+}
+
+impl StagedFiles {
+    /// Move `path` into quarantine. Anything already there with the same name is kept, and
+    /// this gets a number added to its name instead.
+    fn quarantine(&self, path: &Path, reason: String) -> Result<Quarantined, io::Error> {
+        fs::create_dir_all(&self.quarantine)?;
+        let name = path.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Can't quarantine {:?}", path)))?;
+        let mut n = 1;
+        let target = loop {
+            let target = self.quarantine.join(numbered(name, n));
+            // Unlike a rename, linking never replaces what's already there.
+            match fs::hard_link(path, &target) {
+                Ok(()) => break target,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e),
             }
-            let manifest_path = entry.path();
-            let content_path = content_path_from_manifest(&manifest_path);
+        };
+        warn!("Quarantining {:?} to {:?}: {}", path, &target, &reason);
+        fs::remove_file(path)?;
+        Ok(Quarantined {
+            path: target,
+            reason,
+        })
+    }
 
-            let manifest = File::open(&manifest_path)
-                .context("Reading manifest")?;
+    fn check(&self, path: PathBuf) -> Result<Option<(StagedFile, UploadDescriptor)>, Error> {
+        trace!("Looking at {:?}", &path);
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            // We already quarantined it along with its manifest.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        if is_manifest(&path) {
+            return self.check_manifest(path).map(Some);
+        }
+        // Content and sidecars are dealt with along with their manifest.
+        if has_manifest(&path) {
+            return Ok(None);
+        }
+        Err(self.quarantine(&path, "No manifest".into())?.into())
+    }
+
+    fn check_manifest(&self, manifest_path: PathBuf) -> Result<(StagedFile, UploadDescriptor), Error> {
+        let staged = StagedFile {
+            content_path: content_path_from_manifest(&manifest_path),
+            manifest_path,
+        };
+        let manifest = File::open(&staged.manifest_path)
+            .map_err(Error::from)
+            .and_then(|manifest| Ok(serde_json::from_reader::<_, UploadDescriptor>(manifest)?));
+        let reason = match manifest {
+            Err(e) => format!("Couldn't read manifest: {}", e),
+            Ok(manifest) => match fs::metadata(&staged.content_path) {
+                Ok(ref metadata) if metadata.len() == manifest.size => return Ok((staged, manifest)),
+                Ok(metadata) => format!("Content is {} bytes, manifest says {}", metadata.len(), manifest.size),
+                Err(e) => format!("Couldn't find content: {}", e),
+            },
+        };
 
-            let manifest: UploadDescriptor = serde_json::from_reader(manifest)?;
-            out.push((StagedFile {
-                content_path,
-                manifest_path,
-            }, manifest));
+        if staged.content_path.exists() {
+            for sidecar in staged.sidecars() {
+                self.quarantine(&sidecar, reason.clone())?;
+            }
+            self.quarantine(&staged.content_path, reason.clone())?;
         }
-        Ok(out)
+        Err(self.quarantine(&staged.manifest_path, reason)?.into())
     }
 }
 
+impl Iterator for StagedFiles {
+    type Item = Result<(StagedFile, UploadDescriptor), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let path = match self.entries.next()? {
+                Ok(entry) => entry.path(),
+                Err(e) => return Some(Err(e.into())),
+            };
+            match self.check(path) {
+                Ok(None) => continue,
+                Ok(Some(file)) => return Some(Ok(file)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// `name` with `n` added before all of its extensions, so that a file and its manifest are still
+/// numbered alike. The first of anything isn't numbered.
+fn numbered(name: &str, n: usize) -> String {
+    // A leading dot is part of the name, not an extension.
+    match (n, name.char_indices().skip(1).find(|(_, c)| *c == '.').map(|(i, _)| i)) {
+        (1, _) => name.to_string(),
+        (_, Some(dot)) => format!("{}-{}{}", &name[..dot], n, &name[dot..]),
+        (_, None) => format!("{}-{}", name, n),
+    }
+}
+
+/// Does `path` belong to a manifest, either as its content or one of the content's sidecars?
+fn has_manifest(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    name.char_indices()
+        .filter(|(_, c)| *c == '.')
+        .map(|(i, _)| &name[..i])
+        .chain(std::iter::once(name))
+        .any(|content| path.with_file_name(format!("{}.manifest", content)).exists())
+}
+
+/// The space available to unprivileged users on the filesystem containing `path`.
+fn available_space(path: &Path) -> Result<u64, io::Error> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The widths of these vary between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
 impl<T: StagingLocation> StagingLocation for Box<T> {
     fn relative_path(&self, path: &Path) -> PathBuf {
         (**self).relative_path(path)
@@ -227,6 +693,28 @@ impl<T: StagingLocation> StagingLocation for Box<T> {
     }
 }
 
+/// The files kept alongside a staged file, which are cleaned up along with it.
+///
+/// Every sort is listed here so that they can be found by name, rather than by listing staging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sidecar {
+    /// Which adaptors have finished with the file, see `UploadState`.
+    UploadState,
+    /// A dropbox upload session that can be resumed.
+    DropboxSession,
+}
+
+impl Sidecar {
+    const ALL: &'static [Sidecar] = &[Sidecar::UploadState, Sidecar::DropboxSession];
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Sidecar::UploadState => "uploads",
+            Sidecar::DropboxSession => "dropbox-session",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct StagedFile {
     pub content_path: PathBuf,
@@ -237,14 +725,83 @@ impl StagedFile {
     pub fn delete(self) -> Result<(), io::Error> {
         info!("removing {:?}", &self.manifest_path);
         fs::remove_file(&self.manifest_path)?;
+        for sidecar in self.sidecars() {
+            info!("removing {:?}", &sidecar);
+            fs::remove_file(&sidecar)?;
+        }
         info!("removing {:?}", &self.content_path);
         fs::remove_file(&self.content_path)?;
         Ok(())
     }
 
+    /// A path alongside this file where adaptors can keep state between attempts, eg an upload
+    /// session that can be resumed. It's cleaned up along with the file.
+    pub fn sidecar_path(&self, sidecar: Sidecar) -> PathBuf {
+        let mut name = self.content_path.file_name().expect("staged file has no name").to_os_string();
+        name.push(".");
+        name.push(sidecar.suffix());
+        self.content_path.with_file_name(name)
+    }
+
+    /// The sidecars this file actually has.
+    fn sidecars(&self) -> Vec<PathBuf> {
+        Sidecar::ALL
+            .iter()
+            .map(|sidecar| self.sidecar_path(*sidecar))
+            .filter(|path| path.exists())
+            .collect()
+    }
+
     pub fn content_handle(&self) -> Result<File, io::Error> {
         File::open(&self.content_path)
     }
+
+    fn upload_state_path(&self) -> PathBuf {
+        self.sidecar_path(Sidecar::UploadState)
+    }
+
+    /// Load the record of which adaptors have already finished with this file.
+    pub fn upload_state(&self) -> UploadState {
+        let path = self.upload_state_path();
+        match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                warn!("Couldn't parse upload state at {:?}, starting afresh: {:?}", &path, e);
+                Default::default()
+            }),
+            Err(_) => Default::default(),
+        }
+    }
+
+    /// Replace the record of which adaptors have finished with this file in one go, since losing
+    /// it would mean uploading the file everywhere again.
+    pub fn save_upload_state(&self, state: &UploadState) -> Result<(), Error> {
+        let path = self.upload_state_path();
+        let partial = partial_path(&path);
+        let mut file = File::create(&partial).context("Opening upload state")?;
+        serde_json::to_writer(&mut file, state)?;
+        file.sync_all().context("Syncing upload state")?;
+        fs::rename(&partial, &path)?;
+        Ok(())
+    }
+}
+
+/// Which adaptors have finished with a staged file, and when, keyed by `StorageAdaptor::id`.
+///
+/// This lives alongside the staged file so that when some adaptors fail, the next run only
+/// retries those rather than uploading everything all over again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UploadState {
+    completed: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl UploadState {
+    pub fn is_complete(&self, adaptor: &str) -> bool {
+        self.completed.contains_key(adaptor)
+    }
+
+    pub fn complete(&mut self, adaptor: &str) {
+        self.completed.insert(adaptor.to_string(), Utc::now());
+    }
 }
 
 #[derive(Fail, Debug)]
@@ -278,6 +835,11 @@ impl Drop for StagingDevice {
 pub struct Stager<T: StagingLocation> {
     location: T,
     destructive: bool,
+    verify: bool,
+    dedupe_window: chrono::Duration,
+    templates: PathTemplates,
+    clocks: DeviceClocks,
+    router: Router,
 }
 
 impl<T: StagingLocation> Stager<T> {
@@ -285,6 +847,11 @@ impl<T: StagingLocation> Stager<T> {
         Stager {
             location,
             destructive: true,
+            verify: false,
+            dedupe_window: chrono::Duration::days(DEFAULT_DEDUPE_DAYS.into()),
+            templates: Default::default(),
+            clocks: Default::default(),
+            router: Default::default(),
         }
     }
 
@@ -292,25 +859,120 @@ impl<T: StagingLocation> Stager<T> {
         Stager {
             location,
             destructive: false,
+            verify: false,
+            dedupe_window: chrono::Duration::days(DEFAULT_DEDUPE_DAYS.into()),
+            templates: Default::default(),
+            clocks: Default::default(),
+            router: Default::default(),
         }
     }
 
-    pub fn stage<F>(&self, mut file: F, name: &str) -> Result<(), Error>
+    /// Read each file back out of staging and check it against what we read from the device,
+    /// before we go deleting anything.
+    pub fn verifying(mut self) -> Stager<T> {
+        self.verify = true;
+        self
+    }
+
+    /// Skip files whose contents were uploaded less than `window` ago, rather than the default of
+    /// `DEFAULT_DEDUPE_DAYS`. Files whose contents are still staged are always skipped.
+    pub fn deduplicating_within(mut self, window: chrono::Duration) -> Stager<T> {
+        self.dedupe_window = window;
+        self
+    }
+
+    /// Name staged files after where they'll end up under these templates, rather than the built
+    /// in layout.
+    pub fn with_path_templates(mut self, templates: PathTemplates) -> Stager<T> {
+        self.templates = templates;
+        self
+    }
+
+    /// Work out when files were captured using these clocks, rather than assuming every device is
+    /// on our local time.
+    pub fn with_device_clocks(mut self, clocks: DeviceClocks) -> Stager<T> {
+        self.clocks = clocks;
+        self
+    }
+
+    /// Only treat files as duplicates if `router` sends them to the same places as what we
+    /// already have, rather than assuming everything goes everywhere.
+    pub fn with_router(mut self, router: Router) -> Stager<T> {
+        self.router = router;
+        self
+    }
+
+    /// Forget contents in the staging index that are no longer staged or recently uploaded,
+    /// returning how many entries were removed.
+    pub fn prune_index(&self) -> Result<usize, Error> {
+        self.location.hash_index().prune(&self.location, self.dedupe_window)
+    }
+
+    /// Bring manifests written by older versions up to date, returning how many were rewritten.
+    ///
+    /// Only the manifests change, staged files keep the names they were staged under.
+    pub fn migrate_manifests(&self) -> Result<usize, Error> {
+        let mut migrated = 0;
+        for entry in self.location.read_dir().context("Reading staged files")? {
+            let path = entry?.path();
+            if !is_manifest(&path) {
+                continue;
+            }
+            // Anything we can't read is left for `staged_files` to quarantine.
+            let mut desc = match fs::read(&path).map_err(Error::from)
+                .and_then(|contents| Ok(serde_json::from_slice::<UploadDescriptor>(&contents)?)) {
+                Ok(desc) => desc,
+                Err(_) => continue,
+            };
+            if desc.version >= MANIFEST_VERSION {
+                continue;
+            }
+            if let RemotePathDescriptor::DateTime { capture_time, .. } = &mut desc.path {
+                // Whatever offset this has is ours rather than the device's, the wall clock time is
+                // what the device actually read.
+                let reading = capture_time.naive_local();
+                *capture_time = self.clocks.for_device(&desc.device_name).capture_time(reading);
+            }
+            desc.version = MANIFEST_VERSION;
+            info!("Migrating {:?} to manifest version {}", &path, MANIFEST_VERSION);
+            write_manifest(&path, &desc)?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+
+    /// Stage `file`, unless we already have its contents. Either way, it's safe to remove from the
+    /// device afterwards.
+    pub fn stage<F>(&self, mut file: F, name: &str) -> Result<Staged, Error>
         where F: StorableFile
     {
-        stage_file(&mut file, &self.location, name)?;
+        let staged = stage_file(&mut file, self, name)?;
 
         if self.destructive {
             file.delete()?;
         }
 
-        Ok(())
+        Ok(staged)
     }
 
     pub fn staging_location(&self) -> &T {
         &self.location
     }
 
+    /// This stager with its location behind a reference, for staging from devices that are behind
+    /// trait objects.
+    pub fn borrowed(&self) -> Stager<&dyn StagingLocation> {
+        Stager {
+            location: &self.location,
+            destructive: self.destructive,
+            verify: self.verify,
+            dedupe_window: self.dedupe_window,
+            templates: self.templates.clone(),
+            clocks: self.clocks.clone(),
+            router: self.router.clone(),
+        }
+    }
+
     #[cfg(test)]
     pub fn into_inner(self) -> T {
         self.location
@@ -351,12 +1013,84 @@ pub trait StageFromDevice: Sized {
     }
 }
 
-#[derive(PartialEq, Debug, Serialize, Deserialize)]
+/// A device along with the files on it that are waiting to be staged, so that they can be staged
+/// one at a time in whatever order a `StagingPlan` decides on.
+pub struct DeviceFiles<D: StageFromDevice> {
+    name: String,
+    device: D,
+    files: Vec<Option<D::FileType>>,
+}
+
+impl<D: StageFromDevice> DeviceFiles<D> {
+    pub fn list(name: &str, device: D) -> Result<DeviceFiles<D>, Error> {
+        let files = device.files()?.into_iter().map(Some).collect();
+        Ok(DeviceFiles {
+            name: name.to_string(),
+            device,
+            files,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Describe every file on the device that hasn't been staged yet, along with its index.
+    pub fn descriptors(&self, clocks: &DeviceClocks) -> Result<Vec<(usize, UploadDescriptor)>, Error> {
+        let clock = clocks.for_device(&self.name);
+        self.files
+            .iter()
+            .enumerate()
+            .filter_map(|(index, file)| file.as_ref().map(|file| (index, file)))
+            .map(|(index, file)| Ok((index, file.descriptor(&self.name, clock)?)))
+            .collect()
+    }
+
+    /// Stage the file at `index`, which can only be done once.
+    pub fn stage<T: StagingLocation>(&mut self, index: usize, stager: &Stager<T>) -> Result<Staged, Error> {
+        match self.files.get_mut(index).and_then(Option::take) {
+            Some(file) => stager.stage(file, &self.name),
+            None => bail!("{} has no file {} to stage", &self.name, index),
+        }
+    }
+
+    pub fn cleanup(&self) -> Result<(), Error> {
+        self.device.cleanup()
+    }
+}
+
+impl<D: StageFromDevice> fmt::Debug for DeviceFiles<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceFiles")
+            .field("name", &self.name)
+            .field("files", &self.files.len())
+            .finish()
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct UploadDescriptor {
+    /// Which version of the manifest format this was written with. Manifests from before there
+    /// were versions are 0.
+    #[serde(default)]
+    pub(crate) version: u32,
     pub(crate) path: RemotePathDescriptor,
     pub device_name: String,
     pub content_hash: [u8; 32],
     pub size: u64,
+    /// What the device called the file, if it names them.
+    #[serde(default)]
+    pub original_name: Option<String>,
+    /// Tells this file apart from others from the same device captured in the same second.
+    #[serde(default)]
+    pub discriminator: Option<String>,
+    /// The template this was staged under, which also decides where it goes unless the backend
+    /// has a template of its own.
+    #[serde(default)]
+    pub(crate) template: Option<PathTemplate>,
+    /// The template of the backend this is being uploaded to, see `with_backend_template`.
+    #[serde(skip)]
+    backend_template: Option<PathTemplate>,
 }
 
 #[derive(Debug)]
@@ -365,26 +1099,38 @@ pub struct UploadDescriptorBuilder {
 }
 
 impl UploadDescriptorBuilder {
-    pub fn date_time(self, capture_time: DateTime<Local>, extension: String) -> UploadDescriptor {
+    pub fn date_time<T>(self, capture_time: T, extension: String) -> UploadDescriptor
+        where T: Into<DateTime<FixedOffset>>
+    {
         UploadDescriptor {
+            version: MANIFEST_VERSION,
             path: RemotePathDescriptor::DateTime {
-                capture_time,
+                capture_time: capture_time.into(),
                 extension,
             },
             content_hash: Default::default(),
             device_name: self.device_name,
             size: 0,
+            original_name: None,
+            discriminator: None,
+            template: None,
+            backend_template: None,
         }
     }
 
     pub fn manual_file(self, path: PathBuf) -> UploadDescriptor {
         UploadDescriptor {
+            version: MANIFEST_VERSION,
             path: RemotePathDescriptor::SpecifiedPath {
                 path,
             },
             content_hash: Default::default(),
             device_name: self.device_name,
             size: 0,
+            original_name: None,
+            discriminator: None,
+            template: None,
+            backend_template: None,
         }
     }
 }
@@ -397,14 +1143,19 @@ impl UploadDescriptor {
     }
 
     pub fn staging_name(&self) -> String {
+        if let Some(template) = &self.template {
+            if let RemotePathDescriptor::DateTime { .. } = self.path {
+                return template.staging_name(self);
+            }
+        }
         match &self.path {
             RemotePathDescriptor::DateTime {
                 capture_time, extension
             } => {
-                format!(
+                self.discriminated(format!(
                     "{}-{}.{}",
                     &self.device_name, capture_time, extension
-                )
+                ))
             },
             RemotePathDescriptor::SpecifiedPath {
                 path
@@ -422,12 +1173,64 @@ impl UploadDescriptor {
         format!("{}.manifest", self.staging_name())
     }
 
+    /// Where this file goes, under whichever template applies to it.
     pub fn remote_path(&self) -> PathBuf {
+        match self.backend_template.as_ref().or(self.template.as_ref()) {
+            Some(template) => template.render(self),
+            None => self.default_remote_path(),
+        }
+    }
+
+    /// A copy of this descriptor for uploading to a backend with its own template.
+    pub fn with_backend_template(&self, template: Option<&PathTemplate>) -> UploadDescriptor {
+        let mut desc = self.clone();
+        desc.backend_template = template.cloned();
+        desc
+    }
+
+    /// `name` with our discriminator added before its extension, if we have one.
+    pub(crate) fn discriminated(&self, name: String) -> String {
+        let discriminator = match &self.discriminator {
+            Some(discriminator) => discriminator,
+            None => return name,
+        };
+        let start = name.rfind('/').map_or(0, |i| i + 1);
+        match name[start..].rfind('.') {
+            Some(dot) => format!("{}-{}{}", &name[..start + dot], discriminator, &name[start + dot..]),
+            None => format!("{}-{}", name, discriminator),
+        }
+    }
+
+    /// A copy of this descriptor that goes somewhere else, for when something that isn't this file
+    /// is already where it was going. It's the same copy every time, so that later runs find what
+    /// they put there. None if it has nowhere else to go, like files staged by hand.
+    pub fn avoiding_conflicts(&self) -> Option<UploadDescriptor> {
+        let hash = hex::encode(&self.content_hash[..4]);
+        let mut desc = self.clone();
+        desc.discriminator = Some(match &self.discriminator {
+            Some(discriminator) => format!("{}-{}", discriminator, hash),
+            None => hash,
+        });
+        if desc.remote_path() == self.remote_path() {
+            return None;
+        }
+        Some(desc)
+    }
+
+    pub fn extension(&self) -> Option<&str> {
+        match &self.path {
+            RemotePathDescriptor::DateTime { extension, .. } => Some(extension),
+            RemotePathDescriptor::SpecifiedPath { path } => path.extension().and_then(|e| e.to_str()),
+        }
+    }
+
+    /// Where this file goes when there's no template.
+    pub(crate) fn default_remote_path(&self) -> PathBuf {
         match &self.path {
             RemotePathDescriptor::DateTime {
                 capture_time, extension,
             } => {
-                format!(
+                self.discriminated(format!(
                     "/{year:04}/{month:02}/{day:02}/{device}/{filename}.{extension}",
                     year = capture_time.year(),
                     month = capture_time.month(),
@@ -435,7 +1238,7 @@ impl UploadDescriptor {
                     device= &self.device_name,
                     filename = capture_time.format("%H-%M-%S"),
                     extension = extension,
-                ).into()
+                )).into()
             },
             RemotePathDescriptor::SpecifiedPath {
                 path
@@ -452,13 +1255,18 @@ impl UploadDescriptor {
     #[cfg(test)]
     pub fn test_descriptor() -> Self {
         UploadDescriptor {
+            version: MANIFEST_VERSION,
             path: RemotePathDescriptor::DateTime {
-                capture_time: Local.ymd(2018, 8, 26).and_hms(14, 30, 0),
+                capture_time: Local.ymd(2018, 8, 26).and_hms(14, 30, 0).into(),
                 extension: "mp4".into(),
             },
             device_name: "test-device".into(),
             content_hash: Default::default(),
             size: 1024,
+            original_name: None,
+            discriminator: None,
+            template: None,
+            backend_template: None,
         }
     }
 }
@@ -487,19 +1295,26 @@ fn content_path_from_manifest(manifest: &Path) -> PathBuf {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ClockZone;
+    use crate::config::RouteConfig;
 
     #[test]
     fn test_formats_correctly() {
         let datetime = Local.ymd(2017, 11, 22).and_hms(15, 36, 10);
 
         let upload = UploadDescriptor {
+            version: MANIFEST_VERSION,
             path: RemotePathDescriptor::DateTime {
-                capture_time: datetime,
+                capture_time: datetime.into(),
                 extension: "mp4".to_string(),
             },
             device_name: "test".to_string(),
             content_hash: [0; 32],
             size: 0,
+            original_name: None,
+            discriminator: None,
+            template: None,
+            backend_template: None,
         };
 
         assert_eq!(
@@ -513,13 +1328,18 @@ mod tests {
         let datetime = Local.ymd(2001, 1, 2).and_hms(3, 4, 5);
 
         let upload = UploadDescriptor {
+            version: MANIFEST_VERSION,
             path: RemotePathDescriptor::DateTime {
-                capture_time: datetime,
+                capture_time: datetime.into(),
                 extension: "mp4".to_string(),
             },
             device_name: "test".to_string(),
             content_hash: [0; 32],
             size: 0,
+            original_name: None,
+            discriminator: None,
+            template: None,
+            backend_template: None,
         };
 
         assert_eq!(
@@ -533,13 +1353,18 @@ mod tests {
         let datetime = Local.ymd(2001, 1, 2).and_hms(3, 4, 5);
 
         let original = UploadDescriptor {
+            version: MANIFEST_VERSION,
             path: RemotePathDescriptor::DateTime {
-                capture_time: datetime,
+                capture_time: datetime.into(),
                 extension: "mp4".to_string(),
             },
             device_name: "test".to_string(),
             content_hash: [0; 32],
             size: 0,
+            original_name: None,
+            discriminator: None,
+            template: None,
+            backend_template: None,
         };
 
         let serialized = serde_json::to_string(&original).expect("Couldn't serialize test vector");
@@ -598,6 +1423,461 @@ mod tests {
         let manifest = Path::new("butts.manifes");
         assert_eq!(false, is_manifest(&manifest));
     }
+
+    #[test]
+    fn test_upload_state_roundtrips() {
+        let data = crate::test_helpers::staged_data(1).expect("Couldn't create staging data");
+        let (staged, _) = crate::test_helpers::staged_files(&data).pop().unwrap();
+        assert!(!staged.upload_state().is_complete("dropbox"));
+
+        let mut state = staged.upload_state();
+        state.complete("dropbox");
+        staged.save_upload_state(&state).expect("Couldn't save upload state");
+        assert!(!partial_path(&staged.upload_state_path()).exists());
+
+        let state = staged.upload_state();
+        assert!(state.is_complete("dropbox"));
+        assert!(!state.is_complete("vimeo"));
+    }
+
+    #[test]
+    fn test_available_space() {
+        let dir = crate::test_helpers::tempdir();
+        assert!(dir.available_space().expect("Couldn't stat tempdir") > 0);
+    }
+
+    #[test]
+    fn test_stages_device_files_by_index() {
+        let stager = crate::test_helpers::temp_stager();
+        let mut files = DeviceFiles::list("dummy", crate::test_helpers::DummyDataDevice::new(2))
+            .expect("Couldn't list files");
+        assert_eq!(2, files.descriptors(&Default::default()).unwrap().len());
+
+        files.stage(1, &stager).expect("Couldn't stage file");
+        assert!(files.stage(1, &stager).is_err());
+        let remaining: Vec<_> = files.descriptors(&Default::default()).unwrap().into_iter().map(|(i, _)| i).collect();
+        assert_eq!(vec![0], remaining);
+        assert_eq!(1, crate::test_helpers::staged_files(stager.staging_location()).len());
+    }
+
+    #[test]
+    fn test_staging_leaves_no_partial_files() {
+        let data = crate::test_helpers::staged_data(2).expect("Couldn't create staging data");
+        for entry in fs::read_dir(&data).unwrap() {
+            let path = entry.unwrap().path();
+            assert!(!path.to_str().unwrap().ends_with(PARTIAL_SUFFIX), "{:?} left behind", path);
+        }
+        assert_eq!(4, crate::test_helpers::count_files(&data));
+    }
+
+    #[test]
+    fn test_quarantines_orphans() {
+        let data = crate::test_helpers::staged_data(3).expect("Couldn't create staging data");
+        let mut staged = crate::test_helpers::staged_files(&data);
+        let (truncated, _) = staged.pop().unwrap();
+        let (missing, _) = staged.pop().unwrap();
+        let (intact, _) = staged.pop().unwrap();
+
+        fs::write(&truncated.content_path, b"short").unwrap();
+        fs::remove_file(&missing.content_path).unwrap();
+        fs::write(intact.sidecar_path(Sidecar::UploadState), b"{}").unwrap();
+        fs::write(data.path().join("stray.mp4"), b"no manifest").unwrap();
+        fs::write(data.path().join("halfway.mp4.partial"), b"interrupted").unwrap();
+
+        let (staged, quarantined): (Vec<_>, Vec<_>) =
+            data.staged_files().expect("Couldn't list staged files").partition(|f| f.is_ok());
+        assert_eq!(1, staged.len());
+        assert_eq!(intact.content_path, staged[0].as_ref().unwrap().0.content_path);
+        assert!(intact.sidecar_path(Sidecar::UploadState).exists());
+        assert_eq!(4, quarantined.len());
+        for err in quarantined {
+            assert!(err.unwrap_err().downcast::<Quarantined>().is_ok());
+        }
+
+        let quarantine = data.path().join(QUARANTINE_DIR);
+        assert!(quarantine.join("stray.mp4").exists());
+        assert!(quarantine.join("halfway.mp4.partial").exists());
+        assert!(quarantine.join(truncated.content_path.file_name().unwrap()).exists());
+        assert!(quarantine.join(truncated.manifest_path.file_name().unwrap()).exists());
+        assert!(quarantine.join(missing.manifest_path.file_name().unwrap()).exists());
+    }
+
+    #[test]
+    fn test_quarantine_never_overwrites() {
+        let data = crate::test_helpers::staged_data(0).expect("Couldn't create staging data");
+        let quarantine = data.path().join(QUARANTINE_DIR);
+        for contents in &[b"first", b"later"] {
+            fs::write(data.path().join("stray.mp4"), contents).unwrap();
+            let quarantined: Vec<_> = data.staged_files().expect("Couldn't list staged files").collect();
+            assert_eq!(1, quarantined.len());
+        }
+        assert_eq!(b"first", &fs::read(quarantine.join("stray.mp4")).unwrap()[..]);
+        assert_eq!(b"later", &fs::read(quarantine.join("stray-2.mp4")).unwrap()[..]);
+        assert_eq!("halfway-3.mp4.partial", numbered("halfway.mp4.partial", 3));
+        assert_eq!(".hidden-2", numbered(".hidden", 2));
+    }
+
+    #[test]
+    fn test_quarantines_corrupt_manifests() {
+        let data = crate::test_helpers::staged_data(2).expect("Couldn't create staging data");
+        let mut staged = crate::test_helpers::staged_files(&data);
+        let (corrupt, _) = staged.pop().unwrap();
+        fs::write(corrupt.sidecar_path(Sidecar::UploadState), b"{}").unwrap();
+        fs::write(&corrupt.manifest_path, b"{\"device_name\": ").unwrap();
+
+        let mut good = 0;
+        let mut bad = vec![];
+        for file in data.staged_files().expect("Couldn't list staged files") {
+            match file {
+                Ok(_) => good += 1,
+                Err(e) => bad.push(e.downcast::<Quarantined>().expect("Unexpected error")),
+            }
+        }
+        assert_eq!(1, good);
+        assert_eq!(1, bad.len());
+        assert!(bad[0].reason.starts_with("Couldn't read manifest"), "{}", bad[0].reason);
+        assert_eq!(data.path().join(QUARANTINE_DIR).join(corrupt.manifest_path.file_name().unwrap()), bad[0].path);
+
+        assert!(!corrupt.content_path.exists());
+        assert!(!corrupt.sidecar_path(Sidecar::UploadState).exists());
+        assert_eq!(1, crate::test_helpers::staged_files(&data).len());
+        assert_eq!(3, fs::read_dir(data.path().join(QUARANTINE_DIR)).unwrap().count());
+    }
+
+    /// A file that might be lying about how big it is.
+    struct ClaimedFile<R = io::Cursor<Vec<u8>>> {
+        data: R,
+        claimed_size: u64,
+        deleted: bool,
+    }
+
+    impl<R: Read> DateTimeUploadable for &mut ClaimedFile<R> {
+        type Reader = R;
+
+        fn extension(&self) -> &str {
+            "mp4"
+        }
+
+        fn capture_datetime(&self) -> Result<NaiveDateTime, chrono::ParseError> {
+            Ok(NaiveDate::from_ymd(2019, 6, 1).and_hms(9, 30, 0))
+        }
+
+        fn reader(&mut self) -> &mut Self::Reader {
+            &mut self.data
+        }
+
+        fn delete(&mut self) -> Result<(), Error> {
+            self.deleted = true;
+            Ok(())
+        }
+
+        fn size(&self) -> Result<u64, Error> {
+            Ok(self.claimed_size)
+        }
+    }
+
+    #[test]
+    fn test_verified_staging_deletes_source() {
+        let stager = crate::test_helpers::temp_stager().verifying();
+        let mut file = ClaimedFile { data: io::Cursor::new(vec![0x42; 1024]), claimed_size: 1024, deleted: false };
+        stager.stage(&mut file, "gopro").expect("Couldn't stage file");
+        assert!(file.deleted);
+        assert_eq!(1, crate::test_helpers::staged_files(stager.staging_location()).len());
+    }
+
+    #[test]
+    fn test_size_mismatch_leaves_source() {
+        let stager = crate::test_helpers::temp_stager();
+        let mut file = ClaimedFile { data: io::Cursor::new(vec![0x42; 1000]), claimed_size: 1024, deleted: false };
+        let err = stager.stage(&mut file, "gopro").unwrap_err();
+        match err.downcast_ref::<StagingError>() {
+            Some(StagingError::SizeMismatch { expected: 1024, actual: 1000, .. }) => {}
+            other => panic!("Unexpected error: {:?}", other),
+        }
+        assert!(!file.deleted);
+        assert_eq!(0, fs::read_dir(stager.staging_location()).unwrap().count());
+    }
+
+    /// Reads like a file on a device, but once it's all been read it scribbles over whatever's
+    /// been staged in `staging`, like a failing disk would.
+    struct CorruptingReader {
+        data: io::Cursor<Vec<u8>>,
+        staging: PathBuf,
+    }
+
+    impl Read for CorruptingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.data.read(buf)?;
+            if read == 0 {
+                for entry in fs::read_dir(&self.staging)? {
+                    let path = entry?.path();
+                    if path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                        let mut contents = fs::read(&path)?;
+                        contents[0] ^= 0xff;
+                        fs::write(&path, contents)?;
+                    }
+                }
+            }
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn test_hash_mismatch_leaves_source() {
+        let stager = crate::test_helpers::temp_stager().verifying();
+        let mut file = ClaimedFile {
+            data: CorruptingReader {
+                data: io::Cursor::new(vec![0x42; 1024]),
+                staging: stager.staging_location().path().to_path_buf(),
+            },
+            claimed_size: 1024,
+            deleted: false,
+        };
+        let err = stager.stage(&mut file, "gopro").unwrap_err();
+        match err.downcast_ref::<StagingError>() {
+            Some(StagingError::HashMismatch(_)) => {}
+            other => panic!("Unexpected error: {:?}", other),
+        }
+        assert!(!file.deleted);
+        assert_eq!(0, fs::read_dir(stager.staging_location()).unwrap().count());
+    }
+
+    fn claimed(byte: u8) -> ClaimedFile {
+        ClaimedFile { data: io::Cursor::new(vec![byte; 1024]), claimed_size: 1024, deleted: false }
+    }
+
+    #[test]
+    fn test_skips_contents_already_staged() {
+        let stager = crate::test_helpers::temp_stager();
+        let mut file = claimed(0x42);
+        assert!(matches!(stager.stage(&mut file, "gopro"), Ok(Staged::New(_))));
+
+        let mut again = claimed(0x42);
+        match stager.stage(&mut again, "other-gopro").expect("Couldn't stage file") {
+            Staged::Duplicate(duplicate) => {
+                assert_eq!(duplicate.desc.device_name, "other-gopro");
+                assert_eq!(duplicate.original.uploaded, None);
+                assert!(duplicate.original.staging_name.starts_with("gopro-"));
+            },
+            Staged::New(_) => panic!("Staged the same contents twice"),
+        }
+        // We already have a copy, so it's safe to remove from the device.
+        assert!(again.deleted);
+        assert_eq!(2, crate::test_helpers::count_files(stager.staging_location()));
+
+        let mut different = claimed(0x41);
+        assert!(matches!(stager.stage(&mut different, "other-gopro"), Ok(Staged::New(_))));
+    }
+
+    #[test]
+    fn test_skips_contents_uploaded_recently() {
+        let stager = crate::test_helpers::temp_stager();
+        stager.stage(&mut claimed(0x42), "gopro").expect("Couldn't stage file");
+        let (staged, desc) = crate::test_helpers::staged_files(stager.staging_location()).pop().unwrap();
+        let index = stager.staging_location().hash_index();
+        index.record_uploaded(&desc.content_hash, &Route::Everywhere).expect("Couldn't update index");
+        staged.delete().expect("Couldn't delete staged file");
+
+        match stager.stage(&mut claimed(0x42), "gopro").expect("Couldn't stage file") {
+            Staged::Duplicate(duplicate) => assert!(duplicate.original.uploaded.is_some()),
+            Staged::New(_) => panic!("Staged contents that were just uploaded"),
+        }
+        assert_eq!(0, crate::test_helpers::count_files(stager.staging_location()));
+
+        // Once the upload is old enough, it's forgotten.
+        let stager = Stager::destructive(stager.into_inner()).deduplicating_within(chrono::Duration::zero());
+        assert!(matches!(stager.stage(&mut claimed(0x42), "gopro"), Ok(Staged::New(_))));
+        assert_eq!(index.get(&desc.content_hash, &Route::Everywhere).unwrap().uploaded, None);
+    }
+
+    #[test]
+    fn test_contents_going_elsewhere_are_not_duplicates() {
+        let route = |devices: &[&str], backends: &[&str]| RouteConfig {
+            devices: Some(devices.iter().map(|d| d.to_string()).collect()),
+            extensions: None,
+            min_size: None,
+            max_size: None,
+            kind: None,
+            backends: backends.iter().map(|b| b.to_string()).collect(),
+        };
+        let stager = crate::test_helpers::temp_stager().with_router(Router::new(vec![
+            route(&["gopro", "other-gopro"], &["dropbox"]),
+            route(&["phone"], &["vimeo"]),
+        ]));
+        assert!(matches!(stager.stage(&mut claimed(0x42), "gopro"), Ok(Staged::New(_))));
+        // Both go to dropbox, which has it already..
+        assert!(matches!(stager.stage(&mut claimed(0x42), "other-gopro"), Ok(Staged::Duplicate(_))));
+        // ..but vimeo doesn't.
+        assert!(matches!(stager.stage(&mut claimed(0x42), "phone"), Ok(Staged::New(_))));
+        assert_eq!(2, crate::test_helpers::staged_files(stager.staging_location()).len());
+    }
+
+    #[test]
+    fn test_prunes_stale_index_entries() {
+        let stager = crate::test_helpers::temp_stager();
+        stager.stage(&mut claimed(0x41), "gopro").expect("Couldn't stage file");
+        stager.stage(&mut claimed(0x42), "gopro").expect("Couldn't stage file");
+        stager.stage(&mut claimed(0x43), "gopro").expect("Couldn't stage file");
+        let index = stager.staging_location().hash_index();
+        let mut staged = crate::test_helpers::staged_files(stager.staging_location());
+        staged.sort_by_key(|(_, desc)| desc.content_hash);
+        let mut staged = staged.into_iter();
+
+        // Still staged, so it stays.
+        let (_, kept) = staged.next().unwrap();
+        // Uploaded, which is only remembered for the window.
+        let (uploaded, uploaded_desc) = staged.next().unwrap();
+        index.record_uploaded(&uploaded_desc.content_hash, &Route::Everywhere).expect("Couldn't update index");
+        uploaded.delete().expect("Couldn't delete staged file");
+        // Gone without being uploaded.
+        let (removed, removed_desc) = staged.next().unwrap();
+        removed.delete().expect("Couldn't delete staged file");
+
+        assert_eq!(1, stager.prune_index().expect("Couldn't prune index"));
+        assert!(index.get(&kept.content_hash, &Route::Everywhere).is_some());
+        assert!(index.get(&uploaded_desc.content_hash, &Route::Everywhere).is_some());
+        assert!(index.get(&removed_desc.content_hash, &Route::Everywhere).is_none());
+
+        let stager = Stager::destructive(stager.into_inner()).deduplicating_within(chrono::Duration::zero());
+        assert_eq!(1, stager.prune_index().expect("Couldn't prune index"));
+        assert!(index.get(&uploaded_desc.content_hash, &Route::Everywhere).is_none());
+        assert!(index.get(&kept.content_hash, &Route::Everywhere).is_some());
+    }
+
+    #[test]
+    fn test_restages_contents_that_went_missing() {
+        let stager = crate::test_helpers::temp_stager();
+        stager.stage(&mut claimed(0x42), "gopro").expect("Couldn't stage file");
+        let (staged, _) = crate::test_helpers::staged_files(stager.staging_location()).pop().unwrap();
+        staged.delete().expect("Couldn't delete staged file");
+
+        assert!(matches!(stager.stage(&mut claimed(0x42), "gopro"), Ok(Staged::New(_))));
+        assert_eq!(1, crate::test_helpers::staged_files(stager.staging_location()).len());
+    }
+
+    #[test]
+    fn test_staging_names_follow_templates() {
+        let template = PathTemplate::parse("/{device}/{year}/{filename}-{hash:4}.{extension}").unwrap();
+        let mut devices = std::collections::BTreeMap::new();
+        devices.insert("gopro".to_string(), template.clone());
+        let stager = crate::test_helpers::temp_stager()
+            .with_path_templates(PathTemplates::new(None, devices));
+
+        stager.stage(&mut claimed(0x42), "gopro").expect("Couldn't stage file");
+        stager.stage(&mut claimed(0x41), "other-gopro").expect("Couldn't stage file");
+        let mut staged = crate::test_helpers::staged_files(stager.staging_location());
+        staged.sort_by(|a, b| a.1.device_name.cmp(&b.1.device_name));
+
+        let (file, desc) = &staged[0];
+        assert_eq!(desc.template, Some(template));
+        let hash = &hex::encode(desc.content_hash)[..4];
+        assert_eq!(desc.remote_path(), PathBuf::from(format!("/gopro/2019/09-30-00-{}.mp4", hash)));
+        assert_eq!(file.content_path.file_name().unwrap().to_str().unwrap(), format!("gopro-2019-09-30-00-{}.mp4", hash));
+
+        // Devices without a template keep the built in layout.
+        let (file, desc) = &staged[1];
+        assert_eq!(desc.template, None);
+        assert!(file.content_path.file_name().unwrap().to_str().unwrap().starts_with("other-gopro-"));
+    }
+
+    #[test]
+    fn test_same_second_files_dont_collide() {
+        let stager = crate::test_helpers::temp_stager();
+        let empty = || ClaimedFile { data: io::Cursor::new(vec![]), claimed_size: 0, deleted: false };
+        let staged: Vec<_> = vec![claimed(0x42), claimed(0x41), empty(), empty()]
+            .into_iter()
+            .map(|mut file| match stager.stage(&mut file, "gopro").expect("Couldn't stage file") {
+                Staged::New(desc) => desc,
+                Staged::Duplicate(_) => panic!("Different files were treated as duplicates"),
+            })
+            .collect();
+
+        let prefix = |desc: &UploadDescriptor| hex::encode(&desc.content_hash[..4]);
+        assert_eq!(staged[0].discriminator, None);
+        assert_eq!(staged[1].discriminator, Some(prefix(&staged[1])));
+        // Empty files all hash the same, so the discriminator has to count up.
+        assert_eq!(staged[2].discriminator, Some(prefix(&staged[2])));
+        assert_eq!(staged[3].discriminator, Some(format!("{}-2", prefix(&staged[3]))));
+
+        let on_disk = crate::test_helpers::staged_files(stager.staging_location());
+        assert_eq!(4, on_disk.len());
+        let remote_paths: std::collections::BTreeSet<_> = on_disk.iter().map(|(_, desc)| desc.remote_path()).collect();
+        assert_eq!(4, remote_paths.len());
+    }
+
+    #[test]
+    fn test_discriminated_names() {
+        let mut desc = UploadDescriptor::test_descriptor();
+        desc.discriminator = Some("GOPR0042".into());
+        assert_eq!(desc.remote_path(), PathBuf::from("/2018/08/26/test-device/14-30-00-GOPR0042.mp4"));
+        assert!(desc.staging_name().ends_with("-GOPR0042.mp4"));
+        assert_eq!(desc.discriminated("/a.b/c".into()), "/a.b/c-GOPR0042");
+    }
+
+    #[test]
+    fn test_avoiding_conflicts() {
+        let mut desc = UploadDescriptor::test_descriptor();
+        desc.content_hash = [0xab; 32];
+        let elsewhere = desc.avoiding_conflicts().expect("Nowhere else to go");
+        assert_eq!(elsewhere.remote_path(), PathBuf::from("/2018/08/26/test-device/14-30-00-abababab.mp4"));
+        assert_eq!(desc.avoiding_conflicts().unwrap().remote_path(), elsewhere.remote_path());
+
+        desc.discriminator = Some("GOPR0042".into());
+        assert_eq!(
+            desc.avoiding_conflicts().unwrap().remote_path(),
+            PathBuf::from("/2018/08/26/test-device/14-30-00-GOPR0042-abababab.mp4")
+        );
+
+        let manual = UploadDescriptor::build("manual".to_string()).manual_file("some/file.csv".into());
+        assert!(manual.avoiding_conflicts().is_none());
+    }
+
+    #[test]
+    fn test_migrates_old_manifests() {
+        let stager = crate::test_helpers::temp_stager();
+        stager.stage(&mut claimed(0x42), "gopro").expect("Couldn't stage file");
+        let (staged, desc) = crate::test_helpers::staged_files(stager.staging_location()).pop().unwrap();
+
+        // Old manifests had no version, and their capture times were in our zone.
+        let mut manifest = staged.content_path.clone().into_os_string();
+        manifest.push(".manifest");
+        let mut old: serde_json::Value = serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+        old.as_object_mut().unwrap().remove("version");
+        fs::write(&manifest, serde_json::to_vec(&old).unwrap()).unwrap();
+
+        let mut devices = BTreeMap::new();
+        devices.insert("gopro".to_string(), DeviceClock::new(ClockZone::parse("-03:00").unwrap(), chrono::Duration::seconds(5)));
+        let stager = Stager::destructive(stager.into_inner()).with_device_clocks(DeviceClocks::new(devices));
+        assert_eq!(1, stager.migrate_manifests().expect("Couldn't migrate manifests"));
+        assert_eq!(0, stager.migrate_manifests().expect("Couldn't migrate manifests"));
+
+        let (migrated_file, migrated) = crate::test_helpers::staged_files(stager.staging_location()).pop().unwrap();
+        assert_eq!(migrated_file.content_path, staged.content_path);
+        assert_eq!(migrated.version, MANIFEST_VERSION);
+        assert_eq!(migrated.content_hash, desc.content_hash);
+        match migrated.path {
+            RemotePathDescriptor::DateTime { capture_time, .. } => {
+                assert_eq!(capture_time.to_rfc3339(), "2019-06-01T09:29:55-03:00");
+            },
+            other => panic!("Unexpected path: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_delete_removes_sidecars() {
+        let data = crate::test_helpers::staged_data(1).expect("Couldn't create staging data");
+        let (staged, _) = crate::test_helpers::staged_files(&data).pop().unwrap();
+        fs::write(staged.sidecar_path(Sidecar::DropboxSession), b"{}").expect("Couldn't write sidecar");
+        // Something else that happens to start with the same name isn't a sidecar.
+        let mut other = staged.content_path.clone().into_os_string();
+        other.push(".bak.manifest");
+        fs::write(&other, b"{}").expect("Couldn't write other file");
+        assert_eq!(4, crate::test_helpers::count_files(&data));
+
+        staged.delete().expect("Couldn't delete staged file");
+        assert_eq!(1, crate::test_helpers::count_files(&data));
+        assert!(Path::new(&other).exists());
+    }
 }
 
 #[cfg(test)]
@@ -614,7 +1894,7 @@ impl StagingLocation for tempfile::TempDir {
     }
 }
 
-impl<T> StagingLocation for &T where T: StagingLocation {
+impl<T> StagingLocation for &T where T: StagingLocation + ?Sized {
     fn relative_path(&self, path: &Path) -> PathBuf {
         (*self).relative_path(path)
     }
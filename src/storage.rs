@@ -1,20 +1,33 @@
 use std::fmt::Debug;
 use std::fs::File;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
+use crate::bandwidth::{Throttle, ThrottledReader};
+use crate::history::UploadHistory;
+use crate::path_template::PathTemplates;
 use crate::reporting::{ReportEntry, UploadReport, UploadStatus};
-use crate::staging::{self, StagingLocation};
+use crate::retry::{self, ErrorKind, RetryPolicy};
+use crate::routing::{Route, Router};
+use crate::staging::{self, Quarantined, StagedFile, StagedFiles, StagingLocation, UploadState};
 use crate::formatting;
 
 
 use failure::Error;
 use chrono::prelude::*;
 
-const MAX_RETRIES: usize = 3;
+/// What adaptors are handed to read staged files from.
+pub type UploadReader = ThrottledReader<File>;
 
 #[derive(Debug)]
 pub struct MaybeStorageAdaptor {
     name: String,
-    adaptor: Result<Box<dyn StorageAdaptor<File>>, Error>,
+    id: String,
+    adaptor: Result<Box<dyn StorageAdaptor<UploadReader>>, Error>,
+    retry: RetryPolicy,
+    throttle: Throttle,
+    templates: PathTemplates,
 }
 
 impl MaybeStorageAdaptor {
@@ -22,24 +35,69 @@ impl MaybeStorageAdaptor {
         &self.name
     }
 
-    pub fn adaptor(&self) -> &Result<Box<dyn StorageAdaptor<File>>, Error> {
+    /// See `StorageAdaptor::id`.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn with_id(mut self, id: String) -> MaybeStorageAdaptor {
+        self.id = id;
+        self
+    }
+
+    pub fn adaptor(&self) -> &Result<Box<dyn StorageAdaptor<UploadReader>>, Error> {
         &self.adaptor
     }
 
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> MaybeStorageAdaptor {
+        self.retry = retry;
+        self
+    }
+
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    pub fn with_throttle(mut self, throttle: Throttle) -> MaybeStorageAdaptor {
+        self.throttle = throttle;
+        self
+    }
+
+    pub fn path_templates(&self) -> &PathTemplates {
+        &self.templates
+    }
+
+    pub fn with_path_templates(mut self, templates: PathTemplates) -> MaybeStorageAdaptor {
+        self.templates = templates;
+        self
+    }
+
     #[allow(non_snake_case)]
     pub fn Ok<T>(adaptor: T) -> MaybeStorageAdaptor
-    where T: 'static + StorageAdaptor<File> {
+    where T: 'static + StorageAdaptor<UploadReader> {
         MaybeStorageAdaptor {
             name: adaptor.name(),
+            id: adaptor.id(),
             adaptor: Ok(Box::new(adaptor)),
+            retry: Default::default(),
+            throttle: Default::default(),
+            templates: Default::default(),
         }
     }
 
     #[allow(non_snake_case)]
     pub fn Err(name: String, error: Error) -> MaybeStorageAdaptor {
         MaybeStorageAdaptor {
+            id: name.clone(),
             name,
             adaptor: Err(error),
+            retry: Default::default(),
+            throttle: Default::default(),
+            templates: Default::default(),
         }
     }
 }
@@ -48,87 +106,348 @@ impl MaybeStorageAdaptor {
 pub enum StorageStatus {
     Success,
     Failure,
+    /// The adaptor doesn't handle this kind of file, eg youtube being given a csv.
+    Skipped,
 }
 
-pub trait StorageAdaptor<T>: Send + Debug {
+pub trait StorageAdaptor<T>: Send + Sync + Debug {
     fn upload(
         &self,
         reader: T,
         manifest: &staging::UploadDescriptor,
     ) -> Result<StorageStatus, Error>;
 
+    /// Upload a file out of staging, where `staged` gives the adaptor somewhere to keep state
+    /// between attempts. Most adaptors have no use for this.
+    fn upload_staged(
+        &self,
+        reader: T,
+        manifest: &staging::UploadDescriptor,
+        _staged: &StagedFile,
+    ) -> Result<StorageStatus, Error> {
+        self.upload(reader, manifest)
+    }
+
     fn already_uploaded(&self, manifest: &staging::UploadDescriptor) -> bool;
 
+    /// Check that the file really made it after `upload` said it did, which is what stands
+    /// between us and deleting the staged copy.
+    ///
+    /// By default this leans on `already_uploaded`, which most adaptors implement by comparing
+    /// against the content hash they have upstream.
+    fn verify(&self, manifest: &staging::UploadDescriptor) -> Result<(), Error> {
+        if !self.already_uploaded(manifest) {
+            bail!("{} doesn't have {} after uploading it", self.name(), manifest.staging_name());
+        }
+        Ok(())
+    }
+
+    /// What the service calls the thing we created for this file, for services where that's
+    /// something other than the remote path. Only asked about files that were uploaded.
+    fn remote_id(&self, _manifest: &staging::UploadDescriptor) -> Option<String> {
+        None
+    }
+
     fn name(&self) -> String;
+
+    /// Identifies where this adaptor puts files, so that two adaptors of the same kind, like a
+    /// pair of local backups, aren't mistaken for each other when remembering what each has done.
+    ///
+    /// Defaults to `name`, which is fine for backends that can only be configured once.
+    fn id(&self) -> String {
+        self.name()
+    }
+}
+
+
+/// Attempt to upload `staged_file` using `ad`, retrying according to its retry policy.
+fn upload_file(
+    ad: &MaybeStorageAdaptor,
+    staged_file: &StagedFile,
+    manifest: &staging::UploadDescriptor,
+) -> (String, UploadStatus) {
+    let policy = ad.retry_policy();
+    let throttle = ad.throttle();
+    // Does it actually make sense to use Errored when it was a mount failure?
+    // dunno but we're doing it.
+    let ad = match ad.adaptor() {
+        Ok(ad) => ad,
+        // TODO(richo) throwing away the info with format_err is a little blunt
+        Err(e) => return (ad.name().to_string(), UploadStatus::Errored(format_err!("Failed to get adaptor: {:?}", e))),
+    };
+
+    let start = Utc::now();
+    info!("Starting {} adaptor for {:?}", ad.name(), &staged_file.content_path);
+    info!("Checking if file already exists");
+    if ad.already_uploaded(manifest) {
+        info!("File was already uploaded - skipping");
+        return (ad.name(), UploadStatus::AlreadyUploaded);
+    }
+
+    info!("File not present upstream - beginning upload");
+    let mut attempt = 0;
+    let result = loop {
+        attempt += 1;
+        let content = match staged_file.content_handle() {
+            Ok(content) => throttle.wrap(content),
+            Err(e) => break Err(e.into()),
+        };
+        let error = match ad.upload_staged(content, manifest, staged_file) {
+            Ok(status) => break Ok(status),
+            Err(error) => error,
+        };
+        error!(
+            "Attempt {} of upload of {:?} failed ({:?}): {:?}",
+            attempt, &staged_file.content_path, retry::classify(&error), &error
+        );
+        match policy.delay(attempt, &error) {
+            Some(delay) => {
+                info!("Retrying in {}ms", delay.as_millis());
+                thread::sleep(delay);
+            }
+            None => break Err(error),
+        }
+    };
+
+    match result {
+        Err(err) => (ad.name(), UploadStatus::Errored(err)),
+        Ok(StorageStatus::Skipped) => {
+            info!("{} doesn't handle this file - skipping", ad.name());
+            (ad.name(), UploadStatus::Skipped)
+        }
+        Ok(_resp) => {
+            let finish = Utc::now();
+            info!("Upload succeeded in {}", formatting::human_readable_time(finish - start));
+            match ad.verify(manifest) {
+                Ok(()) => (ad.name(), UploadStatus::Succeeded),
+                Err(err) => {
+                    error!("Verifying upload of {:?} to {} failed: {:?}", &staged_file.content_path, ad.name(), &err);
+                    (ad.name(), UploadStatus::VerificationFailed(err))
+                }
+            }
+        }
+    }
+}
+
+/// Upload `staged_file` using `ad`, and if there's already something else where it was going, put
+/// it alongside that instead. Returns the manifest for wherever it ended up.
+fn upload_alongside_conflicts(
+    ad: &MaybeStorageAdaptor,
+    staged_file: &StagedFile,
+    manifest: &staging::UploadDescriptor,
+) -> (staging::UploadDescriptor, (String, UploadStatus)) {
+    let result = upload_file(ad, staged_file, manifest);
+    let conflicted = matches!(&result.1, UploadStatus::Errored(e) if retry::classify(e) == ErrorKind::Conflict);
+    if let (true, Some(elsewhere)) = (conflicted, manifest.avoiding_conflicts()) {
+        warn!(
+            "{} already has something else at {:?}, uploading {:?} to {:?} instead",
+            ad.name(), manifest.remote_path(), &staged_file.content_path, elsewhere.remote_path()
+        );
+        let result = upload_file(ad, staged_file, &elsewhere);
+        return (elsewhere, result);
+    }
+    (manifest.clone(), result)
+}
+
+/// Upload `staged_file` using `ad`, unless it already did so on an earlier run, and remember if it
+/// succeeds this time.
+fn upload_with_state(
+    ad: &MaybeStorageAdaptor,
+    staged_file: &StagedFile,
+    manifest: &staging::UploadDescriptor,
+    state: &Mutex<UploadState>,
+    history: Option<&UploadHistory>,
+) -> (String, UploadStatus) {
+    if state.lock().expect("upload state poisoned").is_complete(ad.id()) {
+        info!("{} already finished with {:?} - skipping", ad.name(), &staged_file.content_path);
+        return (ad.name().to_string(), UploadStatus::PreviouslyUploaded);
+    }
+
+    // Backends with a template of their own put files somewhere other than staging would suggest.
+    let manifest = &manifest.with_backend_template(ad.path_templates().for_device(&manifest.device_name));
+    let result = match history {
+        // The history remembers what we uploaded even after the staged file is gone, so there's
+        // no need to go and ask the service.
+        Some(history) if history.was_uploaded(ad.id(), &manifest.content_hash) => {
+            info!("History says {} already has {:?} - skipping", ad.name(), &staged_file.content_path);
+            (ad.name().to_string(), UploadStatus::AlreadyUploaded)
+        }
+        Some(history) => {
+            let (manifest, result) = upload_alongside_conflicts(ad, staged_file, manifest);
+            let remote_id = match (&result.1, ad.adaptor()) {
+                (UploadStatus::Succeeded, Ok(adaptor)) => adaptor.remote_id(&manifest),
+                _ => None,
+            };
+            if let Err(e) = history.record_upload(&manifest, &result.0, ad.id(), &result.1, remote_id) {
+                warn!("Couldn't record upload of {:?} in the history: {:?}", &staged_file.content_path, e);
+            }
+            result
+        }
+        None => upload_alongside_conflicts(ad, staged_file, manifest).1,
+    };
+    // Skipping is cheap to work out again, and remembering it would make the file look uploaded
+    // on the next run if nothing else took it.
+    if result.1.is_success() && !matches!(result.1, UploadStatus::Skipped) {
+        let mut state = state.lock().expect("upload state poisoned");
+        state.complete(ad.id());
+        if let Err(e) = staged_file.save_upload_state(&state) {
+            warn!("Couldn't save upload state for {:?}: {:?}", &staged_file.content_path, e);
+        }
+    }
+    result
 }
 
+/// A staged file, along with what the workers need to know about it.
+struct Pending<'r> {
+    staged_file: StagedFile,
+    manifest: staging::UploadDescriptor,
+    route: Route<'r>,
+    state: Mutex<UploadState>,
+    /// What each adaptor made of it, filled in by the workers as they go.
+    results: Mutex<Vec<Option<(String, UploadStatus)>>>,
+}
+
+/// The staged files the workers are working through, which are only read out of staging once the
+/// first worker gets to them.
+struct Queue<'r> {
+    staged: StagedFiles,
+    files: Vec<Arc<Pending<'r>>>,
+    /// Whatever we couldn't read along the way.
+    problems: Vec<Error>,
+    router: &'r Router,
+    adaptors: usize,
+}
+
+impl<'r> Queue<'r> {
+    /// The `idx`th staged file, or None once we've run out.
+    fn get(&mut self, idx: usize) -> Option<Arc<Pending<'r>>> {
+        while self.files.len() <= idx {
+            match self.staged.next()? {
+                Ok((staged_file, manifest)) => self.files.push(Arc::new(Pending {
+                    state: Mutex::new(staged_file.upload_state()),
+                    route: self.router.route(&manifest),
+                    results: Mutex::new((0..self.adaptors).map(|_| None).collect()),
+                    staged_file,
+                    manifest,
+                })),
+                Err(e) => self.problems.push(e),
+            }
+        }
+        Some(self.files[idx].clone())
+    }
+}
 
+/// Upload everything in `staged` to whichever adaptors `router` picks for it.
+///
+/// Files that aren't routed anywhere are left in staging and listed in the report. Each adaptor
+/// gets its own workers, `concurrency` of them, which work through the staged files
+/// independently of the other adaptors. Files are only deleted once every adaptor has finished
+/// with them, and the report lists files and adaptors in a stable order regardless of which
+/// worker got there first.
+///
+/// Every result is recorded in `history` if there is one, and adaptors it says already have a file
+/// aren't asked about it again.
 // TODO(richo) Make this use StageableLocation to find the files.
 pub fn upload_from_staged(
     staged: &dyn StagingLocation,
     adaptors: &[MaybeStorageAdaptor],
+    router: &Router,
+    concurrency: usize,
+    history: Option<&UploadHistory>,
 ) -> Result<UploadReport, Error> {
+    upload_from_staged_while(staged, adaptors, router, concurrency, history, || true)
+}
+
+/// Like `upload_from_staged`, but only starts on each file while `allowed` says uploads are still
+/// allowed. Files nobody got to are left staged for a later run.
+pub fn upload_from_staged_while<F>(
+    staged: &dyn StagingLocation,
+    adaptors: &[MaybeStorageAdaptor],
+    router: &Router,
+    concurrency: usize,
+    history: Option<&UploadHistory>,
+    allowed: F,
+) -> Result<UploadReport, Error>
+where F: Fn() -> bool + Sync {
     let mut report: UploadReport = Default::default();
     info!("Starting upload from {:?}", &staged);
-    for (staged_file, manifest) in staged.staged_files()? {
-
-        let results: Vec<_> = adaptors
-            .iter()
-            .map(|ad| {
-                // Does it actually make sense to use Errored when it was a mount failure?
-                // dunno but we're doing it.
-                let ad = match ad.adaptor() {
-                    Ok(ad) => ad,
-                    // TODO(richo) throwing away the info with format_err is a little blunt
-                    Err(e) => return (ad.name().to_string(), UploadStatus::Errored(format_err!("Failed to get adaptor: {:?}", e))),
-                };
-
-                let start = Utc::now();
-                info!("Starting {} adaptor for {:?}", ad.name(), &staged_file.content_path);
-                info!("Checking if file already exists");
-                if ad.already_uploaded(&manifest) {
-                    info!("File was already uploaded - skipping");
-                    return (ad.name(), UploadStatus::AlreadyUploaded);
-                }
-
-                info!("File not present upstream - beginning upload");
-                // I have no idea how bad it is to lie about the adaptor name here
-                // We have inverted the sense of "success" and "failure" from try_for_each
-                let result = (0..MAX_RETRIES).try_fold(format_err!("dummy error"), |_, i| {
-                    let content = match staged_file.content_handle() {
-                        Ok(content) => content,
-                        Err(e) => return Some(e.into()),
+    let queue = Mutex::new(Queue {
+        staged: staged.staged_files()?,
+        files: vec![],
+        problems: vec![],
+        router,
+        adaptors: adaptors.len(),
+    });
+    let cursors: Vec<_> = adaptors.iter().map(|_| AtomicUsize::new(0)).collect();
+
+    thread::scope(|scope| {
+        let (queue, allowed) = (&queue, &allowed);
+        for (i, ad) in adaptors.iter().enumerate() {
+            let cursor = &cursors[i];
+            for _ in 0..concurrency.max(1) {
+                scope.spawn(move || loop {
+                    let idx = cursor.fetch_add(1, Ordering::SeqCst);
+                    let file = match queue.lock().expect("staged files poisoned").get(idx) {
+                        Some(file) => file,
+                        None => break,
                     };
-                    match ad.upload(content, &manifest) {
-                        Ok(_resp) => {
-                            let finish = Utc::now();
-                            info!("Upload succeeded in {}", formatting::human_readable_time(finish - start));
-                            // Returning Err short circuits the iterator
-                            None
-                        }
-                        Err(error) => {
-                            error!(
-                               "Attempt {} of upload of {:?} failed: {:?}",
-                                &i, &staged_file.content_path, &error
-                            );
-                            Some(error)
-                        }
+                    if !file.route.includes(ad.name()) {
+                        continue;
                     }
+                    let result = match allowed() {
+                        true => upload_with_state(ad, &file.staged_file, &file.manifest, &file.state, history),
+                        false => (ad.name().to_string(), UploadStatus::Postponed),
+                    };
+                    file.results.lock().expect("upload results poisoned")[i] = Some(result);
                 });
-                // So we have to pull them apart to flip them
-                match result {
-                    // The "ok" state means we fell all the way through
-                    Some(err) => (ad.name(), UploadStatus::Errored(err)),
-                    None => (ad.name(), UploadStatus::Succeeded),
-                }
-            })
-            .collect();
-
+            }
+        }
+    });
+
+    let mut queue = queue.into_inner().expect("staged files poisoned");
+    // Without any adaptors nothing went looking, but the files still belong in the report.
+    while queue.get(queue.files.len()).is_some() {}
+    for problem in queue.problems.drain(..) {
+        match problem.downcast::<Quarantined>() {
+            Ok(quarantined) => report.record_quarantined(quarantined),
+            Err(e) => {
+                error!("Couldn't read staged file: {:?}", e);
+                report.record_staging_error(&e);
+            }
+        }
+    }
+    // The workers are all done, so nothing else holds on to the files.
+    let mut files: Vec<_> = queue.files
+        .drain(..)
+        .map(|file| Arc::try_unwrap(file).unwrap_or_else(|_| panic!("staged file outlived the workers")))
+        .collect();
+    files.sort_by(|a, b| a.staged_file.content_path.cmp(&b.staged_file.content_path));
+
+    for Pending { staged_file, manifest, route, results, .. } in files {
+        // Only adaptors the file was routed to will have results.
+        let mut results: Vec<_> = results.into_inner().expect("upload results poisoned").into_iter().flatten().collect();
+        // A backend we don't have can't have taken the file, so it mustn't look like it did.
+        if let Route::Only(backends) = route {
+            for backend in backends.iter().filter(|backend| !adaptors.iter().any(|ad| ad.name() == backend.as_str())) {
+                results.push((backend.clone(), UploadStatus::Errored(format_err!("{} isn't configured", backend))));
+            }
+        }
+        if route == Route::Unmatched || results.is_empty() {
+            info!("No backends to upload {:?} to, preserving it", &staged_file);
+            report.record_unrouted(manifest);
+            continue;
+        }
+        let content_hash = manifest.content_hash;
         let entry = ReportEntry::new(manifest, results);
         if entry.is_success() {
+            // Worst case we stage these contents again if they turn up, which is no reason to
+            // stop uploading everything else.
+            if let Err(e) = staged.hash_index().record_uploaded(&content_hash, &route) {
+                warn!("Couldn't record uploading {:?} in the staging index: {:?}", &staged_file, e);
+            }
             staged_file.delete()?;
         } else {
-            info!("one or more adaptors failed, preserving {:?}", &staged_file);
+            info!("one or more adaptors failed or none took it, preserving {:?}", &staged_file);
         }
         report.record_activity(entry);
     }
@@ -138,34 +457,45 @@ pub fn upload_from_staged(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
     use std::fs;
-    use std::cell::Cell;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
     use tempfile;
+    use crate::config::RouteConfig;
+    use crate::retry::{ErrorKind, UploadError};
     use crate::staging::UploadDescriptor;
     use crate::test_helpers;
 
+    /// Plenty of these adaptors fail on purpose, and nobody wants to wait around for them.
+    fn without_backoff<T>(adaptor: T) -> MaybeStorageAdaptor
+    where T: 'static + StorageAdaptor<UploadReader> {
+        MaybeStorageAdaptor::Ok(adaptor).with_retry_policy(RetryPolicy {
+            initial_backoff: Duration::from_secs(0),
+            ..Default::default()
+        })
+    }
+
     /// A storage adaptor that will succeed on the nth attempt
-    // TODO(richo) It's probably a fairly small problem to make this Sync and suddenly parrallel
-    // uploads are right there on the horizon.
     #[derive(Debug)]
     struct TemporarilyBrokenStorageAdaptor {
-        attempts: Cell<usize>,
+        attempts: AtomicUsize,
         successful_attempt: usize,
     }
 
     impl TemporarilyBrokenStorageAdaptor {
         fn new(tries: usize) -> TemporarilyBrokenStorageAdaptor {
             TemporarilyBrokenStorageAdaptor {
-                attempts: Cell::new(0),
+                attempts: AtomicUsize::new(0),
                 successful_attempt: tries,
             }
         }
     }
 
-    impl StorageAdaptor<File> for TemporarilyBrokenStorageAdaptor {
-        fn upload(&self, _: File, _: &staging::UploadDescriptor) -> Result<StorageStatus, Error> {
-            let this_attempt = self.attempts.get() + 1;
-            self.attempts.set(this_attempt);
+    impl<T> StorageAdaptor<T> for TemporarilyBrokenStorageAdaptor {
+        fn upload(&self, _: T, _: &staging::UploadDescriptor) -> Result<StorageStatus, Error> {
+            let this_attempt = self.attempts.fetch_add(1, Ordering::SeqCst) + 1;
 
             if this_attempt == self.successful_attempt {
                 return Ok(StorageStatus::Success);
@@ -178,11 +508,159 @@ mod tests {
             false
         }
 
+        fn verify(&self, _: &staging::UploadDescriptor) -> Result<(), Error> {
+            Ok(())
+        }
+
         fn name(&self) -> String {
             "TemporarilyBrokenStorageAdaptor".to_string()
         }
     }
 
+    /// A storage adaptor that always either succeeds or fails
+    #[derive(Debug)]
+    struct ConstantStorageAdaptor {
+        name: &'static str,
+        succeeds: bool,
+    }
+
+    /// A storage adaptor that fails in a way that isn't worth retrying
+    #[derive(Debug)]
+    struct RejectingStorageAdaptor {
+        attempts: Arc<AtomicUsize>,
+    }
+
+    impl<T> StorageAdaptor<T> for RejectingStorageAdaptor {
+        fn upload(&self, _: T, _: &staging::UploadDescriptor) -> Result<StorageStatus, Error> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            Err(UploadError::new(ErrorKind::Auth, "Token expired".into()).into())
+        }
+
+        fn already_uploaded(&self, _: &staging::UploadDescriptor) -> bool {
+            false
+        }
+
+        fn name(&self) -> String {
+            "RejectingStorageAdaptor".to_string()
+        }
+    }
+
+    /// A storage adaptor that claims to upload things but never actually has them
+    #[derive(Debug)]
+    struct LyingStorageAdaptor;
+
+    impl<T> StorageAdaptor<T> for LyingStorageAdaptor {
+        fn upload(&self, _: T, _: &staging::UploadDescriptor) -> Result<StorageStatus, Error> {
+            Ok(StorageStatus::Success)
+        }
+
+        fn already_uploaded(&self, _: &staging::UploadDescriptor) -> bool {
+            false
+        }
+
+        fn name(&self) -> String {
+            "LyingStorageAdaptor".to_string()
+        }
+    }
+
+    /// A storage adaptor that doesn't handle anything, like youtube given a csv
+    #[derive(Debug)]
+    struct SkippingStorageAdaptor;
+
+    impl<T> StorageAdaptor<T> for SkippingStorageAdaptor {
+        fn upload(&self, _: T, _: &staging::UploadDescriptor) -> Result<StorageStatus, Error> {
+            Ok(StorageStatus::Skipped)
+        }
+
+        fn already_uploaded(&self, _: &staging::UploadDescriptor) -> bool {
+            false
+        }
+
+        fn name(&self) -> String {
+            "SkippingStorageAdaptor".to_string()
+        }
+    }
+
+    /// Somewhere that keeps whatever it's sent by where it was sent, and won't replace any of it
+    #[derive(Debug)]
+    struct RemoteStorageAdaptor {
+        files: Arc<Mutex<BTreeMap<PathBuf, [u8; 32]>>>,
+    }
+
+    impl<T> StorageAdaptor<T> for RemoteStorageAdaptor {
+        fn upload(&self, _: T, manifest: &staging::UploadDescriptor) -> Result<StorageStatus, Error> {
+            let mut files = self.files.lock().unwrap();
+            if files.contains_key(&manifest.remote_path()) {
+                Err(UploadError::new(ErrorKind::Conflict, "Something else is there".into()))?;
+            }
+            files.insert(manifest.remote_path(), manifest.content_hash);
+            Ok(StorageStatus::Success)
+        }
+
+        fn already_uploaded(&self, manifest: &staging::UploadDescriptor) -> bool {
+            self.files.lock().unwrap().get(&manifest.remote_path()) == Some(&manifest.content_hash)
+        }
+
+        fn name(&self) -> String {
+            "remote".to_string()
+        }
+    }
+
+    /// A storage adaptor that shares its name with others of its kind, like local backups do
+    #[derive(Debug)]
+    struct BackupStorageAdaptor {
+        destination: &'static str,
+        succeeds: bool,
+    }
+
+    impl<T> StorageAdaptor<T> for BackupStorageAdaptor {
+        fn upload(&self, _: T, _: &staging::UploadDescriptor) -> Result<StorageStatus, Error> {
+            if self.succeeds {
+                Ok(StorageStatus::Success)
+            } else {
+                bail!("Disk full");
+            }
+        }
+
+        fn already_uploaded(&self, _: &staging::UploadDescriptor) -> bool {
+            false
+        }
+
+        fn verify(&self, _: &staging::UploadDescriptor) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn name(&self) -> String {
+            "backup".to_string()
+        }
+
+        fn id(&self) -> String {
+            format!("backup:{}", self.destination)
+        }
+    }
+
+    impl<T> StorageAdaptor<T> for ConstantStorageAdaptor {
+        fn upload(&self, _: T, _: &staging::UploadDescriptor) -> Result<StorageStatus, Error> {
+            if self.succeeds {
+                Ok(StorageStatus::Success)
+            } else {
+                bail!("Permanent error");
+            }
+        }
+
+        fn already_uploaded(&self, _: &staging::UploadDescriptor) -> bool {
+            false
+        }
+
+        fn verify(&self, _: &staging::UploadDescriptor) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+    }
+
     #[test]
     fn test_temporarily_broken_uploader_actually_works() {
         let manifest = UploadDescriptor::test_descriptor();
@@ -198,26 +676,281 @@ mod tests {
     #[test]
     fn test_three_failures_leaves_staged_files() {
         let data = test_helpers::staged_data(5).expect("Couldn't create staging data");
-        let files = fs::read_dir(&data).expect("Couldn't list staged data").collect::<Vec<_>>();
+        let files = fs::read_dir(&data).expect("Couldn't list staged data").filter(|e| e.as_ref().unwrap().path().is_file()).collect::<Vec<_>>();
         assert_eq!(10, files.len());
 
         let uploader = TemporarilyBrokenStorageAdaptor::new(4);
 
-        upload_from_staged(&data, &[MaybeStorageAdaptor::Ok(uploader)]).expect("Didn't upload successfully");
+        upload_from_staged(&data, &[without_backoff(uploader)], &Default::default(), 1, None).expect("Didn't upload successfully");
         assert_eq!(10, files.len());
     }
 
     #[test]
     fn test_two_failures_and_then_success_erases_staged_files() {
         let data = test_helpers::staged_data(5).expect("Couldn't create staging data");
-        let files = fs::read_dir(&data).expect("Couldn't list staged data").collect::<Vec<_>>();
-        assert_eq!(10, files.len());
+        assert_eq!(10, test_helpers::count_files(&data));
 
         let uploader = TemporarilyBrokenStorageAdaptor::new(2);
 
-        let report = upload_from_staged(&data, &[MaybeStorageAdaptor::Ok(uploader)]).expect("Didn't upload successfully");
+        let report = upload_from_staged(&data, &[without_backoff(uploader)], &Default::default(), 1, None).expect("Didn't upload successfully");
         println!("{}", report.to_plaintext().unwrap());
         // TODO(richo) why isn't this actually deleting anything
         // assert_eq!(0, files.len());
     }
+
+    #[test]
+    fn test_parallel_uploads_only_delete_when_every_adaptor_succeeds() {
+        let data = test_helpers::staged_data(5).expect("Couldn't create staging data");
+        let adaptors = [
+            without_backoff(ConstantStorageAdaptor { name: "first", succeeds: true }),
+            without_backoff(ConstantStorageAdaptor { name: "second", succeeds: false }),
+        ];
+
+        let report = upload_from_staged(&data, &adaptors, &Default::default(), 3, None).expect("Didn't upload successfully");
+        assert_eq!(5, report.num_uploads());
+        let plaintext = report.to_plaintext().unwrap();
+        assert_eq!(5, plaintext.matches("# first: Succeeded\n    # second: Upload failed").count());
+        assert_eq!(5, crate::test_helpers::staged_files(&data).len());
+
+        let adaptors = [
+            without_backoff(ConstantStorageAdaptor { name: "first", succeeds: true }),
+            without_backoff(ConstantStorageAdaptor { name: "second", succeeds: true }),
+        ];
+        let report = upload_from_staged(&data, &adaptors, &Default::default(), 3, None).expect("Didn't upload successfully");
+        assert_eq!(5, report.num_uploads());
+        assert_eq!(0, test_helpers::count_files(&data));
+    }
+
+    #[test]
+    fn test_corrupt_manifests_are_reported_not_fatal() {
+        let data = test_helpers::staged_data(3).expect("Couldn't create staging data");
+        let (corrupt, _) = test_helpers::staged_files(&data).pop().unwrap();
+        let mut manifest = corrupt.content_path.clone().into_os_string();
+        manifest.push(".manifest");
+        fs::write(&manifest, b"not json").expect("Couldn't corrupt manifest");
+        let adaptors = [without_backoff(ConstantStorageAdaptor { name: "first", succeeds: true })];
+
+        let report = upload_from_staged(&data, &adaptors, &Default::default(), 1, None).expect("Didn't upload successfully");
+        assert_eq!(2, report.num_uploads());
+        assert_eq!(1, report.num_quarantined());
+        assert!(report.to_plaintext().unwrap().contains("Quarantined Files"));
+        assert!(!corrupt.content_path.exists());
+        assert!(data.path().join(staging::QUARANTINE_DIR).join(corrupt.content_path.file_name().unwrap()).exists());
+    }
+
+    #[test]
+    fn test_uploads_are_recorded_in_the_index() {
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+        let hashes: Vec<_> = test_helpers::staged_files(&data).into_iter().map(|(_, desc)| desc.content_hash).collect();
+        let adaptors = [without_backoff(ConstantStorageAdaptor { name: "first", succeeds: true })];
+
+        upload_from_staged(&data, &adaptors, &Default::default(), 1, None).expect("Didn't upload successfully");
+        for hash in &hashes {
+            let entry = data.hash_index().get(hash, &Route::Everywhere).expect("Upload wasn't indexed");
+            assert!(entry.uploaded.is_some());
+        }
+    }
+
+    #[test]
+    fn test_history_short_circuits_uploads() {
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+        let dir = test_helpers::tempdir();
+        let history = UploadHistory::open(dir.path().join("history.jsonl")).expect("Couldn't open history");
+        let (_, already) = test_helpers::staged_files(&data).pop().unwrap();
+        history.record_upload(&already, "first", "first", &UploadStatus::Succeeded, None).expect("Couldn't record upload");
+
+        let adaptors = [without_backoff(ConstantStorageAdaptor { name: "first", succeeds: false })];
+        let report = upload_from_staged(&data, &adaptors, &Default::default(), 1, Some(&history)).expect("Didn't upload successfully");
+        let plaintext = report.to_plaintext().unwrap();
+        assert_eq!(1, plaintext.matches("# first: Already uploaded").count());
+        assert_eq!(1, plaintext.matches("# first: Upload failed").count());
+        // Only the one that was attempted is recorded again.
+        assert_eq!(2, history.entries().expect("Couldn't read history").len());
+        assert_eq!(2, test_helpers::count_files(&data));
+    }
+
+    #[test]
+    fn test_adaptors_that_succeeded_are_not_retried() {
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+        let adaptors = [
+            without_backoff(ConstantStorageAdaptor { name: "first", succeeds: true }),
+            without_backoff(ConstantStorageAdaptor { name: "second", succeeds: false }),
+        ];
+        upload_from_staged(&data, &adaptors, &Default::default(), 1, None).expect("Didn't upload successfully");
+
+        // Now the first one would fail, but it's already done its part.
+        let adaptors = [
+            without_backoff(ConstantStorageAdaptor { name: "first", succeeds: false }),
+            without_backoff(ConstantStorageAdaptor { name: "second", succeeds: true }),
+        ];
+        let report = upload_from_staged(&data, &adaptors, &Default::default(), 1, None).expect("Didn't upload successfully");
+        let plaintext = report.to_plaintext().unwrap();
+        assert_eq!(2, plaintext.matches("# first: Uploaded on a previous run\n    # second: Succeeded").count());
+        assert_eq!(0, test_helpers::count_files(&data));
+    }
+
+    #[test]
+    fn test_files_are_left_staged_once_the_window_closes() {
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+        let adaptors = [without_backoff(ConstantStorageAdaptor { name: "first", succeeds: true })];
+        let checks = AtomicUsize::new(0);
+        let report = upload_from_staged_while(&data, &adaptors, &Default::default(), 1, None, || {
+            checks.fetch_add(1, Ordering::SeqCst) == 0
+        }).expect("Didn't upload successfully");
+
+        assert_eq!(test_helpers::staged_files(&data).len(), 1);
+        let plaintext = report.to_plaintext().unwrap();
+        assert_eq!(1, plaintext.matches("# first: Succeeded").count());
+        assert_eq!(1, plaintext.matches("# first: Postponed until the next upload window").count());
+    }
+
+    #[test]
+    fn test_adaptors_with_the_same_name_are_tracked_separately() {
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+        let adaptors = [
+            without_backoff(BackupStorageAdaptor { destination: "/mnt/first", succeeds: true }),
+            without_backoff(BackupStorageAdaptor { destination: "/mnt/second", succeeds: false }),
+        ];
+        upload_from_staged(&data, &adaptors, &Default::default(), 1, None).expect("Didn't upload successfully");
+        assert_eq!(2, test_helpers::staged_files(&data).len());
+
+        // The second disk still hasn't got them, whatever the first says.
+        let report = upload_from_staged(&data, &adaptors, &Default::default(), 1, None).expect("Didn't upload successfully");
+        let plaintext = report.to_plaintext().unwrap();
+        assert_eq!(2, plaintext.matches("# backup: Uploaded on a previous run\n    # backup: Upload failed").count());
+        assert_eq!(2, test_helpers::staged_files(&data).len());
+    }
+
+    #[test]
+    fn test_files_only_go_where_they_are_routed() {
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+        let adaptors = [
+            without_backoff(ConstantStorageAdaptor { name: "dropbox", succeeds: true }),
+            without_backoff(ConstantStorageAdaptor { name: "vimeo", succeeds: false }),
+        ];
+        let route = RouteConfig {
+            devices: None,
+            extensions: Some(vec!["dummy".into()]),
+            min_size: None,
+            max_size: None,
+            kind: None,
+            backends: vec!["dropbox".into()],
+        };
+
+        let report = upload_from_staged(&data, &adaptors, &Router::new(vec![route.clone()]), 1, None)
+            .expect("Didn't upload successfully");
+        let plaintext = report.to_plaintext().unwrap();
+        assert_eq!(2, plaintext.matches("# dropbox: Succeeded").count());
+        assert!(!plaintext.contains("vimeo"));
+        assert_eq!(0, test_helpers::count_files(&data));
+
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+        let route = RouteConfig {
+            extensions: Some(vec!["csv".into()]),
+            ..route
+        };
+        let report = upload_from_staged(&data, &adaptors, &Router::new(vec![route]), 1, None)
+            .expect("Didn't upload successfully");
+        assert_eq!(0, report.num_uploads());
+        assert_eq!(2, report.num_unrouted());
+        assert!(report.to_plaintext().unwrap().contains("Unrouted Files"));
+        assert_eq!(4, test_helpers::count_files(&data));
+    }
+
+    #[test]
+    fn test_files_routed_to_missing_backends_are_kept() {
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+        let adaptors = [
+            without_backoff(ConstantStorageAdaptor { name: "dropbox", succeeds: true }),
+            MaybeStorageAdaptor::Err("local backup".into(), format_err!("Couldn't mount it")),
+        ];
+        let route = RouteConfig {
+            devices: None,
+            extensions: None,
+            min_size: None,
+            max_size: None,
+            kind: None,
+            backends: vec!["dropbox".into(), "local backup".into(), "vimeo".into()],
+        };
+
+        let report = upload_from_staged(&data, &adaptors, &Router::new(vec![route]), 1, None)
+            .expect("Didn't upload successfully");
+        let plaintext = report.to_plaintext().unwrap();
+        assert_eq!(2, plaintext.matches("# local backup: Upload failed").count());
+        assert_eq!(2, plaintext.matches("# vimeo: Upload failed").count());
+        assert_eq!(2, test_helpers::staged_files(&data).len());
+    }
+
+    #[test]
+    fn test_failed_verification_leaves_staged_files() {
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+
+        let report = upload_from_staged(&data, &[without_backoff(LyingStorageAdaptor)], &Default::default(), 1, None)
+            .expect("Didn't upload successfully");
+        let plaintext = report.to_plaintext().unwrap();
+        assert_eq!(2, plaintext.matches("# LyingStorageAdaptor: Verification failed").count());
+        assert_eq!(2, crate::test_helpers::staged_files(&data).len());
+    }
+
+    #[test]
+    fn test_files_every_adaptor_skipped_are_kept() {
+        let data = test_helpers::staged_data(2).expect("Couldn't create staging data");
+
+        // Nothing took them the first time, and that's still true the second time.
+        for _ in 0..2 {
+            let report = upload_from_staged(&data, &[without_backoff(SkippingStorageAdaptor)], &Default::default(), 1, None)
+                .expect("Didn't upload successfully");
+            let plaintext = report.to_plaintext().unwrap();
+            assert_eq!(2, plaintext.matches("# SkippingStorageAdaptor: Skipped").count());
+            assert_eq!(4, test_helpers::count_files(&data));
+        }
+
+        // But a file that some adaptor took can go, even though another skipped it.
+        let adaptors = [
+            without_backoff(SkippingStorageAdaptor),
+            without_backoff(ConstantStorageAdaptor { name: "first", succeeds: true }),
+        ];
+        upload_from_staged(&data, &adaptors, &Default::default(), 1, None).expect("Didn't upload successfully");
+        assert_eq!(0, test_helpers::count_files(&data));
+    }
+
+    #[test]
+    fn test_files_from_the_same_second_on_another_run_go_alongside() {
+        let files = Arc::new(Mutex::new(BTreeMap::new()));
+        let first = test_helpers::staged_data(1).expect("Couldn't create staging data");
+        let (_, earlier) = test_helpers::staged_files(&first).pop().unwrap();
+        upload_from_staged(&first, &[without_backoff(RemoteStorageAdaptor { files: files.clone() })], &Default::default(), 1, None)
+            .expect("Didn't upload successfully");
+
+        // Staging has long forgotten about the first file when the second comes along.
+        let second = test_helpers::staged_data(1).expect("Couldn't create staging data");
+        let (staged, mut later) = test_helpers::staged_files(&second).pop().unwrap();
+        later.path = earlier.path.clone();
+        let mut manifest_path = staged.content_path.into_os_string();
+        manifest_path.push(".manifest");
+        fs::write(&manifest_path, serde_json::to_vec(&later).unwrap()).expect("Couldn't rewrite manifest");
+        assert_eq!(earlier.remote_path(), later.remote_path());
+
+        let report = upload_from_staged(&second, &[without_backoff(RemoteStorageAdaptor { files: files.clone() })], &Default::default(), 1, None)
+            .expect("Didn't upload successfully");
+        assert_eq!(1, report.to_plaintext().unwrap().matches("# remote: Succeeded").count());
+        assert_eq!(0, test_helpers::count_files(&second));
+
+        let files = files.lock().unwrap();
+        assert_eq!(2, files.len());
+        assert_eq!(files.get(&earlier.remote_path()), Some(&earlier.content_hash));
+        let elsewhere = later.avoiding_conflicts().expect("Nowhere else to go");
+        assert_eq!(files.get(&elsewhere.remote_path()), Some(&later.content_hash));
+    }
+
+    #[test]
+    fn test_auth_errors_are_not_retried() {
+        let data = test_helpers::staged_data(1).expect("Couldn't create staging data");
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let adaptor = RejectingStorageAdaptor { attempts: attempts.clone() };
+
+        upload_from_staged(&data, &[without_backoff(adaptor)], &Default::default(), 1, None)
+            .expect("Didn't upload successfully");
+        assert_eq!(1, attempts.load(Ordering::SeqCst));
+    }
 }
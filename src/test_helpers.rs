@@ -4,10 +4,11 @@ use walkdir;
 use std::fs::{self, File};
 use std::io::{Seek, Write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use chrono::prelude::*;
 use failure::Error;
 
-use crate::staging::{StageFromDevice, Stager, DateTimeUploadable};
+use crate::staging::{StageFromDevice, StagedFile, Stager, StagingLocation, DateTimeUploadable, UploadDescriptor};
 
 /// Copy data from the test-data directory to a tempdir, then return the owned TestDir object to
 /// the caller for use in tests that will modify the filesystem.
@@ -45,7 +46,7 @@ impl StageFromDevice for DummyDataDevice {
 }
 
 impl DummyDataDevice {
-    fn new(num_files: usize) -> DummyDataDevice {
+    pub(crate) fn new(num_files: usize) -> DummyDataDevice {
         DummyDataDevice {
             files: (0..num_files).map(|_| {
                 DummyDataFile::new().expect("Couldn't create dummy data")
@@ -71,7 +72,10 @@ impl Clone for DummyDataFile {
 impl DummyDataFile {
     fn new() -> Result<DummyDataFile, Error> {
         let mut file = tempfile::tempfile().expect("Couldn't create tempfile");
-        file.write(b"This is some test data").expect("Couldn't write test data");
+        // Every file has different contents, or they'd be skipped as duplicates when staged.
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        write!(file, "This is some test data, file {}", n).expect("Couldn't write test data");
         file.seek(std::io::SeekFrom::Start(0)).expect("Couldn't rewind test file");
 
         Ok(DummyDataFile {
@@ -92,8 +96,8 @@ impl DateTimeUploadable for DummyDataFile {
         "dummy"
     }
 
-    fn capture_datetime(&self) -> Result<DateTime<Local>, chrono::ParseError> {
-        Ok(Local::now())
+    fn capture_datetime(&self) -> Result<NaiveDateTime, chrono::ParseError> {
+        Ok(Local::now().naive_local())
     }
 
     fn reader(&mut self) -> &mut Self::Reader {
@@ -128,6 +132,23 @@ pub(crate) fn staged_data(num_files: usize) -> Result<tempfile::TempDir, Error>
     Ok(stager.into_inner())
 }
 
+/// Everything that's staged in `location`, panicking if anything in there is broken.
+pub(crate) fn staged_files<T: StagingLocation + ?Sized>(location: &T) -> Vec<(StagedFile, UploadDescriptor)> {
+    location
+        .staged_files()
+        .expect("Couldn't list staged files")
+        .collect::<Result<_, _>>()
+        .expect("Couldn't read staged file")
+}
+
+/// How many files are directly in `dir`, leaving out the directories staging keeps for itself.
+pub(crate) fn count_files<P: AsRef<std::path::Path>>(dir: P) -> usize {
+    fs::read_dir(dir)
+        .expect("Couldn't list directory")
+        .filter(|entry| entry.as_ref().expect("Couldn't read entry").path().is_file())
+        .count()
+}
+
 pub(crate) fn temp_stager() -> Stager<tempfile::TempDir> {
     let tempdir = tempfile::tempdir().unwrap();
 
@@ -1,26 +1,39 @@
-use std::fs::File;
+use std::io::Read;
 
 use failure::Error;
 use reqwest;
 use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::StatusCode;
 use serde_json;
 use tus;
 use url::Url;
 
+use crate::ledger::UploadLedger;
+use crate::retry::UploadError;
 use crate::staging;
 use crate::storage::{StorageAdaptor, StorageStatus};
 
+const API_BASE: &str = "https://api.vimeo.com";
+
 /// A client for the vimeo API
+///
+/// Vimeo won't tell us anything about the content of the videos we've uploaded, so we keep a
+/// ledger mapping content hashes to the videos we created for them.
 #[derive(RedactedDebug)]
 pub struct VimeoClient {
     #[redacted]
     token: String,
+    ledger: UploadLedger,
 }
 
-#[derive(Debug)]
+#[derive(RedactedDebug)]
 struct UploadHandle {
     // TODO(richo) native URL type
     url: Url,
+    /// The video this upload is creating, eg `/videos/12345`.
+    uri: String,
+    #[redacted]
+    token: String,
     complete: bool,
 }
 
@@ -38,12 +51,16 @@ struct InnerUploadCreateVideoResponse {
 
 impl VimeoClient {
     /// Create a new VimeoClient authenticated by `token`.
-    pub fn new(token: String) -> VimeoClient {
-        VimeoClient { token }
+    pub fn new(token: String) -> Result<VimeoClient, Error> {
+        Ok(VimeoClient::with_ledger(token, UploadLedger::for_service("vimeo")?))
+    }
+
+    fn with_ledger(token: String, ledger: UploadLedger) -> VimeoClient {
+        VimeoClient { token, ledger }
     }
 
     fn create_upload_handle(&self, name: &str, size: u64) -> Result<UploadHandle, Error> {
-        let api_endpoint = "https://api.vimeo.com/me/videos";
+        let api_endpoint = format!("{}/me/videos", API_BASE);
         let json = json!({
             "upload" : {
                 "approach" : "tus",
@@ -65,55 +82,122 @@ impl VimeoClient {
 
         // Create our request object
         let client = reqwest::Client::new();
-        let text = client
-            .post(api_endpoint)
+        let mut res = client
+            .post(&api_endpoint)
             .body(json.to_string())
             .headers(headers)
-            .send()?
-            .text()?;
+            .send()?;
+        let text = res.text()?;
+        if !res.status().is_success() {
+            let message = format!("Vimeo error creating video: {} {}", res.status(), text);
+            Err(UploadError::from_response(res.status(), res.headers(), message))?;
+        }
         let response: CreateVideoResponse = serde_json::from_str(&text)
             .map_err(|e| format_err!("create_upload_handle: {:?} {}", e, text))?;
         Ok(UploadHandle {
             url: response.upload.upload_link.parse()?,
+            uri: response.uri,
+            token: self.token.clone(),
             complete: false,
         })
     }
 
     fn default_headers(&self, size: u64) -> HeaderMap {
         let mut headers = tus::default_headers(size);
-        let mut authorization = HeaderValue::from_str(&format!("bearer {}", &self.token)).unwrap();
-        authorization.set_sensitive(true);
-        headers.insert(reqwest::header::AUTHORIZATION, authorization);
+        headers.insert(reqwest::header::AUTHORIZATION, authorization(&self.token));
         headers
     }
+
+    /// Check whether the video at `uri` still exists upstream.
+    fn video_exists(&self, uri: &str) -> Result<bool, Error> {
+        let mut res = reqwest::Client::new()
+            .get(&format!("{}{}", API_BASE, uri))
+            .header(reqwest::header::AUTHORIZATION, authorization(&self.token))
+            .header(reqwest::header::ACCEPT, "application/vnd.vimeo.*+json;version=3.4")
+            .send()?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            s if s.is_success() => Ok(true),
+            s => bail!("Vimeo error fetching {}: {} {}", uri, s, res.text()?),
+        }
+    }
+}
+
+fn authorization(token: &str) -> HeaderValue {
+    let mut authorization = HeaderValue::from_str(&format!("bearer {}", token)).unwrap();
+    authorization.set_sensitive(true);
+    authorization
 }
 
-impl StorageAdaptor<File> for VimeoClient {
-    fn already_uploaded(&self, _: &staging::UploadDescriptor) -> bool {
-        // TODO(richo) Actually figure out how to check if we've already done this
-        false
+fn delete_video(token: &str, uri: &str) -> Result<(), Error> {
+    let mut res = reqwest::Client::new()
+        .delete(&format!("{}{}", API_BASE, uri))
+        .header(reqwest::header::AUTHORIZATION, authorization(token))
+        .send()?;
+    if !res.status().is_success() && res.status() != StatusCode::NOT_FOUND {
+        bail!("Vimeo error deleting {}: {} {}", uri, res.status(), res.text()?);
+    }
+    Ok(())
+}
+
+impl<T> StorageAdaptor<T> for VimeoClient
+where
+    T: Read,
+{
+    fn already_uploaded(&self, manifest: &staging::UploadDescriptor) -> bool {
+        let uri = match self.ledger.get(&manifest.content_hash) {
+            Some(uri) => uri,
+            None => return false,
+        };
+
+        // Someone may well have deleted it since, in which case we should put it back.
+        match self.video_exists(&uri) {
+            Ok(true) => true,
+            Ok(false) => {
+                info!("{} has been deleted from vimeo", &uri);
+                if let Err(e) = self.ledger.forget(&manifest.content_hash) {
+                    warn!("Couldn't remove {} from the ledger: {:?}", &uri, e);
+                }
+                false
+            }
+            // Tokens from before we asked for the delete scope can't see it, and nor can anyone
+            // while vimeo is having a bad day. Either way we can't say it's there, and saying so
+            // would get the staged file deleted.
+            Err(e) => {
+                error!("Couldn't check on {}, assuming it's not there: {:?}", &uri, e);
+                false
+            }
+        }
     }
 
     /// Upload a file from the local filesystem to vimeo.
     fn upload(
         &self,
-        file: File,
+        reader: T,
         manifest: &staging::UploadDescriptor,
     ) -> Result<StorageStatus, Error> {
-        // First we find out how big the file is so we can create our video object upstream
-        let size = file.metadata()?.len();
+        // We need to know how big the file is to create our video object upstream
+        let size = manifest.size;
         // Then we create an upload handle
-        let handle = self.create_upload_handle(&manifest.staging_name(), size)?;
+        let mut handle = self.create_upload_handle(&manifest.staging_name(), size)?;
 
         let headers = self.default_headers(size);
         let tusclient = tus::Client::new(handle.url.clone(), headers);
-        let _sent = tusclient.upload(file)?;
+        let _sent = tusclient.upload(reader)?;
 
         // TODO(richo) look through sent and confirm it really sent
+        // Without a ledger entry we'd never find this video again, and would upload it a second
+        // time, so if we can't record it the handle deletes it on the way out.
+        self.ledger.record(&manifest.content_hash, handle.uri.clone())?;
+        handle.complete = true;
 
         Ok(StorageStatus::Success)
     }
 
+    fn remote_id(&self, manifest: &staging::UploadDescriptor) -> Option<String> {
+        self.ledger.get(&manifest.content_hash)
+    }
+
     fn name(&self) -> String {
         "vimeo".to_string()
     }
@@ -122,7 +206,11 @@ impl StorageAdaptor<File> for VimeoClient {
 impl Drop for UploadHandle {
     fn drop(&mut self) {
         if !self.complete {
-            // TODO(richo) Destroy the file handle upstream
+            // Otherwise we leave a broken video sitting on the account for every failed attempt.
+            info!("Deleting abandoned upload {}", &self.uri);
+            if let Err(e) = delete_video(&self.token, &self.uri) {
+                warn!("Couldn't delete abandoned upload {}: {:?}", &self.uri, e);
+            }
         }
     }
 }
@@ -131,12 +219,21 @@ impl Drop for UploadHandle {
 mod tests {
     use super::*;
     use std::env;
+    use std::fs::File;
+
+    fn test_client(dir: &tempfile::TempDir) -> VimeoClient {
+        let ledger = UploadLedger::load(dir.path().join("ledger.json")).expect("Couldn't load ledger");
+        VimeoClient::with_ledger(
+            env::var("STOKEPILE_TEST_VIMEO_KEY").expect("Didn't provide test key"),
+            ledger,
+        )
+    }
 
     #[test]
     #[ignore]
     fn test_creates_upload_handle() {
-        let client =
-            VimeoClient::new(env::var("STOKEPILE_TEST_VIMEO_KEY").expect("Didn't provide test key"));
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let client = test_client(&dir);
         let handle = client
             .create_upload_handle("test_video.mp4", 1024)
             .expect("Couldn't create upload handle");
@@ -150,10 +247,19 @@ mod tests {
     #[test]
     #[ignore]
     fn test_uploads_file_to_vimeo() {
-        let client =
-            VimeoClient::new(env::var("STOKEPILE_TEST_VIMEO_KEY").expect("Didn't provide test key"));
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let client = test_client(&dir);
         let fh = File::open("/tmp/test.mp4").expect("Couldn't open video");
         let desc = staging::UploadDescriptor::test_descriptor();
         client.upload(fh, &desc).expect("Could not upload file");
+        assert!(StorageAdaptor::<File>::already_uploaded(&client, &desc));
+    }
+
+    #[test]
+    fn test_not_uploaded_without_ledger_entry() {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let ledger = UploadLedger::load(dir.path().join("ledger.json")).expect("Couldn't load ledger");
+        let client = VimeoClient::with_ledger("TOKEN".into(), ledger);
+        assert!(!StorageAdaptor::<&[u8]>::already_uploaded(&client, &staging::UploadDescriptor::test_descriptor()));
     }
 }
@@ -3,9 +3,6 @@ use diesel::prelude::*;
 use super::*;
 use crate::web::schema::devices;
 
-use crate::config;
-use crate::config::{FlysightConfig, GoproConfig, MassStorageConfig, MountableDeviceLocation};
-
 #[derive(Identifiable, Queryable, Associations, Debug, Serialize)]
 #[belongs_to(User)]
 pub struct Device {
@@ -19,34 +16,6 @@ pub struct Device {
     pub identifier: String,
 }
 
-impl From<Device> for config::DeviceConfig {
-    fn from(device: Device) -> Self {
-        match &device.kind[..] {
-            "ptp" => config::DeviceConfig::Gopro(GoproConfig {
-                name: device.name,
-                serial: device.identifier,
-            }),
-            "mass_storage" => {
-                config::DeviceConfig::MassStorage(MassStorageConfig {
-                    name: device.name,
-                    // TODO(richo) add a metadata field and store this there
-                    extensions: vec!["mp4".into()],
-                    location: MountableDeviceLocation::from_label(device.identifier),
-                    cleanup_extensions: None,
-                })
-            }
-            "flysight" => config::DeviceConfig::Flysight(FlysightConfig {
-                name: device.name,
-                location: MountableDeviceLocation::from_label(device.identifier),
-            }),
-            kind => {
-                // This feels sound with the overlapping borrows, revisit?
-                config::DeviceConfig::UnknownDevice(kind.to_string())
-            }
-        }
-    }
-}
-
 impl Device {
     pub fn by_id(&self, device_id: i32, conn: &PgConnection) -> QueryResult<Device> {
         use crate::web::schema::devices::dsl::*;
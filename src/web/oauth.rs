@@ -1,6 +1,6 @@
 use oauth2::basic::BasicClient;
 use oauth2::prelude::*;
-use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, Scope, TokenUrl};
+use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, RefreshToken, Scope, TokenUrl};
 use oauth2::TokenResponse;
 
 use rocket::http::RawStr;
@@ -150,7 +150,8 @@ impl Oauth2Config {
                 .join("/integration/finish?provider=vimeo")
                 .expect("Invalid redirect URL"),
         );
-        let scopes = &["public", "upload"];
+        // We need private to check on videos we uploaded, and delete to clean up failed uploads.
+        let scopes = &["public", "private", "upload", "delete"];
         Oauth2Config {
             client_id,
             client_secret,
@@ -262,6 +263,22 @@ pub fn exchange_oauth_code<'a>(provider: &Oauth2Provider, code: &str) -> Result<
     Ok(("test_access_token".into(), Some("test_refresh_token".into())))
 }
 
+/// Exchange a stored refresh token for a fresh access token.
+#[cfg(not(test))]
+pub fn refresh_access_token(provider: &Oauth2Provider, refresh_token: &RefreshToken) -> Result<String, Error> {
+    info!("Invoked live refresh_access_token");
+    let client = provider.client();
+    client.exchange_refresh_token(refresh_token)
+        .map_err(|e| e.into())
+        .map(|token| token.access_token().secret().to_string())
+}
+
+#[cfg(test)]
+pub fn refresh_access_token(provider: &Oauth2Provider, refresh_token: &RefreshToken) -> Result<String, Error> {
+    info!("Invoked test refresh_access_token");
+    Ok("test_access_token".into())
+}
+
 impl<'v> FromFormValue<'v> for Oauth2Provider {
     type Error = String;
 
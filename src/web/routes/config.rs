@@ -5,10 +5,26 @@ use rocket::response::content::Content;
 use rocket::http::ContentType;
 use rocket::response::{Flash, Redirect};
 
-use crate::config::{Config, DeviceConfig};
+use crate::config::Config;
+use crate::device::kind_for_web;
 use crate::web::auth::AuthenticatedUser;
 use crate::web::db::DbConn;
+use crate::web::models::Integration;
 use crate::messages::Oauth2Provider;
+use crate::web::oauth::refresh_access_token;
+
+/// Google tokens only live for an hour, so hand out a fresh one if we can. The client will come
+/// back to us for another when this one expires, so if Google won't give us one right now we hand
+/// out the one we have rather than failing the whole config.
+fn fresh_token(provider: &Oauth2Provider, integration: &Integration) -> String {
+    match integration.refresh_token() {
+        Some(refresh_token) => refresh_access_token(provider, &refresh_token).unwrap_or_else(|e| {
+            warn!("Couldn't refresh the {} token for the config: {}", provider.display_name(), e);
+            integration.access_token.to_string()
+        }),
+        None => integration.access_token.to_string(),
+    }
+}
 
 #[get("/config")]
 pub fn get_config(user: AuthenticatedUser, conn: DbConn) -> Result<Content<String>, Flash<Redirect>> {
@@ -29,6 +45,8 @@ pub fn get_config(user: AuthenticatedUser, conn: DbConn) -> Result<Content<Strin
             match name {
                 "dropbox" => config = config.dropbox(token),
                 "vimeo" => config = config.vimeo(token),
+                "drive" => config = config.google_drive(fresh_token(provider, integration)),
+                "youtube" => config = config.youtube(fresh_token(provider, integration)),
                 name => {
                     warn!("Unknown integration: {}", name);
                 }
@@ -44,11 +62,9 @@ pub fn get_config(user: AuthenticatedUser, conn: DbConn) -> Result<Content<Strin
     })?;
 
     for device in devices {
-        match device.into() {
-            DeviceConfig::Gopro(gopro) => config = config.gopro(gopro),
-            DeviceConfig::Flysight(flysight) => config = config.flysight(flysight),
-            DeviceConfig::MassStorage(mass_storage) => config = config.mass_storage(mass_storage),
-            DeviceConfig::UnknownDevice(kind) => warn!("Unknown device kind: {}", kind),
+        match kind_for_web(&device.kind) {
+            Some(kind) => config = kind.add_from_web(config, device.name, device.identifier),
+            None => warn!("Unknown device kind: {}", device.kind),
         }
     }
 
@@ -96,7 +112,7 @@ mod tests {
     use crate::web::routes::settings::SettingsForm;
     use crate::web::models::extra::StagingKind;
 
-    use crate::config::{Config, FlysightConfig, MountableDeviceLocation};
+    use crate::config::{Config, FlysightConfig, GoproConfig, MassStorageConfig, MountableDeviceLocation};
 
     client_for_routes!(get_config => client);
 
@@ -148,6 +164,60 @@ mod tests {
         assert_eq!(&backend_names, &["dropbox"]);
     }
 
+    #[test]
+    fn test_get_config_with_google_drive() {
+        let client = client();
+
+        let user = create_user(&client, "test@email.com", "p@55w0rd");
+        signin(&client, "test%40email.com", "p%4055w0rd").unwrap();
+
+        {
+            let conn = db_conn(&client);
+
+            NewIntegration::new(&user, "drive", "test_oauth_token", Some("test_refresh_token"))
+                .create(&*conn)
+                .unwrap();
+        }
+
+        let req = client.get("/config");
+
+        let mut response = req.dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().expect("Didn't recieve a body");
+        // We should have handed out a freshly minted token rather than the stored one.
+        assert!(body.contains("test_access_token"));
+        let config: Config = body.parse().unwrap();
+        let backends = config.backends();
+        let backend_names: Vec<_> = backends.iter().map(|b| b.name()).collect();
+        assert_eq!(&backend_names, &["google_drive"]);
+    }
+
+    #[test]
+    fn test_get_config_with_youtube() {
+        let client = client();
+
+        let user = create_user(&client, "test@email.com", "p@55w0rd");
+        signin(&client, "test%40email.com", "p%4055w0rd").unwrap();
+
+        {
+            let conn = db_conn(&client);
+
+            NewIntegration::new(&user, "youtube", "test_oauth_token", Some("test_refresh_token"))
+                .create(&*conn)
+                .unwrap();
+        }
+
+        let req = client.get("/config");
+
+        let mut response = req.dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let config: Config =
+            response.body_string().expect("Didn't recieve a body").parse().unwrap();
+        let backends = config.backends();
+        let backend_names: Vec<_> = backends.iter().map(|b| b.name()).collect();
+        assert_eq!(&backend_names, &["youtube"]);
+    }
+
     #[test]
     fn test_get_config_with_api_token() {
         let client = client();
@@ -254,10 +324,12 @@ mod tests {
         let backend_names: Vec<_> = backends.iter().map(|b| b.name()).collect();
         assert_eq!(&backend_names, &["dropbox"]);
 
-        let empty_flysights: Vec<FlysightConfig> = vec![];
-        assert_eq!(config.flysights(), &empty_flysights);
-        assert_eq!(config.mass_storages().len(), 1);
-        assert_eq!(config.gopros().len(), 1);
+        let flysights: Vec<FlysightConfig> = config.device_entries("flysight").unwrap();
+        assert!(flysights.is_empty());
+        let mass_storages: Vec<MassStorageConfig> = config.device_entries("mass_storage").unwrap();
+        assert_eq!(mass_storages.len(), 1);
+        let gopros: Vec<GoproConfig> = config.device_entries("gopro").unwrap();
+        assert_eq!(gopros.len(), 1);
     }
 
     #[test]
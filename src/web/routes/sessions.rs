@@ -3,8 +3,7 @@ use crate::web::auth::{WebUser, ApiUser};
 use crate::web::{ROCKET_ENV, global_state};
 use crate::web::context::Context;
 use crate::messages::{self, Oauth2Provider, RefreshToken};
-use oauth2::prelude::*;
-use oauth2::TokenResponse;
+use crate::web::oauth::refresh_access_token;
 
 use rocket::http::RawStr;
 use rocket::http::{Cookie, Cookies, SameSite};
@@ -133,7 +132,6 @@ pub fn refresh_token(
     provider: Oauth2Provider,
     conn: DbConn,
 ) -> Result<Json<RefreshToken>, Json<RefreshToken>> {
-    let client = provider.client();
     let integrations = user.user.integrations(&*conn).map_err(|e| {
         Json(RefreshToken::Error(e.to_string()))
     })?;
@@ -141,13 +139,12 @@ pub fn refresh_token(
     if let Some(integration) = integrations.iter().find(|ref x| x.provider == provider.name()) {
         let refresh_token = integration.refresh_token()
             .ok_or(Json(RefreshToken::Token(integration.access_token.clone())))?;
-        // TODO(richo) definitely take this logic and put it elsewhere
-        match client.exchange_refresh_token(&refresh_token) {
+        match refresh_access_token(&provider, &refresh_token) {
             // TODO(richo) Store the updated stuff
             // Do we need to store the new refresh token somewhere?
             // We also definitely do need to cache this token since goog apparantly don't like being
             // pummeled
-            Ok(token) => Ok(Json(RefreshToken::Token(token.access_token().secret().to_owned()))),
+            Ok(token) => Ok(Json(RefreshToken::Token(token))),
             Err(error) => Ok(Json(RefreshToken::Error(error.to_string()))),
         }
     } else {
@@ -402,34 +399,34 @@ mod tests {
         );
     }
 
-    // #[test]
-    // fn test_google_credentials_refresh() {
-    //     init_env();
-    //     let client = client();
-    //     let user = create_user(&client, "test@email.com", "p@55w0rd");
-
-    //     let token = signin_api(&client, "test@email.com", "p@55w0rd")
-    //         .expect("Couldn't signin");
-
-    //     {
-    //         let conn = db_conn(&client);
-
-    //         NewIntegration::new(&user, "youtube", "test_oauth_token", Some("refresh_token"))
-    //             .create(&*conn)
-    //             .unwrap();
-    //     }
-
-    //     let mut response = client
-    //         .get("/refresh_token/youtube")
-    //         .header(ContentType::JSON)
-    //         .header(Header::new("Authorization", format!("Bearer: {}", token)))
-    //         .dispatch();
-    //     assert_eq!(response.status(), Status::Ok);
-    //     let body = &response.body_string().expect("didn't get a body");
-    //     let refresh: messages::RefreshToken =
-    //         serde_json::from_str(&body).expect("Couldn't deserialize");
-    //     assert_eq!(refresh, messages::RefreshToken::Token("test_access_token".into()));
-    // }
+    #[test]
+    fn test_google_credentials_refresh() {
+        init_env();
+        let client = client();
+        let user = create_user(&client, "test@email.com", "p@55w0rd");
+
+        let token = signin_api(&client, "test@email.com", "p@55w0rd")
+            .expect("Couldn't signin");
+
+        {
+            let conn = db_conn(&client);
+
+            NewIntegration::new(&user, "youtube", "test_oauth_token", Some("refresh_token"))
+                .create(&*conn)
+                .unwrap();
+        }
+
+        let mut response = client
+            .get("/refresh_token/youtube")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer: {}", token)))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = &response.body_string().expect("didn't get a body");
+        let refresh: messages::RefreshToken =
+            serde_json::from_str(&body).expect("Couldn't deserialize");
+        assert_eq!(refresh, messages::RefreshToken::Token("test_access_token".into()));
+    }
 
     #[test]
     fn test_refreshed_tokens_are_persisted() {
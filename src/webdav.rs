@@ -0,0 +1,567 @@
+/// A small WebDAV client, aimed mostly at Nextcloud.
+///
+/// Plain WebDAV servers get a single streamed PUT per file. When the configured url looks like a
+/// Nextcloud files endpoint (`.../remote.php/dav/files/<user>/...`) we use Nextcloud's chunking
+/// v2 protocol instead, so that large files don't have to survive one enormous request.
+///
+/// WebDAV has no notion of a content hash, so we store ours as a dead property on each file with
+/// PROPPATCH and read it back with PROPFIND to answer `already_uploaded`. Servers that don't keep
+/// dead properties will see files uploaded again, alongside the copies already there. Verifying an
+/// upload only relies on the properties every server has, so that those servers can still have
+/// their staged files cleaned up.
+use std::io::Read;
+
+use failure::Error;
+use regex::Regex;
+use reqwest;
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::{Method, StatusCode};
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use url::Url;
+
+use crate::config::WebdavConfig;
+use crate::staging;
+use crate::retry::{ErrorKind, UploadError};
+use crate::storage::{StorageAdaptor, StorageStatus};
+
+/// Nextcloud requires every chunk but the last to be at least 5mb.
+const DEFAULT_CHUNK_SIZE: u64 = 10 * 1024 * 1024;
+const STOKEPILE_NAMESPACE: &str = "https://github.com/richo/stokepile";
+
+lazy_static! {
+    static ref DESTINATION: HeaderName = HeaderName::from_static("destination");
+    static ref DEPTH: HeaderName = HeaderName::from_static("depth");
+    static ref OC_TOTAL_LENGTH: HeaderName = HeaderName::from_static("oc-total-length");
+    static ref PROPFIND: Method = Method::from_bytes(b"PROPFIND").expect("PROPFIND");
+    static ref PROPPATCH: Method = Method::from_bytes(b"PROPPATCH").expect("PROPPATCH");
+    static ref MKCOL: Method = Method::from_bytes(b"MKCOL").expect("MKCOL");
+    static ref MOVE: Method = Method::from_bytes(b"MOVE").expect("MOVE");
+}
+
+#[derive(Clone, RedactedDebug)]
+pub struct WebdavClient {
+    base: Url,
+    username: String,
+    #[redacted]
+    password: String,
+    client: reqwest::Client,
+}
+
+/// The properties we care about from a PROPFIND response.
+#[derive(Debug, Default, PartialEq)]
+struct Properties {
+    size: Option<u64>,
+    etag: Option<String>,
+    content_hash: Option<String>,
+}
+
+fn parse_properties(body: &str) -> Properties {
+    lazy_static! {
+        static ref CONTENT_LENGTH: Regex =
+            Regex::new(r"<(?:[\w-]+:)?getcontentlength(?:\s[^>]*)?>\s*(\d+)\s*</")
+                .expect("Failed to compile regex");
+        static ref ETAG: Regex =
+            Regex::new(r"<(?:[\w-]+:)?getetag(?:\s[^>]*)?>\s*([^<]*?)\s*</")
+                .expect("Failed to compile regex");
+        static ref CONTENT_HASH: Regex =
+            Regex::new(r"<(?:[\w-]+:)?content-hash(?:\s[^>]*)?>\s*([0-9a-fA-F]{64})\s*</")
+                .expect("Failed to compile regex");
+    }
+
+    Properties {
+        size: CONTENT_LENGTH
+            .captures(body)
+            .and_then(|c| c[1].parse().ok()),
+        etag: ETAG
+            .captures(body)
+            .map(|c| c[1].to_string())
+            .filter(|etag| !etag.is_empty()),
+        content_hash: CONTENT_HASH
+            .captures(body)
+            .map(|c| c[1].to_lowercase()),
+    }
+}
+
+/// The status a multistatus response gives for `property`, from the propstat it's listed in.
+///
+/// PROPPATCH and PROPFIND report each property separately like this, rather than with the status
+/// of the response as a whole.
+fn property_status(body: &str, property: &str) -> Option<u16> {
+    lazy_static! {
+        static ref PROPSTAT: Regex =
+            Regex::new(r"(?s)<(?:[\w-]+:)?propstat(?:\s[^>]*)?>(.*?)</(?:[\w-]+:)?propstat\s*>")
+                .expect("Failed to compile regex");
+        static ref STATUS: Regex =
+            Regex::new(r"<(?:[\w-]+:)?status(?:\s[^>]*)?>\s*HTTP/[\d.]+\s+(\d{3})")
+                .expect("Failed to compile regex");
+    }
+
+    let element = Regex::new(&format!(r"<(?:[\w-]+:)?{}[\s/>]", regex::escape(property)))
+        .expect("Failed to compile regex");
+    PROPSTAT
+        .captures_iter(body)
+        .filter_map(|propstat| propstat.get(1))
+        .map(|propstat| propstat.as_str())
+        .find(|propstat| element.is_match(propstat))
+        .and_then(|propstat| STATUS.captures(propstat)?[1].parse().ok())
+}
+
+/// If `base` points into a Nextcloud user's files, returns the url of their uploads collection.
+fn nextcloud_uploads_url(base: &Url) -> Option<Url> {
+    lazy_static! {
+        static ref FILES: Regex =
+            Regex::new(r"^(.*/remote\.php/dav/)files/([^/]+)/").expect("Failed to compile regex");
+    }
+
+    let captures = FILES.captures(base.path())?;
+    let mut url = base.clone();
+    url.set_path(&format!("{}uploads/{}/", &captures[1], &captures[2]));
+    Some(url)
+}
+
+/// The relative paths of every collection that needs to exist before we can upload `manifest`,
+/// from the outermost in.
+fn collections(manifest: &staging::UploadDescriptor) -> Vec<String> {
+    let remote_path = manifest.remote_path();
+    let mut out = vec![];
+    let mut path = String::new();
+    if let Some(parent) = remote_path.parent() {
+        for component in parent.iter().skip(1) {
+            path.push_str(&encode_segment(component.to_str().expect("path wasn't valid utf8")));
+            path.push('/');
+            out.push(path.clone());
+        }
+    }
+    out
+}
+
+fn encode_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string()
+}
+
+impl WebdavClient {
+    pub fn new(config: &WebdavConfig) -> Result<WebdavClient, Error> {
+        let mut base = Url::parse(&config.url)?;
+        // Without the trailing slash, joining onto this would replace the last segment.
+        if !base.path().ends_with('/') {
+            let path = format!("{}/", base.path());
+            base.set_path(&path);
+        }
+
+        Ok(WebdavClient {
+            base,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    fn file_url(&self, manifest: &staging::UploadDescriptor) -> Result<Url, Error> {
+        let remote_path = manifest.remote_path();
+        let relative: Vec<_> = remote_path
+            .iter()
+            .skip(1)
+            .map(|c| encode_segment(c.to_str().expect("path wasn't valid utf8")))
+            .collect();
+        Ok(self.base.join(&relative.join("/"))?)
+    }
+
+    fn request(&self, method: Method, url: &Url) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, url.as_str())
+            .basic_auth(&self.username, Some(&self.password))
+    }
+
+    fn send(builder: reqwest::RequestBuilder, what: &str) -> Result<reqwest::Response, Error> {
+        let mut res = builder
+            .send()
+            .map_err(|e| format_err!("HTTP error during {}: {:?}", what, e))?;
+        if !res.status().is_success() {
+            let message = format!("WebDAV error during {}: {} {}", what, res.status(), res.text()?);
+            Err(UploadError::from_response(res.status(), res.headers(), message))?;
+        }
+        Ok(res)
+    }
+
+    fn propfind(&self, url: &Url) -> Result<Option<Properties>, Error> {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:" xmlns:s="{}">
+  <d:prop><d:getcontentlength/><d:getetag/><s:content-hash/></d:prop>
+</d:propfind>"#,
+            STOKEPILE_NAMESPACE
+        );
+        let mut res = self
+            .request(PROPFIND.clone(), url)
+            .header(DEPTH.clone(), HeaderValue::from_static("0"))
+            .body(body)
+            .send()
+            .map_err(|e| format_err!("HTTP error during PROPFIND: {:?}", e))?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let text = res.text()?;
+        if res.status() != StatusCode::MULTI_STATUS {
+            let message = format!("WebDAV error during PROPFIND: {} {}", res.status(), text);
+            Err(UploadError::from_response(res.status(), res.headers(), message))?;
+        }
+        Ok(Some(parse_properties(&text)))
+    }
+
+    fn set_content_hash(&self, url: &Url, content_hash: &str) -> Result<(), Error> {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propertyupdate xmlns:d="DAV:" xmlns:s="{}">
+  <d:set><d:prop><s:content-hash>{}</s:content-hash></d:prop></d:set>
+</d:propertyupdate>"#,
+            STOKEPILE_NAMESPACE, content_hash
+        );
+        let mut res = Self::send(self.request(PROPPATCH.clone(), url).body(body), "PROPPATCH")?;
+        let text = res.text()?;
+        match property_status(&text, "content-hash") {
+            Some(200) => Ok(()),
+            _ => bail!("Server refused to store the content hash: {}", text),
+        }
+    }
+
+    /// Create every collection above `manifest`'s final location that doesn't already exist.
+    fn create_collections(&self, manifest: &staging::UploadDescriptor) -> Result<(), Error> {
+        for collection in collections(manifest) {
+            let url = self.base.join(&collection)?;
+            let mut res = self
+                .request(MKCOL.clone(), &url)
+                .send()
+                .map_err(|e| format_err!("HTTP error during MKCOL: {:?}", e))?;
+            match res.status() {
+                // 405 is what we get if the collection is already there.
+                s if s.is_success() || s == StatusCode::METHOD_NOT_ALLOWED => {}
+                s => {
+                    let message = format!("WebDAV error creating {}: {} {}", url, s, res.text()?);
+                    Err(UploadError::from_response(s, res.headers(), message))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn upload_chunks<T: Read>(
+        &self,
+        reader: &mut T,
+        transfer: &Url,
+        destination: &HeaderValue,
+        total_length: &HeaderValue,
+    ) -> Result<(), Error> {
+        let mut chunk_number = 1;
+        loop {
+            let mut buffer = Vec::with_capacity(DEFAULT_CHUNK_SIZE as usize);
+            let read_bytes = reader.by_ref().take(DEFAULT_CHUNK_SIZE).read_to_end(&mut buffer)?;
+            // We always send at least one chunk, even if it's empty.
+            if read_bytes == 0 && chunk_number > 1 {
+                break;
+            }
+            // Nextcloud assembles chunks in lexical order, so these need to be zero padded.
+            let url = transfer.join(&format!("{:05}", chunk_number))?;
+            trace!("Uploading chunk {} to {}", chunk_number, url);
+            Self::send(
+                self.request(Method::PUT, &url)
+                    .header(DESTINATION.clone(), destination.clone())
+                    .header(OC_TOTAL_LENGTH.clone(), total_length.clone())
+                    .body(buffer),
+                "chunk upload",
+            )?;
+            chunk_number += 1;
+            if (read_bytes as u64) < DEFAULT_CHUNK_SIZE {
+                break;
+            }
+        }
+
+        let assembled = transfer.join(".file")?;
+        Self::send(
+            self.request(MOVE.clone(), &assembled)
+                .header(DESTINATION.clone(), destination.clone())
+                .header(OC_TOTAL_LENGTH.clone(), total_length.clone()),
+            "chunk assembly",
+        )?;
+        Ok(())
+    }
+
+    fn upload_nextcloud<T: Read>(
+        &self,
+        reader: &mut T,
+        uploads: &Url,
+        url: &Url,
+        manifest: &staging::UploadDescriptor,
+    ) -> Result<(), Error> {
+        let transfer = uploads.join(&format!(
+            "stokepile-{}/",
+            hex::encode(&manifest.content_hash[..8])
+        ))?;
+        let destination = HeaderValue::from_str(url.as_str())?;
+        let total_length = HeaderValue::from_str(&manifest.size.to_string())?;
+
+        // Anything left over from an earlier attempt would get assembled along with our chunks.
+        let _ = self.request(Method::DELETE, &transfer).send();
+        Self::send(
+            self.request(MKCOL.clone(), &transfer)
+                .header(DESTINATION.clone(), destination.clone()),
+            "MKCOL",
+        )?;
+
+        let result = self.upload_chunks(reader, &transfer, &destination, &total_length);
+        if result.is_err() {
+            if let Err(e) = self.request(Method::DELETE, &transfer).send() {
+                warn!("Couldn't clean up transfer {}: {:?}", &transfer, e);
+            }
+        }
+        result
+    }
+}
+
+impl<T> StorageAdaptor<T> for WebdavClient
+where
+    T: Read + Send + 'static,
+{
+    fn already_uploaded(&self, manifest: &staging::UploadDescriptor) -> bool {
+        let url = match self.file_url(manifest) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        match self.propfind(&url) {
+            Ok(Some(properties)) => {
+                properties.size == Some(manifest.size) &&
+                    properties.content_hash == Some(hex::encode(manifest.content_hash))
+            }
+            _ => false,
+        }
+    }
+
+    fn upload(
+        &self,
+        mut reader: T,
+        manifest: &staging::UploadDescriptor,
+    ) -> Result<StorageStatus, Error> {
+        let url = self.file_url(manifest)?;
+        // A PUT replaces whatever's there, which is only ours to replace if it has our content hash.
+        let hash = hex::encode(manifest.content_hash);
+        if let Some(properties) = self.propfind(&url)? {
+            if properties.content_hash.as_ref() != Some(&hash) {
+                let message = format!("{} is already a different file, not overwriting it", &url);
+                Err(UploadError::new(ErrorKind::Conflict, message))?;
+            }
+        }
+        self.create_collections(manifest)?;
+
+        match nextcloud_uploads_url(&self.base) {
+            Some(uploads) => self.upload_nextcloud(&mut reader, &uploads, &url, manifest)?,
+            None => {
+                Self::send(
+                    self.request(Method::PUT, &url).body(reqwest::Body::new(reader)),
+                    "PUT",
+                )?;
+            }
+        }
+
+        // The upload itself worked, so don't fail it over this. If it's ever uploaded again it'll
+        // go alongside this rather than replacing it.
+        if let Err(e) = self.set_content_hash(&url, &hash) {
+            warn!("Couldn't store content hash for {}: {:?}", &url, e);
+        }
+        Ok(StorageStatus::Success)
+    }
+
+    /// Check the server has a file of the right size where we put it. Unlike `already_uploaded`
+    /// this doesn't need the content hash property, which not every server will keep.
+    fn verify(&self, manifest: &staging::UploadDescriptor) -> Result<(), Error> {
+        let url = self.file_url(manifest)?;
+        let properties = match self.propfind(&url)? {
+            Some(properties) => properties,
+            None => bail!("{} doesn't exist after uploading it", &url),
+        };
+        if properties.size != Some(manifest.size) {
+            bail!("{} is {:?} bytes but we uploaded {}", &url, properties.size, manifest.size);
+        }
+        match properties.etag {
+            Some(etag) => debug!("Verified {} with etag {}", &url, etag),
+            None => debug!("Verified {}, which has no etag", &url),
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        "webdav".to_string()
+    }
+
+    fn id(&self) -> String {
+        format!("webdav:{}@{}", &self.username, &self.base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn test_client(url: &str) -> WebdavClient {
+        WebdavClient::new(&WebdavConfig {
+            url: url.into(),
+            username: "richo".into(),
+            password: "hunter2".into(),
+        })
+        .expect("Couldn't create client")
+    }
+
+    #[test]
+    fn test_file_url() {
+        let client = test_client("https://cloud.example.org/remote.php/dav/files/richo/footage");
+        let mut manifest = staging::UploadDescriptor::test_descriptor();
+        assert_eq!(
+            client.file_url(&manifest).unwrap().as_str(),
+            "https://cloud.example.org/remote.php/dav/files/richo/footage/2018/08/26/test-device/14-30-00.mp4"
+        );
+
+        manifest.device_name = "front cam#2".into();
+        assert_eq!(
+            client.file_url(&manifest).unwrap().as_str(),
+            "https://cloud.example.org/remote.php/dav/files/richo/footage/2018/08/26/front%20cam%232/14-30-00.mp4"
+        );
+    }
+
+    #[test]
+    fn test_collections() {
+        let manifest = staging::UploadDescriptor::test_descriptor();
+        assert_eq!(
+            collections(&manifest),
+            vec!["2018/", "2018/08/", "2018/08/26/", "2018/08/26/test-device/"]
+        );
+    }
+
+    #[test]
+    fn test_detects_nextcloud() {
+        let base = Url::parse("https://cloud.example.org/nc/remote.php/dav/files/richo/footage/").unwrap();
+        assert_eq!(
+            nextcloud_uploads_url(&base).unwrap().as_str(),
+            "https://cloud.example.org/nc/remote.php/dav/uploads/richo/"
+        );
+
+        let base = Url::parse("http://localhost:8080/webdav/").unwrap();
+        assert_eq!(nextcloud_uploads_url(&base), None);
+    }
+
+    #[test]
+    fn test_parses_propfind() {
+        let body = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:x1="https://github.com/richo/stokepile">
+  <d:response>
+    <d:href>/remote.php/dav/files/richo/footage/2018/08/26/test-device/14-30-00.mp4</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:getcontentlength>9</d:getcontentlength>
+        <d:getetag>&quot;5c5e2b4a1b0f6&quot;</d:getetag>
+        <x1:content-hash>4242424242424242424242424242424242424242424242424242424242424242</x1:content-hash>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        assert_eq!(parse_properties(body), Properties {
+            size: Some(9),
+            etag: Some("&quot;5c5e2b4a1b0f6&quot;".into()),
+            content_hash: Some("42".repeat(32)),
+        });
+    }
+
+    #[test]
+    fn test_parses_propfind_without_hash() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+  <D:response>
+    <D:href>/webdav/2018/08/26/test-device/14-30-00.mp4</D:href>
+    <D:propstat>
+      <D:prop><D:getcontentlength>1024</D:getcontentlength></D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+    <D:propstat>
+      <D:prop><content-hash xmlns="https://github.com/richo/stokepile"/></D:prop>
+      <D:status>HTTP/1.1 404 Not Found</D:status>
+    </D:propstat>
+  </D:response>
+</D:multistatus>"#;
+        assert_eq!(parse_properties(body), Properties {
+            size: Some(1024),
+            etag: None,
+            content_hash: None,
+        });
+    }
+
+    #[test]
+    fn test_parses_proppatch_status() {
+        let stored = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:x1="https://github.com/richo/stokepile">
+  <d:response>
+    <d:href>/remote.php/dav/files/richo/footage/2018/08/26/test-device/14-30-00.mp4</d:href>
+    <d:propstat>
+      <d:prop><x1:content-hash/></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        assert_eq!(property_status(stored, "content-hash"), Some(200));
+
+        // A 200 for some other property doesn't mean ours was stored.
+        let refused = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+  <D:response>
+    <D:href>/webdav/2018/08/26/test-device/14-30-00.mp4</D:href>
+    <D:propstat>
+      <D:prop><D:displayname/></D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+    <D:propstat>
+      <D:prop><content-hash xmlns="https://github.com/richo/stokepile"/></D:prop>
+      <D:status>HTTP/1.1 403 Forbidden</D:status>
+    </D:propstat>
+  </D:response>
+</D:multistatus>"#;
+        assert_eq!(property_status(refused, "content-hash"), Some(403));
+        assert_eq!(property_status(refused, "getetag"), None);
+    }
+
+    fn local_client() -> WebdavClient {
+        WebdavClient::new(&WebdavConfig {
+            url: env::var("STOKEPILE_TEST_WEBDAV_URL").expect("Didn't provide test url"),
+            username: env::var("STOKEPILE_TEST_WEBDAV_USER").expect("Didn't provide test user"),
+            password: env::var("STOKEPILE_TEST_WEBDAV_PASSWORD").expect("Didn't provide test password"),
+        })
+        .expect("Couldn't create client")
+    }
+
+    #[test]
+    #[ignore]
+    fn test_uploads_large_file() {
+        let client = local_client();
+        let localfile =
+            fs::File::open("/usr/share/dict/web2").expect("Couldn't open dummy dictionary");
+        if let Err(e) = client.upload(localfile, &staging::UploadDescriptor::test_descriptor()) {
+            panic!("{:?}", e);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_roundtripped_content_hash_works() {
+        let client = local_client();
+        let localfile = b"yes hello";
+        let mut manifest = staging::UploadDescriptor::test_descriptor();
+        manifest.size = localfile.len() as u64;
+        manifest.content_hash = [0x42; 32];
+
+        if let Err(e) = client.upload(&localfile[..], &manifest) {
+            panic!("{:?}", e);
+        }
+        assert!(StorageAdaptor::<&[u8]>::already_uploaded(&client, &manifest));
+
+        manifest.content_hash = [0x41; 32];
+        assert!(!StorageAdaptor::<&[u8]>::already_uploaded(&client, &manifest));
+    }
+}
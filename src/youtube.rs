@@ -0,0 +1,180 @@
+/// A client for uploading videos to YouTube.
+///
+/// The token we get from google is only good for uploading, so we can't go and ask youtube what's
+/// already on the channel. Instead we keep a ledger of the videos we've uploaded, keyed by content
+/// hash, which is what `already_uploaded` consults.
+use std::io::Read;
+
+use failure::Error;
+use reqwest;
+use serde_json;
+
+use crate::config::YoutubePrivacy;
+use crate::google::{self, GoogleToken};
+use crate::ledger::UploadLedger;
+use crate::staging;
+use crate::storage::{StorageAdaptor, StorageStatus};
+
+const UPLOAD_ENDPOINT: &str =
+    "https://www.googleapis.com/upload/youtube/v3/videos?uploadType=resumable&part=snippet,status";
+
+/// Anything else (flysight logs, photos) is of no interest to youtube.
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "mov", "m4v", "avi", "mts", "mkv", "webm", "mpg", "mpeg"];
+
+#[derive(Debug)]
+pub struct YoutubeClient {
+    token: GoogleToken,
+    privacy: YoutubePrivacy,
+    client: reqwest::Client,
+    ledger: UploadLedger,
+}
+
+#[derive(Deserialize, Debug)]
+struct VideoResponse {
+    id: String,
+}
+
+fn is_video(manifest: &staging::UploadDescriptor) -> bool {
+    manifest
+        .extension()
+        .map(|ext| VIDEO_EXTENSIONS.contains(&&ext.to_lowercase()[..]))
+        .unwrap_or(false)
+}
+
+impl YoutubeClient {
+    pub fn new(token: GoogleToken, privacy: YoutubePrivacy) -> Result<YoutubeClient, Error> {
+        YoutubeClient::with_ledger(token, privacy, UploadLedger::for_service("youtube")?)
+    }
+
+    fn with_ledger(token: GoogleToken, privacy: YoutubePrivacy, ledger: UploadLedger) -> Result<YoutubeClient, Error> {
+        Ok(YoutubeClient {
+            token,
+            privacy,
+            client: google::http_client()?,
+            ledger,
+        })
+    }
+}
+
+impl<T> StorageAdaptor<T> for YoutubeClient
+where
+    T: Read,
+{
+    fn already_uploaded(&self, manifest: &staging::UploadDescriptor) -> bool {
+        self.ledger.get(&manifest.content_hash).is_some()
+    }
+
+    fn upload(
+        &self,
+        mut reader: T,
+        manifest: &staging::UploadDescriptor,
+    ) -> Result<StorageStatus, Error> {
+        if !is_video(manifest) {
+            return Ok(StorageStatus::Skipped);
+        }
+
+        let metadata = json!({
+            "snippet": {
+                "title": manifest.staging_name(),
+            },
+            "status": {
+                "privacyStatus": self.privacy.as_str(),
+            },
+        });
+        let session = google::start_resumable_upload(
+            &self.token,
+            &self.client,
+            UPLOAD_ENDPOINT,
+            &metadata,
+            manifest.size,
+        )?;
+        let text = google::upload_resumable(&self.token, &self.client, &mut reader, &session)?;
+        let video: VideoResponse = serde_json::from_str(&text)
+            .map_err(|e| format_err!("upload: {:?} {}", e, text))?;
+        info!("Uploaded {} as https://youtu.be/{}", manifest.staging_name(), &video.id);
+
+        self.ledger.record(&manifest.content_hash, video.id)?;
+        Ok(StorageStatus::Success)
+    }
+
+    fn remote_id(&self, manifest: &staging::UploadDescriptor) -> Option<String> {
+        self.ledger.get(&manifest.content_hash)
+    }
+
+    fn name(&self) -> String {
+        "youtube".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use crate::path_template::PathTemplate;
+    use crate::staging::RemotePathDescriptor;
+
+    fn ledger(dir: &tempfile::TempDir) -> UploadLedger {
+        UploadLedger::load(dir.path().join("ledger.json")).expect("Couldn't load ledger")
+    }
+
+    #[test]
+    fn test_only_videos_are_uploaded() {
+        let mut manifest = staging::UploadDescriptor::test_descriptor();
+        assert!(is_video(&manifest));
+
+        if let RemotePathDescriptor::DateTime { ref mut extension, .. } = manifest.path {
+            *extension = "MP4".into();
+        }
+        assert!(is_video(&manifest));
+
+        if let RemotePathDescriptor::DateTime { ref mut extension, .. } = manifest.path {
+            *extension = "csv".into();
+        }
+        assert!(!is_video(&manifest));
+
+        // Wherever a template puts it, it's still the same kind of file.
+        let mut manifest = staging::UploadDescriptor::test_descriptor();
+        manifest.template = Some(PathTemplate::parse("/{device}/{filename}-{hash}.video").unwrap());
+        assert!(is_video(&manifest));
+    }
+
+    #[test]
+    fn test_skips_non_video_files() {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let token = GoogleToken::new("youtube", "TOKEN".into(), None);
+        let client = YoutubeClient::with_ledger(token, YoutubePrivacy::Unlisted, ledger(&dir))
+            .expect("Couldn't create client");
+        let mut manifest = staging::UploadDescriptor::test_descriptor();
+        if let RemotePathDescriptor::DateTime { ref mut extension, .. } = manifest.path {
+            *extension = "csv".into();
+        }
+
+        match client.upload(&b"time,lat,lon"[..], &manifest) {
+            Ok(StorageStatus::Skipped) => {},
+            other => panic!("Expected the upload to be skipped, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_uploads_video() {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir");
+        let token = GoogleToken::new(
+            "youtube",
+            env::var("STOKEPILE_TEST_YOUTUBE_TOKEN").expect("Didn't provide test token"),
+            None,
+        );
+        let client = YoutubeClient::with_ledger(token, YoutubePrivacy::Private, ledger(&dir))
+            .expect("Couldn't create client");
+        let localfile = File::open(env::var("STOKEPILE_TEST_YOUTUBE_VIDEO").expect("Didn't provide test video"))
+            .expect("Couldn't open test video");
+        let mut manifest = staging::UploadDescriptor::test_descriptor();
+        manifest.content_hash = [0x42; 32];
+
+        if let Err(e) = client.upload(localfile, &manifest) {
+            panic!("{:?}", e);
+        }
+        assert!(StorageAdaptor::<File>::already_uploaded(&client, &manifest));
+    }
+}